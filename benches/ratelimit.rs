@@ -0,0 +1,167 @@
+//! Rate limiter hot path: per-request checks, key construction and blocked-IP lookups
+//!
+//! Run with `cargo bench --bench ratelimit`; reports land in `target/criterion/`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use pingwall::ratelimit::limiter::{self, BlockedIps, RequestContext};
+use pingwall::utils::cloudflare::CloudflareContext;
+use pingwall::utils::useragent::UserAgentInfo;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Distinct client IPs cycled through; each stays well under the limit in a window
+const CLIENTS: usize = 10_000;
+
+/// Blocked IPs in the map for the lookup benchmark
+const BLOCKED: usize = 10_000;
+
+/// Threads calling is_blocked at once in the contention benchmark
+const READERS: usize = 8;
+
+fn client_ip(i: usize) -> String {
+    format!("10.{}.{}.{}", (i >> 16) & 0xff, (i >> 8) & 0xff, i & 0xff)
+}
+
+fn request_context(ip: String) -> RequestContext {
+    RequestContext {
+        ip,
+        path: "/api".to_string(),
+        domain: Some("api.example.com".to_string()),
+        cloudflare: CloudflareContext {
+            country: Some("US".to_string()),
+            asn: Some("15169".to_string()),
+            ..Default::default()
+        },
+        user_agent: UserAgentInfo::from_string(
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36",
+        ),
+        cookies: HashMap::from([("session".to_string(), "abc123".to_string())]),
+        referer_host: None,
+        header_names: HashSet::new(),
+        client_cert: None,
+        http_version: http::Version::HTTP_11,
+        jwt_claim: None,
+        request_path: "/api/v1/users/42".to_string(),
+        path_depth: None,
+    }
+}
+
+fn bench_check_and_increment(c: &mut Criterion) {
+    limiter::init_globals_with_window(1_000_000, 60, 1);
+    let ips: Vec<String> = (0..CLIENTS).map(client_ip).collect();
+
+    let mut i = 0;
+    c.bench_function("check_and_increment", |b| {
+        b.iter(|| {
+            i = (i + 1) % ips.len();
+            black_box(limiter::check_and_increment(&ips[i], "/api", Some("api.example.com")))
+        })
+    });
+}
+
+fn bench_check_dimension_limit_with_window(c: &mut Criterion) {
+    let contexts: Vec<RequestContext> = (0..1000).map(|i| request_context(client_ip(i))).collect();
+
+    let mut group = c.benchmark_group("check_dimension_limit_with_window");
+    for dimension in ["ip", "country", "user_agent", "cookie_session"] {
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(dimension), &dimension, |b, dimension| {
+            b.iter(|| {
+                i = (i + 1) % contexts.len();
+                black_box(limiter::check_dimension_limit_with_window(&contexts[i], dimension, 100, 60, Some(300)))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_is_blocked(c: &mut Criterion) {
+    // block_ip also refreshes the blocked gauge, so filling the map takes a few seconds
+    for n in 0..BLOCKED {
+        limiter::block_ip(&format!("192.0.{}.{}", n >> 8, n & 0xff), "/api", Some("api.example.com"), None);
+    }
+
+    // Realistic mix: nine in ten lookups are for clients that are not blocked
+    let lookups: Vec<String> = (0..1000)
+        .map(|n| if n % 10 == 0 { format!("192.0.{}.{}", n >> 8, n & 0xff) } else { client_ip(n) })
+        .collect();
+
+    let mut i = 0;
+    c.bench_function("is_blocked/10k_blocked", |b| {
+        b.iter(|| {
+            i = (i + 1) % lookups.len();
+            black_box(limiter::is_blocked(&lookups[i]))
+        })
+    });
+}
+
+/// is_blocked from several threads while another keeps blocking IPs, as under a
+/// distributed attack: one shard (the old single map) against the sharded map
+fn bench_blocked_ips_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("blocked_ips_contention");
+    for shards in [1, 16] {
+        let blocked = BlockedIps::new(shards);
+        for n in 0..BLOCKED {
+            blocked.block(&format!("192.0.{}.{}", n >> 8, n & 0xff), u64::MAX, "api.example.com:/api".to_string());
+        }
+        let lookups: Vec<String> = (0..1000).map(client_ip).collect();
+
+        group.bench_with_input(BenchmarkId::from_parameter(shards), &shards, |b, _| {
+            b.iter_custom(|iters| {
+                let done = AtomicBool::new(false);
+                std::thread::scope(|scope| {
+                    scope.spawn(|| {
+                        let mut n = 0usize;
+                        while !done.load(Ordering::Relaxed) {
+                            blocked.block(&format!("198.18.{}.{}", (n >> 8) & 0xff, n & 0xff), u64::MAX, String::new());
+                            n += 1;
+                        }
+                    });
+
+                    let start = Instant::now();
+                    let readers: Vec<_> = (0..READERS)
+                        .map(|t| {
+                            let (blocked, lookups) = (&blocked, &lookups);
+                            scope.spawn(move || {
+                                for i in 0..iters as usize {
+                                    black_box(blocked.is_blocked(&lookups[(i + t) % lookups.len()], 0));
+                                }
+                            })
+                        })
+                        .collect();
+                    readers.into_iter().for_each(|reader| reader.join().unwrap());
+                    let elapsed = start.elapsed();
+                    done.store(true, Ordering::Relaxed);
+                    // Time per lookup across all reader threads
+                    Duration::from_secs_f64(elapsed.as_secs_f64() / READERS as f64)
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_create_key(c: &mut Criterion) {
+    let context = request_context(client_ip(7));
+    let depth_context = context.with_path_depth(2);
+
+    let mut group = c.benchmark_group("create_key");
+    for dimension in ["ip", "asn", "user_agent_pattern_facebook", "cookie_session"] {
+        group.bench_with_input(BenchmarkId::from_parameter(dimension), &dimension, |b, dimension| {
+            b.iter(|| black_box(context.create_key(dimension)))
+        });
+    }
+    group.bench_function("ip_path_depth", |b| b.iter(|| black_box(depth_context.create_key("ip"))));
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_check_and_increment,
+    bench_check_dimension_limit_with_window,
+    bench_is_blocked,
+    bench_blocked_ips_contention,
+    bench_create_key
+);
+criterion_main!(benches);
@@ -0,0 +1,225 @@
+use crate::metrics;
+use crate::proxy::sni_handler;
+use crate::utils::secret::constant_time_eq;
+use async_trait::async_trait;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_limits::rate::Rate;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Admin HTTP API, protected by a bearer token
+///
+/// Endpoints:
+/// - `POST /reload-certs`: drop cached certificates so the next handshake re-reads them from disk
+pub struct AdminService {
+    port: u16,
+    token: Arc<String>,
+    rate_limit: Arc<AdminRateLimit>,
+}
+
+impl AdminService {
+    pub fn new(port: u16, token: String, max_req_per_minute: isize) -> Self {
+        Self {
+            port,
+            token: Arc::new(token),
+            rate_limit: Arc::new(AdminRateLimit::new(max_req_per_minute)),
+        }
+    }
+}
+
+/// Requests per minute accepted by the admin API
+///
+/// Requests with a valid token and failed authentication attempts each get their own
+/// budget, so a runaway script (or token guessing) is throttled without locking the
+/// operator out of `/reload-certs`.
+pub struct AdminRateLimit {
+    max_per_minute: isize,
+    rate: Rate,
+}
+
+impl AdminRateLimit {
+    pub fn new(max_per_minute: isize) -> Self {
+        Self { max_per_minute, rate: Rate::new(Duration::from_secs(60)) }
+    }
+
+    fn allow(&self, authorized: bool) -> bool {
+        let budget = if authorized { "authorized" } else { "unauthorized" };
+        self.max_per_minute <= 0 || self.rate.observe(&budget, 1) <= self.max_per_minute
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AdminService {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        if self.token.is_empty() {
+            log::error!("Admin API not started: admin.token must be set");
+            return;
+        }
+
+        let addr = ([127, 0, 0, 1], self.port);
+
+        log::info!("Starting admin API on 127.0.0.1:{}", self.port);
+
+        let token = self.token.clone();
+        let rate_limit = self.rate_limit.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let token = token.clone();
+            let rate_limit = rate_limit.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+                    let token = token.clone();
+                    let rate_limit = rate_limit.clone();
+                    async move { Ok::<_, hyper::Error>(admin_handler(req, &token, &rate_limit).await) }
+                }))
+            }
+        });
+
+        let server = hyper::Server::bind(&addr.into())
+            .serve(make_service);
+
+        if let Err(e) = server.await {
+            log::error!("Admin server error: {}", e);
+        }
+    }
+}
+
+/// Route an admin request; every endpoint requires `Authorization: Bearer <token>`
+pub async fn admin_handler(req: Request<Body>, token: &str, rate_limit: &AdminRateLimit) -> Response<Body> {
+    let path = match req.uri().path() {
+        "/reload-certs" => "/reload-certs",
+        _ => "other",
+    };
+    let response = handle(req, token, rate_limit);
+    metrics::record_internal_request(path, response.status().as_u16());
+    response
+}
+
+fn handle(req: Request<Body>, token: &str, rate_limit: &AdminRateLimit) -> Response<Body> {
+    let authorized = is_authorized(&req, token);
+    if !rate_limit.allow(authorized) {
+        log::warn!(
+            "Admin API: rate limit exceeded ({}), rejecting {} {}",
+            if authorized { "authorized" } else { "unauthorized" }, req.method(), req.uri().path()
+        );
+        return text_response(StatusCode::TOO_MANY_REQUESTS, "too many requests\n");
+    }
+
+    if !authorized {
+        return text_response(StatusCode::UNAUTHORIZED, "unauthorized\n");
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/reload-certs") => {
+            let cleared = sni_handler::clear_cert_cache();
+            log::info!("Admin API: certificate reload requested ({} cached certificate(s) dropped)", cleared);
+            text_response(StatusCode::OK, &format!("cleared {} cached certificate(s)\n", cleared))
+        }
+        _ => text_response(StatusCode::NOT_FOUND, "not found\n"),
+    }
+}
+
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let provided = req
+        .headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) => !token.is_empty() && constant_time_eq(provided.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call_limited(req: Request<Body>, rate_limit: &AdminRateLimit) -> Response<Body> {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(admin_handler(req, "s3cret", rate_limit))
+    }
+
+    fn call(req: Request<Body>) -> Response<Body> {
+        call_limited(req, &AdminRateLimit::new(0))
+    }
+
+    fn reload_request(auth: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(Method::POST).uri("/reload-certs");
+        if let Some(auth) = auth {
+            builder = builder.header("Authorization", auth);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_reload_certs_requires_token() {
+        assert_eq!(call(reload_request(None)).status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call(reload_request(Some("Bearer wrong"))).status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_excessive_admin_requests_are_rejected() {
+        let rate_limit = AdminRateLimit::new(2);
+
+        assert_eq!(call_limited(reload_request(Some("Bearer s3cret")), &rate_limit).status(), StatusCode::OK);
+        assert_eq!(call_limited(reload_request(Some("Bearer s3cret")), &rate_limit).status(), StatusCode::OK);
+        assert_eq!(
+            call_limited(reload_request(Some("Bearer s3cret")), &rate_limit).status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_failed_attempts_do_not_lock_out_the_operator() {
+        let rate_limit = AdminRateLimit::new(2);
+
+        assert_eq!(call_limited(reload_request(None), &rate_limit).status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_limited(reload_request(Some("Bearer wrong")), &rate_limit).status(), StatusCode::UNAUTHORIZED);
+        for _ in 0..10 {
+            let status = call_limited(reload_request(Some("Bearer guess")), &rate_limit).status();
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        assert_eq!(call_limited(reload_request(Some("Bearer s3cret")), &rate_limit).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_reload_certs_rereads_cert_from_disk() {
+        let dir = std::env::temp_dir().join(format!("pingwall-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+
+        std::fs::write(cert, "old cert").unwrap();
+        std::fs::write(key, "old key").unwrap();
+        assert_eq!(sni_handler::load_cert_bytes(cert, key).unwrap().0, b"old cert");
+
+        // Renewed on disk, but the cache still serves the old bytes
+        std::fs::write(cert, "new cert").unwrap();
+        std::fs::write(key, "new key").unwrap();
+        assert_eq!(sni_handler::load_cert_bytes(cert, key).unwrap().0, b"old cert");
+
+        assert_eq!(call(reload_request(Some("Bearer s3cret"))).status(), StatusCode::OK);
+
+        // Next handshake loads the renewed certificate
+        let (cert_bytes, key_bytes) = sni_handler::load_cert_bytes(cert, key).unwrap();
+        assert_eq!(cert_bytes, b"new cert");
+        assert_eq!(key_bytes, b"new key");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
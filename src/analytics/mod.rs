@@ -0,0 +1,201 @@
+use crate::config::{AnalyticsConfig, AnalyticsSink};
+use crate::metrics;
+use crate::utils::sampler::Sampler;
+use async_trait::async_trait;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// One sampled request, built from the request context in the `logging` hook
+#[derive(Debug, Serialize)]
+pub struct AnalyticsRecord<'a> {
+    pub timestamp: String,
+    pub client_ip: Option<&'a str>,
+    pub method: &'a str,
+    pub scheme: &'a str,
+    pub host: &'a str,
+    pub route: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub duration_ms: u128,
+    pub upstream: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub action: &'a str,
+    pub reason_code: Option<&'a str>,
+}
+
+/// Samples request records and queues them for the sink without blocking the request
+pub struct Analytics {
+    sender: mpsc::Sender<String>,
+    sampler: Sampler,
+    percentage: f64,
+    fields: Vec<String>,
+}
+
+impl Analytics {
+    /// Create the sampler and the background service that drains its queue into the sink
+    pub fn new(config: &AnalyticsConfig) -> (Self, AnalyticsService) {
+        let (sender, receiver) = mpsc::channel(config.queue_size.max(1));
+        let analytics = Self {
+            sender,
+            sampler: Sampler::new(),
+            percentage: config.sample_rate * 100.0,
+            fields: config.fields.clone(),
+        };
+        let service = AnalyticsService {
+            receiver: Mutex::new(Some(receiver)),
+            sink: config.sink.clone(),
+        };
+        (analytics, service)
+    }
+
+    /// Queue a record if it is sampled. Never waits: a full queue drops the record
+    pub fn record(&self, record: &AnalyticsRecord) {
+        if !self.sampler.sample(self.percentage) {
+            return;
+        }
+
+        let line = match serde_json::to_value(record) {
+            Ok(Value::Object(map)) => Value::Object(self.select_fields(map)).to_string(),
+            _ => return,
+        };
+
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => metrics::record_analytics_dropped("queue_full"),
+            Err(TrySendError::Closed(_)) => metrics::record_analytics_dropped("sink_closed"),
+        }
+    }
+
+    /// Keep only the configured fields (all of them when none are configured)
+    fn select_fields(&self, mut map: Map<String, Value>) -> Map<String, Value> {
+        if !self.fields.is_empty() {
+            map.retain(|name, _| self.fields.iter().any(|field| field == name));
+        }
+        map
+    }
+}
+
+/// Drains sampled records into the configured sink
+pub struct AnalyticsService {
+    receiver: Mutex<Option<mpsc::Receiver<String>>>,
+    sink: AnalyticsSink,
+}
+
+#[async_trait]
+impl BackgroundService for AnalyticsService {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        let Some(mut receiver) = self.receiver.lock().unwrap().take() else {
+            return;
+        };
+
+        match &self.sink {
+            AnalyticsSink::File { path } => {
+                let mut file = match std::fs::OpenOptions::new().create(true).append(true).open(path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        log::error!("Analytics disabled: cannot open {}: {}", path, e);
+                        return;
+                    }
+                };
+                log::info!("Writing sampled analytics records to {}", path);
+
+                while let Some(line) = receiver.recv().await {
+                    if let Err(e) = writeln!(file, "{}", line) {
+                        log::warn!("Failed to write analytics record to {}: {}", path, e);
+                        metrics::record_analytics_dropped("sink_error");
+                    }
+                }
+            }
+            AnalyticsSink::Http { url } => {
+                let client = reqwest::Client::builder()
+                    .timeout(Duration::from_secs(5))
+                    .build()
+                    .unwrap_or_else(|_| reqwest::Client::new());
+                log::info!("Sending sampled analytics records to {}", url);
+
+                while let Some(line) = receiver.recv().await {
+                    let result = client
+                        .post(url)
+                        .header("Content-Type", "application/json")
+                        .body(line)
+                        .send()
+                        .await;
+                    if let Err(e) = result {
+                        log::debug!("Failed to send analytics record to {}: {}", url, e);
+                        metrics::record_analytics_dropped("sink_error");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(sample_rate: f64, queue_size: usize, fields: &[&str]) -> AnalyticsConfig {
+        AnalyticsConfig {
+            sink: AnalyticsSink::File { path: "/dev/null".to_string() },
+            sample_rate,
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            queue_size,
+        }
+    }
+
+    fn record() -> AnalyticsRecord<'static> {
+        AnalyticsRecord {
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            client_ip: Some("192.0.2.1"),
+            method: "GET",
+            scheme: "https",
+            host: "example.com",
+            route: "/api",
+            path: "/api/users",
+            status: 200,
+            duration_ms: 4,
+            upstream: Some("10.0.0.5:8000"),
+            user_agent: None,
+            action: "allowed",
+            reason_code: None,
+        }
+    }
+
+    fn drain(service: &AnalyticsService) -> Vec<String> {
+        let mut receiver = service.receiver.lock().unwrap();
+        let receiver = receiver.as_mut().unwrap();
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn test_sample_rate_of_requests_emitted() {
+        let (analytics, service) = Analytics::new(&config(0.25, 1000, &["path", "status"]));
+        for _ in 0..400 {
+            analytics.record(&record());
+        }
+
+        let emitted = drain(&service);
+        assert_eq!(emitted.len(), 100);
+        assert_eq!(emitted[0], r#"{"path":"/api/users","status":200}"#);
+    }
+
+    #[test]
+    fn test_queue_overflow_drops_instead_of_blocking() {
+        let dropped = || metrics::ANALYTICS_DROPPED.with_label_values(&["queue_full"]).get();
+        let before = dropped();
+
+        let (analytics, service) = Analytics::new(&config(1.0, 2, &[]));
+        for _ in 0..5 {
+            analytics.record(&record());
+        }
+
+        assert_eq!(drain(&service).len(), 2);
+        assert_eq!(dropped() - before, 3.0);
+    }
+}
@@ -1,7 +1,15 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fs;
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use crate::proxy::method_override::parse_method;
+use crate::utils::cloudflare::CountryCode;
+use crate::utils::host::host_matches_domain;
+use crate::utils::sampler::Sampler;
+use crate::utils::useragent::{user_agent_matches, user_agent_pattern_error};
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,20 +19,64 @@ pub enum ConfigError {
     
     #[error("Failed to parse YAML: {0}")]
     YamlParseError(#[from] serde_yaml::Error),
+
+    #[error("No routes configured: add `domains` or `upstream_addr`, or set `empty_routes: serve_unavailable`")]
+    NoRoutes,
+
+    #[error("route {path} of domain {domain} has an empty upstream list")]
+    EmptyUpstream { domain: String, path: String },
+
+    #[error("advanced_limits use {count} distinct window_secs values, more than max_rate_limit_windows ({max})")]
+    TooManyWindows { count: usize, max: usize },
+
+    #[error("domains {first} and {second} share TLS port {port} but set different {setting}; listener settings must match on a port")]
+    ConflictingTls { port: u16, setting: &'static str, first: String, second: String },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SslConfig {
     pub cert_path: String,
     pub key_path: String,
+    /// PEM bundle of CAs the listener verifies client certificates against. Clients
+    /// are asked for a certificate; one that verifies identifies the client for
+    /// client_cert_limit and client_cert_in. Applies to the whole port, like min_tls_version
     #[serde(default)]
     pub ca_path: Option<String>,
+    /// Oldest TLS version the listener accepts ("1.2" or "1.3"). Applies to the whole
+    /// port, so every domain sharing the port must agree on it
+    #[serde(default)]
+    pub min_tls_version: Option<TlsVersion>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// TLS protocol version
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl DomainConfig {
+    /// Port the domain's TLS listener is opened on ("host:8443" or 443)
+    pub fn tls_port(&self) -> u16 {
+        match self.domain.split_once(':') {
+            Some((_, port)) => port.parse().unwrap_or(443),
+            None => 443,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Router {
+    /// Optional route name used in logs and metrics (defaults to the path)
+    #[serde(default)]
+    pub name: Option<String>,
     pub path: String,
-    pub upstream: String,
+    /// Match request paths against this regex instead of the `path` prefix (see PathRegex)
+    #[serde(default)]
+    pub path_regex: Option<PathRegex>,
+    pub upstream: UpstreamSpec,
     #[serde(default = "default_route_max_req_per_window")]
     pub max_req_per_window: isize,
     #[serde(default = "default_route_block_duration_secs")]
@@ -35,9 +87,70 @@ pub struct Router {
     pub timeout_secs: Option<u64>,
     #[serde(default)]
     pub advanced_limits: Option<AdvancedRateLimitConfig>,
+    /// Connect to the upstream over TLS even when it is given as plain host:port
+    #[serde(default)]
+    pub upstream_tls: bool,
+    /// Copy a percentage of requests to a shadow upstream (responses are discarded)
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    /// Send requests carrying a header (or a percentage of all requests) to a canary upstream
+    #[serde(default)]
+    pub canary: Option<CanaryConfig>,
+    /// Request logging level for this route ("debug", "warn", ...), overriding the global level
+    #[serde(default, deserialize_with = "deserialize_log_level")]
+    pub log_level: Option<String>,
+    /// Only match requests with this Content-Type (e.g. "application/grpc"); preferred
+    /// over a route without one on the same path
+    #[serde(default)]
+    pub content_type_match: Option<String>,
+    /// Abort upstream responses larger than this many bytes
+    #[serde(default)]
+    pub max_response_bytes: Option<u64>,
+    /// Time-of-day limit profiles overriding max_req_per_window / block_duration_secs
+    #[serde(default)]
+    pub limit_schedule: Option<LimitSchedule>,
+    /// Cap on concurrent requests to this route's upstream (overrides max_upstream_connections)
+    #[serde(default)]
+    pub max_upstream_connections: Option<usize>,
+    /// Replay the first response to a repeated idempotency key instead of forwarding again
+    #[serde(default)]
+    pub idempotency: Option<IdempotencyConfig>,
+    /// Security headers (HSTS, nosniff, ...) added to responses that don't set them
+    #[serde(default)]
+    pub security_headers: Option<SecurityHeadersConfig>,
+    /// Log the full request and response headers of this route (credentials redacted)
+    #[serde(default)]
+    pub debug_headers: bool,
+    /// Answer 508 once a request has come back through pingwall this many times
+    /// (an upstream redirecting or proxying to pingwall itself); off when unset
+    #[serde(default)]
+    pub max_redirects: Option<u32>,
+    /// Stream the request body to the upstream as it arrives (default), or read all
+    /// of it (up to 64 KiB) before contacting the upstream
+    #[serde(default)]
+    pub request_buffering: RequestBuffering,
+    /// Cut the request off with 504 when the upstream hasn't delivered the full
+    /// response within this many seconds of the request arriving
+    #[serde(default)]
+    pub response_deadline_secs: Option<u64>,
+    /// Send requests to the upstream with this method (e.g. PUT for legacy clients sending POST)
+    #[serde(default, deserialize_with = "deserialize_method")]
+    pub rewrite_method: Option<String>,
+    /// Headers added to / removed from requests before they are sent upstream
+    #[serde(default)]
+    pub request_headers: Option<HeaderRulesConfig>,
+    /// Headers added to / removed from upstream responses
+    #[serde(default)]
+    pub response_headers: Option<HeaderRulesConfig>,
+    /// Response sent instead of the generic 502 when the upstream times out
+    #[serde(default)]
+    pub timeout_response: Option<TimeoutResponseConfig>,
+    /// Let clients upgrade to WebSocket on this route (None = allowed); false answers upgrades with 400
+    #[serde(default)]
+    pub allow_websocket: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct DomainConfig {
     pub domain: String,
     #[serde(default)]
@@ -46,13 +159,28 @@ pub struct DomainConfig {
     pub routers: Vec<Router>,
     #[serde(default)]
     pub timeout_secs: Option<u64>,
+    /// Redirect plain HTTP requests for this domain to HTTPS
+    #[serde(default)]
+    pub redirect_https: bool,
+    /// Whether this domain sits behind Cloudflare (overrides the global use_cloudflare)
+    /// - false: CF-* headers are ignored for client IP and advanced limits
+    #[serde(default)]
+    pub use_cloudflare: Option<bool>,
+    /// Active health checks for this domain's upstreams (overrides the global health_check)
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
 }
 
 // Legacy route structure for backward compatibility
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct UpstreamRoute {
+    #[serde(default)]
+    pub name: Option<String>,
     pub path: String,
-    pub upstream: String,
+    /// Match request paths against this regex instead of the `path` prefix (see PathRegex)
+    #[serde(default)]
+    pub path_regex: Option<PathRegex>,
+    pub upstream: UpstreamSpec,
     #[serde(default = "default_route_max_req_per_window")]
     pub max_req_per_window: isize,
     #[serde(default = "default_route_block_duration_secs")]
@@ -67,6 +195,385 @@ pub struct UpstreamRoute {
     pub timeout_secs: Option<u64>,
     #[serde(default)]
     pub advanced_limits: Option<AdvancedRateLimitConfig>,
+    #[serde(default)]
+    pub redirect_https: bool,
+    /// Connect to the upstream over TLS even when it is given as plain host:port
+    #[serde(default)]
+    pub upstream_tls: bool,
+    /// Copy a percentage of requests to a shadow upstream (responses are discarded)
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    /// Send requests carrying a header (or a percentage of all requests) to a canary upstream
+    #[serde(default)]
+    pub canary: Option<CanaryConfig>,
+    /// Request logging level for this route ("debug", "warn", ...), overriding the global level
+    #[serde(default, deserialize_with = "deserialize_log_level")]
+    pub log_level: Option<String>,
+    /// Only match requests with this Content-Type (e.g. "application/grpc"); preferred
+    /// over a route without one on the same path
+    #[serde(default)]
+    pub content_type_match: Option<String>,
+    /// Abort upstream responses larger than this many bytes
+    #[serde(default)]
+    pub max_response_bytes: Option<u64>,
+    /// Time-of-day limit profiles overriding max_req_per_window / block_duration_secs
+    #[serde(default)]
+    pub limit_schedule: Option<LimitSchedule>,
+    /// Cap on concurrent requests to this route's upstream (overrides max_upstream_connections)
+    #[serde(default)]
+    pub max_upstream_connections: Option<usize>,
+    /// Replay the first response to a repeated idempotency key instead of forwarding again
+    #[serde(default)]
+    pub idempotency: Option<IdempotencyConfig>,
+    /// Security headers (HSTS, nosniff, ...) added to responses that don't set them
+    #[serde(default)]
+    pub security_headers: Option<SecurityHeadersConfig>,
+    /// Log the full request and response headers of this route (credentials redacted)
+    #[serde(default)]
+    pub debug_headers: bool,
+    /// Answer 508 once a request has come back through pingwall this many times
+    /// (an upstream redirecting or proxying to pingwall itself); off when unset
+    #[serde(default)]
+    pub max_redirects: Option<u32>,
+    /// Stream the request body to the upstream as it arrives (default), or read all
+    /// of it (up to 64 KiB) before contacting the upstream
+    #[serde(default)]
+    pub request_buffering: RequestBuffering,
+    /// Cut the request off with 504 when the upstream hasn't delivered the full
+    /// response within this many seconds of the request arriving
+    #[serde(default)]
+    pub response_deadline_secs: Option<u64>,
+    /// Send requests to the upstream with this method (e.g. PUT for legacy clients sending POST)
+    #[serde(default, deserialize_with = "deserialize_method")]
+    pub rewrite_method: Option<String>,
+    /// Headers added to / removed from requests before they are sent upstream
+    #[serde(default)]
+    pub request_headers: Option<HeaderRulesConfig>,
+    /// Headers added to / removed from upstream responses
+    #[serde(default)]
+    pub response_headers: Option<HeaderRulesConfig>,
+    /// Response sent instead of the generic 502 when the upstream times out
+    #[serde(default)]
+    pub timeout_response: Option<TimeoutResponseConfig>,
+    /// Let clients upgrade to WebSocket on this route (None = allowed); false answers upgrades with 400
+    #[serde(default)]
+    pub allow_websocket: Option<bool>,
+    /// Domain's Cloudflare override (None = global use_cloudflare)
+    #[serde(default)]
+    pub use_cloudflare: Option<bool>,
+}
+
+/// Limit profiles selected by time of day; the first active profile applies, and the
+/// route's own limits apply when none is active. All profiles share one request counter.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LimitSchedule {
+    /// Fixed UTC offset the hours are in ("UTC", "+02:00", "-05:00")
+    #[serde(default = "default_schedule_timezone", deserialize_with = "deserialize_schedule_timezone")]
+    pub timezone: String,
+    pub profiles: Vec<LimitProfile>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LimitProfile {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Active hours, e.g. "09-18" (end exclusive); "22-06" wraps past midnight
+    pub hours: HourRange,
+    /// Active weekdays ("mon", "tue", ...); empty = every day
+    #[serde(default, deserialize_with = "deserialize_weekdays")]
+    pub days: Vec<String>,
+    pub max_req_per_window: isize,
+    /// Defaults to the route's block_duration_secs
+    #[serde(default)]
+    pub block_duration_secs: Option<u64>,
+}
+
+/// Hour-of-day range written as "start-end" (0-24, end exclusive)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct HourRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl HourRange {
+    /// Whether the hour falls in the range, wrapping past midnight when start > end
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            hour >= self.start && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for HourRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid hour range '{}', expected e.g. \"09-18\"", value);
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+        let start: u32 = start.trim().parse().map_err(|_| invalid())?;
+        let end: u32 = end.trim().parse().map_err(|_| invalid())?;
+        if start > 23 || end > 24 || start == end {
+            return Err(invalid());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl From<HourRange> for String {
+    fn from(range: HourRange) -> Self {
+        format!("{:02}-{:02}", range.start, range.end)
+    }
+}
+
+/// HTTP version in a rate limit condition: "HTTP/1.0", "HTTP/1.1", "HTTP/2" or "HTTP/3"
+/// (the "HTTP/" prefix and a trailing ".0" on 2 and 3 are optional)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct HttpVersion(pub http::Version);
+
+impl TryFrom<String> for HttpVersion {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let upper = value.trim().to_ascii_uppercase();
+        let version = match upper.strip_prefix("HTTP/").unwrap_or(&upper) {
+            "0.9" => http::Version::HTTP_09,
+            "1.0" => http::Version::HTTP_10,
+            "1.1" => http::Version::HTTP_11,
+            "2" | "2.0" => http::Version::HTTP_2,
+            "3" | "3.0" => http::Version::HTTP_3,
+            _ => return Err(format!("invalid HTTP version '{}', expected e.g. \"HTTP/1.0\" or \"HTTP/2\"", value)),
+        };
+        Ok(Self(version))
+    }
+}
+
+impl From<HttpVersion> for String {
+    fn from(version: HttpVersion) -> Self {
+        format!("{:?}", version.0)
+    }
+}
+
+/// Route path pattern (`path_regex`), compiled when the config is loaded
+///
+/// Matched from the start of the request path, like a prefix; end it with `$` to
+/// match whole paths only. A regex that doesn't compile fails config load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathRegex {
+    pattern: String,
+    regex: regex::Regex,
+}
+
+impl PathRegex {
+    pub fn is_match(&self, path: &str) -> bool {
+        self.regex.is_match(path)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+}
+
+impl TryFrom<String> for PathRegex {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        match regex::Regex::new(&format!("^(?:{})", pattern)) {
+            Ok(regex) => Ok(Self { pattern, regex }),
+            Err(e) => Err(format!("invalid path_regex '{}': {}", pattern, e)),
+        }
+    }
+}
+
+impl From<PathRegex> for String {
+    fn from(path_regex: PathRegex) -> Self {
+        path_regex.pattern
+    }
+}
+
+/// A route's upstream: one address, or a list to balance requests across
+///
+/// Each address takes the same forms as a single upstream ("http://host:port/base"
+/// or "host:port"). Requests go round-robin over the list, skipping addresses that
+/// recently failed to connect.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum UpstreamSpec {
+    Single(String),
+    Pool(Vec<String>),
+}
+
+impl UpstreamSpec {
+    /// Every address of the upstream, in configured order
+    pub fn addrs(&self) -> &[String] {
+        match self {
+            Self::Single(addr) => std::slice::from_ref(addr),
+            Self::Pool(addrs) => addrs,
+        }
+    }
+}
+
+impl Default for UpstreamSpec {
+    fn default() -> Self {
+        Self::Single(String::new())
+    }
+}
+
+impl From<String> for UpstreamSpec {
+    fn from(addr: String) -> Self {
+        Self::Single(addr)
+    }
+}
+
+impl From<&str> for UpstreamSpec {
+    fn from(addr: &str) -> Self {
+        Self::Single(addr.to_string())
+    }
+}
+
+impl PartialEq<&str> for UpstreamSpec {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, Self::Single(addr) if addr == other)
+    }
+}
+
+impl std::fmt::Display for UpstreamSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.addrs().join(", "))
+    }
+}
+
+/// Shadow upstream receiving a copy of sampled requests
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MirrorConfig {
+    /// Shadow upstream ("http://host:port" or "host:port")
+    pub upstream: String,
+
+    /// Percentage of requests to mirror (0-100)
+    pub percentage: f64,
+
+    /// Requests with bodies larger than this are not mirrored
+    #[serde(default = "default_mirror_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Picks this route's mirrored requests (shared by clones of the route)
+    #[serde(skip)]
+    pub sampler: Arc<Sampler>,
+}
+
+/// Canary upstream for gradual rollouts
+///
+/// A request goes to the canary when it carries `header` (with `value`, if set),
+/// otherwise `percentage`% of the remaining requests do.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CanaryConfig {
+    /// Canary upstream, in the same forms as a route's upstream
+    pub upstream: String,
+
+    /// Request header opting into the canary (e.g. "X-Canary")
+    #[serde(default)]
+    pub header: Option<String>,
+
+    /// Required header value; any value matches when unset
+    #[serde(default)]
+    pub value: Option<String>,
+
+    /// Percentage of other requests sent to the canary (0-100)
+    #[serde(default)]
+    pub percentage: f64,
+
+    /// Picks this route's canary requests (shared by clones of the route)
+    #[serde(skip)]
+    pub sampler: Arc<Sampler>,
+}
+
+/// Request deduplication by idempotency key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdempotencyConfig {
+    /// Request header carrying the key
+    #[serde(default = "default_idempotency_header")]
+    pub header: String,
+
+    /// How long the first response is replayed for
+    #[serde(default = "default_idempotency_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+/// Security baseline response headers; everything is off by default
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SecurityHeadersConfig {
+    /// Strict-Transport-Security (one year, includeSubDomains), on HTTPS responses only
+    #[serde(default)]
+    pub hsts: bool,
+
+    /// X-Content-Type-Options: nosniff
+    #[serde(default)]
+    pub nosniff: bool,
+
+    /// X-Frame-Options value, e.g. "DENY" or "SAMEORIGIN"
+    #[serde(default)]
+    pub frame_options: Option<String>,
+
+    /// Referrer-Policy value, e.g. "strict-origin-when-cross-origin"
+    #[serde(default)]
+    pub referrer_policy: Option<String>,
+
+    /// Content-Security-Policy value
+    #[serde(default)]
+    pub csp: Option<String>,
+}
+
+/// Response sent when the route's upstream doesn't connect or answer within its timeout
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimeoutResponseConfig {
+    /// Error status to answer with (400-599)
+    #[serde(default = "default_timeout_response_status", deserialize_with = "deserialize_error_status")]
+    pub status: u16,
+
+    #[serde(default)]
+    pub body: String,
+
+    #[serde(default = "default_custom_response_content_type")]
+    pub content_type: String,
+
+    /// Retry-After seconds telling clients when to try again (0 = no header)
+    #[serde(default = "default_timeout_response_retry_after")]
+    pub retry_after: u64,
+}
+
+/// Headers a route removes from and adds to requests or responses; removals
+/// happen first, so a header listed in both ends up with the added value
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HeaderRulesConfig {
+    /// Headers to set, replacing any existing value; values may use the
+    /// `$host`, `$client_ip` and `$path` placeholders
+    #[serde(default, deserialize_with = "deserialize_header_additions")]
+    pub add: HashMap<String, String>,
+
+    /// Header names to drop (case-insensitive)
+    #[serde(default, deserialize_with = "deserialize_header_names")]
+    pub remove: Vec<String>,
+}
+
+impl UpstreamRoute {
+    /// Label identifying this route in logs and metrics: its name, or the path when unnamed
+    pub fn route_label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Parsed `log_level` override; None when unset
+    pub fn log_level_filter(&self) -> Option<log::LevelFilter> {
+        self.log_level.as_deref().and_then(|level| level.parse().ok())
+    }
+
+    /// Whether WebSocket upgrades are proxied on this route (allow_websocket, default true)
+    pub fn websocket_allowed(&self) -> bool {
+        self.allow_websocket.unwrap_or(true)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -95,20 +602,554 @@ pub struct Config {
     #[serde(default = "default_api_key")]
     pub api_key: String,
 
+    /// Webhook destinations for block events; when set, block_url / api_key are not used
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// Retries of a block webhook that failed with a connection error or 5xx
+    #[serde(default = "default_webhook_max_retries")]
+    pub webhook_max_retries: u32,
+
+    /// Delay before the first webhook retry in milliseconds, doubled for each further one
+    #[serde(default = "default_webhook_retry_base_ms")]
+    pub webhook_retry_base_ms: u64,
+
+    /// Also tell the webhooks when a block expires (`"type": "unblock"` events)
+    #[serde(default)]
+    pub notify_on_unblock: bool,
+
     #[serde(default = "default_use_cloudflare")]
     pub use_cloudflare: bool,
 
     #[serde(default = "default_timeout_secs")]
     pub timeout_secs: u64,
 
+    /// Lets clients holding a token ask for a longer upstream timeout per request
+    #[serde(default)]
+    pub timeout_override: Option<TimeoutOverrideConfig>,
+
+    /// Lets clients holding a token change the upstream method with X-HTTP-Method-Override
+    #[serde(default)]
+    pub method_override: Option<MethodOverrideConfig>,
+
+    /// gzip / brotli compression of upstream responses for clients that accept it
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+
     #[serde(default)]
     pub metrics_port: Option<u16>,
 
+    /// What to do when the metrics port can't be bound (after retrying)
+    #[serde(default)]
+    pub metrics_bind_failure: MetricsBindFailure,
+
     /// Rate limit window duration in seconds
     /// Default: 1 second (most granular)
     /// Examples: 1 (per second), 60 (per minute), 3600 (per hour)
     #[serde(default = "default_rate_limit_window_secs")]
     pub rate_limit_window_secs: u64,
+
+    /// Proxies (CIDR ranges or IPs) allowed to set forwarding headers
+    /// such as X-Forwarded-Proto
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    /// Header carrying the client IP (e.g. "True-Client-IP", "Fastly-Client-IP"),
+    /// honored only from trusted_proxies and checked before the built-in headers
+    #[serde(default)]
+    pub client_ip_header: Option<String>,
+
+    /// Number of proxies in front of pingwall that append to X-Forwarded-For.
+    /// When set, the client IP is the entry this many places from the right
+    /// (falling back to the peer address if the list is shorter) instead of the leftmost one
+    #[serde(default)]
+    pub trusted_proxy_hops: usize,
+
+    /// Log a warning for requests taking longer than this (milliseconds)
+    #[serde(default)]
+    pub slow_request_threshold_ms: Option<u64>,
+
+    /// Don't fall back to upstream_addr when no route matches; answer 404 instead
+    #[serde(default)]
+    pub disable_default_route: bool,
+
+    /// Custom body served with the 404 for unmatched routes
+    #[serde(default)]
+    pub not_found_response: Option<CustomResponse>,
+
+    /// Answer requests whose URI (path + query) is longer than this with 414
+    #[serde(default)]
+    pub max_uri_length: Option<usize>,
+
+    /// Send webhooks without Authorization while api_key is the default placeholder
+    #[serde(default)]
+    pub dev_mode: bool,
+
+    /// Also send block events to this destination (e.g. syslog), alongside the webhook
+    #[serde(default)]
+    pub notification: Option<NotificationConfig>,
+
+    /// User-Agent substrings of health checkers (e.g. "kube-probe", "ELB-HealthChecker")
+    /// Matching requests are never rate limited (access rules still apply)
+    #[serde(default)]
+    pub health_check_user_agents: Vec<String>,
+
+    /// Also leave health check requests out of request metrics
+    #[serde(default)]
+    pub health_check_skip_metrics: bool,
+
+    /// Reject requests whose Host header disagrees with :authority / the URI authority
+    #[serde(default)]
+    pub strict_host: bool,
+
+    /// Collapse duplicate slashes and resolve `.` / `..` (also percent-encoded) in
+    /// request paths before routing, rate limiting and forwarding; `%2F` is decoded
+    /// for routing and limits only
+    #[serde(default = "default_normalize_path")]
+    pub normalize_path: bool,
+
+    /// Add IETF draft `RateLimit` / `RateLimit-Policy` headers to allowed responses
+    #[serde(default)]
+    pub emit_ratelimit_headers: bool,
+
+    /// What to do with a request when the limiter state can't be used
+    #[serde(default)]
+    pub limiter_failure_mode: LimiterFailureMode,
+
+    /// Close client connections after a 429 (blocked or rate limited); by default they
+    /// stay open so well-behaved clients don't reconnect for every rejected request
+    #[serde(default)]
+    pub close_on_block: bool,
+
+    /// Block IPs that receive too many 4xx responses (e.g. scanners probing for paths),
+    /// even while they stay under the request rate limits
+    #[serde(default)]
+    pub scanner_detection: Option<ScannerDetectionConfig>,
+
+    /// Close new connections from an IP that opens more than max_per_window of them
+    /// per window, before any request on them is read
+    #[serde(default)]
+    pub connection_limit: Option<ConnectionLimitConfig>,
+
+    /// Threat-intel feed of IPs / CIDRs to block or limit more strictly, refreshed in the background
+    #[serde(default)]
+    pub ip_reputation: Option<IpReputationConfig>,
+
+    /// Startup behavior when neither `domains` nor `upstream_addr` defines a route
+    #[serde(default)]
+    pub empty_routes: EmptyRoutesBehavior,
+
+    /// `metrics_only` runs just the metrics (and admin) services, without proxy listeners
+    #[serde(default)]
+    pub mode: RunMode,
+
+    /// Most distinct window_secs values across all advanced limits; each one gets its own limiter
+    #[serde(default = "default_max_rate_limit_windows")]
+    pub max_rate_limit_windows: usize,
+
+    /// Cap on concurrent requests to any single upstream; further requests get 503
+    #[serde(default)]
+    pub max_upstream_connections: Option<usize>,
+
+    /// Admin API (disabled unless configured)
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+
+    /// ACME HTTP-01 challenge serving for an external ACME client
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+
+    /// Sampled request records shipped to a separate sink (disabled unless configured)
+    #[serde(default)]
+    pub analytics: Option<AnalyticsConfig>,
+
+    /// Startup warmup; `/ready` reports 503 and traffic is rejected until it finishes
+    #[serde(default)]
+    pub warmup: Option<WarmupConfig>,
+
+    /// Active health checks for every upstream; domains can override it
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+
+    /// OpenTelemetry span export (requires the `otel` feature)
+    #[serde(default)]
+    pub tracing: Option<TracingConfig>,
+
+    /// TLS session resumption settings applied to every HTTPS listener
+    #[serde(default)]
+    pub tls: TlsSessionConfig,
+}
+
+/// Admin API settings (listens on localhost only)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminConfig {
+    #[serde(default = "default_admin_port")]
+    pub port: u16,
+
+    /// Bearer token required on every admin request
+    pub token: String,
+
+    /// Admin requests accepted per minute, across all clients (<= 0 disables the limit);
+    /// failed authentication attempts are counted in a separate budget of the same size
+    #[serde(default = "default_admin_max_req_per_minute")]
+    pub max_req_per_minute: isize,
+}
+
+/// ACME HTTP-01 challenge settings
+///
+/// Pingwall does not talk to the ACME server itself: an external client (certbot
+/// `--webroot`, lego `--http.webroot`, ...) writes challenge files into `challenge_dir`
+/// and Pingwall serves them on `/.well-known/acme-challenge/` ahead of routing and
+/// rate limiting. Point `ssl.cert_path`/`key_path` at the issued files and call the
+/// admin `POST /reload-certs` after renewal.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AcmeConfig {
+    /// Directory holding challenge files (`<webroot>/.well-known/acme-challenge`)
+    pub challenge_dir: String,
+}
+
+/// Sampled request analytics
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AnalyticsConfig {
+    pub sink: AnalyticsSink,
+
+    /// Fraction of requests to emit (0.0 - 1.0)
+    pub sample_rate: f64,
+
+    /// Record fields to keep (empty = all)
+    #[serde(default)]
+    pub fields: Vec<String>,
+
+    /// Records waiting for the sink; further records are dropped (and counted)
+    #[serde(default = "default_analytics_queue_size")]
+    pub queue_size: usize,
+}
+
+/// Per-IP limit on 4xx responses, counted in the logging hook
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScannerDetectionConfig {
+    /// 4xx responses an IP may receive per window before it is blocked
+    pub max_4xx: isize,
+
+    #[serde(default = "default_scanner_window_secs")]
+    pub window_secs: u64,
+
+    /// How long a detected scanner stays blocked (default: block_duration_secs)
+    #[serde(default)]
+    pub block_duration_secs: Option<u64>,
+}
+
+/// Per-IP rate of new TCP connections, checked when a connection is accepted
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectionLimitConfig {
+    /// New connections an IP may open per window; further ones are closed at once
+    pub max_per_window: isize,
+
+    #[serde(default = "default_connection_limit_window_secs")]
+    pub window_secs: u64,
+}
+
+/// IP reputation feed: a URL returning one IP or CIDR per line (`#` starts a comment)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IpReputationConfig {
+    pub url: String,
+
+    /// Seconds between fetches; unchanged feeds (ETag / Last-Modified) are not re-parsed
+    #[serde(default = "default_ip_reputation_refresh_secs")]
+    pub refresh_secs: u64,
+
+    #[serde(default)]
+    pub action: IpReputationAction,
+
+    /// Requests per suspicious_window_secs allowed from a listed IP with `action: suspicious`
+    #[serde(default = "default_ip_reputation_suspicious_max_req")]
+    pub suspicious_max_req: isize,
+
+    #[serde(default = "default_ip_reputation_suspicious_window_secs")]
+    pub suspicious_window_secs: u64,
+}
+
+/// What happens to requests from IPs on the reputation feed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IpReputationAction {
+    /// Reject every request, like a blocked IP (default)
+    #[default]
+    Block,
+    /// Allow only `suspicious_max_req` requests per window, whatever the route allows
+    Suspicious,
+}
+
+/// Per-request upstream timeout chosen by trusted clients (e.g. internal batch jobs)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimeoutOverrideConfig {
+    /// Secret a client must send in `token_header` for its timeout header to be honored
+    pub token: String,
+
+    #[serde(default = "default_timeout_override_token_header")]
+    pub token_header: String,
+
+    /// Header carrying the requested timeout in seconds
+    #[serde(default = "default_timeout_override_header")]
+    pub header: String,
+
+    /// Longest timeout a client can get; larger requests are capped to this
+    pub max_secs: u64,
+}
+
+/// Method override honored for trusted clients (e.g. legacy clients that can only send POST)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MethodOverrideConfig {
+    /// Secret a client must send in `token_header` for its override header to be honored
+    pub token: String,
+
+    #[serde(default = "default_method_override_token_header")]
+    pub token_header: String,
+
+    /// Header carrying the method to use upstream
+    #[serde(default = "default_method_override_header")]
+    pub header: String,
+}
+
+/// Compression of upstream responses, negotiated with the client's Accept-Encoding
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Responses declaring a smaller Content-Length are sent as is
+    #[serde(default = "default_compression_min_size_bytes")]
+    pub min_size_bytes: u64,
+
+    /// Content types to compress; "text/*" matches every text type
+    #[serde(default = "default_compression_types")]
+    pub types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size_bytes: default_compression_min_size_bytes(),
+            types: default_compression_types(),
+        }
+    }
+}
+
+/// OTLP trace export
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TracingConfig {
+    /// OTLP/HTTP traces endpoint, e.g. "http://otel-collector:4318/v1/traces"
+    pub otlp_endpoint: String,
+
+    #[serde(default = "default_tracing_service_name")]
+    pub service_name: String,
+}
+
+/// Periodic `GET` of each upstream; one failing it is skipped in routing
+///
+/// An upstream is marked unhealthy after `unhealthy_threshold` failed checks in a
+/// row and back in rotation after `healthy_threshold` successful ones. A check
+/// succeeds on a 2xx response within `timeout_secs`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
+
+    #[serde(default = "default_health_check_healthy_threshold")]
+    pub healthy_threshold: u32,
+
+    #[serde(default = "default_health_check_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            path: default_health_check_path(),
+            interval_secs: default_health_check_interval_secs(),
+            timeout_secs: default_health_check_timeout_secs(),
+            healthy_threshold: default_health_check_healthy_threshold(),
+            unhealthy_threshold: default_health_check_unhealthy_threshold(),
+        }
+    }
+}
+
+/// Startup warmup run before the proxy reports ready
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WarmupConfig {
+    /// Steps run in order
+    #[serde(default)]
+    pub steps: Vec<WarmupStep>,
+
+    /// Connect timeout for `probe_upstreams`
+    #[serde(default = "default_warmup_probe_timeout_ms")]
+    pub probe_timeout_ms: u64,
+
+    /// Extra wait after the steps, e.g. for a load balancer to notice the new instance
+    #[serde(default)]
+    pub delay_secs: u64,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self {
+            steps: Vec::new(),
+            probe_timeout_ms: default_warmup_probe_timeout_ms(),
+            delay_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupStep {
+    /// Resolve every upstream host name
+    ResolveUpstreams,
+    /// Read every route's certificate into the SNI certificate cache
+    LoadCerts,
+    /// Open a TCP connection to every upstream
+    ProbeUpstreams,
+}
+
+impl WarmupStep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WarmupStep::ResolveUpstreams => "resolve_upstreams",
+            WarmupStep::LoadCerts => "load_certs",
+            WarmupStep::ProbeUpstreams => "probe_upstreams",
+        }
+    }
+}
+
+/// Where sampled analytics records go, one JSON object per record
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnalyticsSink {
+    /// Append JSON lines to a file, e.g. { type: file, path: "logs/analytics.jsonl" }
+    File { path: String },
+    /// POST each record to an HTTP endpoint, e.g. { type: http, url: "http://collector:8080/ingest" }
+    Http { url: String },
+}
+
+/// A webhook receiving block events
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Sent as `Authorization: Bearer <api_key>` (chat webhooks usually need none)
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub format: WebhookFormat,
+}
+
+/// Body shape a webhook destination expects
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookFormat {
+    /// The full event as JSON (default)
+    #[default]
+    Raw,
+    /// Slack incoming webhook: `{"text": "..."}`
+    Slack,
+    /// Discord webhook: `{"content": "..."}`
+    Discord,
+}
+
+/// Block event destination
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationConfig {
+    /// RFC 5424 syslog, e.g. { type: syslog, address: "10.0.0.5:514", facility: local0 }
+    Syslog {
+        address: String,
+        #[serde(default)]
+        protocol: SyslogProtocol,
+        #[serde(default = "default_syslog_facility")]
+        facility: String,
+    },
+    /// Kafka topic (build with the `kafka` feature), e.g. { type: kafka, brokers: ["kafka-1:9092"], topic: pingwall-blocks }
+    Kafka {
+        brokers: Vec<String>,
+        topic: String,
+        #[serde(default)]
+        acks: KafkaAcks,
+        /// Events queued for the brokers before new ones are dropped
+        #[serde(default = "default_kafka_queue_size")]
+        queue_size: usize,
+    },
+}
+
+/// Broker acknowledgements a Kafka producer waits for
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KafkaAcks {
+    #[serde(rename = "0")]
+    None,
+    #[serde(rename = "1")]
+    Leader,
+    /// All in-sync replicas (default)
+    #[default]
+    #[serde(rename = "all")]
+    All,
+}
+
+impl KafkaAcks {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KafkaAcks::None => "0",
+            KafkaAcks::Leader => "1",
+            KafkaAcks::All => "all",
+        }
+    }
+}
+
+/// Syslog transport
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SyslogProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+/// TLS session resumption (session cache + session tickets)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsSessionConfig {
+    /// Allow clients to resume sessions instead of doing a full handshake
+    #[serde(default = "default_session_resumption")]
+    pub session_resumption: bool,
+
+    /// Issue stateless session tickets (keys are rotated automatically by BoringSSL)
+    #[serde(default = "default_session_tickets")]
+    pub session_tickets: bool,
+
+    /// Maximum number of sessions kept in the server-side session cache
+    #[serde(default = "default_session_cache_size")]
+    pub session_cache_size: u32,
+}
+
+impl Default for TlsSessionConfig {
+    fn default() -> Self {
+        Self {
+            session_resumption: default_session_resumption(),
+            session_tickets: default_session_tickets(),
+            session_cache_size: default_session_cache_size(),
+        }
+    }
+}
+
+/// A static response body served directly by the proxy
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CustomResponse {
+    pub body: String,
+    #[serde(default = "default_custom_response_content_type")]
+    pub content_type: String,
 }
 
 fn default_max_req_per_window() -> isize { 60 }
@@ -118,15 +1159,63 @@ fn default_route_block_duration_secs() -> u64 { 300 }
 fn default_upstream_addr() -> String { "127.0.0.1:9992".to_string() }
 fn default_block_url() -> String { "https://example.com/api/v1/block".to_string() }
 fn default_api_key() -> String { "your-api-key".to_string() }
+fn default_webhook_max_retries() -> u32 { 3 }
+fn default_webhook_retry_base_ms() -> u64 { 200 }
 fn default_use_cloudflare() -> bool { false }
 fn default_timeout_secs() -> u64 { 30 }
 fn default_rate_limit_window_secs() -> u64 { 1 }  // Default: 1 second (most granular)
+fn default_custom_response_content_type() -> String { "application/json".to_string() }
+fn default_compression_min_size_bytes() -> u64 { 1024 }
+fn default_compression_types() -> Vec<String> {
+    ["application/json", "application/javascript", "application/xml", "image/svg+xml", "text/*"]
+        .iter()
+        .map(|t| t.to_string())
+        .collect()
+}
+fn default_timeout_response_status() -> u16 { 504 }
+fn default_timeout_response_retry_after() -> u64 { 5 }
+fn default_mirror_max_body_bytes() -> usize { 1024 * 1024 }
+fn default_analytics_queue_size() -> usize { 10_000 }
+fn default_warmup_probe_timeout_ms() -> u64 { 2000 }
+fn default_schedule_timezone() -> String { "UTC".to_string() }
+fn default_tracing_service_name() -> String { "pingwall".to_string() }
+fn default_ip_reputation_refresh_secs() -> u64 { 300 }
+fn default_ip_reputation_suspicious_max_req() -> isize { 10 }
+fn default_ip_reputation_suspicious_window_secs() -> u64 { 60 }
+fn default_scanner_window_secs() -> u64 { 60 }
+fn default_connection_limit_window_secs() -> u64 { 1 }
+fn default_timeout_override_token_header() -> String { "X-Bypass-Token".to_string() }
+fn default_timeout_override_header() -> String { "X-Upstream-Timeout".to_string() }
+fn default_method_override_header() -> String { "X-HTTP-Method-Override".to_string() }
+fn default_method_override_token_header() -> String { "X-Bypass-Token".to_string() }
+fn default_idempotency_header() -> String { "Idempotency-Key".to_string() }
+fn default_idempotency_ttl_secs() -> u64 { 86400 }
+fn default_browser_headers() -> Vec<String> {
+    vec!["accept".to_string(), "accept-language".to_string(), "accept-encoding".to_string()]
+}
+fn default_health_check_path() -> String { "/healthz".to_string() }
+fn default_health_check_interval_secs() -> u64 { 10 }
+fn default_health_check_timeout_secs() -> u64 { 2 }
+fn default_health_check_healthy_threshold() -> u32 { 2 }
+fn default_health_check_unhealthy_threshold() -> u32 { 3 }
+fn default_admin_port() -> u16 { 9091 }
+fn default_admin_max_req_per_minute() -> isize { 60 }
+
+fn default_max_rate_limit_windows() -> usize { 32 }
+fn default_normalize_path() -> bool { true }
+fn default_syslog_facility() -> String { "local0".to_string() }
+fn default_kafka_queue_size() -> usize { 10_000 }
+fn default_session_resumption() -> bool { true }
+fn default_session_tickets() -> bool { true }
+fn default_session_cache_size() -> u32 { 20480 }
 
 fn default_routes() -> Vec<UpstreamRoute> {
     vec![
         UpstreamRoute {
+            name: None,
             path: "/".to_string(),
-            upstream: default_upstream_addr(),
+            path_regex: None,
+            upstream: default_upstream_addr().into(),
             max_req_per_window: default_route_max_req_per_window(),
             block_duration_secs: default_route_block_duration_secs(),
             domain: None,
@@ -134,6 +1223,27 @@ fn default_routes() -> Vec<UpstreamRoute> {
             ssl: None,
             timeout_secs: None,
             advanced_limits: None,
+            redirect_https: false,
+            upstream_tls: false,
+            mirror: None,
+            canary: None,
+            log_level: None,
+            content_type_match: None,
+            max_response_bytes: None,
+            limit_schedule: None,
+            max_upstream_connections: None,
+            idempotency: None,
+            security_headers: None,
+            debug_headers: false,
+            max_redirects: None,
+            request_buffering: RequestBuffering::default(),
+            response_deadline_secs: None,
+            rewrite_method: None,
+            request_headers: None,
+            response_headers: None,
+            timeout_response: None,
+            allow_websocket: None,
+            use_cloudflare: None,
         }
     ]
 }
@@ -149,10 +1259,48 @@ impl Default for Config {
             domains: Vec::new(),
             block_url: default_block_url(),
             api_key: default_api_key(),
+            webhooks: Vec::new(),
+            webhook_max_retries: default_webhook_max_retries(),
+            webhook_retry_base_ms: default_webhook_retry_base_ms(),
+            notify_on_unblock: false,
             use_cloudflare: default_use_cloudflare(),
             timeout_secs: default_timeout_secs(),
+            timeout_override: None,
+            method_override: None,
+            compression: None,
             metrics_port: None,
+            metrics_bind_failure: MetricsBindFailure::default(),
             rate_limit_window_secs: default_rate_limit_window_secs(),
+            trusted_proxies: Vec::new(),
+            client_ip_header: None,
+            trusted_proxy_hops: 0,
+            slow_request_threshold_ms: None,
+            disable_default_route: false,
+            not_found_response: None,
+            max_uri_length: None,
+            dev_mode: false,
+            notification: None,
+            health_check_user_agents: Vec::new(),
+            health_check_skip_metrics: false,
+            strict_host: false,
+            normalize_path: default_normalize_path(),
+            emit_ratelimit_headers: false,
+            limiter_failure_mode: LimiterFailureMode::default(),
+            close_on_block: false,
+            scanner_detection: None,
+            connection_limit: None,
+            ip_reputation: None,
+            empty_routes: EmptyRoutesBehavior::default(),
+            mode: RunMode::default(),
+            max_rate_limit_windows: default_max_rate_limit_windows(),
+            max_upstream_connections: None,
+            admin: None,
+            acme: None,
+            analytics: None,
+            warmup: None,
+            health_check: None,
+            tracing: None,
+            tls: TlsSessionConfig::default(),
         }
     }
 }
@@ -164,6 +1312,110 @@ impl Config {
         Ok(config)
     }
 
+    /// Whether any request can be routed: a domain router or an explicit upstream_addr
+    /// (the built-in 127.0.0.1:9992 fallback does not count)
+    pub fn has_routes(&self) -> bool {
+        self.upstream_addr.is_some() || self.domains.iter().any(|domain| !domain.routers.is_empty())
+    }
+
+    /// Every distinct upstream address with the health check that applies to it
+    /// (its domain's, else the global one); upstreams without one are not checked
+    pub fn health_check_targets(&self) -> Vec<(String, HealthCheckConfig)> {
+        let mut targets: Vec<(String, HealthCheckConfig)> = Vec::new();
+        let mut add = |upstream: &str, health_check: Option<&HealthCheckConfig>| {
+            if let Some(health_check) = health_check {
+                if !targets.iter().any(|(target, _)| target == upstream) {
+                    targets.push((upstream.to_string(), health_check.clone()));
+                }
+            }
+        };
+
+        for domain_config in &self.domains {
+            let health_check = domain_config.health_check.as_ref().or(self.health_check.as_ref());
+            for router in &domain_config.routers {
+                for upstream in router.upstream.addrs() {
+                    add(upstream, health_check);
+                }
+            }
+        }
+        if let Some(upstream_addr) = &self.upstream_addr {
+            add(upstream_addr, self.health_check.as_ref());
+        }
+        targets
+    }
+
+    /// Refuse a configuration without routes unless empty_routes allows serving it, and
+    /// routes whose upstream list is empty (`upstream: []`)
+    pub fn check_routes(&self) -> Result<(), ConfigError> {
+        for domain_config in &self.domains {
+            if let Some(router) = domain_config.routers.iter().find(|router| router.upstream.addrs().is_empty()) {
+                return Err(ConfigError::EmptyUpstream {
+                    domain: domain_config.domain.clone(),
+                    path: router.path.clone(),
+                });
+            }
+        }
+        match self.empty_routes {
+            EmptyRoutesBehavior::Refuse if !self.has_routes() => Err(ConfigError::NoRoutes),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse domains sharing a TLS port with different listener-level settings
+    ///
+    /// Certificates are picked per domain by SNI, but settings such as min_tls_version
+    /// belong to the listener, which exists once per port.
+    pub fn check_tls_ports(&self) -> Result<(), ConfigError> {
+        let mut listeners: HashMap<u16, (&str, Option<TlsVersion>, Option<&str>)> = HashMap::new();
+        for domain_config in &self.domains {
+            let Some(ssl) = &domain_config.ssl else {
+                continue;
+            };
+            let port = domain_config.tls_port();
+            let (first, min_tls_version, ca_path) = *listeners
+                .entry(port)
+                .or_insert((domain_config.domain.as_str(), ssl.min_tls_version, ssl.ca_path.as_deref()));
+            let setting = if min_tls_version != ssl.min_tls_version {
+                "min_tls_version"
+            } else if ca_path != ssl.ca_path.as_deref() {
+                "ca_path"
+            } else {
+                continue;
+            };
+            return Err(ConfigError::ConflictingTls {
+                port,
+                setting,
+                first: first.to_string(),
+                second: domain_config.domain.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Window lengths the advanced limits of all routes, scanner detection and the
+    /// reputation feed's suspicious limit count in
+    /// (limits without window_secs use rate_limit_window_secs)
+    pub fn rate_limit_windows(&self) -> BTreeSet<u64> {
+        self.domain_routes()
+            .iter()
+            .filter_map(|route| route.advanced_limits.as_ref())
+            .flat_map(|advanced| advanced.limits())
+            .filter(|limit| limit.algorithm() == LimitAlgorithm::SlidingWindow)
+            .map(|limit| limit.window_secs().unwrap_or(self.rate_limit_window_secs))
+            .chain(self.scanner_detection.as_ref().map(|scanner| scanner.window_secs))
+            .chain(self.ip_reputation.as_ref().map(|reputation| reputation.suspicious_window_secs))
+            .collect()
+    }
+
+    /// Refuse a configuration that would create more window limiters than allowed
+    pub fn check_rate_limit_windows(&self) -> Result<(), ConfigError> {
+        let count = self.rate_limit_windows().len();
+        if count > self.max_rate_limit_windows {
+            return Err(ConfigError::TooManyWindows { count, max: self.max_rate_limit_windows });
+        }
+        Ok(())
+    }
+
     /// Get effective timeout for a route with priority: path > domain > global
     pub fn get_effective_timeout(&self, route: &Router, domain: &DomainConfig) -> u64 {
         route.timeout_secs
@@ -175,6 +1427,52 @@ impl Config {
     pub fn get_effective_timeout_legacy(&self, route: &UpstreamRoute) -> u64 {
         route.timeout_secs.unwrap_or(self.timeout_secs)
     }
+
+    /// Flatten the `domains` section into the route list used by the proxy
+    pub fn domain_routes(&self) -> Vec<UpstreamRoute> {
+        let mut routes = Vec::new();
+
+        for domain_config in &self.domains {
+            for router in &domain_config.routers {
+                routes.push(UpstreamRoute {
+                    name: router.name.clone(),
+                    path: router.path.clone(),
+                    path_regex: router.path_regex.clone(),
+                    upstream: router.upstream.clone(),
+                    max_req_per_window: router.max_req_per_window,
+                    block_duration_secs: router.block_duration_secs,
+                    domain: Some(domain_config.domain.clone()),
+                    follow_domain: router.follow_domain,
+                    ssl: domain_config.ssl.clone(),
+                    timeout_secs: router.timeout_secs,
+                    advanced_limits: router.advanced_limits.clone(),
+                    redirect_https: domain_config.redirect_https,
+                    upstream_tls: router.upstream_tls,
+                    mirror: router.mirror.clone(),
+                    canary: router.canary.clone(),
+                    log_level: router.log_level.clone(),
+                    content_type_match: router.content_type_match.clone(),
+                    max_response_bytes: router.max_response_bytes,
+                    limit_schedule: router.limit_schedule.clone(),
+                    max_upstream_connections: router.max_upstream_connections,
+                    idempotency: router.idempotency.clone(),
+                    security_headers: router.security_headers.clone(),
+                    debug_headers: router.debug_headers,
+                    max_redirects: router.max_redirects,
+                    request_buffering: router.request_buffering,
+                    response_deadline_secs: router.response_deadline_secs,
+                    rewrite_method: router.rewrite_method.clone(),
+                    request_headers: router.request_headers.clone(),
+                    response_headers: router.response_headers.clone(),
+                    timeout_response: router.timeout_response.clone(),
+                    allow_websocket: router.allow_websocket,
+                    use_cloudflare: domain_config.use_cloudflare,
+                });
+            }
+        }
+
+        routes
+    }
 }
 
 // ==================== Advanced Rate Limiting Configuration ====================
@@ -216,6 +1514,124 @@ impl LimitConfig {
             LimitConfig::Extended(config) => config.block_duration_secs,
         }
     }
+
+    /// Get counting algorithm (simple format always uses sliding window)
+    pub fn algorithm(&self) -> LimitAlgorithm {
+        match self {
+            LimitConfig::Simple(_) => LimitAlgorithm::SlidingWindow,
+            LimitConfig::Extended(config) => config.algorithm,
+        }
+    }
+
+    /// Get leaky bucket drain rate in requests/sec (default: max_req / window)
+    pub fn leak_rate(&self, window_secs: u64) -> f64 {
+        match self {
+            LimitConfig::Extended(ExtendedLimitConfig { leak_rate: Some(rate), .. }) => *rate,
+            _ => self.max_req() as f64 / window_secs.max(1) as f64,
+        }
+    }
+
+    /// Get leaky bucket capacity (default: max_req)
+    pub fn capacity(&self) -> f64 {
+        match self {
+            LimitConfig::Extended(ExtendedLimitConfig { capacity: Some(capacity), .. }) => *capacity,
+            _ => self.max_req() as f64,
+        }
+    }
+
+    /// Get token bucket refill rate in requests/sec (default: max_req / window)
+    pub fn refill_per_sec(&self, window_secs: u64) -> f64 {
+        match self {
+            LimitConfig::Extended(ExtendedLimitConfig { refill_per_sec: Some(rate), .. }) => *rate,
+            _ => self.max_req() as f64 / window_secs.max(1) as f64,
+        }
+    }
+
+    /// Get token bucket size (default: max_req)
+    pub fn burst(&self) -> f64 {
+        match self {
+            LimitConfig::Extended(ExtendedLimitConfig { burst: Some(burst), .. }) => *burst,
+            _ => self.max_req() as f64,
+        }
+    }
+
+    /// Get number of request path segments the key is scoped to (None = route path)
+    pub fn path_depth(&self) -> Option<usize> {
+        match self {
+            LimitConfig::Simple(_) => None,
+            LimitConfig::Extended(config) => config.path_depth,
+        }
+    }
+}
+
+/// Handling of requests when the limiter backend fails
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LimiterFailureMode {
+    /// Let the request through unlimited (default)
+    #[default]
+    FailOpen,
+    /// Reject the request with 503
+    FailClosed,
+}
+
+/// How a route forwards request bodies
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestBuffering {
+    /// Pass each chunk on as soon as it is received (default)
+    #[default]
+    Stream,
+    /// Read the body in full before contacting the upstream, then send it in one
+    /// piece with a Content-Length
+    Buffer,
+}
+
+/// Handling of a configuration that defines no routes
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyRoutesBehavior {
+    /// Exit at startup with an error (default)
+    #[default]
+    Refuse,
+    /// Start and answer every request with 503 "not configured"
+    ServeUnavailable,
+}
+
+/// What a pingwall process serves
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RunMode {
+    /// Reverse proxy with rate limiting, plus the metrics and admin services (default)
+    #[default]
+    Proxy,
+    /// Only the metrics and admin services, e.g. a sidecar re-exporting metrics;
+    /// no proxy listener is opened and routes aren't required
+    MetricsOnly,
+}
+
+/// Handling of a metrics port that is still in use after the bind retries
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsBindFailure {
+    /// Log an error and keep running without metrics (default)
+    #[default]
+    Warn,
+    /// Fail startup, so a supervisor notices and restarts it
+    Fatal,
+}
+
+/// Counting algorithm used for a limit
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAlgorithm {
+    /// Fixed counter per window (default)
+    #[default]
+    SlidingWindow,
+    /// Counter drains continuously at `leak_rate` requests/sec up to `capacity`
+    LeakyBucket,
+    /// Up to `burst` requests at once, refilled at `refill_per_sec` requests/sec
+    TokenBucket,
 }
 
 /// Extended limit configuration with window and block behavior
@@ -239,6 +1655,36 @@ pub struct ExtendedLimitConfig {
     /// - Some(N): Hard block IP for N seconds
     #[serde(default)]
     pub block_duration_secs: Option<u64>,
+
+    /// Counting algorithm: sliding_window (default), leaky_bucket or token_bucket
+    #[serde(default)]
+    pub algorithm: LimitAlgorithm,
+
+    /// Leaky bucket drain rate in requests/sec
+    /// - None: max_req / window_secs
+    #[serde(default)]
+    pub leak_rate: Option<f64>,
+
+    /// Leaky bucket capacity (burst size)
+    /// - None: max_req
+    #[serde(default)]
+    pub capacity: Option<f64>,
+
+    /// Token bucket refill rate in requests/sec
+    /// - None: max_req / window_secs
+    #[serde(default)]
+    pub refill_per_sec: Option<f64>,
+
+    /// Token bucket size (requests allowed at once after an idle period)
+    /// - None: max_req
+    #[serde(default)]
+    pub burst: Option<f64>,
+
+    /// Key on the first N segments of the request path instead of the route path
+    /// - None: one counter per route
+    /// - Some(1): "/user/1" and "/user/2" share the "/user" counter
+    #[serde(default)]
+    pub path_depth: Option<usize>,
 }
 
 /// Advanced rate limiting configuration with multi-dimensional limits
@@ -260,11 +1706,51 @@ pub struct AdvancedRateLimitConfig {
     /// Simple: "CN": 50
     /// Extended: "CN": { max_req: 50, window_secs: 3600, block_duration_secs: 3600 }
     #[serde(default)]
-    pub country_limits: Option<HashMap<String, LimitConfig>>,
+    pub country_limits: Option<HashMap<CountryCode, LimitConfig>>,
+
+    /// Limits for a specific ASN within a specific country (one shared bucket)
+    /// Example: - { asn: "12345", country: "RU", limit: { max_req: 10, window_secs: 60 } }
+    #[serde(default)]
+    pub asn_country_limits: Option<Vec<AsnCountryLimit>>,
+
+    /// Session cookie based limits, keyed by cookie name (one bucket per cookie value)
+    /// Requests without the cookie fall through to IP-based limiting
+    /// Example: "session_id": { max_req: 100, window_secs: 60 }
+    #[serde(default)]
+    pub cookie_limits: Option<HashMap<String, LimitConfig>>,
+
+    /// Limit per TLS client certificate (one bucket per certificate fingerprint)
+    /// Requests without a client certificate fall through to IP-based limiting
+    /// Example: { max_req: 600, window_secs: 60 }
+    #[serde(default)]
+    pub client_cert_limit: Option<LimitConfig>,
+
+    /// Limit per value of a claim of the verified bearer JWT (e.g. `sub` or a tenant id)
+    /// Example: { claim: "sub", secret: "...", limit: { max_req: 100, window_secs: 60 } }
+    #[serde(default)]
+    pub jwt_limits: Option<JwtLimitConfig>,
+
+    /// Referer domain based limits (one shared bucket per referer host, subdomains included)
+    /// Example: "hotlinker.example": { max_req: 10, window_secs: 60, block_duration_secs: 0 }
+    #[serde(default)]
+    pub referer_limits: Option<HashMap<String, LimitConfig>>,
+
+    /// Networks to block outright, whatever their request rate (CIDR ranges or single IPs)
+    /// Checked first; an entry that doesn't parse fails config load.
+    /// Example: ["10.0.0.0/8", "185.220.0.0/16"]
+    #[serde(default, deserialize_with = "deserialize_cidrs", serialize_with = "serialize_cidrs")]
+    pub block_cidrs: Option<Vec<IpNetwork>>,
 
     /// List of countries to completely block (2-letter ISO codes)
     #[serde(default)]
-    pub block_countries: Option<Vec<String>>,
+    pub block_countries: Option<Vec<CountryCode>>,
+
+    /// Only these User-Agents may use the route; any other gets an instant 403 (reason UA_NOT_ALLOWED)
+    /// Entries are case-insensitive substrings, or regexes written as "/pattern/".
+    /// Checked before every limit, so user_agent_limits still count the allowed clients.
+    /// Example: ["InternalClient/", "/^deploy-bot/[0-9]+$/"]
+    #[serde(default)]
+    pub allow_user_agents: Option<Vec<String>>,
 
     /// Cloudflare threat score threshold (0-100). Block if above this value.
     #[serde(default)]
@@ -275,6 +1761,55 @@ pub struct AdvancedRateLimitConfig {
     pub rules: Option<Vec<RateLimitRule>>,
 }
 
+/// Limit for requests coming from a given ASN inside a given country
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AsnCountryLimit {
+    /// ASN without the "AS" prefix (e.g., "12345")
+    pub asn: String,
+
+    /// 2-letter ISO country code
+    pub country: CountryCode,
+
+    /// Limit applied to the combined bucket
+    pub limit: LimitConfig,
+}
+
+/// Limit keyed by a claim of the request's bearer JWT
+///
+/// The token's signature is checked against `secret` (HS256) or the PEM public
+/// key at `public_key_path` (RS256/ES256) before the claim is trusted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JwtLimitConfig {
+    /// Claim whose value keys the bucket (string or number)
+    pub claim: String,
+
+    /// Limit applied to each claim value
+    pub limit: LimitConfig,
+
+    /// Shared secret for HS256 tokens
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// PEM public key for RS256/ES256 tokens
+    #[serde(default)]
+    pub public_key_path: Option<String>,
+
+    /// What to do with requests whose token is missing, invalid or lacks the claim
+    #[serde(default)]
+    pub on_invalid: JwtInvalidAction,
+}
+
+/// Handling of requests without a usable JWT
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JwtInvalidAction {
+    /// Limit the request by IP like any anonymous request (default)
+    #[default]
+    FallThrough,
+    /// Reject the request with 401
+    Reject,
+}
+
 /// A rate limit rule with conditions
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RateLimitRule {
@@ -299,16 +1834,38 @@ pub enum RateLimitCondition {
     UserAgentContains { value: String },
 
     /// Country is in the list
-    CountryIn { values: Vec<String> },
+    CountryIn { values: Vec<CountryCode> },
 
     /// Country is NOT in the list
-    CountryNotIn { values: Vec<String> },
+    CountryNotIn { values: Vec<CountryCode> },
 
     /// ASN is in the list
     AsnIn { values: Vec<String> },
 
+    /// ASN and country both match
+    AsnCountry { asn: String, country: CountryCode },
+
+    /// Referer host is one of the domains (or a subdomain); no/invalid Referer never matches
+    RefererDomainIn { values: Vec<String> },
+
+    /// Referer host is NOT one of the domains; no/invalid Referer never matches
+    RefererDomainNotIn { values: Vec<String> },
+
     /// Threat score is above threshold
     ThreatScoreAbove { value: u8 },
+
+    /// TLS client certificate SHA-256 fingerprint is in the list (hex, colons optional);
+    /// requests without a client certificate never match
+    ClientCertIn { values: Vec<String> },
+
+    /// HTTP version the client negotiated is in the list
+    HttpVersionIn { values: Vec<HttpVersion> },
+
+    /// Any of the headers every mainstream browser sends is absent (bot heuristic)
+    MissingBrowserHeaders {
+        #[serde(default = "default_browser_headers")]
+        headers: Vec<String>,
+    },
 }
 
 impl AdvancedRateLimitConfig {
@@ -330,7 +1887,43 @@ impl AdvancedRateLimitConfig {
     pub fn get_country_limit(&self, country: &str) -> Option<&LimitConfig> {
         self.country_limits
             .as_ref()
-            .and_then(|limits| limits.get(country))
+            .and_then(|limits| limits.get(country.to_ascii_uppercase().as_str()))
+    }
+
+    /// Get the limit for a specific ASN + country combination
+    pub fn get_asn_country_limit(&self, asn: &str, country: &str) -> Option<&AsnCountryLimit> {
+        self.asn_country_limits
+            .as_ref()
+            .and_then(|limits| {
+                limits.iter().find(|l| l.asn == asn && l.country.matches(country))
+            })
+    }
+
+    /// Get the cookie limits that apply to the cookies present on a request
+    pub fn matching_cookie_limits<'a>(
+        &'a self,
+        cookies: &'a HashMap<String, String>,
+    ) -> impl Iterator<Item = (&'a str, &'a str, &'a LimitConfig)> + 'a {
+        self.cookie_limits
+            .iter()
+            .flatten()
+            .filter_map(move |(name, limit)| {
+                cookies.get(name).map(|value| (name.as_str(), value.as_str(), limit))
+            })
+    }
+
+    /// Get the referer limit for a referer host (matches the domain or its subdomains)
+    pub fn get_referer_limit(&self, referer_host: &str) -> Option<(&str, &LimitConfig)> {
+        self.referer_limits
+            .iter()
+            .flatten()
+            .find(|(domain, _)| host_matches_domain(referer_host, domain))
+            .map(|(domain, limit)| (domain.as_str(), limit))
+    }
+
+    /// The block_cidrs entry containing this IP, if any
+    pub fn blocked_cidr(&self, ip: IpAddr) -> Option<&IpNetwork> {
+        self.block_cidrs.iter().flatten().find(|network| network.contains(ip))
     }
 
     /// Check if country is in block list
@@ -338,13 +1931,535 @@ impl AdvancedRateLimitConfig {
         self.block_countries
             .as_ref()
             .map_or(false, |blocked| {
-                blocked.iter().any(|c| c.eq_ignore_ascii_case(country))
+                blocked.iter().any(|c| c.matches(country))
             })
     }
 
+    /// Check if the User-Agent passes allow_user_agents (always true when no allowlist is set)
+    ///
+    /// An empty User-Agent matches no entry, so it is rejected whenever an allowlist is set.
+    pub fn is_user_agent_allowed(&self, user_agent: &str) -> bool {
+        self.allow_user_agents
+            .as_ref()
+            .map_or(true, |allowed| allowed.iter().any(|pattern| user_agent_matches(user_agent, pattern)))
+    }
+
     /// Check if threat score should be blocked
     pub fn should_block_threat(&self, threat_score: u8) -> bool {
         self.threat_score_threshold
             .map_or(false, |threshold| threat_score > threshold)
     }
+
+    /// Every dimension limit of this block
+    pub fn limits(&self) -> Vec<&LimitConfig> {
+        let keyed_limits = [
+            &self.user_agent_limits,
+            &self.asn_limits,
+            &self.country_limits,
+            &self.cookie_limits,
+            &self.referer_limits,
+        ];
+        keyed_limits
+            .into_iter()
+            .flat_map(|limits| limits.iter().flat_map(|limits| limits.values()))
+            .chain(self.asn_country_limits.iter().flatten().map(|limit| &limit.limit))
+            .chain(self.client_cert_limit.as_ref())
+            .chain(self.jwt_limits.as_ref().map(|jwt| &jwt.limit))
+            .collect()
+    }
+
+    /// Problems that make part of the block never (or always) apply, for load-time warnings
+    ///
+    /// The config is still used as is: a bad entry under-enforces but does not stop the proxy.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        // Country codes themselves are checked when the config is parsed (CountryCode)
+        for (code, limit) in self.country_limits.iter().flatten() {
+            check_limit(&format!("country_limits.{}", code), limit, &mut problems);
+        }
+        for limit in self.asn_country_limits.iter().flatten() {
+            check_limit(&format!("asn_country_limits.{}/{}", limit.asn, limit.country), &limit.limit, &mut problems);
+        }
+
+        let keyed_limits = [
+            ("user_agent_limits", &self.user_agent_limits),
+            ("asn_limits", &self.asn_limits),
+            ("cookie_limits", &self.cookie_limits),
+            ("referer_limits", &self.referer_limits),
+        ];
+        for (section, limits) in keyed_limits {
+            for (key, limit) in limits.iter().flatten() {
+                check_limit(&format!("{}.{}", section, key), limit, &mut problems);
+            }
+        }
+        if let Some(limit) = &self.client_cert_limit {
+            check_limit("client_cert_limit", limit, &mut problems);
+        }
+        if let Some(jwt) = &self.jwt_limits {
+            check_limit("jwt_limits", &jwt.limit, &mut problems);
+            if jwt.secret.is_none() && jwt.public_key_path.is_none() {
+                problems.push("jwt_limits needs a secret or public_key_path; every token will be treated as invalid".to_string());
+            }
+        }
+
+        if let Some(allowed) = &self.allow_user_agents {
+            if allowed.iter().all(|pattern| pattern.is_empty()) {
+                problems.push("allow_user_agents has no entries; every request will be rejected".to_string());
+            }
+            for pattern in allowed {
+                if let Some(e) = user_agent_pattern_error(pattern) {
+                    problems.push(format!("allow_user_agents entry '{}' is not a valid regex and never matches: {}", pattern, e));
+                }
+            }
+        }
+
+        if let Some(threshold) = self.threat_score_threshold.filter(|t| *t >= 100) {
+            problems.push(format!("threat_score_threshold {} can never be exceeded (scores are 0-100)", threshold));
+        }
+
+        for rule in self.rules.iter().flatten() {
+            let context = format!("rule '{}'", rule.name);
+            if rule.conditions.is_empty() {
+                problems.push(format!("{} has no conditions and matches every request", context));
+            }
+            if rule.max_req <= 0 {
+                problems.push(format!("{}: max_req {} disables limiting for matching requests", context, rule.max_req));
+            }
+            for condition in &rule.conditions {
+                match condition {
+                    RateLimitCondition::CountryIn { values } | RateLimitCondition::CountryNotIn { values } if values.is_empty() => {
+                        problems.push(format!("{}: country condition has no values", context));
+                    }
+                    RateLimitCondition::AsnIn { values }
+                    | RateLimitCondition::RefererDomainIn { values }
+                    | RateLimitCondition::RefererDomainNotIn { values }
+                    | RateLimitCondition::ClientCertIn { values } if values.is_empty() => {
+                        problems.push(format!("{}: condition has no values", context));
+                    }
+                    RateLimitCondition::HttpVersionIn { values } if values.is_empty() => {
+                        problems.push(format!("{}: condition has no values", context));
+                    }
+                    RateLimitCondition::ThreatScoreAbove { value } if *value >= 100 => {
+                        problems.push(format!("{}: threat score above {} can never match", context, value));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        problems
+    }
+}
+
+fn deserialize_method<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let Some(method) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    match parse_method(&method) {
+        Some(method) => Ok(Some(method.to_string())),
+        None => Err(serde::de::Error::custom(format!(
+            "invalid rewrite_method '{}' (expected GET, HEAD, POST, PUT, PATCH, DELETE or OPTIONS)",
+            method
+        ))),
+    }
+}
+
+fn deserialize_log_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let Some(level) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    match level.parse::<log::LevelFilter>() {
+        Ok(_) => Ok(Some(level)),
+        Err(_) => Err(serde::de::Error::custom(format!(
+            "invalid log_level '{}' (expected trace, debug, info, warn, error or off)",
+            level
+        ))),
+    }
+}
+
+fn deserialize_schedule_timezone<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let timezone = String::deserialize(deserializer)?;
+    if crate::ratelimit::schedule::parse_utc_offset(&timezone).is_none() {
+        return Err(serde::de::Error::custom(format!(
+            "invalid limit_schedule timezone '{}' (expected UTC or a fixed offset like +02:00)",
+            timezone
+        )));
+    }
+    Ok(timezone)
+}
+
+fn deserialize_weekdays<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let days = Vec::<String>::deserialize(deserializer)?;
+    if let Some(day) = days.iter().find(|day| day.parse::<chrono::Weekday>().is_err()) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid limit_schedule day '{}' (expected mon, tue, wed, thu, fri, sat or sun)",
+            day
+        )));
+    }
+    Ok(days)
+}
+
+fn deserialize_error_status<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let status = u16::deserialize(deserializer)?;
+    if !(400..=599).contains(&status) {
+        return Err(serde::de::Error::custom(format!("invalid timeout_response status {} (expected 400-599)", status)));
+    }
+    Ok(status)
+}
+
+fn check_header_name<E: serde::de::Error>(name: &str) -> Result<(), E> {
+    http::HeaderName::from_bytes(name.as_bytes())
+        .map(|_| ())
+        .map_err(|_| E::custom(format!("invalid header name '{}'", name)))
+}
+
+fn deserialize_header_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    for name in &names {
+        check_header_name(name)?;
+    }
+    Ok(names)
+}
+
+fn deserialize_header_additions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, String>, D::Error> {
+    let headers = HashMap::<String, String>::deserialize(deserializer)?;
+    for (name, value) in &headers {
+        check_header_name(name)?;
+        if http::HeaderValue::from_str(value).is_err() {
+            return Err(serde::de::Error::custom(format!("invalid value for header '{}': {:?}", name, value)));
+        }
+    }
+    Ok(headers)
+}
+
+fn deserialize_cidrs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<IpNetwork>>, D::Error> {
+    let Some(entries) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    entries
+        .iter()
+        .map(|entry| {
+            entry.trim().parse::<IpNetwork>().map_err(|e| {
+                serde::de::Error::custom(format!("invalid CIDR '{}' in block_cidrs: {}", entry, e))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+fn serialize_cidrs<S: Serializer>(cidrs: &Option<Vec<IpNetwork>>, serializer: S) -> Result<S::Ok, S::Error> {
+    cidrs
+        .as_ref()
+        .map(|networks| networks.iter().map(|network| network.to_string()).collect::<Vec<_>>())
+        .serialize(serializer)
+}
+
+/// Flag limits that can't behave as configured
+fn check_limit(context: &str, limit: &LimitConfig, problems: &mut Vec<String>) {
+    if limit.max_req() <= 0 {
+        problems.push(format!("{}: max_req {} disables this limit", context, limit.max_req()));
+    }
+    if limit.window_secs() == Some(0) {
+        problems.push(format!("{}: window_secs must be at least 1", context));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn advanced(yaml: &str) -> AdvancedRateLimitConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_token_bucket_limit_defaults() {
+        let config = advanced(
+            "asn_limits:\n  \"64500\": { max_req: 60, window_secs: 60, algorithm: token_bucket, burst: 30 }\n  \"64501\": 60\n",
+        );
+        let limits = config.asn_limits.unwrap();
+
+        let bucket = &limits["64500"];
+        assert_eq!(bucket.algorithm(), LimitAlgorithm::TokenBucket);
+        assert_eq!(bucket.burst(), 30.0);
+        assert_eq!(bucket.refill_per_sec(60), 1.0);
+        // Existing limits keep the sliding window
+        assert_eq!(limits["64501"].algorithm(), LimitAlgorithm::SlidingWindow);
+    }
+
+    #[test]
+    fn test_invalid_country_code_fails_load() {
+        let err = serde_yaml::from_str::<AdvancedRateLimitConfig>("country_limits:\n  UK: 50\n  DE: 100\n").unwrap_err();
+        assert!(err.to_string().contains("'UK' is not an ISO 3166-1 alpha-2 country code (did you mean GB?)"), "{}", err);
+
+        assert!(serde_yaml::from_str::<AdvancedRateLimitConfig>("block_countries: [CN, XX]\n").is_err());
+        assert!(serde_yaml::from_str::<AdvancedRateLimitConfig>(
+            "rules:\n  - name: eu\n    conditions:\n      - type: country_in\n        values: [DE, EU]\n    max_req: 5\n"
+        ).is_err());
+        assert!(serde_yaml::from_str::<AdvancedRateLimitConfig>(
+            "asn_country_limits:\n  - { asn: \"12345\", country: RUS, limit: 10 }\n"
+        ).is_err());
+    }
+
+    #[test]
+    fn test_country_codes_match_case_insensitively() {
+        let config = advanced("country_limits:\n  de: 100\nblock_countries: [cn]\n");
+
+        assert!(config.validate().is_empty());
+        assert!(config.get_country_limit("DE").is_some());
+        assert!(config.get_country_limit("de").is_some());
+        assert!(config.is_country_blocked("CN"));
+        assert!(config.is_country_blocked("cn"));
+        assert!(!config.is_country_blocked("TW"));
+    }
+
+    #[test]
+    fn test_block_cidrs_are_parsed_at_load() {
+        let config = advanced("block_cidrs: [\"10.0.0.0/8\", \"185.220.0.0/16\", \"2001:db8::/32\", \"192.0.2.7\"]\n");
+
+        assert_eq!(config.blocked_cidr("10.20.30.40".parse().unwrap()).unwrap().to_string(), "10.0.0.0/8");
+        assert!(config.blocked_cidr("185.220.101.4".parse().unwrap()).is_some());
+        assert!(config.blocked_cidr("2001:db8::1".parse().unwrap()).is_some());
+        assert!(config.blocked_cidr("192.0.2.7".parse().unwrap()).is_some());
+        assert!(config.blocked_cidr("192.0.2.8".parse().unwrap()).is_none());
+        assert!(config.blocked_cidr("185.221.0.1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_invalid_block_cidr_fails_load() {
+        let err = serde_yaml::from_str::<AdvancedRateLimitConfig>("block_cidrs: [\"10.0.0.0/8\", \"10.0.0.0/33\"]\n").unwrap_err();
+        assert!(err.to_string().contains("invalid CIDR '10.0.0.0/33' in block_cidrs"), "{}", err);
+    }
+
+    #[test]
+    fn test_empty_condition_rule_is_flagged() {
+        let config = advanced("rules:\n  - name: catch-all\n    conditions: []\n    max_req: 10\n    block_duration: 60\n");
+        let problems = config.validate();
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("rule 'catch-all' has no conditions"));
+    }
+
+    #[test]
+    fn test_valid_advanced_limits_have_no_problems() {
+        let config = advanced(
+            "country_limits:\n  US: 100\nrules:\n  - name: ru-bots\n    conditions:\n      - type: country_in\n        values: [RU]\n    max_req: 5\n    block_duration: 600\n",
+        );
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+    }
+
+    fn route_with_windows(windows: &[u64]) -> String {
+        let mut yaml = String::from(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /\n        upstream: \"http://api:8000\"\n        advanced_limits:\n          asn_limits:\n",
+        );
+        for (i, window) in windows.iter().enumerate() {
+            yaml.push_str(&format!("            \"{}\": {{ max_req: 10, window_secs: {} }}\n", 1000 + i, window));
+        }
+        yaml
+    }
+
+    #[test]
+    fn test_too_many_distinct_windows_are_rejected() {
+        let config = parse(&format!("max_rate_limit_windows: 2\n{}", route_with_windows(&[1, 60, 3600])));
+        assert_eq!(config.rate_limit_windows().len(), 3);
+        assert!(matches!(config.check_rate_limit_windows(), Err(ConfigError::TooManyWindows { count: 3, max: 2 })));
+
+        // Repeated windows share one limiter
+        let config = parse(&format!("max_rate_limit_windows: 2\n{}", route_with_windows(&[1, 60, 60, 1])));
+        assert!(config.check_rate_limit_windows().is_ok());
+    }
+
+    #[test]
+    fn test_conflicting_tls_settings_on_a_port_are_rejected() {
+        let domain = |name: &str, version: &str| {
+            format!(
+                "  - domain: {}\n    ssl:\n      cert_path: /c.pem\n      key_path: /k.pem\n      min_tls_version: \"{}\"\n",
+                name, version
+            )
+        };
+
+        let config = parse(&format!("domains:\n{}{}", domain("a.example.com", "1.2"), domain("b.example.com", "1.3")));
+        match config.check_tls_ports() {
+            Err(ConfigError::ConflictingTls { port, setting, first, second }) => {
+                assert_eq!(port, 443);
+                assert_eq!(setting, "min_tls_version");
+                assert_eq!((first.as_str(), second.as_str()), ("a.example.com", "b.example.com"));
+            }
+            other => panic!("conflict not reported: {:?}", other),
+        }
+
+        // Same settings, or different ports, are fine
+        let config = parse(&format!("domains:\n{}{}", domain("a.example.com", "1.3"), domain("b.example.com", "1.3")));
+        assert!(config.check_tls_ports().is_ok());
+        let config = parse(&format!("domains:\n{}{}", domain("a.example.com", "1.2"), domain("b.example.com:8443", "1.3")));
+        assert!(config.check_tls_ports().is_ok());
+
+        // Client certificate verification is a listener setting too
+        let with_ca = format!("{}      ca_path: /clients-ca.pem\n", domain("b.example.com", "1.3"));
+        let config = parse(&format!("domains:\n{}{}", domain("a.example.com", "1.3"), with_ca));
+        assert!(matches!(config.check_tls_ports(), Err(ConfigError::ConflictingTls { setting: "ca_path", .. })));
+    }
+
+    #[test]
+    fn test_empty_config_refuses_to_start() {
+        let config = parse("max_req_per_window: 100\n");
+        assert!(!config.has_routes());
+        assert!(matches!(config.check_routes(), Err(ConfigError::NoRoutes)));
+
+        let config = parse("domains: []\nroutes: []\n");
+        assert!(matches!(config.check_routes(), Err(ConfigError::NoRoutes)));
+    }
+
+    #[test]
+    fn test_empty_upstream_list_refuses_to_start() {
+        let config = parse("domains:\n  - domain: api.example.com\n    routers:\n      - path: /api\n        upstream: []\n");
+        match config.check_routes() {
+            Err(ConfigError::EmptyUpstream { domain, path }) => {
+                assert_eq!(domain, "api.example.com");
+                assert_eq!(path, "/api");
+            }
+            other => panic!("expected EmptyUpstream, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_empty_config_can_serve_unavailable() {
+        let config = parse("empty_routes: serve_unavailable\n");
+        assert!(config.check_routes().is_ok());
+        // Still not routable: the proxy answers 503 instead of using the dev fallback
+        assert!(!config.has_routes());
+    }
+
+    #[test]
+    fn test_configured_routes_start() {
+        let config = parse("upstream_addr: \"10.0.0.5:8080\"\n");
+        assert!(config.check_routes().is_ok());
+
+        let config = parse(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /\n        upstream: \"http://api:8000\"\n",
+        );
+        assert!(config.has_routes());
+        assert!(config.check_routes().is_ok());
+    }
+
+    #[test]
+    fn test_health_check_targets_use_domain_override() {
+        let config = parse(concat!(
+            "health_check:\n  path: /status\n",
+            "upstream_addr: \"10.0.0.5:8080\"\n",
+            "domains:\n",
+            "  - domain: api.example.com\n",
+            "    health_check:\n      path: /healthz\n      interval_secs: 5\n",
+            "    routers:\n",
+            "      - path: /api\n        upstream: [\"http://api-1:8000\", \"http://api-2:8000\"]\n",
+            "      - path: /\n        upstream: \"http://api-1:8000\"\n",
+            "  - domain: www.example.com\n",
+            "    routers:\n      - path: /\n        upstream: \"http://web:8000\"\n",
+        ));
+
+        let targets = config.health_check_targets();
+        let upstreams: Vec<&str> = targets.iter().map(|(upstream, _)| upstream.as_str()).collect();
+        assert_eq!(upstreams, ["http://api-1:8000", "http://api-2:8000", "http://web:8000", "10.0.0.5:8080"]);
+        assert_eq!(targets[0].1.path, "/healthz");
+        assert_eq!(targets[0].1.interval_secs, 5);
+        assert_eq!(targets[2].1.path, "/status");
+        assert_eq!(targets[2].1.unhealthy_threshold, 3);
+
+        assert!(parse("upstream_addr: \"10.0.0.5:8080\"\n").health_check_targets().is_empty());
+    }
+
+    #[test]
+    fn test_limit_schedule_timezone_and_days_are_checked() {
+        let route = |timezone: &str, days: &str| format!(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /\n        upstream: \"http://api:8000\"\n        limit_schedule:\n          timezone: \"{}\"\n          profiles:\n            - hours: \"09-18\"\n              days: {}\n              max_req_per_window: 100\n",
+            timezone, days
+        );
+
+        let config = parse(&route("+02:00", "[mon, Friday]"));
+        assert_eq!(config.domain_routes()[0].limit_schedule.as_ref().unwrap().timezone, "+02:00");
+
+        let err = serde_yaml::from_str::<Config>(&route("Europe/Paris", "[mon]")).unwrap_err();
+        assert!(err.to_string().contains("invalid limit_schedule timezone 'Europe/Paris'"));
+
+        let err = serde_yaml::from_str::<Config>(&route("UTC", "[mon, tues]")).unwrap_err();
+        assert!(err.to_string().contains("invalid limit_schedule day 'tues'"));
+    }
+
+    #[test]
+    fn test_override_token_headers_default_independently() {
+        let config = parse("timeout_override:\n  token: t0ken\n  token_header: X-Batch-Token\n  max_secs: 600\nmethod_override:\n  token: m3thod\n");
+        assert_eq!(config.timeout_override.unwrap().token_header, "X-Batch-Token");
+        assert_eq!(config.method_override.unwrap().token_header, "X-Bypass-Token");
+    }
+
+    #[test]
+    fn test_log_level_must_be_a_level_name() {
+        let route = |level: &str| format!(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /\n        upstream: \"http://api:8000\"\n        log_level: {}\n",
+            level
+        );
+
+        let config = parse(&route("DEBUG"));
+        assert_eq!(config.domain_routes()[0].log_level_filter(), Some(log::LevelFilter::Debug));
+
+        let err = serde_yaml::from_str::<Config>(&route("verbose")).unwrap_err();
+        assert!(err.to_string().contains("invalid log_level 'verbose'"));
+    }
+
+    #[test]
+    fn test_rewrite_method_must_be_a_forwarded_method() {
+        let route = |method: &str| format!(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /legacy\n        upstream: \"http://api:8000\"\n        rewrite_method: {}\n",
+            method
+        );
+
+        let config = parse(&route("put"));
+        assert_eq!(config.domain_routes()[0].rewrite_method.as_deref(), Some("PUT"));
+
+        let err = serde_yaml::from_str::<Config>(&route("TRACE")).unwrap_err();
+        assert!(err.to_string().contains("invalid rewrite_method 'TRACE'"));
+    }
+
+    #[test]
+    fn test_websocket_allowed_unless_disabled() {
+        let config = parse(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /ws\n        upstream: \"http://chat:8000\"\n      - path: /api\n        upstream: \"http://api:8000\"\n        allow_websocket: false\n",
+        );
+        let routes = config.domain_routes();
+        assert!(routes[0].websocket_allowed());
+        assert!(!routes[1].websocket_allowed());
+    }
+
+    #[test]
+    fn test_invalid_path_regex_fails_load() {
+        let route = |pattern: &str| format!(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /api\n        path_regex: '{}'\n        upstream: \"http://api:8000\"\n",
+            pattern
+        );
+
+        let config = parse(&route(r"/api/v\d+/users"));
+        let path_regex = config.domain_routes()[0].path_regex.clone().unwrap();
+        assert_eq!(path_regex.as_str(), r"/api/v\d+/users");
+        assert!(path_regex.is_match("/api/v1/users"));
+
+        let err = serde_yaml::from_str::<Config>(&route("/api/(v1")).unwrap_err();
+        assert!(err.to_string().contains("invalid path_regex '/api/(v1'"), "{}", err);
+    }
+
+    #[test]
+    fn test_upstream_accepts_single_address_or_list() {
+        let config = parse(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /api\n        upstream:\n          - \"http://api-1:8000\"\n          - \"http://api-2:8000\"\n      - path: /\n        upstream: \"http://web:8000\"\n",
+        );
+        let routes = config.domain_routes();
+
+        assert_eq!(routes[0].upstream.addrs(), ["http://api-1:8000", "http://api-2:8000"]);
+        assert_eq!(routes[1].upstream, UpstreamSpec::Single("http://web:8000".to_string()));
+        assert_eq!(routes[1].upstream.addrs(), ["http://web:8000"]);
+    }
 }
@@ -0,0 +1,126 @@
+//! Pingwall as a library
+//!
+//! The `pingwall` binary is a thin wrapper around this crate. Embedders can reuse the
+//! configuration types, the rate limiter and the [`ReverseProxy`] pingora handler in
+//! their own pingora server.
+//!
+//! Public surface:
+//! - [`config`]: YAML configuration types ([`Config`], [`UpstreamRoute`], limits)
+//! - [`ratelimit`]: the limiter and [`ratelimit::service::RateLimitService`]
+//! - [`proxy`]: [`ReverseProxy`] and [`build_service`]
+//! - [`utils`]: client IP, scheme, Cloudflare and User-Agent helpers
+//! - [`metrics`]: Prometheus metrics and the metrics HTTP service
+//! - [`warmup`]: startup warmup and the readiness flag behind `/ready`
+//! - `otel` (feature `otel`): OpenTelemetry request spans exported over OTLP
+//!
+//! Minimal embed:
+//!
+//! ```no_run
+//! use pingora_core::server::Server;
+//! use pingwall::{build_service, Config, ReverseProxy};
+//!
+//! let config = Config::from_file("config.yaml").unwrap();
+//! pingwall::init_globals(&config);
+//!
+//! let proxy = ReverseProxy::new(
+//!     config.block_url.clone(),
+//!     config.api_key.clone(),
+//!     config.upstream_addr.clone().unwrap_or_else(|| "127.0.0.1:9992".to_string()),
+//!     config.clone(),
+//! )
+//! .with_routes(config.domain_routes());
+//!
+//! let mut server = Server::new(None).unwrap();
+//! server.bootstrap();
+//! let service = build_service(&server.configuration, proxy, config.port.unwrap_or(8081)).unwrap();
+//! server.add_service(service);
+//! server.run_forever();
+//! ```
+
+pub mod admin;
+pub mod analytics;
+pub mod config;
+pub mod logging;
+pub mod metrics;
+pub mod notification;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod proxy;
+pub mod ratelimit;
+pub mod types;
+pub mod utils;
+pub mod warmup;
+
+pub use config::{Config, UpstreamRoute};
+pub use proxy::handler::{build_service, ReverseProxy};
+
+use pingora_core::services::background::GenBackgroundService;
+use pingora_core::services::Service;
+use std::sync::Arc;
+
+/// Services of a `mode: metrics_only` process: the metrics server and, when
+/// configured, the admin API. No proxy service, so no proxy listener is bound.
+/// Err when the metrics port can't be bound under `metrics_bind_failure: fatal`.
+pub fn metrics_only_services(config: &Config) -> std::io::Result<Vec<Box<dyn Service>>> {
+    let mut services: Vec<Box<dyn Service>> = Vec::new();
+    if let Some(metrics_service) = metrics::MetricsService::bind(config.metrics_port.unwrap_or(9090), config.metrics_bind_failure)? {
+        services.push(Box::new(GenBackgroundService::new("metrics".to_string(), Arc::new(metrics_service))));
+    }
+
+    if let Some(admin) = &config.admin {
+        let admin_service = Arc::new(admin::AdminService::new(admin.port, admin.token.clone(), admin.max_req_per_minute));
+        services.push(Box::new(GenBackgroundService::new("admin".to_string(), admin_service)));
+    }
+    Ok(services)
+}
+
+/// Initialize process-wide state (client IP detection, limiter defaults, per-route limits)
+/// from a configuration. Must be called once before serving requests.
+pub fn init_globals(config: &Config) {
+    utils::ip::set_use_cloudflare(config.use_cloudflare);
+    utils::ip::set_trusted_proxies(&config.trusted_proxies);
+    utils::ip::set_client_ip_header(config.client_ip_header.as_deref());
+    utils::ip::set_trusted_proxy_hops(config.trusted_proxy_hops);
+    // Without warmup there is nothing to wait for
+    warmup::set_ready(config.warmup.is_none());
+    ratelimit::limiter::init_globals_with_window(
+        config.max_req_per_window,
+        config.block_duration_secs,
+        config.rate_limit_window_secs,
+    );
+
+    for route in config.domain_routes() {
+        let domain_path_key = if let Some(domain) = &route.domain {
+            format!("{}{}", domain, route.path)
+        } else {
+            route.path.clone()
+        };
+
+        log::info!("Setting rate limits for {}: {} req/window, {} sec block",
+              domain_path_key, route.max_req_per_window, route.block_duration_secs);
+
+        ratelimit::limiter::set_route_limits(
+            &domain_path_key,
+            route.max_req_per_window,
+            route.block_duration_secs
+        );
+
+        if let Some(limit_schedule) = &route.limit_schedule {
+            ratelimit::limiter::set_route_schedule(&domain_path_key, limit_schedule.clone());
+        }
+
+        if let Some(advanced) = &route.advanced_limits {
+            for problem in advanced.validate() {
+                log::warn!("advanced_limits for {}: {}", domain_path_key, problem);
+            }
+            if let Some(path) = advanced.jwt_limits.as_ref().and_then(|jwt| jwt.public_key_path.as_deref()) {
+                if let Err(e) = ratelimit::jwt::load_public_key(path) {
+                    log::warn!("jwt_limits for {}: {}; every RS256/ES256 token will be treated as invalid", domain_path_key, e);
+                }
+            }
+            for rule in advanced.rules.iter().flatten() {
+                metrics::register_rule(&rule.name);
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use log::{LevelFilter, Record};
+use log::{Level, LevelFilter, Record};
+use std::fmt;
 use log4rs::{
     append::console::ConsoleAppender,
     append::file::FileAppender,
@@ -59,10 +60,75 @@ pub fn init_logger() -> Result<(), Box<dyn std::error::Error>> {
                 .appender("stdout")
                 .appender("all_logs")
                 .appender("error_logs")
-                .build(LevelFilter::Info)
+                .build(LevelFilter::Trace)
         )?;
 
     // Initialize the log4rs logger with our config
     log4rs::init_config(config)?;
+
+    // The appenders accept everything so routes with a `log_level` override can log below
+    // the global level; the global level itself is enforced by the `log` macros.
+    log::set_max_level(GLOBAL_LEVEL);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Level applied to all logging outside of per-route request logging
+const GLOBAL_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Request logging for one route, at the route's `log_level` or the global level
+#[derive(Debug, Clone, Copy)]
+pub struct RouteLog {
+    level: LevelFilter,
+}
+
+impl RouteLog {
+    pub fn new(level: Option<LevelFilter>) -> Self {
+        Self {
+            level: level.unwrap_or_else(log::max_level),
+        }
+    }
+
+    pub fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    /// Log a record if this route's level allows it, bypassing the global level
+    pub fn log(&self, level: Level, args: fmt::Arguments) {
+        if self.enabled(level) {
+            log::logger().log(
+                &Record::builder()
+                    .args(args)
+                    .level(level)
+                    .target("pingwall::request")
+                    .build(),
+            );
+        }
+    }
+}
+
+impl Default for RouteLog {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Log at debug level through a [`RouteLog`]
+macro_rules! route_debug {
+    ($log:expr, $($arg:tt)+) => { $log.log(::log::Level::Debug, format_args!($($arg)+)) };
+}
+
+/// Log at info level through a [`RouteLog`]
+macro_rules! route_info {
+    ($log:expr, $($arg:tt)+) => { $log.log(::log::Level::Info, format_args!($($arg)+)) };
+}
+
+/// Log at warn level through a [`RouteLog`]
+macro_rules! route_warn {
+    ($log:expr, $($arg:tt)+) => { $log.log(::log::Level::Warn, format_args!($($arg)+)) };
+}
+
+pub(crate) use {route_debug, route_info, route_warn};
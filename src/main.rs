@@ -1,80 +1,73 @@
 mod args;
-mod proxy;
-mod utils;
-mod types;
-mod notification;
-mod ratelimit;
-mod logging;
-mod config;
-mod metrics;
 
 use args::Args;
-use proxy::handler::{build_service, ReverseProxy};
+use pingwall::admin::AdminService;
+use pingwall::analytics::Analytics;
+use pingwall::notification::block_service::UnblockService;
+use pingwall::proxy::health_check::HealthCheckService;
+use pingwall::ratelimit::reputation::{ReputationFeed, REPUTATION_LIST};
+use pingwall::warmup::WarmupService;
+use pingwall::config::RunMode;
+use pingwall::{build_service, init_globals, logging, metrics, metrics_only_services, Config, ReverseProxy};
 use pingora_core::server::Server;
 use pingora_core::services::background::GenBackgroundService;
 use clap::Parser;
-use crate::utils::ip::set_use_cloudflare;
-use crate::config::{Config, UpstreamRoute};
 use std::path::Path;
 use std::sync::Arc;
-use log::{info, warn};
+use log::{error, info, warn};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     logging::init_logger()?;
 
     let config_path = "config.yaml";
-    let config = load_config(config_path);
+    let config = load_config(config_path)?;
+
+    if config.mode == RunMode::MetricsOnly {
+        info!("Running in metrics_only mode: serving metrics on port {} without proxy listeners", config.metrics_port.unwrap_or(9090));
+        let mut server = Server::new(None).unwrap();
+        server.bootstrap();
+        server.add_services(metrics_only_services(&config)?);
+        server.run_forever();
+    }
 
-    set_use_cloudflare(config.use_cloudflare);
-    ratelimit::limiter::init_globals_with_window(
-        config.max_req_per_window,
-        config.block_duration_secs,
-        config.rate_limit_window_secs,
-    );
+    if let Err(e) = config
+        .check_routes()
+        .and_then(|_| config.check_rate_limit_windows())
+        .and_then(|_| config.check_tls_ports())
+    {
+        error!("{}", e);
+        return Err(e.into());
+    }
+    if !config.has_routes() {
+        warn!("No routes configured, answering every request with 503");
+    }
 
-    let mut all_routes = Vec::new();
+    init_globals(&config);
 
     for domain_config in &config.domains {
         info!("Processing domain configuration for: {}", domain_config.domain);
-
-        for router in &domain_config.routers {
-            let route = UpstreamRoute {
-                path: router.path.clone(),
-                upstream: router.upstream.clone(),
-                max_req_per_window: router.max_req_per_window,
-                block_duration_secs: router.block_duration_secs,
-                domain: Some(domain_config.domain.clone()),
-                follow_domain: router.follow_domain,
-                ssl: domain_config.ssl.clone(),
-                timeout_secs: router.timeout_secs,
-                advanced_limits: router.advanced_limits.clone(),
-            };
-
-            all_routes.push(route);
-        }
-    }
-
-    for route in &all_routes {
-        let domain_path_key = if let Some(domain) = &route.domain {
-            format!("{}{}", domain, route.path)
-        } else {
-            route.path.clone()
-        };
-        
-        info!("Setting rate limits for {}: {} req/window, {} sec block", 
-              domain_path_key, route.max_req_per_window, route.block_duration_secs);
-              
-        ratelimit::limiter::set_route_limits(
-            &domain_path_key, 
-            route.max_req_per_window, 
-            route.block_duration_secs
-        );
     }
+    let all_routes = config.domain_routes();
 
     let default_upstream = "127.0.0.1:9992".to_string();
-    let proxy = ReverseProxy::new(config.block_url.clone(), config.api_key.clone(), config.upstream_addr.clone().unwrap_or(default_upstream), config.clone())
+    let mut proxy = ReverseProxy::new(config.block_url.clone(), config.api_key.clone(), config.upstream_addr.clone().unwrap_or(default_upstream), config.clone())
         .with_routes(all_routes.clone());
 
+    // Cleanup in the rate limiter hands expired blocks to this service
+    let mut unblock_service = None;
+    if config.notify_on_unblock {
+        let (sender, service) = UnblockService::new(proxy.rate_limiter.block_notifier.clone());
+        proxy.rate_limiter = proxy.rate_limiter.with_unblock_sender(sender);
+        unblock_service = Some(service);
+    }
+
+    let mut analytics_service = None;
+    if let Some(analytics_config) = &config.analytics {
+        let (analytics, service) = Analytics::new(analytics_config);
+        proxy = proxy.with_analytics(Arc::new(analytics));
+        analytics_service = Some(service);
+    }
+
     info!("Configured routing with {} routes:", all_routes.len());
     for route in &all_routes {
         if let Some(domain) = &route.domain {
@@ -101,12 +94,52 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut server = Server::new(None).unwrap();
     server.bootstrap();
     let default_port = 8081;
-    let proxy_service = build_service(&server.configuration, proxy.clone(), config.port.unwrap_or(default_port));
+    let proxy_service = build_service(&server.configuration, proxy.clone(), config.port.unwrap_or(default_port))?;
     server.add_service(proxy_service);
 
     let metrics_port = config.metrics_port.unwrap_or(9090);
-    let metrics_service = Arc::new(metrics::MetricsService::new(metrics_port));
-    server.add_service(GenBackgroundService::new("metrics".to_string(), metrics_service));
+    if let Some(metrics_service) = metrics::MetricsService::bind(metrics_port, config.metrics_bind_failure)? {
+        server.add_service(GenBackgroundService::new("metrics".to_string(), Arc::new(metrics_service)));
+    }
+
+    if let Some(service) = analytics_service {
+        server.add_service(GenBackgroundService::new("analytics".to_string(), Arc::new(service)));
+    }
+
+    if let Some(service) = unblock_service {
+        server.add_service(GenBackgroundService::new("unblock_notifier".to_string(), Arc::new(service)));
+    }
+
+    if let Some(warmup) = &config.warmup {
+        let warmup_service = Arc::new(WarmupService::new(warmup.clone(), all_routes.clone()));
+        server.add_service(GenBackgroundService::new("warmup".to_string(), warmup_service));
+    }
+
+    if let Some(tracing) = &config.tracing {
+        #[cfg(feature = "otel")]
+        {
+            let tracing_service = Arc::new(pingwall::otel::TracingService::new(tracing.clone()));
+            server.add_service(GenBackgroundService::new("tracing".to_string(), tracing_service));
+        }
+        #[cfg(not(feature = "otel"))]
+        log::warn!("tracing is configured for {} but pingwall was built without the otel feature", tracing.otlp_endpoint);
+    }
+
+    let health_check_targets = config.health_check_targets();
+    if !health_check_targets.is_empty() {
+        let health_check_service = Arc::new(HealthCheckService::new(health_check_targets));
+        server.add_service(GenBackgroundService::new("health_check".to_string(), health_check_service));
+    }
+
+    if let Some(ip_reputation) = &config.ip_reputation {
+        let feed = Arc::new(ReputationFeed::new(ip_reputation.clone(), REPUTATION_LIST.clone()));
+        server.add_service(GenBackgroundService::new("ip_reputation".to_string(), feed));
+    }
+
+    if let Some(admin) = &config.admin {
+        let admin_service = Arc::new(AdminService::new(admin.port, admin.token.clone(), admin.max_req_per_minute));
+        server.add_service(GenBackgroundService::new("admin".to_string(), admin_service));
+    }
 
     let domain_ports = extract_domain_ports(&config.routes);
     
@@ -125,7 +158,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     server.run_forever();
 }
 
-fn extract_domain_ports(routes: &[config::UpstreamRoute]) -> Vec<u16> {
+fn extract_domain_ports(routes: &[pingwall::UpstreamRoute]) -> Vec<u16> {
     let mut ports = Vec::new();
     
     for route in routes {
@@ -143,24 +176,27 @@ fn extract_domain_ports(routes: &[config::UpstreamRoute]) -> Vec<u16> {
     ports
 }
 
-fn load_config(config_path: &str) -> Config {
+/// Configuration from `config_path`, or from the command line when there is no such file
+///
+/// A config file that exists but can't be read or parsed is an error: starting on the
+/// command line defaults instead would silently drop its routes, limits and TLS settings.
+fn load_config(config_path: &str) -> Result<Config, pingwall::config::ConfigError> {
     if Path::new(config_path).exists() {
-        match Config::from_file(config_path) {
+        return match Config::from_file(config_path) {
             Ok(config) => {
                 info!("Loaded configuration from {}", config_path);
-                return config;
+                Ok(config)
             }
             Err(e) => {
-                warn!("Failed to load config from {}: {}", config_path, e);
-                warn!("Falling back to command line arguments");
+                error!("Failed to load config from {}: {}", config_path, e);
+                Err(e)
             }
-        }
-    } else {
-        info!("Config file {} not found, using command line arguments", config_path);
+        };
     }
+    info!("Config file {} not found, using command line arguments", config_path);
 
     let args = Args::parse();
-    Config {
+    Ok(Config {
         max_req_per_window: args.max_req_per_window,
         block_duration_secs: args.block_duration_secs,
         port: Some(args.port),
@@ -173,5 +209,6 @@ fn load_config(config_path: &str) -> Config {
         timeout_secs: 30,
         metrics_port: None,
         rate_limit_window_secs: 1,  // Default: 1 second (per-second rate limiting)
-    }
+        ..Config::default()
+    })
 }
\ No newline at end of file
@@ -6,21 +6,44 @@ use prometheus::{
 use pingora_core::server::ShutdownWatch;
 use pingora_core::services::background::BackgroundService;
 use async_trait::async_trait;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::config::MetricsBindFailure;
+use crate::utils::cloudflare::is_known_country_code;
 
 lazy_static! {
     pub static ref HTTP_REQUESTS_TOTAL: CounterVec = register_counter_vec!(
         "pingwall_http_requests_total",
         "Total number of HTTP requests processed",
-        &["domain", "path", "method", "status"]
+        &["domain", "route", "path", "method", "status", "scheme"]
     ).unwrap();
 
     pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
         "pingwall_http_request_duration_seconds",
         "HTTP request duration in seconds",
-        &["domain", "path", "method"],
+        &["domain", "route", "path", "method"],
         vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
     ).unwrap();
 
+    pub static ref REQUESTS_BY_ACTION: CounterVec = register_counter_vec!(
+        "pingwall_requests_total",
+        "Total number of requests by rate limiting action (allowed, soft_limited, blocked)",
+        &["action"]
+    ).unwrap();
+
+    pub static ref REQUESTS_BY_COUNTRY: CounterVec = register_counter_vec!(
+        "pingwall_requests_by_country_total",
+        "Total number of requests by client country (XX when unknown), for requests with Cloudflare headers trusted",
+        &["country"]
+    ).unwrap();
+
+    pub static ref LIMITER_ERRORS: CounterVec = register_counter_vec!(
+        "pingwall_limiter_errors_total",
+        "Total number of requests that could not be checked by the limiter",
+        &["kind", "mode"]
+    ).unwrap();
+
     pub static ref RATE_LIMIT_BLOCKS: CounterVec = register_counter_vec!(
         "pingwall_rate_limit_blocks_total",
         "Total number of requests blocked by rate limiting",
@@ -33,10 +56,28 @@ lazy_static! {
         &["domain"]
     ).unwrap();
 
+    pub static ref WEBSOCKET_CONNECTIONS: GaugeVec = register_gauge_vec!(
+        "pingwall_websocket_connections",
+        "Number of open WebSocket connections proxied to upstreams",
+        &["domain"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_CONNECTIONS: GaugeVec = register_gauge_vec!(
+        "pingwall_upstream_connections",
+        "Number of requests currently in flight to each upstream",
+        &["upstream"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_HEALTHY: GaugeVec = register_gauge_vec!(
+        "pingwall_upstream_healthy",
+        "Whether each load-balanced upstream is in rotation (1) or benched after a failed connection (0)",
+        &["upstream"]
+    ).unwrap();
+
     pub static ref UPSTREAM_ERRORS: CounterVec = register_counter_vec!(
         "pingwall_upstream_errors_total",
         "Total number of upstream errors",
-        &["domain", "path", "error_type"]
+        &["domain", "route", "method", "error_type"]
     ).unwrap();
 
     pub static ref SSL_HANDSHAKES: CounterVec = register_counter_vec!(
@@ -45,12 +86,66 @@ lazy_static! {
         &["domain", "success"]
     ).unwrap();
 
+    pub static ref SSL_RESUMPTIONS: CounterVec = register_counter_vec!(
+        "pingwall_ssl_resumptions_total",
+        "Total number of TLS handshakes that resumed a previous session",
+        &["domain"]
+    ).unwrap();
+
+    pub static ref MIRROR_REQUESTS: CounterVec = register_counter_vec!(
+        "pingwall_mirror_requests_total",
+        "Total number of requests copied to a shadow upstream",
+        &["result"]
+    ).unwrap();
+
+    pub static ref RESPONSES_TOO_LARGE: CounterVec = register_counter_vec!(
+        "pingwall_responses_too_large_total",
+        "Total number of upstream responses aborted for exceeding max_response_bytes",
+        &["domain", "route"]
+    ).unwrap();
+
+    pub static ref RESPONSE_DEADLINES_EXCEEDED: CounterVec = register_counter_vec!(
+        "pingwall_response_deadline_exceeded_total",
+        "Total number of upstream responses cut off at the route's response_deadline_secs",
+        &["domain", "route"]
+    ).unwrap();
+
+    pub static ref CONNECTIONS_REJECTED: CounterVec = register_counter_vec!(
+        "pingwall_connection_rejected_total",
+        "Total number of new connections dropped for exceeding the per-IP connection rate",
+        &["port"]
+    ).unwrap();
+
+    pub static ref NOTIFICATIONS_DROPPED: CounterVec = register_counter_vec!(
+        "pingwall_notifications_dropped_total",
+        "Total number of block events dropped because a notification destination could not keep up",
+        &["destination"]
+    ).unwrap();
+
+    pub static ref ANALYTICS_DROPPED: CounterVec = register_counter_vec!(
+        "pingwall_analytics_dropped_total",
+        "Total number of sampled analytics records dropped before reaching the sink",
+        &["reason"]
+    ).unwrap();
+
     pub static ref BLOCKED_IPS: GaugeVec = register_gauge_vec!(
         "pingwall_blocked_ips",
         "Number of currently blocked IPs",
         &["domain", "path"]
     ).unwrap();
 
+    pub static ref INTERNAL_REQUESTS: CounterVec = register_counter_vec!(
+        "pingwall_metrics_requests_total",
+        "Total number of requests served by the metrics and admin servers",
+        &["path", "status"]
+    ).unwrap();
+
+    pub static ref RULE_MATCHES: CounterVec = register_counter_vec!(
+        "pingwall_rule_matches_total",
+        "Total number of requests matched by each advanced_limits rule",
+        &["rule"]
+    ).unwrap();
+
     pub static ref WEBHOOK_NOTIFICATIONS: CounterVec = register_counter_vec!(
         "pingwall_webhook_notifications_total",
         "Total number of webhook notifications sent",
@@ -58,77 +153,162 @@ lazy_static! {
     ).unwrap();
 }
 
+/// Metrics server on a listener bound at startup, so a taken port is dealt with
+/// (see `bind`) before the server starts running
 pub struct MetricsService {
-    port: u16,
+    addr: SocketAddr,
+    listener: Mutex<Option<TcpListener>>,
 }
 
+/// Tries at binding the metrics port before giving up, waiting
+/// BIND_RETRY_BASE, then twice as long, ... in between
+const BIND_ATTEMPTS: u32 = 4;
+const BIND_RETRY_BASE: Duration = Duration::from_millis(250);
+
 impl MetricsService {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    /// Bind the metrics port, retrying with backoff while it is taken (e.g. by the
+    /// previous process during a restart). When it stays taken: None under
+    /// `metrics_bind_failure: warn` (run without metrics), Err under `fatal`.
+    pub fn bind(port: u16, bind_failure: MetricsBindFailure) -> std::io::Result<Option<Self>> {
+        let mut attempt = 1;
+        let mut backoff = BIND_RETRY_BASE;
+        let e = loop {
+            match TcpListener::bind(("0.0.0.0", port)) {
+                Ok(listener) => {
+                    let addr = listener.local_addr()?;
+                    return Ok(Some(Self { addr, listener: Mutex::new(Some(listener)) }));
+                }
+                Err(e) if attempt < BIND_ATTEMPTS => {
+                    log::warn!("Cannot bind metrics port {} ({}), retrying in {:?}", port, e, backoff);
+                    std::thread::sleep(backoff);
+                    attempt += 1;
+                    backoff *= 2;
+                }
+                Err(e) => break e,
+            }
+        };
+        match bind_failure {
+            MetricsBindFailure::Warn => {
+                log::error!("Metrics server not started, port {} unavailable: {}; continuing without metrics", port, e);
+                Ok(None)
+            }
+            MetricsBindFailure::Fatal => {
+                log::error!("Metrics server not started, port {} unavailable: {} (metrics_bind_failure: fatal)", port, e);
+                Err(e)
+            }
+        }
     }
-}
 
-#[async_trait]
-impl BackgroundService for MetricsService {
-    async fn start(&self, _shutdown: ShutdownWatch) {
-        let addr = ([0, 0, 0, 0], self.port);
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
 
-        log::info!("Starting Prometheus metrics server on port {}", self.port);
+    /// Serve metrics on the bound listener until the server fails
+    pub async fn serve(&self) -> Result<(), hyper::Error> {
+        let Some(listener) = self.listener.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let builder = hyper::Server::from_tcp(listener)?;
+        log::info!("Starting Prometheus metrics server on port {}", self.addr.port());
 
         let make_service = hyper::service::make_service_fn(|_| async {
             Ok::<_, hyper::Error>(hyper::service::service_fn(metrics_handler))
         });
+        builder.serve(make_service).await
+    }
+}
 
-        let server = hyper::Server::bind(&addr.into())
-            .serve(make_service);
-
-        if let Err(e) = server.await {
+#[async_trait]
+impl BackgroundService for MetricsService {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        if let Err(e) = self.serve().await {
             log::error!("Metrics server error: {}", e);
         }
     }
 }
 
-async fn metrics_handler(
-    _req: hyper::Request<hyper::Body>,
+/// Serves `/ready` (200 once warmup is done, 503 before) and metrics on every other path
+pub(crate) async fn metrics_handler(
+    req: hyper::Request<hyper::Body>,
 ) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+    let ready = req.uri().path() == "/ready";
+    let response = if ready { ready_response() } else { metrics_response() };
+
+    // Any other path serves metrics too, so count it as /metrics
+    record_internal_request(if ready { "/ready" } else { "/metrics" }, response.status().as_u16());
+    Ok(response)
+}
+
+fn ready_response() -> hyper::Response<hyper::Body> {
+    let (status, body) = if crate::warmup::is_ready() { (200, "ready\n") } else { (503, "warming up\n") };
+    hyper::Response::builder()
+        .status(status)
+        .body(hyper::Body::from(body))
+        .unwrap()
+}
+
+fn metrics_response() -> hyper::Response<hyper::Body> {
     let encoder = TextEncoder::new();
     let metric_families = prometheus::gather();
     let mut buffer = vec![];
 
     if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
         log::error!("Failed to encode metrics: {}", e);
-        return Ok(hyper::Response::builder()
+        return hyper::Response::builder()
             .status(500)
             .body(hyper::Body::from("Failed to encode metrics"))
-            .unwrap());
+            .unwrap();
     }
 
-    Ok(hyper::Response::builder()
+    hyper::Response::builder()
         .status(200)
         .header("Content-Type", encoder.format_type())
         .body(hyper::Body::from(buffer))
-        .unwrap())
+        .unwrap()
 }
 
-pub fn record_request(domain: &str, path: &str, method: &str, status: u16, duration_secs: f64) {
+pub fn record_request(domain: &str, route: &str, path: &str, method: &str, status: u16, scheme: &str, duration_secs: f64) {
     HTTP_REQUESTS_TOTAL
-        .with_label_values(&[domain, path, method, &status.to_string()])
+        .with_label_values(&[domain, route, path, method, &status.to_string(), scheme])
         .inc();
 
     HTTP_REQUEST_DURATION
-        .with_label_values(&[domain, path, method])
+        .with_label_values(&[domain, route, path, method])
         .observe(duration_secs);
 }
 
+pub fn record_limit_action(action: &str) {
+    REQUESTS_BY_ACTION
+        .with_label_values(&[action])
+        .inc();
+}
+
+/// Count a request by country; missing or unrecognized codes all land in "XX" to bound cardinality
+pub fn record_request_country(country: Option<&str>) {
+    let country = country
+        .filter(|code| is_known_country_code(code))
+        .map(|code| code.to_ascii_uppercase());
+    REQUESTS_BY_COUNTRY
+        .with_label_values(&[country.as_deref().unwrap_or("XX")])
+        .inc();
+}
+
+pub fn record_limiter_error(kind: &str, mode: &str) {
+    LIMITER_ERRORS
+        .with_label_values(&[kind, mode])
+        .inc();
+}
+
 pub fn record_rate_limit_block(domain: &str, path: &str, ip: &str) {
     RATE_LIMIT_BLOCKS
         .with_label_values(&[domain, path, ip])
         .inc();
 }
 
-pub fn record_upstream_error(domain: &str, path: &str, error_type: &str) {
+/// `route` is the route label, not the raw request path, to keep cardinality bounded
+pub fn record_upstream_error(domain: &str, route: &str, method: &str, error_type: &str) {
     UPSTREAM_ERRORS
-        .with_label_values(&[domain, path, error_type])
+        .with_label_values(&[domain, route, method, error_type])
         .inc();
 }
 
@@ -138,6 +318,13 @@ pub fn record_ssl_handshake(domain: &str, success: bool) {
         .inc();
 }
 
+/// Record a completed TLS handshake; resumed sessions skipped the full key exchange
+pub fn record_ssl_handshake_complete(domain: &str, session_reused: bool) {
+    if session_reused {
+        SSL_RESUMPTIONS.with_label_values(&[domain]).inc();
+    }
+}
+
 pub fn update_active_connections(domain: &str, delta: i64) {
     if delta > 0 {
         ACTIVE_CONNECTIONS.with_label_values(&[domain]).add(delta as f64);
@@ -146,14 +333,193 @@ pub fn update_active_connections(domain: &str, delta: i64) {
     }
 }
 
+pub fn update_websocket_connections(domain: &str, delta: i64) {
+    WEBSOCKET_CONNECTIONS.with_label_values(&[domain]).add(delta as f64);
+}
+
+pub fn set_upstream_connections(upstream: &str, open: usize) {
+    UPSTREAM_CONNECTIONS
+        .with_label_values(&[upstream])
+        .set(open as f64);
+}
+
+pub fn set_upstream_healthy(upstream: &str, healthy: bool) {
+    UPSTREAM_HEALTHY
+        .with_label_values(&[upstream])
+        .set(if healthy { 1.0 } else { 0.0 });
+}
+
 pub fn update_blocked_ips(domain: &str, path: &str, count: i64) {
     BLOCKED_IPS
         .with_label_values(&[domain, path])
         .set(count as f64);
 }
 
+pub fn record_mirror_request(result: &str) {
+    MIRROR_REQUESTS
+        .with_label_values(&[result])
+        .inc();
+}
+
+pub fn record_response_too_large(domain: &str, route: &str) {
+    RESPONSES_TOO_LARGE
+        .with_label_values(&[domain, route])
+        .inc();
+}
+
+pub fn record_response_deadline_exceeded(domain: &str, route: &str) {
+    RESPONSE_DEADLINES_EXCEEDED
+        .with_label_values(&[domain, route])
+        .inc();
+}
+
+pub fn record_connection_rejected(port: u16) {
+    CONNECTIONS_REJECTED
+        .with_label_values(&[&port.to_string()])
+        .inc();
+}
+
+pub fn record_notification_dropped(destination: &str) {
+    NOTIFICATIONS_DROPPED
+        .with_label_values(&[destination])
+        .inc();
+}
+
+pub fn record_analytics_dropped(reason: &str) {
+    ANALYTICS_DROPPED
+        .with_label_values(&[reason])
+        .inc();
+}
+
+/// Count a request to the metrics or admin server (`path` must be a fixed label, not the raw URI)
+pub fn record_internal_request(path: &str, status: u16) {
+    INTERNAL_REQUESTS
+        .with_label_values(&[path, &status.to_string()])
+        .inc();
+}
+
+/// Export a rule's match counter at 0, so a rule that never matches still shows up
+pub fn register_rule(rule: &str) {
+    RULE_MATCHES.with_label_values(&[rule]);
+}
+
+pub fn record_rule_match(rule: &str) {
+    RULE_MATCHES
+        .with_label_values(&[rule])
+        .inc();
+}
+
 pub fn record_webhook_notification(success: bool) {
     WEBHOOK_NOTIFICATIONS
         .with_label_values(&[if success { "true" } else { "false" }])
         .inc();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpstreamRoute;
+    use crate::utils::cloudflare::CloudflareContext;
+
+    fn route(name: Option<&str>, path: &str) -> UpstreamRoute {
+        UpstreamRoute {
+            name: name.map(|n| n.to_string()),
+            path: path.to_string(),
+            upstream: "127.0.0.1:9000".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_named_route_label_in_metrics() {
+        let named = route(Some("users-api"), "/api/users");
+        record_request("metrics.test", named.route_label(), "/api/users/1", "GET", 200, "http", 0.01);
+
+        let count = HTTP_REQUESTS_TOTAL
+            .with_label_values(&["metrics.test", "users-api", "/api/users/1", "GET", "200", "http"])
+            .get();
+        assert_eq!(count, 1.0);
+    }
+
+    #[test]
+    fn test_upstream_error_records_method() {
+        let named = route(Some("orders-api"), "/api/orders");
+        record_upstream_error("metrics.test", named.route_label(), "POST", "ConnectTimedout");
+
+        let post = UPSTREAM_ERRORS
+            .with_label_values(&["metrics.test", "orders-api", "POST", "ConnectTimedout"])
+            .get();
+        let get = UPSTREAM_ERRORS
+            .with_label_values(&["metrics.test", "orders-api", "GET", "ConnectTimedout"])
+            .get();
+        assert_eq!(post, 1.0);
+        assert_eq!(get, 0.0);
+    }
+
+    #[test]
+    fn test_unnamed_route_label_falls_back_to_path() {
+        let unnamed = route(None, "/static");
+        assert_eq!(unnamed.route_label(), "/static");
+
+        record_request("metrics.test", unnamed.route_label(), "/static/app.js", "GET", 200, "http", 0.01);
+        let count = HTTP_REQUESTS_TOTAL
+            .with_label_values(&["metrics.test", "/static", "/static/app.js", "GET", "200", "http"])
+            .get();
+        assert_eq!(count, 1.0);
+    }
+
+    #[test]
+    fn test_metrics_scrape_is_counted() {
+        let scrapes = || INTERNAL_REQUESTS.with_label_values(&["/metrics", "200"]).get();
+        let before = scrapes();
+
+        let req = hyper::Request::get("/metrics").body(hyper::Body::empty()).unwrap();
+        let resp = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(metrics_handler(req))
+            .unwrap();
+
+        assert_eq!(resp.status(), 200);
+        assert_eq!(scrapes(), before + 1.0);
+    }
+
+    fn country_count(country: &str) -> f64 {
+        REQUESTS_BY_COUNTRY.with_label_values(&[country]).get()
+    }
+
+    #[test]
+    fn test_request_country_is_counted() {
+        let mut req = pingora_http::RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("CF-IPCountry", "nz").unwrap();
+        let cloudflare = CloudflareContext::from_request(&req, Some(true));
+        let before = country_count("NZ");
+
+        record_request_country(cloudflare.country.as_deref());
+
+        assert_eq!(country_count("NZ"), before + 1.0);
+    }
+
+    #[test]
+    fn test_unknown_country_is_counted_as_xx() {
+        let req = pingora_http::RequestHeader::build("GET", b"/", None).unwrap();
+        let cloudflare = CloudflareContext::from_request(&req, Some(true));
+        let before = country_count("XX");
+
+        record_request_country(cloudflare.country.as_deref());
+        // A spoofed or garbled header must not create a new label
+        record_request_country(Some("not-a-country"));
+
+        assert_eq!(country_count("XX"), before + 2.0);
+        assert_eq!(country_count("NOT-A-COUNTRY"), 0.0);
+    }
+
+    #[test]
+    fn test_resumed_session_is_counted() {
+        record_ssl_handshake_complete("resume.test", false);
+        assert_eq!(SSL_RESUMPTIONS.with_label_values(&["resume.test"]).get(), 0.0);
+
+        record_ssl_handshake_complete("resume.test", true);
+        assert_eq!(SSL_RESUMPTIONS.with_label_values(&["resume.test"]).get(), 1.0);
+    }
+}
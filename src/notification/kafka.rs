@@ -0,0 +1,164 @@
+use crate::metrics;
+use crate::notification::Notifier;
+use crate::types::RateLimitExceeded;
+use async_trait::async_trait;
+use log::debug;
+use pingora_core::{Error, ErrorType, Result};
+use thiserror::Error as ThisError;
+
+/// Failure to hand a record to the producer
+#[derive(ThisError, Debug, PartialEq, Eq)]
+pub enum ProduceError {
+    /// The producer's local queue is full (brokers slow or unreachable)
+    #[error("producer queue is full")]
+    QueueFull,
+
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Queues records for a Kafka topic without waiting for the brokers
+pub trait EventProducer: Send + Sync {
+    fn send(&self, topic: &str, key: &str, payload: &[u8]) -> std::result::Result<(), ProduceError>;
+}
+
+/// Publishes block events as JSON (the webhook payload) to a Kafka topic, keyed by IP
+///
+/// Records go to the producer's in-memory queue and are delivered by its own thread,
+/// so a slow cluster never holds up a request. When the queue is full the event is
+/// dropped and counted in `pingwall_notifications_dropped_total{destination="kafka"}`.
+pub struct KafkaNotifier<P: EventProducer> {
+    producer: P,
+    topic: String,
+}
+
+impl<P: EventProducer> KafkaNotifier<P> {
+    pub fn with_producer(producer: P, topic: &str) -> Self {
+        Self { producer, topic: topic.to_string() }
+    }
+}
+
+#[async_trait]
+impl<P: EventProducer> Notifier for KafkaNotifier<P> {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn notify(&self, event: &RateLimitExceeded) -> Result<()> {
+        let payload = serde_json::to_vec(event)
+            .map_err(|e| Error::explain(ErrorType::InternalError, format!("kafka event encoding failed: {}", e)))?;
+
+        match self.producer.send(&self.topic, &event.ip, &payload) {
+            Ok(()) => {
+                debug!("Queued block event for {} on kafka topic {}", event.ip, self.topic);
+                Ok(())
+            }
+            Err(ProduceError::QueueFull) => {
+                debug!("Kafka producer queue full, dropping block event for {}", event.ip);
+                metrics::record_notification_dropped("kafka");
+                Ok(())
+            }
+            Err(e) => Err(Error::explain(
+                ErrorType::WriteError,
+                format!("kafka send to topic {} failed: {}", self.topic, e),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod producer {
+    use super::{EventProducer, KafkaNotifier, ProduceError};
+    use crate::config::KafkaAcks;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+    use rdkafka::producer::{BaseRecord, DefaultProducerContext, ThreadedProducer};
+
+    /// librdkafka producer; its background thread delivers the queued records
+    pub struct RdKafkaProducer(ThreadedProducer<DefaultProducerContext>);
+
+    impl EventProducer for RdKafkaProducer {
+        fn send(&self, topic: &str, key: &str, payload: &[u8]) -> Result<(), ProduceError> {
+            let record = BaseRecord::to(topic).key(key).payload(payload);
+            self.0.send(record).map_err(|(e, _)| match e {
+                KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull) => ProduceError::QueueFull,
+                e => ProduceError::Other(e.to_string()),
+            })
+        }
+    }
+
+    impl KafkaNotifier<RdKafkaProducer> {
+        pub fn new(brokers: &[String], topic: &str, acks: KafkaAcks, queue_size: usize) -> Result<Self, KafkaError> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", brokers.join(","))
+                .set("acks", acks.as_str())
+                .set("queue.buffering.max.messages", queue_size.to_string())
+                .create()?;
+            Ok(Self::with_producer(RdKafkaProducer(producer), topic))
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub use producer::RdKafkaProducer;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::test_event;
+    use std::sync::Mutex;
+
+    /// Records what was sent; fails with QueueFull once `capacity` records are queued
+    struct MockProducer {
+        capacity: usize,
+        sent: Mutex<Vec<(String, String, Vec<u8>)>>,
+    }
+
+    impl EventProducer for MockProducer {
+        fn send(&self, topic: &str, key: &str, payload: &[u8]) -> std::result::Result<(), ProduceError> {
+            let mut sent = self.sent.lock().unwrap();
+            if sent.len() >= self.capacity {
+                return Err(ProduceError::QueueFull);
+            }
+            sent.push((topic.to_string(), key.to_string(), payload.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn notifier(capacity: usize) -> KafkaNotifier<MockProducer> {
+        KafkaNotifier::with_producer(MockProducer { capacity, sent: Mutex::new(Vec::new()) }, "pingwall-blocks")
+    }
+
+    #[test]
+    fn test_block_event_is_published_keyed_by_ip() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let kafka = notifier(10);
+
+        rt.block_on(kafka.notify(&test_event("192.0.2.1"))).unwrap();
+
+        let sent = kafka.producer.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let (topic, key, payload) = &sent[0];
+        assert_eq!(topic, "pingwall-blocks");
+        assert_eq!(key, "192.0.2.1");
+        let json: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        assert_eq!(json["ip"], "192.0.2.1");
+        assert_eq!(json["domain"], "api.example.com");
+        assert_eq!(json["lock_duration"], 300);
+        assert_eq!(json["max_requests"], 10);
+    }
+
+    #[test]
+    fn test_full_queue_drops_event_without_error() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let kafka = notifier(1);
+        let dropped = || metrics::NOTIFICATIONS_DROPPED.with_label_values(&["kafka"]).get();
+        let before = dropped();
+
+        rt.block_on(kafka.notify(&test_event("192.0.2.1"))).unwrap();
+        assert!(rt.block_on(kafka.notify(&test_event("192.0.2.2"))).is_ok());
+
+        assert_eq!(kafka.producer.sent.lock().unwrap().len(), 1);
+        assert!(dropped() >= before + 1.0);
+    }
+}
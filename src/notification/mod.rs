@@ -1 +1,34 @@
-pub mod block_service;
\ No newline at end of file
+pub mod block_service;
+pub mod kafka;
+pub mod syslog;
+
+use crate::types::RateLimitExceeded;
+use async_trait::async_trait;
+use pingora_core::Result;
+
+/// A destination for block events, in addition to the webhook
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Short name used in logs (e.g. "syslog")
+    fn name(&self) -> &'static str;
+
+    /// Deliver one block event
+    async fn notify(&self, event: &RateLimitExceeded) -> Result<()>;
+}
+
+/// Block event for the notifier tests; override fields with `..test_event(ip)`
+#[cfg(test)]
+pub(crate) fn test_event(ip: &str) -> RateLimitExceeded {
+    RateLimitExceeded {
+        message: "Rate limit exceeded on path '/api', IP blocked (count: 11/10)".to_string(),
+        ip: ip.to_string(),
+        lock_duration: 300,
+        domain: Some("api.example.com".to_string()),
+        path: "/api".to_string(),
+        request_url: Some("/api/users".to_string()),
+        user_agent: None,
+        current_count: 11,
+        max_requests: 10,
+        timestamp: "2026-01-01T00:00:00+00:00".to_string(),
+    }
+}
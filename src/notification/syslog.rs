@@ -0,0 +1,186 @@
+use crate::config::SyslogProtocol;
+use crate::notification::Notifier;
+use crate::types::RateLimitExceeded;
+use async_trait::async_trait;
+use log::debug;
+use pingora_core::{Error, ErrorType, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Private enterprise number used for the structured data ID
+const SD_ID: &str = "pingwall@32473";
+
+/// Severity "warning" (RFC 5424 section 6.2.1)
+const SEVERITY_WARNING: u8 = 4;
+
+/// Sends block events as RFC 5424 syslog messages over UDP or TCP
+pub struct SyslogNotifier {
+    address: String,
+    protocol: SyslogProtocol,
+    facility: u8,
+    hostname: String,
+}
+
+impl SyslogNotifier {
+    /// Returns None if the facility name is unknown
+    pub fn new(address: &str, protocol: SyslogProtocol, facility: &str) -> Option<Self> {
+        Some(Self {
+            address: address.to_string(),
+            protocol,
+            facility: facility_code(facility)?,
+            hostname: std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string()),
+        })
+    }
+
+    /// Format a block event as an RFC 5424 message
+    pub fn format_message(&self, event: &RateLimitExceeded) -> String {
+        let pri = self.facility * 8 + SEVERITY_WARNING;
+
+        let mut params = vec![
+            ("ip", event.ip.clone()),
+            ("path", event.path.clone()),
+            ("lock_duration", event.lock_duration.to_string()),
+            ("current_count", event.current_count.to_string()),
+            ("max_requests", event.max_requests.to_string()),
+        ];
+        if let Some(ref domain) = event.domain {
+            params.push(("domain", domain.clone()));
+        }
+        if let Some(ref url) = event.request_url {
+            params.push(("request_url", url.clone()));
+        }
+        if let Some(ref user_agent) = event.user_agent {
+            params.push(("user_agent", user_agent.clone()));
+        }
+
+        let structured_data = params
+            .iter()
+            .map(|(name, value)| format!(" {}=\"{}\"", name, escape_param_value(value)))
+            .collect::<String>();
+
+        format!(
+            "<{}>1 {} {} pingwall {} BLOCK [{}{}] {}",
+            pri,
+            event.timestamp,
+            self.hostname,
+            std::process::id(),
+            SD_ID,
+            structured_data,
+            event.message
+        )
+    }
+}
+
+#[async_trait]
+impl Notifier for SyslogNotifier {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    async fn notify(&self, event: &RateLimitExceeded) -> Result<()> {
+        let message = self.format_message(event);
+        debug!("Sending syslog message to {}: {}", self.address, message);
+
+        let result = match self.protocol {
+            SyslogProtocol::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await;
+                match socket {
+                    Ok(socket) => socket.send_to(message.as_bytes(), &self.address).await.map(|_| ()),
+                    Err(e) => Err(e),
+                }
+            }
+            SyslogProtocol::Tcp => {
+                // Octet-counting framing (RFC 6587 section 3.4.1)
+                let framed = format!("{} {}", message.len(), message);
+                match TcpStream::connect(&self.address).await {
+                    Ok(mut stream) => stream.write_all(framed.as_bytes()).await,
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        result.map_err(|e| {
+            Error::explain(ErrorType::WriteError, format!("syslog send to {} failed: {}", self.address, e))
+        })
+    }
+}
+
+/// Map a syslog facility name to its numeric code
+fn facility_code(name: &str) -> Option<u8> {
+    let code = match name.to_ascii_lowercase().as_str() {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Escape '"', '\' and ']' in structured data parameter values (RFC 5424 section 6.3.3)
+fn escape_param_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::test_event;
+    use std::time::Duration;
+
+    #[test]
+    fn test_block_produces_well_formed_syslog_over_udp() {
+        let listener = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let notifier = SyslogNotifier::new(&address, SyslogProtocol::Udp, "local0").unwrap();
+        let event = RateLimitExceeded { user_agent: Some("bad \"bot\"]".to_string()), ..test_event("192.0.2.1") };
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(notifier.notify(&event))
+            .unwrap();
+
+        let mut buf = [0u8; 2048];
+        let len = listener.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+
+        // local0 (16) * 8 + warning (4)
+        assert!(message.starts_with("<132>1 2026-01-01T00:00:00+00:00 "), "{}", message);
+        assert!(message.contains(" pingwall "));
+        assert!(message.contains(" BLOCK [pingwall@32473 ip=\"192.0.2.1\" path=\"/api\""));
+        assert!(message.contains("domain=\"api.example.com\""));
+        assert!(message.contains("user_agent=\"bad \\\"bot\\\"\\]\""));
+        assert!(message.ends_with("] Rate limit exceeded on path '/api', IP blocked (count: 11/10)"));
+    }
+
+    #[test]
+    fn test_unknown_facility_is_rejected() {
+        assert!(SyslogNotifier::new("127.0.0.1:514", SyslogProtocol::Udp, "nope").is_none());
+    }
+}
@@ -0,0 +1,242 @@
+//! OpenTelemetry request spans exported over OTLP (`otel` feature)
+
+use crate::config::TracingConfig;
+use async_trait::async_trait;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{Span, SpanKind, Status, TraceContextExt, Tracer, TracerProvider as _};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use pingora_http::RequestHeader;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+// Set once the OTLP pipeline is installed; spans are not started before that
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// W3C `traceparent` headers of a request, for the propagator
+struct HeaderExtractor<'a>(&'a RequestHeader);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.headers.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.headers.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut RequestHeader);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let Err(e) = self.0.insert_header(key.to_string(), value) {
+            log::debug!("Failed to propagate {} header: {}", key, e);
+        }
+    }
+}
+
+/// The server span of one proxied request
+pub struct RequestTrace {
+    cx: Context,
+    method: String,
+    upstream_start: Option<Instant>,
+}
+
+impl RequestTrace {
+    /// Start a span for the request, child of its `traceparent` if present, named by
+    /// its method until the route is known (raw paths would make span names unbounded)
+    /// None when tracing is not configured
+    pub fn start(req: &RequestHeader) -> Option<Self> {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(Self::start_with(&global::tracer("pingwall"), req))
+    }
+
+    fn start_with<T>(tracer: &T, req: &RequestHeader) -> Self
+    where
+        T: Tracer,
+        T::Span: Send + Sync + 'static,
+    {
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req));
+        let method = req.method.to_string();
+        let span = tracer
+            .span_builder(method.clone())
+            .with_kind(SpanKind::Server)
+            .with_attributes([
+                KeyValue::new("http.request.method", method.clone()),
+                KeyValue::new("url.path", req.uri.path().to_string()),
+            ])
+            .start_with_context(tracer, &parent);
+        Self {
+            cx: parent.with_span(span),
+            method,
+            upstream_start: None,
+        }
+    }
+
+    /// Name the span `<method> <route label>`
+    pub fn set_route(&self, route: &str) {
+        let span = self.cx.span();
+        span.update_name(format!("{} {}", self.method, route));
+        span.set_attribute(KeyValue::new("http.route", route.to_string()));
+    }
+
+    /// The request is about to be sent to `upstream`
+    pub fn upstream_started(&mut self, upstream: &str) {
+        self.cx.span().set_attribute(KeyValue::new("server.address", upstream.to_string()));
+        self.upstream_start = Some(Instant::now());
+    }
+
+    /// Pass this span on to the upstream as its parent
+    pub fn inject(&self, upstream_request: &mut RequestHeader) {
+        TraceContextPropagator::new().inject_context(&self.cx, &mut HeaderInjector(upstream_request));
+    }
+
+    /// The upstream response headers arrived
+    pub fn upstream_responded(&mut self) {
+        if let Some(start) = self.upstream_start.take() {
+            let upstream_ms = start.elapsed().as_secs_f64() * 1000.0;
+            self.cx.span().set_attribute(KeyValue::new("pingwall.upstream_duration_ms", upstream_ms));
+        }
+    }
+
+    /// Finish the span with the response status (0 if none was sent)
+    pub fn end(self, status: u16, error: Option<String>) {
+        let span = self.cx.span();
+        span.set_attribute(KeyValue::new("http.response.status_code", status as i64));
+        if let Some(error) = error {
+            span.set_status(Status::error(error));
+        } else if status >= 500 {
+            span.set_status(Status::error(format!("HTTP {}", status)));
+        }
+        span.end();
+    }
+}
+
+/// Installs the OTLP exporter and flushes pending spans on shutdown
+///
+/// The batch exporter needs a tokio runtime, so it is set up from this background
+/// service rather than in `main`.
+pub struct TracingService {
+    config: TracingConfig,
+}
+
+impl TracingService {
+    pub fn new(config: TracingConfig) -> Self {
+        Self { config }
+    }
+
+    fn install(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&self.config.otlp_endpoint)
+            .build()?;
+        let resource = Resource::new([KeyValue::new("service.name", self.config.service_name.clone())]);
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(resource)
+            .build();
+        global::set_tracer_provider(provider);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TracingService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if let Err(e) = self.install() {
+            log::error!("Tracing not started: failed to set up OTLP exporter for {}: {}", self.config.otlp_endpoint, e);
+            return;
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        log::info!("Exporting OpenTelemetry traces to {}", self.config.otlp_endpoint);
+
+        let _ = shutdown.changed().await;
+        ENABLED.store(false, Ordering::Relaxed);
+        global::shutdown_tracer_provider();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanId, TraceId};
+    use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+
+    fn finished_span(req: &RequestHeader) -> opentelemetry_sdk::export::trace::SpanData {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        let tracer = provider.tracer("test");
+
+        let mut trace = RequestTrace::start_with(&tracer, req);
+        trace.set_route("users-api");
+        trace.upstream_started("10.0.0.5:8080");
+        trace.upstream_responded();
+        trace.end(200, None);
+
+        let mut spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 1);
+        spans.remove(0)
+    }
+
+    fn attribute<'a>(span: &'a opentelemetry_sdk::export::trace::SpanData, key: &str) -> Option<&'a opentelemetry::Value> {
+        span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| &kv.value)
+    }
+
+    #[test]
+    fn test_request_span_attributes() {
+        let req = RequestHeader::build("POST", b"/api/users", None).unwrap();
+        let span = finished_span(&req);
+
+        assert_eq!(span.name, "POST users-api");
+        assert_eq!(attribute(&span, "url.path"), Some(&"/api/users".into()));
+        assert_eq!(span.span_kind, SpanKind::Server);
+        assert_eq!(attribute(&span, "http.route"), Some(&"users-api".into()));
+        assert_eq!(attribute(&span, "server.address"), Some(&"10.0.0.5:8080".into()));
+        assert_eq!(attribute(&span, "http.response.status_code"), Some(&200i64.into()));
+        assert!(attribute(&span, "pingwall.upstream_duration_ms").is_some());
+    }
+
+    #[test]
+    fn test_unrouted_span_is_named_by_method_only() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        let req = RequestHeader::build("GET", b"/wp-login.php?id=12345", None).unwrap();
+
+        RequestTrace::start_with(&provider.tracer("test"), &req).end(404, None);
+
+        assert_eq!(exporter.get_finished_spans().unwrap()[0].name, "GET");
+    }
+
+    #[test]
+    fn test_incoming_traceparent_is_parent() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let span = finished_span(&req);
+
+        assert_eq!(span.span_context.trace_id(), TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap());
+        assert_eq!(span.parent_span_id, SpanId::from_hex("00f067aa0ba902b7").unwrap());
+    }
+
+    #[test]
+    fn test_inject_propagates_own_span() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder().with_simple_exporter(exporter).build();
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        let trace = RequestTrace::start_with(&provider.tracer("test"), &req);
+
+        let mut upstream_request = RequestHeader::build("GET", b"/", None).unwrap();
+        trace.inject(&mut upstream_request);
+
+        let span_id = trace.cx.span().span_context().span_id().to_string();
+        let traceparent = upstream_request.headers.get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.contains(&span_id));
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+
+/// A single access log record, written once per request from the `logging` hook
+pub struct AccessLogEntry<'a> {
+    pub method: &'a str,
+    pub scheme: &'a str,
+    pub host: &'a str,
+    pub route: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub duration_ms: u128,
+    /// Rate limiting action: allowed, soft_limited or blocked
+    pub action: &'a str,
+    /// Limit that rejected the request, "-" if allowed
+    pub reason_code: &'a str,
+}
+
+impl fmt::Display for AccessLogEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "access method={} scheme={} host={} route={} path={} status={} duration_ms={} action={} reason_code={}",
+            self.method, self.scheme, self.host, self.route, self.path, self.status, self.duration_ms,
+            self.action, self.reason_code
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::decision::LimitDecision;
+
+    fn entry(status: u16, decision: &LimitDecision) -> String {
+        AccessLogEntry {
+            method: "GET",
+            scheme: "https",
+            host: "example.com",
+            route: "/api",
+            path: "/api/users",
+            status,
+            duration_ms: 3,
+            action: decision.action.as_str(),
+            reason_code: decision.reason_code.unwrap_or("-"),
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn test_blocked_request_logs_action_and_reason() {
+        let line = entry(429, &LimitDecision::blocked("country"));
+        assert!(line.ends_with(" status=429 duration_ms=3 action=blocked reason_code=country"), "{}", line);
+    }
+
+    #[test]
+    fn test_allowed_request_logs_action() {
+        let line = entry(200, &LimitDecision::allowed());
+        assert!(line.ends_with(" action=allowed reason_code=-"), "{}", line);
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::Path;
+
+/// Path prefix of ACME HTTP-01 challenge requests (RFC 8555 section 8.3)
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Token from a challenge request path, if it is one
+///
+/// Tokens are base64url, so anything else (including `..` or `/`) is rejected
+/// before it gets near the filesystem.
+pub fn challenge_token(path: &str) -> Option<&str> {
+    let token = path.strip_prefix(CHALLENGE_PREFIX)?;
+    let valid = !token.is_empty()
+        && token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    valid.then_some(token)
+}
+
+/// Key authorization written by the ACME client for `token`, if present
+///
+/// The ACME client writes files while the proxy runs, so they are read per request,
+/// off the async worker threads.
+pub async fn challenge_response(challenge_dir: &str, token: &str) -> Option<Vec<u8>> {
+    tokio::fs::read(Path::new(challenge_dir).join(token)).await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_token_validation() {
+        assert_eq!(challenge_token("/.well-known/acme-challenge/abc-DEF_123"), Some("abc-DEF_123"));
+        assert_eq!(challenge_token("/.well-known/acme-challenge/"), None);
+        assert_eq!(challenge_token("/.well-known/acme-challenge/../../etc/passwd"), None);
+        assert_eq!(challenge_token("/api/users"), None);
+    }
+
+    #[test]
+    fn test_challenge_response_served_from_dir() {
+        let dir = std::env::temp_dir().join(format!("pingwall-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tok3n"), "tok3n.thumbprint").unwrap();
+        let dir_str = dir.to_str().unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        assert_eq!(rt.block_on(challenge_response(dir_str, "tok3n")).as_deref(), Some(&b"tok3n.thumbprint"[..]));
+        assert_eq!(rt.block_on(challenge_response(dir_str, "missing")), None);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,87 @@
+use crate::config::CanaryConfig;
+use pingora_http::RequestHeader;
+
+/// Upstream for this request: the canary when selected, otherwise `stable`
+pub fn select_upstream<'a>(stable: &'a str, canary: Option<&'a CanaryConfig>, req: &RequestHeader) -> &'a str {
+    match canary {
+        Some(config) if is_canary(config, req) => &config.upstream,
+        _ => stable,
+    }
+}
+
+/// Whether the request opted into the canary by header or falls in the sampled percentage
+fn is_canary(config: &CanaryConfig, req: &RequestHeader) -> bool {
+    header_matches(config, req) || config.sampler.sample(config.percentage)
+}
+
+fn header_matches(config: &CanaryConfig, req: &RequestHeader) -> bool {
+    let Some(name) = config.header.as_deref() else {
+        return false;
+    };
+    req.headers.get_all(name).iter().any(|value| match config.value.as_deref() {
+        Some(expected) => value.to_str().map_or(false, |v| v.trim().eq_ignore_ascii_case(expected)),
+        None => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(percentage: f64) -> CanaryConfig {
+        CanaryConfig {
+            upstream: "canary:8000".to_string(),
+            header: Some("X-Canary".to_string()),
+            value: Some("true".to_string()),
+            percentage,
+            sampler: Default::default(),
+        }
+    }
+
+    fn request(canary_header: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/api", None).unwrap();
+        if let Some(value) = canary_header {
+            req.insert_header("X-Canary", value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_header_matched_requests_always_hit_canary() {
+        let config = config(0.0);
+
+        assert!((0..100).all(|_| is_canary(&config, &request(Some("true")))));
+        assert!(is_canary(&config, &request(Some("TRUE"))));
+        assert!(!is_canary(&config, &request(Some("false"))));
+        assert!(!is_canary(&config, &request(None)));
+        assert_eq!(select_upstream("stable:8000", Some(&config), &request(Some("true"))), "canary:8000");
+        assert_eq!(select_upstream("stable:8000", None, &request(Some("true"))), "stable:8000");
+    }
+
+    #[test]
+    fn test_percentage_of_other_requests_hit_canary() {
+        let config = config(20.0);
+
+        let canary = (0..1000).filter(|_| is_canary(&config, &request(None))).count();
+        assert!((180..=220).contains(&canary), "{} of 1000 went to the canary", canary);
+    }
+
+    #[test]
+    fn test_interleaved_routes_keep_their_own_weight() {
+        let (light, heavy) = (config(10.0), config(50.0));
+
+        let (mut light_canary, mut heavy_canary) = (0, 0);
+        for _ in 0..1000 {
+            light_canary += (select_upstream("stable:8000", Some(&light), &request(None)) == "canary:8000") as usize;
+            heavy_canary += (select_upstream("stable:8000", Some(&heavy), &request(None)) == "canary:8000") as usize;
+        }
+        assert_eq!(light_canary, 100);
+        assert_eq!(heavy_canary, 500);
+    }
+
+    #[test]
+    fn test_header_without_value_matches_any_value() {
+        let config = CanaryConfig { value: None, ..config(0.0) };
+        assert!(is_canary(&config, &request(Some("anything"))));
+    }
+}
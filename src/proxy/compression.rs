@@ -0,0 +1,382 @@
+use crate::config::CompressionConfig;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use pingora_core::{Error, ErrorType, Result};
+use pingora_http::{RequestHeader, ResponseHeader};
+use std::io::Write;
+
+/// brotli quality and window: fast enough for on-the-fly compression
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Encodings pingwall can compress a response with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Encoding to use for a client's Accept-Encoding: the supported one with the
+/// highest q-value, brotli on a tie; None when neither is acceptable
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut wildcard = None;
+
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match coding.as_str() {
+            "br" => brotli = Some(q),
+            "gzip" | "x-gzip" => gzip = Some(q),
+            "*" => wildcard = Some(q),
+            _ => {}
+        }
+    }
+
+    // "*" stands for every coding not listed on its own
+    let brotli = brotli.or(wildcard).unwrap_or(0.0);
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    if brotli <= 0.0 && gzip <= 0.0 {
+        None
+    } else if brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else {
+        Some(Encoding::Gzip)
+    }
+}
+
+/// Whether a Content-Type is listed in `types` ("text/*" matches any text type)
+fn type_matches(content_type: &str, types: &[String]) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or("").trim();
+    types.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(top_level) => content_type
+            .split_once('/')
+            .map_or(false, |(top, _)| top.eq_ignore_ascii_case(top_level)),
+        None => content_type.eq_ignore_ascii_case(pattern),
+    })
+}
+
+fn header<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn has_no_transform(cache_control: &str) -> bool {
+    cache_control.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+/// ETag for the compressed body: a strong ETag promises byte-identical content,
+/// which no longer holds, so it is weakened (`"abc"` becomes `W/"abc"`)
+fn weaken_etag(etag: &str) -> Option<String> {
+    (!etag.starts_with("W/")).then(|| format!("W/{}", etag))
+}
+
+/// Encoding to compress this response with, None to send it as is
+fn choose_encoding(config: &CompressionConfig, req: &RequestHeader, resp: &ResponseHeader) -> Option<Encoding> {
+    if !config.enabled || req.method == http::Method::HEAD {
+        return None;
+    }
+    // No body, or one the upstream already encoded
+    if matches!(resp.status.as_u16(), 101 | 204 | 304) || resp.headers.contains_key("content-encoding") {
+        return None;
+    }
+    // A range's bytes refer to the uncompressed representation
+    if resp.status.as_u16() == 206 || resp.headers.contains_key("content-range") {
+        return None;
+    }
+    // The upstream asked for the body to reach the client unmodified
+    if resp.headers.get_all("cache-control").iter().filter_map(|v| v.to_str().ok()).any(has_no_transform) {
+        return None;
+    }
+    if !header(&resp.headers, "content-type").map_or(false, |ct| type_matches(ct, &config.types)) {
+        return None;
+    }
+    // Chunked responses of unknown size are compressed
+    let declared_len = header(&resp.headers, "content-length").and_then(|len| len.trim().parse::<u64>().ok());
+    if declared_len.map_or(false, |len| len < config.min_size_bytes) {
+        return None;
+    }
+    negotiate(header(&req.headers, "accept-encoding")?)
+}
+
+/// Set up compression of an upstream response: when it is eligible, rewrite its
+/// headers for the encoded body and return the compressor for its body chunks
+pub fn start(config: &CompressionConfig, req: &RequestHeader, resp: &mut ResponseHeader) -> Result<Option<ResponseCompressor>> {
+    let Some(encoding) = choose_encoding(config, req, resp) else {
+        return Ok(None);
+    };
+
+    // The compressed length isn't known until the body has been streamed
+    resp.remove_header("content-length");
+    if resp.version == http::Version::HTTP_11 {
+        resp.insert_header("Transfer-Encoding", "chunked")?;
+    }
+    resp.insert_header("Content-Encoding", encoding.as_str())?;
+    if let Some(etag) = header(&resp.headers, "etag").and_then(weaken_etag) {
+        resp.insert_header("ETag", etag)?;
+    }
+    resp.append_header("Vary", "Accept-Encoding")?;
+    Ok(Some(ResponseCompressor::new(encoding)))
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// Compress a chunk and return the output so far; flushed per chunk so a
+    /// streamed response isn't held back
+    fn write(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(writer) => {
+                writer.write_all(chunk)?;
+                writer.flush()?;
+                Ok(std::mem::take(writer.get_mut()))
+            }
+            Encoder::Gzip(writer) => {
+                writer.write_all(chunk)?;
+                writer.flush()?;
+                Ok(std::mem::take(writer.get_mut()))
+            }
+        }
+    }
+
+    /// Compress the last chunk and end the compressed stream
+    fn finish(self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(mut writer) => {
+                writer.write_all(chunk)?;
+                Ok(writer.into_inner())
+            }
+            Encoder::Gzip(mut writer) => {
+                writer.write_all(chunk)?;
+                writer.finish()
+            }
+        }
+    }
+}
+
+/// Compresses one response body as it streams through the body filter
+pub struct ResponseCompressor {
+    encoder: Option<Encoder>,
+}
+
+impl ResponseCompressor {
+    pub fn new(encoding: Encoding) -> Self {
+        let encoder = match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_BITS,
+            ))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+        };
+        Self { encoder: Some(encoder) }
+    }
+
+    /// Replace a body chunk with its compressed form; the final chunk also
+    /// carries the end of the compressed stream
+    pub fn compress_chunk(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) -> Result<()> {
+        let Some(mut encoder) = self.encoder.take() else {
+            return Ok(());
+        };
+        let chunk = body.take().unwrap_or_default();
+
+        let compressed = if end_of_stream {
+            encoder.finish(&chunk)
+        } else {
+            let compressed = encoder.write(&chunk);
+            self.encoder = Some(encoder);
+            compressed
+        }
+        .map_err(compression_error)?;
+
+        if !compressed.is_empty() {
+            *body = Some(Bytes::from(compressed));
+        }
+        Ok(())
+    }
+}
+
+fn compression_error(e: std::io::Error) -> Box<Error> {
+    Error::because(ErrorType::InternalError, "compressing response body", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(accept_encoding: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/api/orders", None).unwrap();
+        if let Some(accept_encoding) = accept_encoding {
+            req.insert_header("Accept-Encoding", accept_encoding).unwrap();
+        }
+        req
+    }
+
+    fn response(content_type: &str, content_length: Option<usize>) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Content-Type", content_type).unwrap();
+        if let Some(len) = content_length {
+            resp.insert_header("Content-Length", len.to_string()).unwrap();
+        }
+        resp
+    }
+
+    fn enabled() -> CompressionConfig {
+        CompressionConfig { enabled: true, ..Default::default() }
+    }
+
+    /// Run a body through the compressor in the given chunks
+    fn compress(encoding: Encoding, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut compressor = ResponseCompressor::new(encoding);
+        let mut out = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut body = Some(Bytes::copy_from_slice(chunk));
+            compressor.compress_chunk(&mut body, i == chunks.len() - 1).unwrap();
+            out.extend_from_slice(&body.unwrap_or_default());
+        }
+        out
+    }
+
+    #[test]
+    fn test_negotiate_prefers_brotli_and_respects_q() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.5, gzip;q=0.8"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=0, *;q=0.1"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+    }
+
+    #[test]
+    fn test_eligible_response_headers_are_rewritten() {
+        let mut resp = response("application/json; charset=utf-8", Some(4096));
+        let compressor = start(&enabled(), &request(Some("gzip, br")), &mut resp).unwrap();
+
+        assert!(compressor.is_some());
+        assert_eq!(resp.headers.get("Content-Encoding").unwrap(), "br");
+        assert!(resp.headers.get("Content-Length").is_none());
+        assert_eq!(resp.headers.get("Transfer-Encoding").unwrap(), "chunked");
+        assert_eq!(resp.headers.get("Vary").unwrap(), "Accept-Encoding");
+    }
+
+    #[test]
+    fn test_ineligible_responses_pass_through() {
+        let config = enabled();
+        let accepts = request(Some("gzip"));
+        let skipped = |req: &RequestHeader, mut resp: ResponseHeader| {
+            let compressor = start(&config, req, &mut resp).unwrap();
+            compressor.is_none() && !resp.headers.contains_key("Content-Encoding")
+        };
+
+        // Below min_size_bytes
+        assert!(skipped(&accepts, response("application/json", Some(100))));
+        // Not a listed type
+        assert!(skipped(&accepts, response("image/png", Some(4096))));
+        // Client doesn't accept a supported encoding
+        assert!(skipped(&request(None), response("application/json", Some(4096))));
+        assert!(skipped(&request(Some("deflate")), response("application/json", Some(4096))));
+        // Disabled
+        let mut resp = response("application/json", Some(4096));
+        assert!(start(&CompressionConfig::default(), &accepts, &mut resp).unwrap().is_none());
+
+        // Already encoded by the upstream
+        let mut resp = response("application/json", Some(4096));
+        resp.insert_header("Content-Encoding", "gzip").unwrap();
+        assert!(start(&config, &accepts, &mut resp).unwrap().is_none());
+        assert_eq!(resp.headers.get("Content-Length").unwrap(), "4096");
+    }
+
+    #[test]
+    fn test_partial_content_is_not_compressed() {
+        let mut resp = ResponseHeader::build(206, None).unwrap();
+        resp.insert_header("Content-Type", "application/json").unwrap();
+        resp.insert_header("Content-Range", "bytes 0-4095/10000").unwrap();
+        resp.insert_header("Content-Length", "4096").unwrap();
+        assert!(start(&enabled(), &request(Some("gzip")), &mut resp).unwrap().is_none());
+        assert_eq!(resp.headers.get("Content-Length").unwrap(), "4096");
+
+        let mut resp = response("application/json", Some(4096));
+        resp.insert_header("Content-Range", "bytes */10000").unwrap();
+        assert!(start(&enabled(), &request(Some("gzip")), &mut resp).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_no_transform_is_respected() {
+        let mut resp = response("application/json", Some(4096));
+        resp.insert_header("Cache-Control", "public, No-Transform, max-age=60").unwrap();
+        assert!(start(&enabled(), &request(Some("gzip")), &mut resp).unwrap().is_none());
+        assert!(resp.headers.get("Content-Encoding").is_none());
+
+        let mut resp = response("application/json", Some(4096));
+        resp.insert_header("Cache-Control", "max-age=60").unwrap();
+        assert!(start(&enabled(), &request(Some("gzip")), &mut resp).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_strong_etag_is_weakened() {
+        let mut resp = response("application/json", Some(4096));
+        resp.insert_header("ETag", "\"v42\"").unwrap();
+        start(&enabled(), &request(Some("gzip")), &mut resp).unwrap();
+        assert_eq!(resp.headers.get("ETag").unwrap(), "W/\"v42\"");
+
+        let mut resp = response("application/json", Some(4096));
+        resp.insert_header("ETag", "W/\"v42\"").unwrap();
+        start(&enabled(), &request(Some("gzip")), &mut resp).unwrap();
+        assert_eq!(resp.headers.get("ETag").unwrap(), "W/\"v42\"");
+
+        // Left alone when the body isn't compressed
+        let mut resp = response("application/json", Some(100));
+        resp.insert_header("ETag", "\"v42\"").unwrap();
+        start(&enabled(), &request(Some("gzip")), &mut resp).unwrap();
+        assert_eq!(resp.headers.get("ETag").unwrap(), "\"v42\"");
+    }
+
+    #[test]
+    fn test_text_wildcard_and_unknown_length() {
+        let mut resp = response("text/html", None);
+        assert!(start(&enabled(), &request(Some("gzip")), &mut resp).unwrap().is_some());
+        assert_eq!(resp.headers.get("Content-Encoding").unwrap(), "gzip");
+    }
+
+    #[test]
+    fn test_gzip_body_round_trips() {
+        let body = br#"{"orders":[{"id":1,"status":"shipped"},{"id":2,"status":"shipped"}]}"#;
+        let compressed = compress(Encoding::Gzip, &[&body[..20], &body[20..], b""]);
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn test_brotli_body_round_trips() {
+        let body = "{\"id\":1,\"status\":\"shipped\"}".repeat(200);
+        let compressed = compress(Encoding::Brotli, &[&body.as_bytes()[..1000], &body.as_bytes()[1000..]]);
+        assert!(compressed.len() < body.len());
+
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, body.as_bytes());
+    }
+}
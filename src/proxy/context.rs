@@ -0,0 +1,141 @@
+use crate::config::{HeaderRulesConfig, SecurityHeadersConfig, TimeoutResponseConfig};
+use crate::logging::RouteLog;
+use crate::proxy::compression::ResponseCompressor;
+use crate::proxy::idempotency::ResponseRecorder;
+use crate::proxy::mirror::MirrorRequest;
+use crate::proxy::response_deadline::ResponseDeadline;
+use crate::proxy::response_limit::ResponseLimit;
+use crate::proxy::upstream_connections::UpstreamSlot;
+use crate::ratelimit::decision::LimitDecision;
+use std::time::{Duration, Instant};
+
+/// Per-request state carried through the proxy phases
+pub struct RequestCtx {
+    /// When the request was first seen
+    pub start: Instant,
+
+    /// Scheme the client originally used ("http" or "https")
+    pub scheme: &'static str,
+
+    /// Client IP as determined for rate limiting
+    pub client_ip: Option<String>,
+
+    /// Address of the upstream the request was sent to
+    pub upstream: Option<String>,
+
+    /// Pool address the request was balanced to, reported unhealthy if it can't be reached
+    pub pool_upstream: Option<String>,
+
+    /// Name (or path) of the matched route
+    pub route: Option<String>,
+
+    /// Request is not recorded in request metrics (e.g. health checks)
+    pub skip_metrics: bool,
+
+    /// Copy of this request for the route's shadow upstream, if sampled
+    pub mirror: Option<MirrorRequest>,
+
+    /// Request logging at the matched route's level
+    pub log: RouteLog,
+
+    /// What rate limiting decided for this request
+    pub limit_decision: LimitDecision,
+
+    /// Size limit on the upstream response, from the route's max_response_bytes
+    pub response_limit: Option<ResponseLimit>,
+
+    /// Cap on concurrent requests to the chosen upstream
+    pub upstream_connection_limit: Option<usize>,
+
+    /// This request's claim on an upstream connection, released when the request ends
+    pub upstream_slot: Option<UpstreamSlot>,
+
+    /// Captures the response to store under the request's idempotency key
+    pub idempotency: Option<ResponseRecorder>,
+
+    /// Security headers of the matched route
+    pub security_headers: Option<SecurityHeadersConfig>,
+
+    /// Log the response headers (the matched route has debug_headers set)
+    pub debug_headers: bool,
+
+    /// Trips this request already made through pingwall, when the route limits them
+    pub redirect_hops: Option<u32>,
+
+    /// Length of the request body read up front (the matched route has request_buffering: buffer)
+    pub buffered_body_length: Option<usize>,
+
+    /// Time limit on the whole upstream response, from the route's response_deadline_secs
+    pub response_deadline: Option<ResponseDeadline>,
+
+    /// Method the matched route sends upstream (rewrite_method)
+    pub rewrite_method: Option<http::Method>,
+
+    /// Header rules of the matched route for the upstream request
+    pub request_headers: Option<HeaderRulesConfig>,
+
+    /// Header rules of the matched route for the response
+    pub response_headers: Option<HeaderRulesConfig>,
+
+    /// Response of the matched route for upstream timeouts
+    pub timeout_response: Option<TimeoutResponseConfig>,
+
+    /// The last proxying attempt failed because the upstream timed out
+    pub upstream_timed_out: bool,
+
+    /// The upstream accepted a WebSocket upgrade, counted in pingwall_websocket_connections until the request ends
+    pub websocket_open: bool,
+
+    /// Compresses the response body for the client (see CompressionConfig)
+    pub compressor: Option<ResponseCompressor>,
+
+    /// OpenTelemetry span of this request, when tracing is enabled
+    #[cfg(feature = "otel")]
+    pub trace: Option<crate::otel::RequestTrace>,
+}
+
+impl RequestCtx {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            scheme: "http",
+            client_ip: None,
+            upstream: None,
+            pool_upstream: None,
+            route: None,
+            skip_metrics: false,
+            mirror: None,
+            log: RouteLog::default(),
+            limit_decision: LimitDecision::allowed(),
+            response_limit: None,
+            upstream_connection_limit: None,
+            upstream_slot: None,
+            idempotency: None,
+            security_headers: None,
+            debug_headers: false,
+            redirect_hops: None,
+            buffered_body_length: None,
+            response_deadline: None,
+            rewrite_method: None,
+            request_headers: None,
+            response_headers: None,
+            timeout_response: None,
+            upstream_timed_out: false,
+            websocket_open: false,
+            compressor: None,
+            #[cfg(feature = "otel")]
+            trace: None,
+        }
+    }
+
+    /// Time spent on this request so far
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl Default for RequestCtx {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,113 @@
+use crate::config::Config;
+use http::HeaderMap;
+use pingora_http::{RequestHeader, ResponseHeader};
+
+/// Headers whose values are credentials and never logged
+const REDACTED_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"];
+
+/// Header names never logged for this config: REDACTED_HEADERS plus the secret
+/// token headers of timeout_override and method_override
+pub fn redacted_headers(config: &Config) -> Vec<String> {
+    let token_headers = [
+        config.timeout_override.as_ref().map(|o| o.token_header.as_str()),
+        config.method_override.as_ref().map(|o| o.token_header.as_str()),
+    ];
+    REDACTED_HEADERS
+        .iter()
+        .copied()
+        .chain(token_headers.into_iter().flatten())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// One-line dump of a request's method, path and headers, for routes with
+/// `debug_headers`; the query string is redacted, as it often carries tokens
+pub fn request_dump(req: &RequestHeader, redacted: &[String]) -> String {
+    let query = if req.uri.query().is_some() { "?[redacted]" } else { "" };
+    format!("{} {}{} {:?} | {}", req.method, req.uri.path(), query, req.version, headers_dump(&req.headers, redacted))
+}
+
+/// One-line dump of a response's status and headers, for routes with `debug_headers`
+pub fn response_dump(resp: &ResponseHeader, redacted: &[String]) -> String {
+    format!("{} | {}", resp.status.as_u16(), headers_dump(&resp.headers, redacted))
+}
+
+fn headers_dump(headers: &HeaderMap, redacted: &[String]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if redacted.iter().any(|redacted| redacted == name.as_str()) {
+                "[redacted]"
+            } else {
+                value.to_str().unwrap_or("[binary]")
+            };
+            format!("{}: {}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_headers_are_dumped_with_credentials_redacted() {
+        let mut req = RequestHeader::build("POST", b"/webhooks/stripe?id=1&token=tok_secret", None).unwrap();
+        req.insert_header("Content-Type", "application/json").unwrap();
+        req.insert_header("Authorization", "Bearer sk_live_secret").unwrap();
+        req.insert_header("Cookie", "session=abc123").unwrap();
+
+        let dump = request_dump(&req, &redacted_headers(&Config::default()));
+        assert!(dump.starts_with("POST /webhooks/stripe?[redacted] HTTP/1.1"), "{}", dump);
+        assert!(!dump.contains("tok_secret"));
+        assert!(dump.contains("content-type: application/json"));
+        assert!(dump.contains("authorization: [redacted]"));
+        assert!(dump.contains("cookie: [redacted]"));
+        assert!(!dump.contains("sk_live_secret"));
+        assert!(!dump.contains("abc123"));
+    }
+
+    #[test]
+    fn test_response_headers_are_dumped_with_set_cookie_redacted() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Set-Cookie", "session=abc123; HttpOnly").unwrap();
+        resp.insert_header("Cache-Control", "no-store").unwrap();
+
+        let dump = response_dump(&resp, &redacted_headers(&Config::default()));
+        assert!(dump.starts_with("200 | "));
+        assert!(dump.contains("cache-control: no-store"));
+        assert!(dump.contains("set-cookie: [redacted]"));
+        assert!(!dump.contains("abc123"));
+    }
+
+    #[test]
+    fn test_configured_token_headers_are_redacted() {
+        let config: Config = serde_yaml::from_str(
+            "timeout_override:\n  token: t0ken\n  token_header: X-Batch-Token\n  max_secs: 600\nmethod_override:\n  token: m3thod\n  token_header: X-Legacy-Token\n",
+        )
+        .unwrap();
+        let mut req = RequestHeader::build("POST", b"/reports", None).unwrap();
+        req.insert_header("X-Batch-Token", "t0ken").unwrap();
+        req.insert_header("X-Legacy-Token", "m3thod").unwrap();
+        req.insert_header("X-Request-Timeout", "300").unwrap();
+
+        let dump = request_dump(&req, &redacted_headers(&config));
+        assert_eq!(
+            dump,
+            "POST /reports HTTP/1.1 | x-batch-token: [redacted] | x-legacy-token: [redacted] | x-request-timeout: 300"
+        );
+    }
+
+    #[test]
+    fn test_only_flagged_route_dumps_headers() {
+        let config: Config = serde_yaml::from_str(
+            "domains:\n  - domain: api.example.com\n    routers:\n      - path: /webhooks\n        upstream: \"http://hooks:8000\"\n        debug_headers: true\n      - path: /\n        upstream: \"http://api:8000\"\n",
+        )
+        .unwrap();
+
+        let routes = config.domain_routes();
+        let flagged: Vec<&str> = routes.iter().filter(|r| r.debug_headers).map(|r| r.path.as_str()).collect();
+        assert_eq!(flagged, vec!["/webhooks"]);
+    }
+}
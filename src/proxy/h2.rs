@@ -0,0 +1,123 @@
+use http::Uri;
+use pingora_core::{Error, ErrorType::InvalidHTTPHeader, Result};
+use pingora_http::RequestHeader;
+
+/// Connection-specific headers that are illegal in HTTP/2 (RFC 9113 section 8.2.2)
+const H2_FORBIDDEN_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Normalize a request that is about to be sent to an HTTP/2 upstream
+///
+/// - The `Host` header is moved into the URI authority (sent as `:authority`)
+/// - Connection-specific headers (and any header listed in `Connection`) are dropped
+/// - `TE` is only kept when it is exactly `trailers`
+pub fn normalize_h2_upstream_request(req: &mut RequestHeader, tls: bool) -> Result<()> {
+    // Headers nominated by the Connection header are hop-by-hop as well
+    let nominated: Vec<String> = req
+        .headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    for name in nominated.iter().map(String::as_str).chain(H2_FORBIDDEN_HEADERS.iter().copied()) {
+        req.remove_header(name);
+    }
+
+    let te_is_trailers = req
+        .headers
+        .get("te")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().eq_ignore_ascii_case("trailers"));
+    if te_is_trailers == Some(false) {
+        req.remove_header("te");
+    }
+
+    let host = req
+        .remove_header("host")
+        .and_then(|h| h.to_str().ok().map(|s| s.to_string()))
+        .or_else(|| req.uri.authority().map(|a| a.as_str().to_string()));
+
+    let Some(authority) = host else {
+        return Error::e_explain(InvalidHTTPHeader, "HTTP/2 upstream request without Host or authority");
+    };
+
+    let path_and_query = req
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/")
+        .to_string();
+
+    let uri = Uri::builder()
+        .scheme(if tls { "https" } else { "http" })
+        .authority(authority.as_str())
+        .path_and_query(path_and_query)
+        .build()
+        .map_err(|e| Error::explain(InvalidHTTPHeader, format!("invalid :authority '{}': {}", authority, e)))?;
+
+    req.set_uri(uri);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn h1_request() -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/api/users?page=2", None).unwrap();
+        req.insert_header("Host", "api.example.com").unwrap();
+        req.insert_header("Connection", "keep-alive, X-Hop").unwrap();
+        req.insert_header("Keep-Alive", "timeout=5").unwrap();
+        req.insert_header("X-Hop", "1").unwrap();
+        req.insert_header("Transfer-Encoding", "chunked").unwrap();
+        req.insert_header("TE", "gzip").unwrap();
+        req.insert_header("Accept", "application/json").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_h1_to_h2_sets_authority_and_drops_host() {
+        let mut req = h1_request();
+        normalize_h2_upstream_request(&mut req, true).unwrap();
+
+        assert_eq!(req.uri.authority().map(|a| a.as_str()), Some("api.example.com"));
+        assert_eq!(req.uri.scheme_str(), Some("https"));
+        assert_eq!(req.uri.path_and_query().map(|pq| pq.as_str()), Some("/api/users?page=2"));
+        assert!(req.headers.get("host").is_none());
+    }
+
+    #[test]
+    fn test_h1_to_h2_strips_connection_headers() {
+        let mut req = h1_request();
+        normalize_h2_upstream_request(&mut req, true).unwrap();
+
+        for name in ["connection", "keep-alive", "transfer-encoding", "te", "x-hop"] {
+            assert!(req.headers.get(name).is_none(), "{} should be removed", name);
+        }
+        assert_eq!(req.headers.get("accept").unwrap(), "application/json");
+    }
+
+    #[test]
+    fn test_te_trailers_is_kept() {
+        let mut req = h1_request();
+        req.insert_header("TE", "trailers").unwrap();
+        normalize_h2_upstream_request(&mut req, true).unwrap();
+
+        assert_eq!(req.headers.get("te").unwrap(), "trailers");
+    }
+
+    #[test]
+    fn test_missing_host_is_rejected() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(normalize_h2_upstream_request(&mut req, true).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::config::HeaderRulesConfig;
+use crate::utils::host::extract_host;
+use pingora_core::Result;
+use pingora_http::{RequestHeader, ResponseHeader};
+use pingora_proxy::Session;
+
+/// Placeholders allowed in added header values
+const PLACEHOLDERS: [&str; 3] = ["$client_ip", "$host", "$path"];
+
+/// Values of the `$host`, `$client_ip` and `$path` placeholders for one request
+#[derive(Debug, Default)]
+pub struct HeaderVars {
+    host: String,
+    client_ip: String,
+    path: String,
+}
+
+impl HeaderVars {
+    /// Placeholders of the client's request (its Host header and path, before any rewrite)
+    pub fn from_session(session: &Session, client_ip: Option<&str>) -> Self {
+        Self {
+            host: extract_host(session).unwrap_or_default(),
+            client_ip: client_ip.unwrap_or_default().to_string(),
+            path: session.req_header().uri.path().to_string(),
+        }
+    }
+
+    fn value(&self, placeholder: &str) -> &str {
+        match placeholder {
+            "$client_ip" => &self.client_ip,
+            "$host" => &self.host,
+            _ => &self.path,
+        }
+    }
+
+    /// Header value with its placeholders replaced; resolved values are not
+    /// expanded again, and an unknown `$name` is kept as is
+    fn expand(&self, value: &str) -> String {
+        let mut expanded = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+            match PLACEHOLDERS.iter().find(|placeholder| rest.starts_with(*placeholder)) {
+                Some(placeholder) => {
+                    expanded.push_str(self.value(placeholder));
+                    rest = &rest[placeholder.len()..];
+                }
+                None => {
+                    expanded.push('$');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+/// Apply the route's `request_headers` to the request sent upstream
+pub fn apply_to_request(rules: &HeaderRulesConfig, req: &mut RequestHeader, vars: &HeaderVars) -> Result<()> {
+    for name in &rules.remove {
+        req.remove_header(name.to_ascii_lowercase().as_str());
+    }
+    for (name, value) in &rules.add {
+        req.insert_header(name.clone(), vars.expand(value))?;
+    }
+    Ok(())
+}
+
+/// Apply the route's `response_headers` to the upstream response
+pub fn apply_to_response(rules: &HeaderRulesConfig, resp: &mut ResponseHeader, vars: &HeaderVars) -> Result<()> {
+    for name in &rules.remove {
+        resp.remove_header(name.to_ascii_lowercase().as_str());
+    }
+    for (name, value) in &rules.add {
+        resp.insert_header(name.clone(), vars.expand(value))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> HeaderVars {
+        HeaderVars {
+            host: "shop.example.com".to_string(),
+            client_ip: "203.0.113.7".to_string(),
+            path: "/api/orders".to_string(),
+        }
+    }
+
+    fn rules(yaml: &str) -> HeaderRulesConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn header<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+        req.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    #[test]
+    fn test_request_rules_add_and_remove() {
+        let rules = rules("add:\n  X-Forwarded-Host: $host\n  X-Env: prod\nremove: [x-DEBUG]\n");
+        let mut req = RequestHeader::build("GET", b"/api/orders", None).unwrap();
+        req.insert_header("X-Debug", "1").unwrap();
+        req.insert_header("X-Env", "staging").unwrap();
+
+        apply_to_request(&rules, &mut req, &vars()).unwrap();
+        assert_eq!(header(&req, "X-Debug"), None);
+        assert_eq!(header(&req, "X-Forwarded-Host"), Some("shop.example.com"));
+        assert_eq!(header(&req, "X-Env"), Some("prod"));
+        assert_eq!(req.headers.get_all("X-Env").iter().count(), 1);
+    }
+
+    #[test]
+    fn test_response_rules_add_and_remove() {
+        let rules = rules("add:\n  X-Served-For: $client_ip\nremove: [Server, x-powered-by]\n");
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("Server", "nginx").unwrap();
+        resp.insert_header("X-Powered-By", "PHP").unwrap();
+
+        apply_to_response(&rules, &mut resp, &vars()).unwrap();
+        assert!(resp.headers.get("Server").is_none());
+        assert!(resp.headers.get("X-Powered-By").is_none());
+        assert_eq!(resp.headers.get("X-Served-For").unwrap(), "203.0.113.7");
+    }
+
+    #[test]
+    fn test_placeholders_expand_once() {
+        let vars = HeaderVars { path: "/$host".to_string(), ..vars() };
+        assert_eq!(vars.expand("$client_ip via $host$path"), "203.0.113.7 via shop.example.com/$host");
+        assert_eq!(vars.expand("cost: $5 $"), "cost: $5 $");
+    }
+
+    #[test]
+    fn test_invalid_header_rules_fail_load() {
+        let err = serde_yaml::from_str::<HeaderRulesConfig>("remove: [\"X Debug\"]\n").unwrap_err();
+        assert!(err.to_string().contains("invalid header name 'X Debug'"), "{}", err);
+        let err = serde_yaml::from_str::<HeaderRulesConfig>("add:\n  X-Env: \"prod\\n\"\n").unwrap_err();
+        assert!(err.to_string().contains("invalid value for header 'X-Env'"), "{}", err);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::config::HealthCheckConfig;
+use crate::proxy::upstream::set_health_check_state;
+use async_trait::async_trait;
+use futures::future::join_all;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use reqwest::Client;
+use std::time::Duration;
+
+/// Consecutive check results of one upstream and whether it is in rotation
+#[derive(Debug)]
+struct HealthState {
+    healthy: bool,
+    successes: u32,
+    failures: u32,
+}
+
+impl HealthState {
+    /// Upstreams start in rotation until they fail `unhealthy_threshold` checks
+    fn new() -> Self {
+        Self { healthy: true, successes: 0, failures: 0 }
+    }
+
+    /// Count one check; returns the new state when the upstream flips
+    fn record(&mut self, ok: bool, config: &HealthCheckConfig) -> Option<bool> {
+        if ok {
+            self.successes += 1;
+            self.failures = 0;
+            if !self.healthy && self.successes >= config.healthy_threshold.max(1) {
+                self.healthy = true;
+                return Some(true);
+            }
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+            if self.healthy && self.failures >= config.unhealthy_threshold.max(1) {
+                self.healthy = false;
+                return Some(false);
+            }
+        }
+        None
+    }
+}
+
+/// URL checked for an upstream: its scheme and authority with the check path
+/// (a base path or query on the upstream is not used)
+fn health_check_url(upstream: &str, path: &str) -> Option<String> {
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    if upstream.starts_with("http://") || upstream.starts_with("https://") {
+        let mut url = url::Url::parse(upstream).ok()?;
+        url.set_path(&path);
+        url.set_query(None);
+        return Some(url.to_string());
+    }
+    let authority = upstream.split(['/', '?']).next().filter(|authority| !authority.is_empty())?;
+    Some(format!("http://{}{}", authority, path))
+}
+
+/// Whether the upstream answered the check with a 2xx in time
+async fn probe(client: &Client, url: &str, timeout: Duration) -> bool {
+    match client.get(url).timeout(timeout).send().await {
+        Ok(response) => response.status().is_success(),
+        Err(e) => {
+            log::debug!("Health check {} failed: {}", url, e);
+            false
+        }
+    }
+}
+
+/// Periodically checks every upstream with its `health_check` and takes failing
+/// ones out of rotation (see `select_pool_upstream`), instead of waiting for real
+/// requests to fail against them
+pub struct HealthCheckService {
+    targets: Vec<(String, HealthCheckConfig)>,
+}
+
+impl HealthCheckService {
+    /// `targets` as returned by `Config::health_check_targets`
+    pub fn new(targets: Vec<(String, HealthCheckConfig)>) -> Self {
+        Self { targets }
+    }
+
+    async fn watch(client: &Client, upstream: &str, config: &HealthCheckConfig) {
+        let Some(url) = health_check_url(upstream, &config.path) else {
+            log::warn!("Not health checking upstream {}: cannot build a check URL", upstream);
+            return;
+        };
+        let timeout = Duration::from_secs(config.timeout_secs.max(1));
+        let mut state = HealthState::new();
+        set_health_check_state(upstream, true);
+
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let ok = probe(client, &url, timeout).await;
+            match state.record(ok, config) {
+                Some(true) => log::info!("Upstream {} passed {} health checks, back in rotation", upstream, config.healthy_threshold),
+                Some(false) => log::warn!("Upstream {} failed {} health checks, taking it out of rotation", upstream, config.unhealthy_threshold),
+                None => continue,
+            }
+            set_health_check_state(upstream, state.healthy);
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HealthCheckService {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        let client = Client::builder()
+            .danger_accept_invalid_certs(true) // upstreams often use internal certificates
+            .build()
+            .unwrap_or_else(|_| Client::new());
+
+        log::info!("Health checking {} upstream(s)", self.targets.len());
+        join_all(self.targets.iter().map(|(upstream, config)| Self::watch(&client, upstream, config))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thresholds_flip_health() {
+        let config = HealthCheckConfig { healthy_threshold: 2, unhealthy_threshold: 3, ..Default::default() };
+        let mut state = HealthState::new();
+
+        assert_eq!(state.record(false, &config), None);
+        assert_eq!(state.record(false, &config), None);
+        // A success in between resets the failure streak
+        assert_eq!(state.record(true, &config), None);
+        assert_eq!(state.record(false, &config), None);
+        assert_eq!(state.record(false, &config), None);
+        assert_eq!(state.record(false, &config), Some(false));
+        assert_eq!(state.record(false, &config), None);
+
+        assert_eq!(state.record(true, &config), None);
+        assert_eq!(state.record(true, &config), Some(true));
+        assert!(state.healthy);
+    }
+
+    #[test]
+    fn test_health_check_url_per_upstream_form() {
+        assert_eq!(health_check_url("http://api:8000", "/healthz").as_deref(), Some("http://api:8000/healthz"));
+        assert_eq!(health_check_url("https://api.internal/v1?region=eu", "/healthz").as_deref(), Some("https://api.internal/healthz"));
+        assert_eq!(health_check_url("10.0.0.5:8080/app", "status").as_deref(), Some("http://10.0.0.5:8080/status"));
+        assert_eq!(health_check_url("", "/healthz"), None);
+    }
+
+    #[test]
+    fn test_probe_requires_2xx() {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response};
+
+        async fn endpoint(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+            let status = if req.uri().path() == "/healthz" { 200 } else { 503 };
+            Ok(Response::builder().status(status).body(Body::empty()).unwrap())
+        }
+
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let server = rt.block_on(async {
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+                .serve(make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(endpoint)) }))
+        });
+        let addr = server.local_addr();
+        rt.spawn(server);
+
+        let client = Client::new();
+        let timeout = Duration::from_secs(2);
+        assert!(rt.block_on(probe(&client, &format!("http://{}/healthz", addr), timeout)));
+        assert!(!rt.block_on(probe(&client, &format!("http://{}/status", addr), timeout)));
+        // Nothing listening
+        assert!(!rt.block_on(probe(&client, "http://127.0.0.1:1/healthz", timeout)));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::config::IdempotencyConfig;
+use bytes::{Bytes, BytesMut};
+use once_cell::sync::Lazy;
+use pingora_core::tls::hash::{hash, MessageDigest};
+use pingora_core::Result;
+use pingora_http::{RequestHeader, ResponseHeader};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most responses kept at once; the oldest key is evicted beyond this
+const MAX_ENTRIES: usize = 10_000;
+
+/// Most body bytes kept at once across all responses; the oldest keys are evicted
+/// to make room, so the store stays well below MAX_ENTRIES * MAX_BODY_BYTES
+const MAX_STORE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Responses with larger bodies are forwarded but not cached
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+static STORE: Lazy<Mutex<IdempotencyStore>> = Lazy::new(|| Mutex::new(IdempotencyStore::new(MAX_ENTRIES, MAX_STORE_BYTES)));
+
+/// A stored upstream response, replayed for repeated keys
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    /// Header sent for a replay: the original one with the complete body length
+    pub fn replay_header(&self) -> Result<ResponseHeader> {
+        let mut header = self.header.clone();
+        header.remove_header("Transfer-Encoding");
+        header.insert_header("Content-Length", self.body.len().to_string())?;
+        header.insert_header("X-Idempotent-Replay", "true")?;
+        Ok(header)
+    }
+}
+
+struct Entry {
+    response: CachedResponse,
+    expires_at: Instant,
+}
+
+/// Responses by idempotency key, bounded to `capacity` keys and `max_bytes` of
+/// stored bodies (oldest first out)
+pub struct IdempotencyStore {
+    entries: HashMap<String, Entry>,
+    order: VecDeque<String>,
+    capacity: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl IdempotencyStore {
+    pub fn new(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    /// The response stored for `key`, unless it has expired
+    pub fn get(&self, key: &str, now: Instant) -> Option<CachedResponse> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| entry.response.clone())
+    }
+
+    pub fn insert(&mut self, key: String, response: CachedResponse, ttl: Duration, now: Instant) {
+        let size = response.body.len();
+        if size > self.max_bytes {
+            return;
+        }
+        if let Some(existing) = self.entries.remove(&key) {
+            self.bytes -= existing.response.body.len();
+            self.order.retain(|k| *k != key);
+        }
+
+        while self.entries.len() >= self.capacity || self.bytes + size > self.max_bytes {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.bytes -= evicted.response.body.len();
+            }
+        }
+        self.bytes += size;
+        self.order.push_back(key.clone());
+        self.entries.insert(key, Entry { response, expires_at: now + ttl });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Body bytes currently stored
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Store key of a request on an idempotent route; None without the key header
+///
+/// Keys are scoped to the host, route, method and path, and to the caller: the
+/// Authorization credentials when sent, the client IP otherwise. A client that
+/// guesses or sees another caller's key gets its own request forwarded instead of
+/// that caller's stored response.
+pub fn request_key(config: &IdempotencyConfig, req: &RequestHeader, host: &str, route: &str, client_ip: &str) -> Option<String> {
+    let key = req.headers.get(config.header.as_str())?.to_str().ok()?.trim();
+    if key.is_empty() {
+        return None;
+    }
+    Some(format!("{}|{}|{}|{} {}|{}", host, route, caller(req, client_ip), req.method, req.uri.path(), key))
+}
+
+/// Identity of the caller a stored response belongs to; credentials are hashed
+/// so they are not kept in the store
+fn caller(req: &RequestHeader, client_ip: &str) -> String {
+    let credentials = req.headers.get(http::header::AUTHORIZATION).map(|v| v.as_bytes()).filter(|v| !v.is_empty());
+    match credentials.map(|credentials| hash(MessageDigest::sha256(), credentials)) {
+        Some(Ok(digest)) => format!("auth:{}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        _ => format!("ip:{}", client_ip),
+    }
+}
+
+/// Stored response for `key`, if one is still replayable
+pub fn cached_response(key: &str) -> Option<CachedResponse> {
+    STORE.lock().unwrap().get(key, Instant::now())
+}
+
+/// Captures the upstream response of the first request with a key
+#[derive(Debug)]
+pub struct ResponseRecorder {
+    key: String,
+    ttl: Duration,
+    header: Option<ResponseHeader>,
+    body: BytesMut,
+}
+
+impl ResponseRecorder {
+    pub fn new(key: String, ttl: Duration) -> Self {
+        Self { key, ttl, header: None, body: BytesMut::new() }
+    }
+
+    /// Keep the response header; false if the response is not worth caching
+    ///
+    /// 5xx responses are not stored so the client can retry a failed attempt.
+    pub fn record_header(&mut self, resp: &ResponseHeader) -> bool {
+        if resp.status.is_server_error() {
+            return false;
+        }
+        self.header = Some(resp.clone());
+        true
+    }
+
+    /// Append a body chunk; false once the body is too large to cache
+    pub fn record_chunk(&mut self, chunk: Option<&Bytes>) -> bool {
+        if let Some(chunk) = chunk {
+            if self.body.len() + chunk.len() > MAX_BODY_BYTES {
+                return false;
+            }
+            self.body.extend_from_slice(chunk);
+        }
+        true
+    }
+
+    fn into_entry(self) -> Option<(String, CachedResponse, Duration)> {
+        let header = self.header?;
+        Some((self.key, CachedResponse { header, body: self.body.freeze() }, self.ttl))
+    }
+
+    /// Store the complete response for later replays
+    pub fn finish(self) {
+        if let Some((key, response, ttl)) = self.into_entry() {
+            STORE.lock().unwrap().insert(key, response, ttl, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> IdempotencyConfig {
+        IdempotencyConfig { header: "Idempotency-Key".to_string(), ttl_secs: 60 }
+    }
+
+    const CLIENT_IP: &str = "203.0.113.7";
+
+    fn payment_request(key: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/payments", None).unwrap();
+        if let Some(key) = key {
+            req.insert_header("Idempotency-Key", key).unwrap();
+        }
+        req
+    }
+
+    fn authorized(mut req: RequestHeader, authorization: &str) -> RequestHeader {
+        req.insert_header("Authorization", authorization).unwrap();
+        req
+    }
+
+    fn recorded(key: &str, status: u16, body: &'static [u8]) -> ResponseRecorder {
+        let mut recorder = ResponseRecorder::new(key.to_string(), Duration::from_secs(60));
+        let mut resp = ResponseHeader::build(status, None).unwrap();
+        resp.insert_header("Transfer-Encoding", "chunked").unwrap();
+        if recorder.record_header(&resp) {
+            recorder.record_chunk(Some(&Bytes::from_static(body)));
+        }
+        recorder
+    }
+
+    #[test]
+    fn test_repeated_key_replays_cached_response() {
+        let req = payment_request(Some("pay-123"));
+        let key = request_key(&config(), &req, "api.example.com", "payments", CLIENT_IP).unwrap();
+
+        // First request is forwarded and its response stored
+        assert!(cached_response(&key).is_none());
+        recorded(&key, 201, b"{\"id\":\"ch_1\"}").finish();
+
+        // The retry is served from the store
+        let replay = cached_response(&key).unwrap();
+        assert_eq!(replay.body, Bytes::from_static(b"{\"id\":\"ch_1\"}"));
+        let header = replay.replay_header().unwrap();
+        assert_eq!(header.status.as_u16(), 201);
+        assert_eq!(header.headers.get("X-Idempotent-Replay").unwrap(), "true");
+        assert_eq!(header.headers.get("Content-Length").unwrap(), "13");
+        assert!(header.headers.get("Transfer-Encoding").is_none());
+    }
+
+    #[test]
+    fn test_new_key_is_forwarded() {
+        let stored = request_key(&config(), &payment_request(Some("pay-a")), "api.example.com", "payments", CLIENT_IP).unwrap();
+        recorded(&stored, 201, b"a").finish();
+
+        let fresh = request_key(&config(), &payment_request(Some("pay-b")), "api.example.com", "payments", CLIENT_IP).unwrap();
+        assert!(cached_response(&fresh).is_none());
+
+        // The same key on another route is a different request
+        let other_route = request_key(&config(), &payment_request(Some("pay-a")), "api.example.com", "refunds", CLIENT_IP).unwrap();
+        assert!(cached_response(&other_route).is_none());
+
+        // Requests without a key are never deduplicated
+        assert!(request_key(&config(), &payment_request(None), "api.example.com", "payments", CLIENT_IP).is_none());
+    }
+
+    #[test]
+    fn test_keys_are_scoped_to_the_caller() {
+        let config = config();
+        let alice = authorized(payment_request(Some("pay-shared")), "Bearer alice-token");
+        let stored = request_key(&config, &alice, "api.example.com", "payments", CLIENT_IP).unwrap();
+        recorded(&stored, 201, b"alice").finish();
+        assert!(!stored.contains("alice-token"));
+
+        // Another user's credentials never see Alice's response, even from her IP
+        let mallory = authorized(payment_request(Some("pay-shared")), "Bearer mallory-token");
+        let key = request_key(&config, &mallory, "api.example.com", "payments", CLIENT_IP).unwrap();
+        assert!(cached_response(&key).is_none());
+
+        // Without credentials the client IP scopes the key
+        let anonymous = request_key(&config, &payment_request(Some("pay-shared")), "api.example.com", "payments", CLIENT_IP).unwrap();
+        let other_ip = request_key(&config, &payment_request(Some("pay-shared")), "api.example.com", "payments", "198.51.100.9").unwrap();
+        assert_ne!(anonymous, other_ip);
+
+        // Same key on another path or method of the route is a different request
+        let mut other_path = payment_request(Some("pay-shared"));
+        other_path.set_uri("/payments/refund".parse().unwrap());
+        let other_path = request_key(&config, &authorized(other_path, "Bearer alice-token"), "api.example.com", "payments", CLIENT_IP).unwrap();
+        assert_ne!(stored, other_path);
+        let mut other_method = alice.clone();
+        other_method.set_method(http::Method::PUT);
+        assert_ne!(stored, request_key(&config, &other_method, "api.example.com", "payments", CLIENT_IP).unwrap());
+
+        // The same caller retrying gets the replay
+        assert_eq!(cached_response(&stored).unwrap().body, Bytes::from_static(b"alice"));
+    }
+
+    #[test]
+    fn test_server_errors_are_not_cached() {
+        let key = request_key(&config(), &payment_request(Some("pay-503")), "api.example.com", "payments", CLIENT_IP).unwrap();
+        recorded(&key, 503, b"unavailable").finish();
+        assert!(cached_response(&key).is_none());
+    }
+
+    #[test]
+    fn test_store_expires_and_evicts_oldest() {
+        let now = Instant::now();
+        let response = || recorded("k", 200, b"ok").into_entry().unwrap().1;
+        let mut store = IdempotencyStore::new(2, MAX_STORE_BYTES);
+
+        store.insert("a".to_string(), response(), Duration::from_secs(10), now);
+        assert!(store.get("a", now + Duration::from_secs(11)).is_none());
+
+        store.insert("b".to_string(), response(), Duration::from_secs(10), now);
+        store.insert("c".to_string(), response(), Duration::from_secs(10), now);
+        assert_eq!(store.len(), 2);
+        assert!(store.get("a", now).is_none());
+        assert!(store.get("c", now).is_some());
+    }
+
+    #[test]
+    fn test_store_evicts_oldest_to_stay_within_its_byte_budget() {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(10);
+        let response = |len: usize| {
+            let header = ResponseHeader::build(200, None).unwrap();
+            CachedResponse { header, body: Bytes::from(vec![b'x'; len]) }
+        };
+        let mut store = IdempotencyStore::new(100, 1000);
+
+        store.insert("a".to_string(), response(400), ttl, now);
+        store.insert("b".to_string(), response(400), ttl, now);
+        store.insert("c".to_string(), response(400), ttl, now);
+        assert!(store.get("a", now).is_none());
+        assert!(store.get("b", now).is_some() && store.get("c", now).is_some());
+        assert_eq!(store.bytes(), 800);
+
+        // Replacing a key releases its old body
+        store.insert("b".to_string(), response(100), ttl, now);
+        assert_eq!(store.bytes(), 500);
+        store.insert("d".to_string(), response(500), ttl, now);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.bytes(), 1000);
+
+        // A body over the whole budget is not stored and evicts nothing
+        store.insert("e".to_string(), response(1001), ttl, now);
+        assert!(store.get("e", now).is_none());
+        assert_eq!(store.len(), 3);
+    }
+}
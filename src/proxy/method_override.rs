@@ -0,0 +1,134 @@
+use crate::config::MethodOverrideConfig;
+use crate::utils::secret::constant_time_eq;
+use http::Method;
+use pingora_http::RequestHeader;
+
+/// Methods a request can be rewritten to; CONNECT and TRACE are never produced
+const REWRITABLE_METHODS: [Method; 7] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+    Method::OPTIONS,
+];
+
+/// Method named by a config value or override header (case-insensitive), if it may be used upstream
+pub fn parse_method(value: &str) -> Option<Method> {
+    let method = Method::from_bytes(value.trim().to_ascii_uppercase().as_bytes()).ok()?;
+    REWRITABLE_METHODS.contains(&method).then_some(method)
+}
+
+/// Method to send upstream, None to keep the client's
+///
+/// A valid override header from a client presenting the token wins over the
+/// route's `rewrite_method`. Invalid override values are ignored.
+pub fn upstream_method(req: &RequestHeader, rewrite_method: Option<&Method>, config: Option<&MethodOverrideConfig>) -> Option<Method> {
+    config
+        .and_then(|config| requested_method(req, config))
+        .or_else(|| rewrite_method.cloned())
+}
+
+/// Method asked for in the request, None unless it carries the right token
+fn requested_method(req: &RequestHeader, config: &MethodOverrideConfig) -> Option<Method> {
+    if !is_authorized(req, config) {
+        return None;
+    }
+    let value = req.headers.get(config.header.as_str())?.to_str().ok()?;
+    let method = parse_method(value);
+    if method.is_none() {
+        log::warn!("Ignoring {}: '{}' is not a method pingwall forwards", config.header, value);
+    }
+    method
+}
+
+fn is_authorized(req: &RequestHeader, config: &MethodOverrideConfig) -> bool {
+    match req.headers.get(config.token_header.as_str()) {
+        Some(provided) => !config.token.is_empty() && constant_time_eq(provided.as_bytes(), config.token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Rewrite the upstream request's method and drop the override headers, so the
+/// upstream can't be made to honor an override pingwall rejected
+pub fn apply(upstream_request: &mut RequestHeader, method: Option<Method>, config: Option<&MethodOverrideConfig>) {
+    if let Some(method) = method {
+        upstream_request.set_method(method);
+    }
+    if let Some(config) = config {
+        upstream_request.remove_header(config.header.as_str());
+        upstream_request.remove_header(config.token_header.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MethodOverrideConfig {
+        serde_yaml::from_str("token: legacy-secret\n").unwrap()
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, b"/api/orders/7", None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_authorized_override_changes_upstream_method() {
+        let config = config();
+        let req = request("POST", &[("X-Bypass-Token", "legacy-secret"), ("X-HTTP-Method-Override", "delete")]);
+
+        let method = upstream_method(&req, None, Some(&config));
+        assert_eq!(method, Some(Method::DELETE));
+
+        let mut upstream = req.clone();
+        apply(&mut upstream, method, Some(&config));
+        assert_eq!(upstream.method, Method::DELETE);
+        assert!(upstream.headers.get("X-HTTP-Method-Override").is_none());
+        assert!(upstream.headers.get("X-Bypass-Token").is_none());
+    }
+
+    #[test]
+    fn test_unauthorized_override_is_ignored() {
+        let config = config();
+
+        let req = request("POST", &[("X-HTTP-Method-Override", "DELETE")]);
+        assert_eq!(upstream_method(&req, None, Some(&config)), None);
+        let mut upstream = req.clone();
+        apply(&mut upstream, None, Some(&config));
+        assert_eq!(upstream.method, Method::POST);
+        assert!(upstream.headers.get("X-HTTP-Method-Override").is_none());
+
+        let req = request("POST", &[("X-Bypass-Token", "guess"), ("X-HTTP-Method-Override", "DELETE")]);
+        assert_eq!(upstream_method(&req, None, Some(&config)), None);
+
+        // Authorized, but not a method pingwall forwards
+        let req = request("POST", &[("X-Bypass-Token", "legacy-secret"), ("X-HTTP-Method-Override", "CONNECT")]);
+        assert_eq!(upstream_method(&req, None, Some(&config)), None);
+    }
+
+    #[test]
+    fn test_route_rewrites_method() {
+        let req = request("POST", &[]);
+        assert_eq!(upstream_method(&req, Some(&Method::PUT), None), Some(Method::PUT));
+
+        // A trusted override still wins
+        let config = config();
+        let req = request("POST", &[("X-Bypass-Token", "legacy-secret"), ("X-HTTP-Method-Override", "PATCH")]);
+        assert_eq!(upstream_method(&req, Some(&Method::PUT), Some(&config)), Some(Method::PATCH));
+    }
+
+    #[test]
+    fn test_parse_method() {
+        assert_eq!(parse_method("put"), Some(Method::PUT));
+        assert_eq!(parse_method(" Options "), Some(Method::OPTIONS));
+        assert_eq!(parse_method("TRACE"), None);
+        assert_eq!(parse_method("P UT"), None);
+        assert_eq!(parse_method("PURGE"), None);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::MirrorConfig;
+use crate::metrics;
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use pingora_http::RequestHeader;
+use reqwest::Client;
+use std::time::Duration;
+
+static MIRROR_CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_else(|_| Client::new())
+});
+
+/// Hop-by-hop headers that are not copied to the mirror request
+const SKIPPED_HEADERS: &[&str] = &["connection", "keep-alive", "transfer-encoding", "upgrade", "content-length"];
+
+/// A sampled request being captured for the shadow upstream
+#[derive(Debug)]
+pub struct MirrorRequest {
+    url: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    max_body_bytes: usize,
+    body_too_large: bool,
+}
+
+impl MirrorRequest {
+    /// Start mirroring this request if it falls in the configured percentage
+    pub fn sample(config: &MirrorConfig, req: &RequestHeader) -> Option<Self> {
+        if !config.sampler.sample(config.percentage) {
+            return None;
+        }
+        Some(Self::new(config, req))
+    }
+
+    fn new(config: &MirrorConfig, req: &RequestHeader) -> Self {
+        let base = if config.upstream.starts_with("http://") || config.upstream.starts_with("https://") {
+            config.upstream.trim_end_matches('/').to_string()
+        } else {
+            format!("http://{}", config.upstream.trim_end_matches('/'))
+        };
+        let path_and_query = req.uri.path_and_query().map_or("/", |pq| pq.as_str());
+
+        let headers = req
+            .headers
+            .iter()
+            .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str()))
+            .filter_map(|(name, value)| value.to_str().ok().map(|v| (name.to_string(), v.to_string())))
+            .collect();
+
+        Self {
+            url: format!("{}{}", base, path_and_query),
+            method: req.method.as_str().to_string(),
+            headers,
+            body: Vec::new(),
+            max_body_bytes: config.max_body_bytes,
+            body_too_large: false,
+        }
+    }
+
+    /// Capture a chunk of the request body; bodies over the cap are not mirrored
+    pub fn push_body(&mut self, chunk: &[u8]) {
+        if self.body_too_large {
+            return;
+        }
+        if self.body.len() + chunk.len() > self.max_body_bytes {
+            self.body_too_large = true;
+            self.body = Vec::new();
+            return;
+        }
+        self.body.extend_from_slice(chunk);
+    }
+
+    /// Send the captured request to the shadow upstream in the background; the response is discarded
+    pub fn send(self) {
+        if self.body_too_large {
+            log::debug!("Not mirroring {} {}: body exceeds {} bytes", self.method, self.url, self.max_body_bytes);
+            metrics::record_mirror_request("skipped_body");
+            return;
+        }
+
+        tokio::spawn(async move {
+            let method = match reqwest::Method::from_bytes(self.method.as_bytes()) {
+                Ok(method) => method,
+                Err(_) => {
+                    metrics::record_mirror_request("error");
+                    return;
+                }
+            };
+
+            let mut request = MIRROR_CLIENT.request(method, &self.url);
+            for (name, value) in &self.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+
+            match request.body(Bytes::from(self.body)).send().await {
+                Ok(response) => {
+                    log::debug!("Mirror {} {} -> {}", self.method, self.url, response.status());
+                    metrics::record_mirror_request("success");
+                }
+                Err(e) => {
+                    log::debug!("Mirror {} {} failed: {}", self.method, self.url, e);
+                    metrics::record_mirror_request("error");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(percentage: f64) -> MirrorConfig {
+        MirrorConfig {
+            upstream: "shadow:8000".to_string(),
+            percentage,
+            max_body_bytes: 8,
+            sampler: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_configured_percentage_hits_mirror() {
+        let req = RequestHeader::build("GET", b"/api/users?page=2", None).unwrap();
+        let mirror = config(20.0);
+
+        let sampled = (0..500).filter(|_| MirrorRequest::sample(&mirror, &req).is_some()).count();
+        assert_eq!(sampled, 100);
+    }
+
+    #[test]
+    fn test_interleaved_routes_keep_their_own_percentage() {
+        let req = RequestHeader::build("GET", b"/api/users", None).unwrap();
+        let (light, heavy) = (config(10.0), config(50.0));
+
+        let (mut light_sampled, mut heavy_sampled) = (0, 0);
+        for _ in 0..1000 {
+            light_sampled += MirrorRequest::sample(&light, &req).is_some() as usize;
+            heavy_sampled += MirrorRequest::sample(&heavy, &req).is_some() as usize;
+        }
+        assert_eq!(light_sampled, 100);
+        assert_eq!(heavy_sampled, 500);
+
+        // Clones of a route (as built per domain) share its sampler
+        let clone = light.clone();
+        let sampled = (0..10).filter(|n| {
+            let route = if n % 2 == 0 { &light } else { &clone };
+            MirrorRequest::sample(route, &req).is_some()
+        }).count();
+        assert_eq!(sampled, 1);
+    }
+
+    #[test]
+    fn test_mirror_request_url_and_body_cap() {
+        let mut req = RequestHeader::build("POST", b"/api/users?page=2", None).unwrap();
+        req.insert_header("Host", "api.example.com").unwrap();
+        req.insert_header("Connection", "keep-alive").unwrap();
+
+        let mut mirror = MirrorRequest::new(&config(100.0), &req);
+        assert_eq!(mirror.url, "http://shadow:8000/api/users?page=2");
+        assert!(mirror.headers.iter().any(|(name, _)| name == "host"));
+        assert!(!mirror.headers.iter().any(|(name, _)| name == "connection"));
+
+        mirror.push_body(b"1234");
+        assert!(!mirror.body_too_large);
+        mirror.push_body(b"56789");
+        assert!(mirror.body_too_large);
+        assert!(mirror.body.is_empty());
+    }
+}
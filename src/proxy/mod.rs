@@ -1,3 +1,23 @@
 pub mod handler;
 pub mod upstream;
 pub mod sni_handler;
+pub mod context;
+pub mod access_log;
+pub mod h2;
+pub mod mirror;
+pub mod canary;
+pub mod acme;
+pub mod response_limit;
+pub mod upstream_connections;
+pub mod idempotency;
+pub mod security_headers;
+pub mod debug_headers;
+pub mod redirect_loop;
+pub mod timeout_override;
+pub mod request_buffer;
+pub mod response_deadline;
+pub mod health_check;
+pub mod method_override;
+pub mod header_rules;
+pub mod timeout_response;
+pub mod compression;
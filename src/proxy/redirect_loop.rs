@@ -0,0 +1,68 @@
+use pingora_core::Result;
+use pingora_http::RequestHeader;
+
+/// Hop counter pingwall adds to requests it forwards on routes with `max_redirects`
+pub const HOPS_HEADER: &str = "X-Pingwall-Hops";
+
+/// Times this request already passed through pingwall (0 for a client request)
+pub fn incoming_hops(req: &RequestHeader) -> u32 {
+    req.headers
+        .get(HOPS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Whether a request that already made `hops` trips is caught in a loop
+pub fn is_loop(hops: u32, max_redirects: u32) -> bool {
+    hops >= max_redirects
+}
+
+/// Count this trip on the request sent to the upstream
+pub fn mark_forwarded(upstream_request: &mut RequestHeader, hops: u32) -> Result<()> {
+    upstream_request.insert_header(HOPS_HEADER, hops.saturating_add(1).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What request_filter / upstream_request_filter do with one request: None if
+    /// it is answered with 508, else the request forwarded to the upstream
+    fn proxy(req: &RequestHeader, max_redirects: u32) -> Option<RequestHeader> {
+        let hops = incoming_hops(req);
+        if is_loop(hops, max_redirects) {
+            return None;
+        }
+        let mut upstream_request = req.clone();
+        mark_forwarded(&mut upstream_request, hops).unwrap();
+        Some(upstream_request)
+    }
+
+    #[test]
+    fn test_upstream_redirecting_to_itself_is_broken_after_max_redirects() {
+        let mut req = RequestHeader::build("GET", b"/loop", None).unwrap();
+        req.insert_header("Host", "app.example.com").unwrap();
+
+        // The upstream sends every request straight back through pingwall
+        let mut forwarded = 0;
+        while let Some(upstream_request) = proxy(&req, 3) {
+            forwarded += 1;
+            assert!(forwarded <= 3, "loop was not broken");
+            req = upstream_request;
+        }
+
+        assert_eq!(forwarded, 3);
+        assert_eq!(incoming_hops(&req), 3);
+    }
+
+    #[test]
+    fn test_client_requests_start_at_zero_hops() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert_eq!(incoming_hops(&req), 0);
+        assert!(proxy(&req, 1).is_some());
+
+        req.insert_header(HOPS_HEADER, "garbage").unwrap();
+        assert_eq!(incoming_hops(&req), 0);
+    }
+}
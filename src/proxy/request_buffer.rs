@@ -0,0 +1,52 @@
+use pingora_core::Result;
+use pingora_http::RequestHeader;
+use pingora_proxy::Session;
+
+/// Largest request body a `request_buffering: buffer` route accepts: the size of
+/// Pingora's retry buffer, from which the body is replayed to the upstream
+pub const MAX_BUFFERED_BODY_BYTES: usize = 64 * 1024;
+
+/// Read the whole request body before the upstream is contacted, for routes with
+/// `request_buffering: buffer`, so it can go out in one piece with a Content-Length
+/// (see `set_content_length`)
+///
+/// The body is kept in Pingora's retry buffer, which sends it on once the upstream
+/// connection is up. Returns its length, or None when it is larger than
+/// MAX_BUFFERED_BODY_BYTES (the caller answers 413).
+pub async fn read_body(session: &mut Session) -> Result<Option<usize>> {
+    session.as_mut().enable_retry_buffering();
+
+    let mut length = 0;
+    while let Some(chunk) = session.read_request_body().await? {
+        length += chunk.len();
+        if length > MAX_BUFFERED_BODY_BYTES {
+            return Ok(None);
+        }
+    }
+    if session.as_ref().retry_buffer_truncated() {
+        return Ok(None);
+    }
+    Ok(Some(length))
+}
+
+/// Send a buffered body with its length instead of chunked
+pub fn set_content_length(upstream_request: &mut RequestHeader, length: usize) -> Result<()> {
+    upstream_request.remove_header("transfer-encoding");
+    upstream_request.insert_header("Content-Length", length.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_upload_goes_out_with_its_length() {
+        let mut req = RequestHeader::build("POST", b"/upload", None).unwrap();
+        req.insert_header("Transfer-Encoding", "chunked").unwrap();
+
+        set_content_length(&mut req, 17).unwrap();
+        assert!(req.headers.get("transfer-encoding").is_none());
+        assert_eq!(req.headers.get("content-length").unwrap(), "17");
+    }
+}
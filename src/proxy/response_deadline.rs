@@ -0,0 +1,109 @@
+use pingora_core::{Error, ErrorType, Result};
+use std::time::{Duration, Instant};
+
+/// Enforces a route's `response_deadline_secs` on one request
+///
+/// The connect and read timeouts bound each single wait on the upstream, so an
+/// upstream that drips a byte just inside the read timeout can keep a request open
+/// forever. The deadline bounds the whole exchange instead, counted from when the
+/// request arrived: the upstream timeouts are clamped to what is left of it (so an
+/// upstream that goes silent is given up on), response headers arriving after it are
+/// answered with 504, and a body still streaming when it passes is cut off at the next
+/// chunk.
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseDeadline {
+    expires: Instant,
+    limit: Duration,
+}
+
+impl ResponseDeadline {
+    pub fn new(start: Instant, limit: Duration) -> Self {
+        Self { expires: start + limit, limit }
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+
+    pub fn limit(&self) -> Duration {
+        self.limit
+    }
+
+    /// Time left until the deadline (zero once it has passed)
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+
+    /// Clamp an upstream timeout so it can't outlast the deadline
+    pub fn clamp(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let remaining = self.remaining();
+        Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)))
+    }
+}
+
+/// Error returned from the response filters once the deadline has passed
+pub fn deadline_exceeded(limit: Duration) -> Box<Error> {
+    Error::explain(
+        ErrorType::HTTPStatus(504),
+        format!("upstream response exceeded response_deadline_secs ({})", limit.as_secs()),
+    )
+}
+
+/// Check a body chunk against the deadline; a chunk arriving after it is dropped and the response aborted
+pub fn check_chunk(deadline: &ResponseDeadline, body: &mut Option<bytes::Bytes>) -> Result<()> {
+    if !deadline.is_exceeded() {
+        return Ok(());
+    }
+    *body = None;
+    Err(deadline_exceeded(deadline.limit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_fast_response_passes_intact() {
+        let deadline = ResponseDeadline::new(Instant::now(), Duration::from_secs(30));
+        assert!(!deadline.is_exceeded());
+
+        let mut body = Some(Bytes::from_static(b"complete body"));
+        assert!(check_chunk(&deadline, &mut body).is_ok());
+        assert_eq!(body.as_deref(), Some(&b"complete body"[..]));
+        // End of stream
+        assert!(check_chunk(&deadline, &mut None).is_ok());
+    }
+
+    #[test]
+    fn test_deadline_counts_from_request_start() {
+        let start = Instant::now() - Duration::from_secs(5);
+        assert!(ResponseDeadline::new(start, Duration::from_secs(2)).is_exceeded());
+        assert!(!ResponseDeadline::new(start, Duration::from_secs(60)).is_exceeded());
+    }
+
+    #[test]
+    fn test_late_chunk_is_dropped() {
+        let deadline = ResponseDeadline::new(Instant::now() - Duration::from_secs(5), Duration::from_secs(2));
+        let mut body = Some(Bytes::from_static(b"x"));
+
+        let e = check_chunk(&deadline, &mut body).unwrap_err();
+        assert_eq!(e.etype(), &ErrorType::HTTPStatus(504));
+        assert_eq!(body, None);
+    }
+
+    #[test]
+    fn test_upstream_timeouts_are_clamped_to_the_time_left() {
+        let start = Instant::now() - Duration::from_secs(5);
+        let deadline = ResponseDeadline::new(start, Duration::from_secs(8));
+
+        let clamped = deadline.clamp(Some(Duration::from_secs(30))).unwrap();
+        assert!(clamped <= Duration::from_secs(3) && clamped > Duration::from_secs(2), "{:?}", clamped);
+        assert_eq!(deadline.clamp(Some(Duration::from_secs(1))), Some(Duration::from_secs(1)));
+        // No timeout configured: the deadline is the only bound
+        assert!(deadline.clamp(None).unwrap() <= Duration::from_secs(3));
+
+        let passed = ResponseDeadline::new(start, Duration::from_secs(2));
+        assert_eq!(passed.remaining(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,106 @@
+use pingora_core::{Error, ErrorType, Result};
+use pingora_http::ResponseHeader;
+
+/// Enforces a route's `max_response_bytes` on one response
+///
+/// A declared `Content-Length` over the limit is rejected before any byte reaches the
+/// client; chunked or undeclared bodies are counted as they stream and the connection
+/// is aborted at the chunk that crosses the limit.
+#[derive(Debug)]
+pub struct ResponseLimit {
+    max_bytes: u64,
+    seen_bytes: u64,
+}
+
+impl ResponseLimit {
+    pub fn new(max_bytes: u64) -> Self {
+        Self { max_bytes, seen_bytes: 0 }
+    }
+
+    /// Whether the response announces a body larger than the limit
+    pub fn declared_too_large(&self, resp: &ResponseHeader) -> bool {
+        resp.headers
+            .get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map_or(false, |len| len > self.max_bytes)
+    }
+
+    /// Count a streamed chunk; false once the body has gone over the limit
+    pub fn consume(&mut self, chunk_len: usize) -> bool {
+        self.seen_bytes = self.seen_bytes.saturating_add(chunk_len as u64);
+        self.seen_bytes <= self.max_bytes
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+}
+
+/// Error returned from the response filters to abort an over-limit response
+pub fn response_too_large(max_bytes: u64) -> Box<Error> {
+    Error::explain(
+        ErrorType::Custom("ResponseTooLarge"),
+        format!("upstream response exceeds max_response_bytes ({})", max_bytes),
+    )
+}
+
+/// Count a body chunk against the limit; the chunk that crosses it is dropped and the response aborted
+pub fn check_chunk(limit: &mut ResponseLimit, body: &mut Option<bytes::Bytes>) -> Result<()> {
+    let chunk_len = body.as_ref().map_or(0, |chunk| chunk.len());
+    if limit.consume(chunk_len) {
+        return Ok(());
+    }
+    *body = None;
+    Err(response_too_large(limit.max_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn response(content_length: Option<&str>) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        if let Some(len) = content_length {
+            resp.insert_header("Content-Length", len).unwrap();
+        }
+        resp
+    }
+
+    #[test]
+    fn test_declared_content_length() {
+        let limit = ResponseLimit::new(1024);
+
+        assert!(limit.declared_too_large(&response(Some("1025"))));
+        assert!(!limit.declared_too_large(&response(Some("1024"))));
+        // Chunked responses are only checked while streaming
+        assert!(!limit.declared_too_large(&response(None)));
+    }
+
+    #[test]
+    fn test_over_limit_stream_is_aborted() {
+        let mut limit = ResponseLimit::new(10);
+        let mut first = Some(Bytes::from_static(b"123456"));
+        let mut second = Some(Bytes::from_static(b"789012"));
+
+        assert!(check_chunk(&mut limit, &mut first).is_ok());
+        assert_eq!(first.as_deref(), Some(&b"123456"[..]));
+        assert!(check_chunk(&mut limit, &mut second).is_err());
+        assert_eq!(second, None);
+    }
+
+    #[test]
+    fn test_under_limit_stream_passes_intact() {
+        let mut limit = ResponseLimit::new(10);
+        let chunks: [&'static [u8]; 3] = [b"1234", b"5678", b"90"];
+
+        for chunk in chunks {
+            let mut body = Some(Bytes::from_static(chunk));
+            assert!(check_chunk(&mut limit, &mut body).is_ok());
+            assert_eq!(body.as_deref(), Some(chunk));
+        }
+        // End of stream
+        assert!(check_chunk(&mut limit, &mut None).is_ok());
+    }
+}
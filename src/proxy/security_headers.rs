@@ -0,0 +1,81 @@
+use crate::config::SecurityHeadersConfig;
+use pingora_core::Result;
+use pingora_http::ResponseHeader;
+
+/// Strict-Transport-Security value sent when `hsts` is enabled (one year)
+const HSTS_VALUE: &str = "max-age=31536000; includeSubDomains";
+
+/// Add the route's security baseline headers to a response
+///
+/// Headers already set by the upstream are left alone. HSTS is only sent on
+/// responses to HTTPS requests, browsers ignore it over plain HTTP.
+pub fn apply(config: &SecurityHeadersConfig, resp: &mut ResponseHeader, https: bool) -> Result<()> {
+    let headers: [(&str, Option<&str>); 5] = [
+        ("Strict-Transport-Security", (config.hsts && https).then_some(HSTS_VALUE)),
+        ("X-Content-Type-Options", config.nosniff.then_some("nosniff")),
+        ("X-Frame-Options", config.frame_options.as_deref()),
+        ("Referrer-Policy", config.referrer_policy.as_deref()),
+        ("Content-Security-Policy", config.csp.as_deref()),
+    ];
+
+    for (name, value) in headers {
+        if let Some(value) = value {
+            if !resp.headers.contains_key(name) {
+                resp.insert_header(name, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baseline() -> SecurityHeadersConfig {
+        SecurityHeadersConfig {
+            hsts: true,
+            nosniff: true,
+            frame_options: Some("DENY".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            csp: Some("default-src 'self'".to_string()),
+        }
+    }
+
+    fn header<'a>(resp: &'a ResponseHeader, name: &str) -> Option<&'a str> {
+        resp.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    #[test]
+    fn test_enabled_block_adds_headers() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        apply(&baseline(), &mut resp, true).unwrap();
+
+        assert_eq!(header(&resp, "Strict-Transport-Security"), Some(HSTS_VALUE));
+        assert_eq!(header(&resp, "X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(header(&resp, "X-Frame-Options"), Some("DENY"));
+        assert_eq!(header(&resp, "Referrer-Policy"), Some("strict-origin-when-cross-origin"));
+        assert_eq!(header(&resp, "Content-Security-Policy"), Some("default-src 'self'"));
+    }
+
+    #[test]
+    fn test_hsts_omitted_on_plaintext() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        apply(&baseline(), &mut resp, false).unwrap();
+
+        assert_eq!(header(&resp, "Strict-Transport-Security"), None);
+        assert_eq!(header(&resp, "X-Content-Type-Options"), Some("nosniff"));
+    }
+
+    #[test]
+    fn test_defaults_add_nothing_and_upstream_headers_win() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("X-Frame-Options", "SAMEORIGIN").unwrap();
+
+        apply(&SecurityHeadersConfig::default(), &mut resp, true).unwrap();
+        assert_eq!(resp.headers.len(), 1);
+
+        apply(&baseline(), &mut resp, true).unwrap();
+        assert_eq!(header(&resp, "X-Frame-Options"), Some("SAMEORIGIN"));
+    }
+}
@@ -9,6 +9,7 @@ use pingora_core::{
         ext::{ssl_use_certificate, ssl_use_private_key},
     },
 };
+use std::any::Any;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use log::{info, error, debug};
@@ -20,6 +21,57 @@ use once_cell::sync::Lazy;
 static CERT_CACHE: Lazy<Mutex<HashMap<String, (Vec<u8>, Vec<u8>)>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
+/// Get certificate and key bytes, from the cache or from disk on a cache miss
+pub(crate) fn load_cert_bytes(cert_path: &str, key_path: &str) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    // Create a cache key based on cert and key paths
+    let cache_key = format!("{}:{}", cert_path, key_path);
+
+    if let Some((cached_cert, cached_key)) = CERT_CACHE.lock().unwrap().get(&cache_key) {
+        debug!("Using cached certificate bytes for {}", cert_path);
+        return Ok((cached_cert.clone(), cached_key.clone()));
+    }
+
+    // Cache miss, load from disk (lock released before I/O)
+    debug!("Loading certificate from disk: {}", cert_path);
+    let cert_bytes = std::fs::read(cert_path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("failed to read certificate file {}: {}", cert_path, e))
+    })?;
+    let key_bytes = std::fs::read(key_path).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("failed to read private key file {}: {}", key_path, e))
+    })?;
+
+    // Store raw bytes in cache for future use
+    CERT_CACHE.lock().unwrap().insert(cache_key, (cert_bytes.clone(), key_bytes.clone()));
+    info!("Cached certificate bytes for {}", cert_path);
+
+    Ok((cert_bytes, key_bytes))
+}
+
+/// Drop all cached certificates so the next handshake re-reads them from disk
+/// Returns the number of cached certificates that were dropped
+pub fn clear_cert_cache() -> usize {
+    let mut cache = CERT_CACHE.lock().unwrap();
+    let cleared = cache.len();
+    cache.clear();
+    info!("Cleared {} cached certificate(s)", cleared);
+    cleared
+}
+
+/// Check that a PEM private key belongs to a PEM certificate (same public key)
+///
+/// Swapped or stale files otherwise only fail inside the handshake with an opaque
+/// BoringSSL error.
+pub fn verify_key_pair(cert_pem: &[u8], key_pem: &[u8]) -> Result<(), String> {
+    let cert = X509::from_pem(cert_pem).map_err(|e| format!("invalid certificate: {}", e))?;
+    let key = PKey::private_key_from_pem(key_pem).map_err(|e| format!("invalid private key: {}", e))?;
+    let cert_key = cert.public_key().map_err(|e| format!("certificate has no usable public key: {}", e))?;
+    if cert_key.public_eq(&key) {
+        Ok(())
+    } else {
+        Err("private key does not match the certificate's public key".to_string())
+    }
+}
+
 /// SNI handler for managing multiple SSL certificates per port
 pub struct SniHandler {
     /// Map of domain names to (cert_path, key_path)
@@ -78,47 +130,13 @@ impl TlsAccept for SniHandler {
             }
         };
 
-        // Create a cache key based on cert and key paths
-        let cache_key = format!("{}:{}", cert_path, key_path);
-
-        // Try to get certificate bytes from cache first
-        let (cert_bytes, key_bytes) = {
-            let cache = CERT_CACHE.lock().unwrap();
-            if let Some((cached_cert, cached_key)) = cache.get(&cache_key) {
-                debug!("Using cached certificate bytes for domain: {}", server_name);
-                (cached_cert.clone(), cached_key.clone())
-            } else {
-                // Cache miss, need to load from disk
-                drop(cache); // Release lock before I/O
-
-                debug!("Loading certificate from disk for domain: {}", server_name);
-
-                // Load certificate from file
-                let cert_bytes = match std::fs::read(&cert_path) {
-                    Ok(bytes) => bytes,
-                    Err(e) => {
-                        error!("Failed to read certificate file {}: {}", cert_path, e);
-                        metrics::record_ssl_handshake(&server_name, false);
-                        return;
-                    }
-                };
-
-                // Load private key from file
-                let key_bytes = match std::fs::read(&key_path) {
-                    Ok(bytes) => bytes,
-                    Err(e) => {
-                        error!("Failed to read private key file {}: {}", key_path, e);
-                        metrics::record_ssl_handshake(&server_name, false);
-                        return;
-                    }
-                };
-
-                // Store raw bytes in cache for future use
-                let mut cache = CERT_CACHE.lock().unwrap();
-                cache.insert(cache_key.clone(), (cert_bytes.clone(), key_bytes.clone()));
-                info!("Cached certificate bytes for domain: {}", server_name);
-
-                (cert_bytes, key_bytes)
+        // Get certificate bytes from cache, loading from disk on a miss
+        let (cert_bytes, key_bytes) = match load_cert_bytes(&cert_path, &key_path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Failed to load certificate for domain {}: {}", server_name, e);
+                metrics::record_ssl_handshake(&server_name, false);
+                return;
             }
         };
 
@@ -142,6 +160,17 @@ impl TlsAccept for SniHandler {
             }
         };
 
+        // A mismatched pair would fail below with an unhelpful error
+        let key_matches = cert.public_key().map_or(false, |cert_key| cert_key.public_eq(&key));
+        if !key_matches {
+            error!(
+                "Private key {} does not match certificate {} for domain {}",
+                key_path, cert_path, server_name
+            );
+            metrics::record_ssl_handshake(&server_name, false);
+            return;
+        }
+
         // Set the certificate and key
         if let Err(e) = ssl_use_certificate(ssl, &cert) {
             error!("Failed to set certificate for domain {}: {}", server_name, e);
@@ -158,4 +187,64 @@ impl TlsAccept for SniHandler {
         debug!("SNI certificate successfully configured for domain: {}", server_name);
         metrics::record_ssl_handshake(&server_name, true);
     }
-}
\ No newline at end of file
+
+    async fn handshake_complete_callback(&self, ssl: &TlsRef) -> Option<Arc<dyn Any + Send + Sync>> {
+        let server_name = ssl.servername(NameType::HOST_NAME).unwrap_or("unknown");
+        let reused = ssl.session_reused();
+        if reused {
+            debug!("TLS session resumed for domain: {}", server_name);
+        }
+        metrics::record_ssl_handshake_complete(server_name, reused);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::tls::{
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::Private,
+        x509::X509Builder,
+    };
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn self_signed_cert(key: &PKey<Private>) -> Vec<u8> {
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build().to_pem().unwrap()
+    }
+
+    #[test]
+    fn test_matching_key_pair_is_accepted() {
+        let key = generate_key();
+        let cert = self_signed_cert(&key);
+
+        assert!(verify_key_pair(&cert, &key.private_key_to_pem_pkcs8().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_mismatched_key_pair_is_rejected() {
+        let cert = self_signed_cert(&generate_key());
+        let other_key = generate_key().private_key_to_pem_pkcs8().unwrap();
+
+        let err = verify_key_pair(&cert, &other_key).unwrap_err();
+        assert_eq!(err, "private key does not match the certificate's public key");
+    }
+
+    #[test]
+    fn test_swapped_files_are_rejected() {
+        let key = generate_key();
+        let cert = self_signed_cert(&key);
+        let key_pem = key.private_key_to_pem_pkcs8().unwrap();
+
+        assert!(verify_key_pair(&key_pem, &cert).unwrap_err().starts_with("invalid certificate"));
+    }
+}
@@ -0,0 +1,77 @@
+use crate::config::TimeoutOverrideConfig;
+use crate::utils::secret::constant_time_eq;
+use pingora_http::RequestHeader;
+
+/// Upstream timeout for a request: the route's, or the longer one asked for by a
+/// client presenting the override token (capped at `max_secs`)
+pub fn effective_timeout(route_timeout_secs: u64, req: &RequestHeader, config: Option<&TimeoutOverrideConfig>) -> u64 {
+    config
+        .and_then(|config| requested_timeout(req, config))
+        .map_or(route_timeout_secs, |requested| requested.max(route_timeout_secs))
+}
+
+/// Timeout asked for in the request, None unless it carries the right token
+fn requested_timeout(req: &RequestHeader, config: &TimeoutOverrideConfig) -> Option<u64> {
+    if !is_authorized(req, config) {
+        return None;
+    }
+    req.headers
+        .get(config.header.as_str())
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|secs| secs.min(config.max_secs))
+}
+
+fn is_authorized(req: &RequestHeader, config: &TimeoutOverrideConfig) -> bool {
+    match req.headers.get(config.token_header.as_str()) {
+        Some(provided) => !config.token.is_empty() && constant_time_eq(provided.as_bytes(), config.token.as_bytes()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TimeoutOverrideConfig {
+        serde_yaml::from_str("token: batch-secret\nmax_secs: 600\n").unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/jobs/export", None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_authorized_request_gets_extended_capped_timeout() {
+        let config = config();
+
+        let req = request(&[("X-Bypass-Token", "batch-secret"), ("X-Upstream-Timeout", "300")]);
+        assert_eq!(effective_timeout(30, &req, Some(&config)), 300);
+
+        let req = request(&[("X-Bypass-Token", "batch-secret"), ("X-Upstream-Timeout", "86400")]);
+        assert_eq!(effective_timeout(30, &req, Some(&config)), 600);
+
+        // The override only lengthens the route timeout
+        let req = request(&[("X-Bypass-Token", "batch-secret"), ("X-Upstream-Timeout", "5")]);
+        assert_eq!(effective_timeout(30, &req, Some(&config)), 30);
+    }
+
+    #[test]
+    fn test_unauthorized_timeout_header_is_ignored() {
+        let config = config();
+
+        let req = request(&[("X-Upstream-Timeout", "300")]);
+        assert_eq!(effective_timeout(30, &req, Some(&config)), 30);
+
+        let req = request(&[("X-Bypass-Token", "guess"), ("X-Upstream-Timeout", "300")]);
+        assert_eq!(effective_timeout(30, &req, Some(&config)), 30);
+
+        // Without timeout_override configured the header means nothing
+        let req = request(&[("X-Bypass-Token", "batch-secret"), ("X-Upstream-Timeout", "300")]);
+        assert_eq!(effective_timeout(30, &req, None), 30);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::config::TimeoutResponseConfig;
+use bytes::Bytes;
+use pingora_core::{Error, ErrorType, Result};
+use pingora_http::ResponseHeader;
+use pingora_proxy::Session;
+
+/// Whether a proxying error means the upstream didn't connect or answer in time
+/// (as opposed to refusing the connection or breaking it off)
+pub fn is_timeout(e: &Error) -> bool {
+    matches!(
+        e.etype(),
+        ErrorType::ConnectTimedout | ErrorType::ReadTimedout | ErrorType::WriteTimedout
+    )
+}
+
+/// Header and body of the route's `timeout_response`
+pub fn response(config: &TimeoutResponseConfig) -> Result<(ResponseHeader, Bytes)> {
+    let body = Bytes::from(config.body.clone());
+    let mut header = ResponseHeader::build(config.status, None)?;
+    if !body.is_empty() {
+        header.insert_header("Content-Type", config.content_type.as_str())?;
+    }
+    header.insert_header("Content-Length", body.len().to_string())?;
+    if config.retry_after > 0 {
+        header.insert_header("Retry-After", config.retry_after.to_string())?;
+    }
+    Ok((header, body))
+}
+
+/// Answer a request whose upstream timed out with the route's `timeout_response`
+pub async fn send(session: &mut Session, config: &TimeoutResponseConfig) -> Result<()> {
+    let (header, body) = response(config)?;
+    let has_body = !body.is_empty();
+
+    session.write_response_header(Box::new(header), !has_body).await?;
+    if has_body {
+        session.write_response_body(Some(body), true).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> TimeoutResponseConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn header<'a>(resp: &'a ResponseHeader, name: &str) -> Option<&'a str> {
+        resp.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    #[test]
+    fn test_only_timeouts_are_detected() {
+        assert!(is_timeout(&Error::new(ErrorType::ReadTimedout)));
+        assert!(is_timeout(&Error::new(ErrorType::ConnectTimedout)));
+        assert!(is_timeout(&Error::new(ErrorType::WriteTimedout)));
+        assert!(!is_timeout(&Error::new(ErrorType::ConnectRefused)));
+        assert!(!is_timeout(&Error::new(ErrorType::ConnectionClosed)));
+        assert!(!is_timeout(&Error::new(ErrorType::HTTPStatus(504))));
+    }
+
+    #[test]
+    fn test_configured_status_body_and_retry_after() {
+        let config = config("status: 503\nbody: '{\"error\":\"upstream busy\"}'\nretry_after: 30\n");
+        let (resp, body) = response(&config).unwrap();
+
+        assert_eq!(resp.status.as_u16(), 503);
+        assert_eq!(body, Bytes::from_static(b"{\"error\":\"upstream busy\"}"));
+        assert_eq!(header(&resp, "Content-Type"), Some("application/json"));
+        assert_eq!(header(&resp, "Content-Length"), Some("25"));
+        assert_eq!(header(&resp, "Retry-After"), Some("30"));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let (resp, body) = response(&config("{}")).unwrap();
+
+        assert_eq!(resp.status.as_u16(), 504);
+        assert!(body.is_empty());
+        assert_eq!(header(&resp, "Content-Type"), None);
+        assert_eq!(header(&resp, "Content-Length"), Some("0"));
+        assert_eq!(header(&resp, "Retry-After"), Some("5"));
+
+        let (resp, _) = response(&config("retry_after: 0\n")).unwrap();
+        assert_eq!(header(&resp, "Retry-After"), None);
+    }
+
+    #[test]
+    fn test_non_error_status_fails_load() {
+        let err = serde_yaml::from_str::<TimeoutResponseConfig>("status: 200\n").unwrap_err();
+        assert!(err.to_string().contains("invalid timeout_response status 200"), "{}", err);
+    }
+}
@@ -1,21 +1,68 @@
-use pingora_core::upstreams::peer::HttpPeer;
+use pingora_core::upstreams::peer::{HttpPeer, Scheme};
+use pingora_http::RequestHeader;
 use pingora_proxy::Session;
 use pingora_core::{Result, Error};
 use pingora_error::{ErrorType};
-use log::error;
-use crate::config::UpstreamRoute;
+use log::{error, warn};
+use crate::config::{PathRegex, UpstreamRoute};
+use crate::metrics;
+use crate::proxy::canary;
+use crate::utils::host::{extract_host, is_wildcard_domain, wildcard_subdomain};
+use once_cell::sync::Lazy;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+// How long a pooled upstream that failed to connect is left out of rotation
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(10);
+
+// Pooled upstreams that recently failed to connect, with when they may be tried again
+static UNHEALTHY_UNTIL: Lazy<RwLock<HashMap<String, Instant>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Upstreams currently failing their active health check
+static FAILING_HEALTH_CHECK: Lazy<RwLock<HashSet<String>>> =
+    Lazy::new(|| RwLock::new(HashSet::new()));
+
+// Round-robin position of each upstream pool, keyed by its addresses
+static POOL_CURSORS: Lazy<RwLock<HashMap<String, Arc<AtomicUsize>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
 
 /// A wrapper around HttpPeer that includes base path information
 #[derive(Debug)]
 pub struct PeerWithPath {
     pub peer: HttpPeer,
     pub base_path: Option<String>,
+    /// Query string configured on the upstream URL (without the leading '?')
+    pub query: Option<String>,
 }
 
 impl PeerWithPath {
     /// Create a new PeerWithPath
     pub fn new(peer: HttpPeer, base_path: Option<String>) -> Self {
-        Self { peer, base_path }
+        Self { peer, base_path, query: None }
+    }
+
+    /// Attach the query string configured on the upstream URL
+    pub fn with_query(mut self, query: Option<String>) -> Self {
+        self.query = query.filter(|q| !q.is_empty());
+        self
+    }
+
+    /// Force a TLS connection to the upstream regardless of how it was written
+    pub fn with_tls(mut self, force_tls: bool) -> Self {
+        if force_tls && !self.peer.is_tls() {
+            self.peer.scheme = Scheme::HTTPS;
+            if self.peer.sni.is_empty() {
+                // host:port upstreams have no Host override; use the host part for SNI
+                let addr = self.peer._address.to_string();
+                self.peer.sni = addr.rsplit_once(':').map_or(addr.clone(), |(host, _)| host.to_string());
+            }
+        }
+        self
     }
 
     /// Convert to a boxed HttpPeer
@@ -30,16 +77,70 @@ pub async fn resolve_upstream(upstream: &str) -> Result<PeerWithPath> {
     resolve_upstream_with_host(upstream, None).await
 }
 
+/// Why an upstream string could not be turned into a peer
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum UpstreamError {
+    #[error("invalid upstream '{upstream}': {reason}")]
+    InvalidUrl { upstream: String, reason: String },
+    #[error("unsupported upstream scheme '{0}' (expected http or https)")]
+    UnsupportedScheme(String),
+    #[error("cannot resolve upstream host '{0}'")]
+    UnresolvableHost(String),
+}
+
+impl UpstreamError {
+    fn invalid(upstream: &str, reason: impl ToString) -> Self {
+        Self::InvalidUrl { upstream: upstream.to_string(), reason: reason.to_string() }
+    }
+
+    /// Pingora error type reported for this failure (and used as the metric label)
+    pub fn error_type(&self) -> ErrorType {
+        match self {
+            Self::InvalidUrl { .. } | Self::UnsupportedScheme(_) => ErrorType::Custom("InvalidUpstream"),
+            Self::UnresolvableHost(_) => ErrorType::ConnectNoRoute,
+        }
+    }
+}
+
+impl From<UpstreamError> for Box<Error> {
+    fn from(e: UpstreamError) -> Self {
+        Error::explain(e.error_type(), e.to_string())
+    }
+}
+
+/// Host header for an upstream request following the route's domain: the domain
+/// without port or leading dot
+fn follow_domain_host(domain: &str) -> String {
+    let domain_only = domain.split_once(':').map_or(domain, |(domain, _)| domain);
+    domain_only.strip_prefix('.').unwrap_or(domain_only).to_string()
+}
+
+/// Resolve `host:port` to a socket address without blocking the runtime
+async fn lookup(host: &str, port: u16) -> std::result::Result<SocketAddr, UpstreamError> {
+    tokio::net::lookup_host((host, port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| UpstreamError::UnresolvableHost(host.to_string()))
+}
+
 /// Resolves a URL or host:port string to an HttpPeer with an optional custom host header
 /// Returns a PeerWithPath containing the HttpPeer and optionally the base path if present
 pub async fn resolve_upstream_with_host(upstream: &str, custom_host: Option<&str>) -> Result<PeerWithPath> {
-    if upstream.starts_with("http://") || upstream.starts_with("https://") {
-        let url = url::Url::parse(upstream).map_err(|e| {
-            error!("URL parse error: {}", e);
-            Error::explain(ErrorType::InvalidHTTPHeader, "Invalid upstream URL")
-        })?;
+    parse_upstream(upstream, custom_host).await.map_err(|e| {
+        error!("Upstream error: {}", e);
+        e.into()
+    })
+}
+
+async fn parse_upstream(upstream: &str, custom_host: Option<&str>) -> std::result::Result<PeerWithPath, UpstreamError> {
+    if let Some((scheme, _)) = upstream.split_once("://") {
+        if scheme != "http" && scheme != "https" {
+            return Err(UpstreamError::UnsupportedScheme(scheme.to_string()));
+        }
 
-        let host = url.host_str().unwrap_or("localhost").to_string();
+        let url = url::Url::parse(upstream).map_err(|e| UpstreamError::invalid(upstream, e))?;
+        let host = url.host_str().ok_or_else(|| UpstreamError::invalid(upstream, "missing host"))?.to_string();
         let port = url.port().unwrap_or_else(|| if url.scheme() == "https" { 443 } else { 80 });
         let use_ssl = url.scheme() == "https";
 
@@ -47,28 +148,12 @@ pub async fn resolve_upstream_with_host(upstream: &str, custom_host: Option<&str
         let path = url.path();
         let path_str = if path.is_empty() || path == "/" { String::new() } else { path.to_string() };
 
-        // Create a peer with the extracted host, port, and SSL setting
         // If custom_host is provided, use it for the host header
-        let host_header = if let Some(h) = custom_host {
-            // Extract only the domain part without port
-            let domain_only = match h.split_once(':') {
-                Some((domain, _)) => domain,  // Strip port if present
-                None => h                     // No port, use as is
-            };
-            
-            // Remove leading dot if present (common in cookie domains)
-            let clean_domain = if domain_only.starts_with('.') {
-                &domain_only[1..]
-            } else {
-                domain_only
-            };
+        let host_header = custom_host.map_or_else(|| host.clone(), follow_domain_host);
 
-            clean_domain.to_string()
-        } else {
-            host.clone()
-        };
-
-        let peer = HttpPeer::new(format!("{}:{}", host, port), use_ssl, host_header);
+        // host_str keeps the brackets of an IPv6 literal, which the Host header needs but lookup doesn't
+        let addr = lookup(host.trim_start_matches('[').trim_end_matches(']'), port).await?;
+        let peer = HttpPeer::new(addr, use_ssl, host_header);
         
         let base_path = if !path_str.is_empty() {
             Some(path_str)
@@ -76,34 +161,28 @@ pub async fn resolve_upstream_with_host(upstream: &str, custom_host: Option<&str
             None
         };
 
-        Ok(PeerWithPath::new(peer, base_path))
+        Ok(PeerWithPath::new(peer, base_path).with_query(url.query().map(|q| q.to_string())))
     } else {
-        // Handle host:port format with potential path
-        let parts: Vec<&str> = upstream.split('/').collect();
-        let host_port = parts[0].to_string();
-        
-        // Create the peer with the host:port part
-        // If custom_host is provided, use it for the host header
-        let host_header = if let Some(h) = custom_host {
-            // Extract only the domain part without port
-            let domain_only = match h.split_once(':') {
-                Some((domain, _)) => domain,  // Strip port if present
-                None => h                     // No port, use as is
-            };
-            
-            // Remove leading dot if present (common in cookie domains)
-            let clean_domain = if domain_only.starts_with('.') {
-                &domain_only[1..]
-            } else {
-                domain_only
-            };
-            
-            clean_domain.to_string()
-        } else {
-            String::new()
+        // Handle host:port format with potential path and query
+        let (upstream_addr, query) = match upstream.split_once('?') {
+            Some((rest, query)) => (rest, Some(query.to_string())),
+            None => (upstream, None),
         };
+        let parts: Vec<&str> = upstream_addr.split('/').collect();
+        let (host, port) = parts[0]
+            .rsplit_once(':')
+            .ok_or_else(|| UpstreamError::invalid(upstream, "expected host:port"))?;
+        let port: u16 = port.parse().map_err(|_| UpstreamError::invalid(upstream, "invalid port"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(UpstreamError::invalid(upstream, "missing host"));
+        }
 
-        let peer = HttpPeer::new(host_port, false, host_header);
+        // If custom_host is provided, use it for the host header
+        let host_header = custom_host.map(follow_domain_host).unwrap_or_default();
+
+        let addr = lookup(host, port).await?;
+        let peer = HttpPeer::new(addr, false, host_header);
 
         let base_path = if parts.len() > 1 {
             let path = format!("/{}", parts[1..].join("/"));
@@ -112,12 +191,220 @@ pub async fn resolve_upstream_with_host(upstream: &str, custom_host: Option<&str
             None
         };
         
-        Ok(PeerWithPath::new(peer, base_path))
+        Ok(PeerWithPath::new(peer, base_path).with_query(query))
+    }
+}
+
+fn pool_cursor(addrs: &[String]) -> Arc<AtomicUsize> {
+    let key = addrs.join(",");
+    if let Some(cursor) = POOL_CURSORS.read().unwrap().get(&key) {
+        return Arc::clone(cursor);
+    }
+    let mut cursors = POOL_CURSORS.write().unwrap();
+    Arc::clone(cursors.entry(key).or_default())
+}
+
+fn is_healthy(upstream: &str, now: Instant) -> bool {
+    !FAILING_HEALTH_CHECK.read().unwrap().contains(upstream)
+        && UNHEALTHY_UNTIL.read().unwrap().get(upstream).map_or(true, |until| now >= *until)
+}
+
+/// Record the outcome of an upstream's active health check; failing ones are skipped in routing
+pub fn set_health_check_state(upstream: &str, healthy: bool) {
+    let mut failing = FAILING_HEALTH_CHECK.write().unwrap();
+    if healthy {
+        failing.remove(upstream);
+    } else {
+        failing.insert(upstream.to_string());
     }
+    metrics::set_upstream_healthy(upstream, healthy);
+}
+
+/// Take a pooled upstream out of rotation after a failed connection
+pub fn mark_unhealthy(upstream: &str) {
+    mark_unhealthy_at(upstream, Instant::now());
+}
+
+fn mark_unhealthy_at(upstream: &str, now: Instant) {
+    warn!("Upstream {} failed to connect, skipping it for {}s", upstream, UNHEALTHY_COOLDOWN.as_secs());
+    UNHEALTHY_UNTIL.write().unwrap().insert(upstream.to_string(), now + UNHEALTHY_COOLDOWN);
+    metrics::set_upstream_healthy(upstream, false);
+}
+
+/// Whether a request error means the upstream couldn't be reached at all
+pub fn is_connect_failure(e: &Error) -> bool {
+    matches!(
+        e.etype(),
+        ErrorType::ConnectTimedout
+            | ErrorType::ConnectRefused
+            | ErrorType::ConnectNoRoute
+            | ErrorType::ConnectError
+            | ErrorType::TLSHandshakeFailure
+            | ErrorType::TLSHandshakeTimedout
+    )
 }
 
-/// Finds the best matching route for a given path and optional domain
-pub fn find_matching_route<'a>(routes: &'a [UpstreamRoute], path: &str, host: Option<&str>) -> Option<&'a UpstreamRoute> {
+/// Next address of an upstream pool: round-robin over the healthy ones
+///
+/// A single address is always returned as is. When every address of a pool is
+/// unhealthy they are all tried again rather than failing requests outright.
+pub fn select_pool_upstream(addrs: &[String]) -> &str {
+    select_pool_upstream_at(addrs, Instant::now())
+}
+
+fn select_pool_upstream_at(addrs: &[String], now: Instant) -> &str {
+    if addrs.len() <= 1 {
+        return addrs.first().map_or("", |addr| addr.as_str());
+    }
+
+    let healthy: Vec<&String> = addrs
+        .iter()
+        .filter(|addr| {
+            let healthy = is_healthy(addr, now);
+            metrics::set_upstream_healthy(addr, healthy);
+            healthy
+        })
+        .collect();
+    let candidates = if healthy.is_empty() { addrs.iter().collect() } else { healthy };
+
+    let position = pool_cursor(addrs).fetch_add(1, Ordering::Relaxed);
+    candidates[position % candidates.len()]
+}
+
+/// Merge the query configured on the upstream URL with the client's query
+///
+/// Upstream parameters take precedence: a client parameter with the same name
+/// as an upstream one is dropped, so clients cannot override values pinned in
+/// the config. Upstream parameters come first, followed by the remaining client ones.
+pub fn merge_query(upstream_query: Option<&str>, client_query: Option<&str>) -> Option<String> {
+    let upstream_query = upstream_query.filter(|q| !q.is_empty());
+    let client_query = client_query.filter(|q| !q.is_empty());
+
+    let upstream_query = match upstream_query {
+        Some(q) => q,
+        None => return client_query.map(|q| q.to_string()),
+    };
+
+    let param_name = |pair: &str| pair.split('=').next().unwrap_or("").to_string();
+    let upstream_names: Vec<String> = upstream_query.split('&').map(param_name).collect();
+
+    let mut params: Vec<&str> = upstream_query.split('&').collect();
+    if let Some(client_query) = client_query {
+        params.extend(
+            client_query
+                .split('&')
+                .filter(|pair| !pair.is_empty() && !upstream_names.contains(&param_name(pair))),
+        );
+    }
+
+    Some(params.join("&"))
+}
+
+/// Build the request URI sent upstream from the rewritten path and both queries
+pub fn build_upstream_uri(path: &str, upstream_query: Option<&str>, client_query: Option<&str>) -> String {
+    match merge_query(upstream_query, client_query) {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    }
+}
+
+/// Replace the request URI with the rewritten path, merging the upstream query
+fn rewrite_request_uri(session: &mut Session, new_path: &str, upstream_query: Option<&str>) {
+    let new_uri_str = build_upstream_uri(new_path, upstream_query, session.req_header().uri.query());
+
+    // Modify the request URI
+    match new_uri_str.parse() {
+        Ok(new_uri) => {
+            session.req_header_mut().set_uri(new_uri);
+        },
+        Err(e) => {
+            error!("Failed to parse URI '{}': {}", new_uri_str, e);
+        }
+    }
+}
+
+/// Media type of the request body ("application/grpc" for "application/grpc; charset=utf-8")
+pub fn request_content_type(req: &RequestHeader) -> Option<&str> {
+    req.headers.get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim())
+        .filter(|v| !v.is_empty())
+}
+
+/// Whether a route's `content_type_match` accepts the request's Content-Type
+///
+/// A configured type also matches its "+suffix" variants ("application/grpc" matches
+/// "application/grpc+proto"). Routes without `content_type_match` accept anything.
+fn content_type_matches(route: &UpstreamRoute, content_type: Option<&str>) -> bool {
+    let Some(expected) = route.content_type_match.as_deref() else {
+        return true;
+    };
+    let Some(actual) = content_type else {
+        return false;
+    };
+    let actual = actual.to_ascii_lowercase();
+    let expected = expected.to_ascii_lowercase();
+    actual == expected || actual.strip_prefix(&expected).map_or(false, |rest| rest.starts_with('+'))
+}
+
+/// Whether the route's domain names the same port as the Host header (e.g. "api.example.com:8443")
+fn port_matches(route: &UpstreamRoute, host_port: Option<&str>) -> bool {
+    let route_port = route.domain.as_deref().and_then(|d| d.split_once(':')).map(|(_, port)| port);
+    route_port.is_some() && route_port == host_port
+}
+
+/// Whether the request path belongs to a route: its `path_regex` when set,
+/// otherwise its `path` prefix
+pub(crate) fn route_path_matches(prefix: &str, path_regex: Option<&PathRegex>, path: &str) -> bool {
+    match path_regex {
+        Some(path_regex) => path_regex.is_match(path),
+        None => path.starts_with(prefix),
+    }
+}
+
+fn path_matches(route: &UpstreamRoute, path: &str) -> bool {
+    route_path_matches(&route.path, route.path_regex.as_ref(), path)
+}
+
+/// Part of the request path forwarded below the upstream's base path: what follows
+/// the route's `path` prefix, or the whole path when a `path_regex` route matched
+/// a path outside that prefix
+fn path_below_route<'a>(route: &UpstreamRoute, path: &'a str) -> &'a str {
+    path.strip_prefix(route.path.as_str()).unwrap_or(path)
+}
+
+/// Most specific of the candidate routes, in this order of precedence:
+/// 1. a `path_regex` match over a prefix match, whatever the prefix length
+/// 2. longest path prefix
+/// 3. a `content_type_match` over none
+/// 4. a domain with the request's port over a domain without one
+/// 5. declared first in the config
+fn most_specific<'a>(
+    candidates: impl Iterator<Item = &'a UpstreamRoute>,
+    host_port: Option<&str>,
+) -> Option<&'a UpstreamRoute> {
+    candidates
+        .enumerate()
+        .max_by_key(|(index, route)| (
+            route.path_regex.is_some(),
+            route.path.len(),
+            route.content_type_match.is_some(),
+            port_matches(route, host_port),
+            Reverse(*index),
+        ))
+        .map(|(_, route)| route)
+}
+
+/// Finds the best matching route for a given path, optional domain and request Content-Type
+pub fn find_matching_route<'a>(
+    routes: &'a [UpstreamRoute],
+    path: &str,
+    host: Option<&str>,
+    content_type: Option<&str>,
+) -> Option<&'a UpstreamRoute> {
+    let candidates = || routes.iter().filter(|route| content_type_matches(route, content_type));
+    let host_port = host.and_then(|h| h.split_once(':')).map(|(_, port)| port);
+
     // First try to match both domain and path if host is provided
     if let Some(host_value) = host {
         // Extract domain and port from host header
@@ -126,53 +413,40 @@ pub fn find_matching_route<'a>(routes: &'a [UpstreamRoute], path: &str, host: Op
             None => (host_value, false)           // Host without port
         };
         
-        // First, try to find the most specific domain+path match (longest path wins)
-        let domain_path_matches: Vec<&UpstreamRoute> = routes.iter()
-            .filter(|route| {
-                // Check if this route has a domain requirement
-                if let Some(route_domain) = &route.domain {
-                    // Extract domain part from route domain (without port)
-                    let route_domain_part = match route_domain.split_once(':') {
-                        Some((d, _)) => d,
-                        None => route_domain.as_str()
-                    };
-                    
-                    route_domain_part == domain_part && path.starts_with(&route.path)
-                } else {
-                    false
-                }
-            })
-            .collect();
-        
-        // Sort matches by path length (descending) to find most specific match
-        if !domain_path_matches.is_empty() {
-            // Find the match with the longest path (most specific)
-            let best_match = domain_path_matches.iter()
-                .max_by_key(|route| route.path.len());
-            
-            if let Some(route) = best_match {
-                return Some(route);
-            }
+        // Domain part of the route's domain (without port)
+        let route_domain = |route: &UpstreamRoute| {
+            route.domain.as_deref().map(|d| d.split_once(':').map_or(d, |(d, _)| d))
+        };
+
+        // First, try to find the most specific domain+path match
+        let domain_path_matches = candidates()
+            .filter(|route| route_domain(route) == Some(domain_part) && path_matches(route, path));
+
+        if let Some(route) = most_specific(domain_path_matches, host_port) {
+            return Some(route);
+        }
+
+        // Then wildcard domains ("*.tenants.example.com"); exact domains take precedence
+        let wildcard_matches = candidates().filter(|route| {
+            route_domain(route).map_or(false, |d| wildcard_subdomain(domain_part, d).is_some())
+                && path_matches(route, path)
+        });
+
+        if let Some(route) = most_specific(wildcard_matches, host_port) {
+            return Some(route);
         }
     }
     
     // If no domain-specific match or no host provided, fall back to path-only matching
     // Only consider routes without domain requirements
-    let path_matches: Vec<&UpstreamRoute> = routes.iter()
+    let path_matches = candidates()
         .filter(|route| {
             // Only consider routes with no domain requirement
-            route.domain.is_none() && path.starts_with(&route.path)
-        })
-        .collect();
+            route.domain.is_none() && path_matches(route, path)
+        });
     
-    if !path_matches.is_empty() {
-        // Find the match with the longest path (most specific)
-        let best_match = path_matches.iter()
-            .max_by_key(|route| route.path.len());
-        
-        if let Some(route) = best_match {
-            return Some(route);
-        }
+    if let Some(route) = most_specific(path_matches, host_port) {
+        return Some(route);
     }
     
     // If no specific match found, try to find a default route for the domain
@@ -183,8 +457,8 @@ pub fn find_matching_route<'a>(routes: &'a [UpstreamRoute], path: &str, host: Op
         };
         
         // Look for a root path (/) route for this domain
-        let domain_default = routes.iter()
-            .find(|route| {
+        let domain_defaults = candidates()
+            .filter(|route| {
                 if let Some(route_domain) = &route.domain {
                     // Extract domain part from route domain (without port)
                     let route_domain_part = match route_domain.split_once(':') {
@@ -199,120 +473,76 @@ pub fn find_matching_route<'a>(routes: &'a [UpstreamRoute], path: &str, host: Op
                 }
             });
         
-        if let Some(route) = domain_default {
+        if let Some(route) = most_specific(domain_defaults, host_port) {
             return Some(route);
         }
     }
     
     // Last resort: find a global default route (path="/" with no domain)
-    let global_default = routes.iter()
-        .find(|route| route.domain.is_none() && route.path == "/");
-    
-    global_default
+    most_specific(candidates().filter(|route| route.domain.is_none() && route.path == "/"), host_port)
 }
 
 /// Get the upstream peer based on the request path and host
-pub async fn upstream_peer_by_path(routes: &[UpstreamRoute], default_upstream: &str, session: &mut Session) -> Result<Box<HttpPeer>> {
+///
+/// The route is picked by `routing_path` (see `utils::path::routing_path`); a base
+/// path rewrite keeps the request's own path. Also returns the pool address the
+/// request was balanced to, if the route has several upstreams, so a failed
+/// connection can be reported with `mark_unhealthy`.
+pub async fn upstream_peer_by_path(routes: &[UpstreamRoute], default_upstream: &str, routing_path: &str, session: &mut Session) -> Result<(Box<HttpPeer>, Option<String>)> {
     // Store all the information we need from the immutable session first
     let path = session.req_header().uri.path().to_string();
     
-    // Extract the host header if present for domain-based routing
-    // In HTTP/2, the host information is in :authority pseudo-header
-    // but Pingora should provide it through various means
-    let host = session.req_header()
-        .headers
-        .get("host")
-        .and_then(|h| h.to_str().ok())
-        .or_else(|| {
-            // Try to get from authority pseudo-header
-            session.req_header()
-                .headers
-                .get(":authority")
-                .and_then(|h| h.to_str().ok())
-        })
-        .or_else(|| {
-            // Try to get from URI if it contains host information
-            let uri = &session.req_header().uri;
-            uri.authority().map(|auth| auth.as_str())
-        })
-        .map(|s| s.to_string());
+    // Extract the host for domain-based routing (Host for HTTP/1.x, :authority for HTTP/2)
+    let host = extract_host(session);
+    let content_type = request_content_type(session.req_header()).map(|ct| ct.to_string());
     
-    // Find the best matching route considering both domain and path
-    if let Some(route) = find_matching_route(routes, &path, host.as_deref()) {
-        // Check if we need to follow domain for this route
-        let custom_host = if route.follow_domain && route.domain.is_some() {
-            route.domain.as_deref()
-        } else {
-            None
+    // Find the best matching route considering domain, path and Content-Type
+    if let Some(route) = find_matching_route(routes, routing_path, host.as_deref(), content_type.as_deref()) {
+        // Check if we need to follow domain for this route; a wildcard route forwards
+        // the subdomain the client asked for
+        let custom_host = match route.domain.as_deref() {
+            Some(domain) if route.follow_domain && is_wildcard_domain(domain) => host.as_deref(),
+            Some(domain) if route.follow_domain => Some(domain),
+            _ => None,
         };
         
+        let stable = select_pool_upstream(route.upstream.addrs());
+        let upstream = canary::select_upstream(stable, route.canary.as_ref(), session.req_header());
+        let pool_upstream = (route.upstream.addrs().len() > 1 && upstream == stable).then(|| stable.to_string());
+
         // Resolve the upstream with the custom host if needed
-        let peer_with_path = resolve_upstream_with_host(&route.upstream, custom_host).await?;
+        let peer_with_path = resolve_upstream_with_host(upstream, custom_host).await?
+            .with_tls(route.upstream_tls);
         
-        // If there's a base path, modify the request URI
-        if let Some(ref base_path) = peer_with_path.base_path {
-            // Get the path after the matched route path
-            let remaining_path = &path[route.path.len()..];
-            let new_path = if remaining_path.is_empty() || remaining_path == "/" {
-                base_path.clone()
-            } else {
-                format!("{}{}", base_path, remaining_path)
+        // If there's a base path or upstream query, modify the request URI
+        if peer_with_path.base_path.is_some() || peer_with_path.query.is_some() {
+            let new_path = match peer_with_path.base_path {
+                Some(ref base_path) => {
+                    // Get the path after the matched route path
+                    let remaining_path = path_below_route(route, &path);
+                    if remaining_path.is_empty() || remaining_path == "/" {
+                        base_path.clone()
+                    } else {
+                        format!("{}{}", base_path, remaining_path)
+                    }
+                }
+                None => path.clone(),
             };
 
-            // Modify the URI directly by setting a new URI string
-            let uri_str = session.req_header().uri.to_string();
-            let uri_parts: Vec<&str> = uri_str.split('?').collect();
-            
-            let new_uri_str = if uri_parts.len() > 1 {
-                // URI has a query string
-                format!("{}?{}", new_path, uri_parts[1])
-            } else {
-                new_path
-            };
-            
-            // Modify the request URI
-            let uri_result = new_uri_str.parse();
-            match uri_result {
-                Ok(new_uri) => {
-                    session.req_header_mut().set_uri(new_uri);
-                },
-                Err(e) => {
-                    error!("Failed to parse URI '{}': {}", new_uri_str, e);
-                }
-            }
+            rewrite_request_uri(session, &new_path, peer_with_path.query.as_deref());
         }
 
-        Ok(peer_with_path.into_boxed_http_peer())
+        Ok((peer_with_path.into_boxed_http_peer(), pool_upstream))
     } else {
         let peer_with_path = resolve_upstream(default_upstream).await?;
         
-        // If there's a base path, modify the request URI
-        if let Some(ref base_path) = peer_with_path.base_path {
-            let new_path = format!("{}{}", base_path, path);
-            // Modify the URI directly by setting a new URI string
-            let uri_str = session.req_header().uri.to_string();
-            let uri_parts: Vec<&str> = uri_str.split('?').collect();
-            
-            let new_uri_str = if uri_parts.len() > 1 {
-                // URI has a query string
-                format!("{}?{}", new_path, uri_parts[1])
-            } else {
-                new_path
-            };
-            
-            // Modify the request URI
-            let uri_result = new_uri_str.parse();
-            match uri_result {
-                Ok(new_uri) => {
-                    session.req_header_mut().set_uri(new_uri);
-                },
-                Err(e) => {
-                    error!("Failed to parse URI '{}': {}", new_uri_str, e);
-                }
-            }
+        // If there's a base path or upstream query, modify the request URI
+        if peer_with_path.base_path.is_some() || peer_with_path.query.is_some() {
+            let new_path = format!("{}{}", peer_with_path.base_path.as_deref().unwrap_or(""), path);
+            rewrite_request_uri(session, &new_path, peer_with_path.query.as_deref());
         }
 
-        Ok(peer_with_path.into_boxed_http_peer())
+        Ok((peer_with_path.into_boxed_http_peer(), None))
     }
 }
 
@@ -320,31 +550,297 @@ pub async fn upstream_peer_by_path(routes: &[UpstreamRoute], default_upstream: &
 pub async fn upstream_peer(upstream: &str, session: &mut Session) -> Result<Box<HttpPeer>> {
     let peer_with_path = resolve_upstream(upstream).await?;
 
-    if let Some(ref base_path) = peer_with_path.base_path {
+    if peer_with_path.base_path.is_some() || peer_with_path.query.is_some() {
         let path = session.req_header().uri.path();
-        let new_path = format!("{}{}", base_path, path);
-        // Modify the URI directly by setting a new URI string
-        let uri_str = session.req_header().uri.to_string();
-        let uri_parts: Vec<&str> = uri_str.split('?').collect();
-        
-        let new_uri_str = if uri_parts.len() > 1 {
-            // URI has a query string
-            format!("{}?{}", new_path, uri_parts[1])
-        } else {
-            new_path
-        };
-        
-        // Modify the request URI
-        let uri_result = new_uri_str.parse();
-        match uri_result {
-            Ok(new_uri) => {
-                session.req_header_mut().set_uri(new_uri);
-            },
-            Err(e) => {
-                error!("Failed to parse URI '{}': {}", new_uri_str, e);
-            }
-        }
+        let new_path = format!("{}{}", peer_with_path.base_path.as_deref().unwrap_or(""), path);
+        rewrite_request_uri(session, &new_path, peer_with_path.query.as_deref());
     }
     
     Ok(peer_with_path.into_boxed_http_peer())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_query_merged_with_client_query() {
+        assert_eq!(
+            build_upstream_uri("/app/users", Some("region=eu"), Some("page=2")),
+            "/app/users?region=eu&page=2"
+        );
+    }
+
+    #[test]
+    fn test_upstream_query_without_client_query() {
+        assert_eq!(build_upstream_uri("/app", Some("region=eu"), None), "/app?region=eu");
+        assert_eq!(build_upstream_uri("/app", None, Some("page=2")), "/app?page=2");
+        assert_eq!(build_upstream_uri("/app", None, None), "/app");
+    }
+
+    #[test]
+    fn test_upstream_params_take_precedence() {
+        assert_eq!(
+            merge_query(Some("region=eu"), Some("region=us&page=2")),
+            Some("region=eu&page=2".to_string())
+        );
+    }
+
+    fn resolve_blocking(upstream: &str) -> PeerWithPath {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(resolve_upstream(upstream))
+            .unwrap()
+    }
+
+    fn parse_blocking(upstream: &str) -> std::result::Result<PeerWithPath, UpstreamError> {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(parse_upstream(upstream, None))
+    }
+
+    #[test]
+    fn test_malformed_upstreams_yield_matching_error() {
+        assert!(matches!(parse_blocking("http://exa mple.com"), Err(UpstreamError::InvalidUrl { .. })));
+        assert!(matches!(parse_blocking("backend"), Err(UpstreamError::InvalidUrl { .. })));
+        assert!(matches!(parse_blocking("backend:http"), Err(UpstreamError::InvalidUrl { .. })));
+        assert!(matches!(parse_blocking(":8080"), Err(UpstreamError::InvalidUrl { .. })));
+        assert_eq!(
+            parse_blocking("ftp://files.example.com").unwrap_err(),
+            UpstreamError::UnsupportedScheme("ftp".to_string())
+        );
+        assert_eq!(
+            parse_blocking("backend.invalid:8080").unwrap_err(),
+            UpstreamError::UnresolvableHost("backend.invalid".to_string())
+        );
+    }
+
+    #[test]
+    fn test_ipv6_upstreams() {
+        let url = parse_blocking("http://[::1]:8080/api").unwrap();
+        assert_eq!(url.peer._address.to_string(), "[::1]:8080");
+        assert_eq!(url.base_path.as_deref(), Some("/api"));
+
+        let host_port = parse_blocking("[::1]:8080").unwrap();
+        assert_eq!(host_port.peer._address.to_string(), "[::1]:8080");
+    }
+
+    #[test]
+    fn test_upstream_error_types() {
+        let invalid: Box<Error> = UpstreamError::UnsupportedScheme("ws".to_string()).into();
+        assert_eq!(invalid.etype(), &ErrorType::Custom("InvalidUpstream"));
+
+        let unresolvable: Box<Error> = UpstreamError::UnresolvableHost("backend.invalid".to_string()).into();
+        assert_eq!(unresolvable.etype(), &ErrorType::ConnectNoRoute);
+    }
+
+    #[test]
+    fn test_host_port_upstream_with_upstream_tls_uses_tls() {
+        let peer = resolve_blocking("10.0.0.5:8443").with_tls(true).peer;
+
+        assert!(peer.is_tls());
+        assert_eq!(peer.sni, "10.0.0.5");
+    }
+
+    #[test]
+    fn test_host_port_upstream_without_upstream_tls_stays_plaintext() {
+        let peer = resolve_blocking("10.0.0.5:8080").with_tls(false).peer;
+
+        assert!(!peer.is_tls());
+    }
+
+    fn route(path: &str, upstream: &str, content_type_match: Option<&str>) -> UpstreamRoute {
+        UpstreamRoute {
+            path: path.to_string(),
+            upstream: upstream.into(),
+            domain: Some("api.example.com".to_string()),
+            content_type_match: content_type_match.map(|ct| ct.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_wildcard_domain_matches_subdomains() {
+        let mut tenants = route("/", "tenants:8000", None);
+        tenants.domain = Some("*.tenants.example.com".to_string());
+        let mut admin = route("/", "admin:8000", None);
+        admin.domain = Some("admin.tenants.example.com".to_string());
+        let routes = vec![tenants, admin];
+
+        let tenant = find_matching_route(&routes, "/dashboard", Some("a.tenants.example.com"), None);
+        assert_eq!(tenant.unwrap().upstream, "tenants:8000");
+        let with_port = find_matching_route(&routes, "/", Some("b.tenants.example.com:8443"), None);
+        assert_eq!(with_port.unwrap().upstream, "tenants:8000");
+
+        // Exact domains win over the wildcard
+        let exact = find_matching_route(&routes, "/", Some("admin.tenants.example.com"), None);
+        assert_eq!(exact.unwrap().upstream, "admin:8000");
+
+        assert!(find_matching_route(&routes, "/", Some("a.other.example.com"), None).is_none());
+        assert!(find_matching_route(&routes, "/", Some("tenants.example.com"), None).is_none());
+    }
+
+    #[test]
+    fn test_content_type_routes_same_path_to_different_upstreams() {
+        let routes = vec![
+            route("/api", "rest:8000", None),
+            route("/api", "grpc:9000", Some("application/grpc")),
+        ];
+        let host = Some("api.example.com");
+
+        let grpc = find_matching_route(&routes, "/api/Users/Get", host, Some("application/grpc+proto"));
+        assert_eq!(grpc.unwrap().upstream, "grpc:9000");
+
+        let json = find_matching_route(&routes, "/api/users", host, Some("application/json"));
+        assert_eq!(json.unwrap().upstream, "rest:8000");
+
+        let none = find_matching_route(&routes, "/api/users", host, None);
+        assert_eq!(none.unwrap().upstream, "rest:8000");
+    }
+
+    #[test]
+    fn test_equal_length_prefixes_first_declared_wins() {
+        let routes = vec![
+            route("/api", "first:8000", None),
+            route("/api", "second:8000", None),
+        ];
+        let host = Some("api.example.com");
+
+        for _ in 0..3 {
+            assert_eq!(find_matching_route(&routes, "/api/users", host, None).unwrap().upstream, "first:8000");
+        }
+    }
+
+    #[test]
+    fn test_longer_prefix_beats_content_type() {
+        let routes = vec![
+            route("/api", "grpc:9000", Some("application/grpc")),
+            route("/api/v2", "rest:8000", None),
+        ];
+        let host = Some("api.example.com");
+
+        let matched = find_matching_route(&routes, "/api/v2/users", host, Some("application/grpc"));
+        assert_eq!(matched.unwrap().upstream, "rest:8000");
+    }
+
+    #[test]
+    fn test_domain_with_matching_port_breaks_tie() {
+        let mut with_port = route("/api", "tls-only:8000", None);
+        with_port.domain = Some("api.example.com:8443".to_string());
+        let routes = vec![route("/api", "any-port:8000", None), with_port];
+
+        assert_eq!(find_matching_route(&routes, "/api", Some("api.example.com:8443"), None).unwrap().upstream, "tls-only:8000");
+        assert_eq!(find_matching_route(&routes, "/api", Some("api.example.com"), None).unwrap().upstream, "any-port:8000");
+    }
+
+    fn pool(addrs: &[&str]) -> Vec<String> {
+        addrs.iter().map(|addr| addr.to_string()).collect()
+    }
+
+    #[test]
+    fn test_pool_is_balanced_round_robin() {
+        let addrs = pool(&["rr-a.test:8000", "rr-b.test:8000", "rr-c.test:8000"]);
+        let now = Instant::now();
+
+        let picked: Vec<&str> = (0..6).map(|_| select_pool_upstream_at(&addrs, now)).collect();
+        assert_eq!(&picked[..3], &picked[3..]);
+        for addr in &addrs {
+            assert_eq!(picked.iter().filter(|p| **p == addr.as_str()).count(), 2);
+        }
+
+        let single = pool(&["single.test:8000"]);
+        assert_eq!(select_pool_upstream_at(&single, now), "single.test:8000");
+    }
+
+    #[test]
+    fn test_failed_upstream_is_skipped_until_cooldown_ends() {
+        let addrs = pool(&["hc-a.test:8000", "hc-b.test:8000"]);
+        let now = Instant::now();
+        mark_unhealthy_at("hc-a.test:8000", now);
+
+        for _ in 0..4 {
+            assert_eq!(select_pool_upstream_at(&addrs, now), "hc-b.test:8000");
+        }
+        assert_eq!(metrics::UPSTREAM_HEALTHY.with_label_values(&["hc-a.test:8000"]).get(), 0.0);
+
+        let later = now + UNHEALTHY_COOLDOWN;
+        let picked: Vec<&str> = (0..2).map(|_| select_pool_upstream_at(&addrs, later)).collect();
+        assert!(picked.contains(&"hc-a.test:8000"));
+        assert_eq!(metrics::UPSTREAM_HEALTHY.with_label_values(&["hc-a.test:8000"]).get(), 1.0);
+    }
+
+    #[test]
+    fn test_upstream_failing_health_check_is_skipped() {
+        let addrs = pool(&["ahc-a.test:8000", "ahc-b.test:8000"]);
+        let now = Instant::now();
+
+        set_health_check_state("ahc-b.test:8000", false);
+        for _ in 0..4 {
+            assert_eq!(select_pool_upstream_at(&addrs, now), "ahc-a.test:8000");
+        }
+
+        set_health_check_state("ahc-b.test:8000", true);
+        let picked: Vec<&str> = (0..2).map(|_| select_pool_upstream_at(&addrs, now)).collect();
+        assert!(picked.contains(&"ahc-b.test:8000"));
+    }
+
+    #[test]
+    fn test_all_unhealthy_pool_still_serves() {
+        let addrs = pool(&["down-a.test:8000", "down-b.test:8000"]);
+        let now = Instant::now();
+        mark_unhealthy_at("down-a.test:8000", now);
+        mark_unhealthy_at("down-b.test:8000", now);
+
+        let picked: Vec<&str> = (0..2).map(|_| select_pool_upstream_at(&addrs, now)).collect();
+        assert!(picked.contains(&"down-a.test:8000") && picked.contains(&"down-b.test:8000"));
+    }
+
+    #[test]
+    fn test_connect_errors_mark_upstream_unhealthy() {
+        assert!(is_connect_failure(&Error::new(ErrorType::ConnectRefused)));
+        assert!(is_connect_failure(&Error::new(ErrorType::ConnectTimedout)));
+        assert!(!is_connect_failure(&Error::new(ErrorType::ReadTimedout)));
+        assert!(!is_connect_failure(&Error::new(ErrorType::HTTPStatus(502))));
+    }
+
+    #[test]
+    fn test_regex_route_outranks_prefix_routes() {
+        let mut versioned = route("/api", "users:8000", None);
+        versioned.path_regex = Some(serde_yaml::from_str(r#""/api/v\d+/users""#).unwrap());
+        let mut assets = route("/", "cdn:8000", None);
+        assets.path_regex = Some(serde_yaml::from_str(r#"".*\.(css|js|png)$""#).unwrap());
+        let routes = vec![route("/api/v2", "legacy:8000", None), versioned, assets, route("/", "web:8000", None)];
+        let host = Some("api.example.com");
+        let upstream = |path: &str| find_matching_route(&routes, path, host, None).unwrap().upstream.to_string();
+
+        // The regex wins over the longer /api/v2 prefix
+        assert_eq!(upstream("/api/v2/users/7"), "users:8000");
+        assert_eq!(upstream("/api/v13/users"), "users:8000");
+        assert_eq!(upstream("/api/v2/orders"), "legacy:8000");
+        // Anchored at the start of the path
+        assert_eq!(upstream("/static/api/v2/users"), "web:8000");
+        assert_eq!(upstream("/static/app.js"), "cdn:8000");
+        assert_eq!(upstream("/static/app.js.map"), "web:8000");
+    }
+
+    #[test]
+    fn test_regex_route_outside_its_prefix_keeps_the_whole_path() {
+        let mut downloads = route("/downloads", "files:8000/storage", None);
+        downloads.path_regex = Some(serde_yaml::from_str(r#""/.*\.pdf""#).unwrap());
+        let routes = vec![downloads];
+        let matched = find_matching_route(&routes, "/a.pdf", Some("api.example.com"), None).unwrap();
+
+        assert_eq!(path_below_route(matched, "/a.pdf"), "/a.pdf");
+        assert_eq!(path_below_route(matched, "/downloads/b.pdf"), "/b.pdf");
+        assert_eq!(path_below_route(matched, "/"), "/");
+    }
+
+    #[test]
+    fn test_request_content_type_strips_parameters() {
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        req.insert_header("Content-Type", "Application/JSON; charset=utf-8").unwrap();
+        assert_eq!(request_content_type(&req), Some("Application/JSON"));
+    }
+}
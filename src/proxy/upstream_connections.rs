@@ -0,0 +1,86 @@
+use crate::metrics;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+// Requests currently in flight to each upstream address
+static OPEN_CONNECTIONS: Lazy<RwLock<HashMap<String, Arc<AtomicUsize>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn counter(upstream: &str) -> Arc<AtomicUsize> {
+    if let Some(counter) = OPEN_CONNECTIONS.read().unwrap().get(upstream) {
+        return Arc::clone(counter);
+    }
+    let mut counters = OPEN_CONNECTIONS.write().unwrap();
+    Arc::clone(counters.entry(upstream.to_string()).or_default())
+}
+
+/// Number of requests currently proxied to an upstream
+pub fn open_connections(upstream: &str) -> usize {
+    counter(upstream).load(Ordering::SeqCst)
+}
+
+/// One request's claim on an upstream connection, released on drop
+///
+/// Pingora pools and reuses upstream connections, so this counts requests in flight
+/// to the upstream: the number of connections it needs at once.
+#[derive(Debug)]
+pub struct UpstreamSlot {
+    upstream: String,
+    counter: Arc<AtomicUsize>,
+}
+
+impl UpstreamSlot {
+    /// Claim a connection to `upstream`; None if `max` are already open
+    pub fn acquire(upstream: &str, max: Option<usize>) -> Option<Self> {
+        let counter = counter(upstream);
+        let open = counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                max.map_or(true, |max| open < max).then_some(open + 1)
+            })
+            .ok()?;
+        metrics::set_upstream_connections(upstream, open + 1);
+        Some(Self { upstream: upstream.to_string(), counter })
+    }
+}
+
+impl Drop for UpstreamSlot {
+    fn drop(&mut self) {
+        let open = self.counter.fetch_sub(1, Ordering::SeqCst) - 1;
+        metrics::set_upstream_connections(&self.upstream, open);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capped_upstream_rejects_when_saturated() {
+        let first = UpstreamSlot::acquire("capped.test:8000", Some(2));
+        let second = UpstreamSlot::acquire("capped.test:8000", Some(2));
+        assert!(first.is_some() && second.is_some());
+
+        // N+1th concurrent request is rejected
+        assert!(UpstreamSlot::acquire("capped.test:8000", Some(2)).is_none());
+        assert_eq!(open_connections("capped.test:8000"), 2);
+
+        // Another upstream is unaffected
+        assert!(UpstreamSlot::acquire("other.test:8000", Some(2)).is_some());
+
+        // A finished request frees its slot
+        drop(first);
+        assert!(UpstreamSlot::acquire("capped.test:8000", Some(2)).is_some());
+    }
+
+    #[test]
+    fn test_uncapped_upstream_is_only_counted() {
+        let slots: Vec<_> = (0..5).filter_map(|_| UpstreamSlot::acquire("uncapped.test:8000", None)).collect();
+        assert_eq!(slots.len(), 5);
+        assert_eq!(open_connections("uncapped.test:8000"), 5);
+
+        drop(slots);
+        assert_eq!(open_connections("uncapped.test:8000"), 0);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::config::ConnectionLimitConfig;
+use crate::metrics;
+use async_trait::async_trait;
+use pingora_core::apps::ServerApp;
+use pingora_core::protocols::{GetSocketDigest, Stream};
+use pingora_core::server::ShutdownWatch;
+use pingora_limits::rate::Rate;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Rate of new TCP connections per client IP
+///
+/// Runs when a connection is handed to the proxy, before any HTTP parsing, so clients
+/// churning connections are dropped without costing a request.
+pub struct ConnectionRateLimiter {
+    max_per_window: isize,
+    rate: Rate,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(max_per_window: isize, window: Duration) -> Self {
+        Self { max_per_window, rate: Rate::new(window) }
+    }
+
+    pub fn from_config(config: &ConnectionLimitConfig) -> Self {
+        Self::new(config.max_per_window, Duration::from_secs(config.window_secs))
+    }
+
+    /// Count a new connection from `ip` on `port`; false if it should be dropped
+    pub fn accept(&self, ip: IpAddr, port: u16) -> bool {
+        let opened = self.rate.observe(&ip, 1);
+        if opened > self.max_per_window {
+            log::debug!("Dropping connection from {} on port {}: {} new connections this window", ip, port, opened);
+            metrics::record_connection_rejected(port);
+            return false;
+        }
+        true
+    }
+}
+
+/// Accept path of the proxy service: checks each new connection against the per-IP
+/// connection rate before `app` reads anything from it
+///
+/// On TLS ports pingora completes the handshake before the connection gets here, so
+/// handshake floods still have to be limited in front of pingwall.
+pub struct ConnectionGate<A> {
+    app: Arc<A>,
+    limiter: Option<ConnectionRateLimiter>,
+}
+
+impl<A> ConnectionGate<A> {
+    pub fn new(app: A, limiter: Option<ConnectionRateLimiter>) -> Self {
+        Self { app: Arc::new(app), limiter }
+    }
+
+    /// Whether a new connection may be served; connections without an IP peer
+    /// (e.g. Unix sockets) always are
+    fn admit(&self, stream: &Stream) -> bool {
+        let (Some(limiter), Some(digest)) = (&self.limiter, stream.get_socket_digest()) else {
+            return true;
+        };
+        let peer = digest.peer_addr().and_then(|addr| addr.as_inet());
+        let local = digest.local_addr().and_then(|addr| addr.as_inet());
+        match (peer, local) {
+            (Some(peer), Some(local)) => limiter.accept(peer.ip(), local.port()),
+            _ => true,
+        }
+    }
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> ServerApp for ConnectionGate<A> {
+    async fn process_new(self: &Arc<Self>, session: Stream, shutdown: &ShutdownWatch) -> Option<Stream> {
+        if !self.admit(&session) {
+            // Dropping the stream closes the connection
+            return None;
+        }
+        self.app.process_new(session, shutdown).await
+    }
+
+    async fn cleanup(&self) {
+        self.app.cleanup().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::protocols::l4::socket::SocketDigest;
+    use pingora_core::protocols::l4::stream::Stream as L4Stream;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_excess_connections_are_dropped() {
+        let limiter = ConnectionRateLimiter::new(3, Duration::from_secs(60));
+        let attacker: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let accepted = (0..5).filter(|_| limiter.accept(attacker, 443)).count();
+        assert_eq!(accepted, 3);
+        assert!(!limiter.accept(attacker, 443));
+
+        // Other clients keep their own budget
+        assert!(limiter.accept("198.51.100.1".parse().unwrap(), 443));
+    }
+
+    /// Stands in for the proxy: counts the connections it is handed
+    #[derive(Default)]
+    struct CountingApp {
+        served: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ServerApp for CountingApp {
+        async fn process_new(self: &Arc<Self>, _session: Stream, _shutdown: &ShutdownWatch) -> Option<Stream> {
+            self.served.fetch_add(1, Ordering::SeqCst);
+            None
+        }
+    }
+
+    #[test]
+    fn test_gate_drops_connections_over_the_rate_before_the_app() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let gate = Arc::new(ConnectionGate::new(
+            CountingApp::default(),
+            Some(ConnectionRateLimiter::new(2, Duration::from_secs(60))),
+        ));
+        let (_shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut clients = Vec::new();
+            for _ in 0..5 {
+                clients.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+                let (accepted, _) = listener.accept().await.unwrap();
+                // As the listener does: the digest resolves peer and local address from the socket
+                let mut stream = L4Stream::from(accepted);
+                stream.set_socket_digest(SocketDigest::from_raw_fd(stream.as_raw_fd()));
+                gate.process_new(Box::new(stream), &shutdown).await;
+            }
+        });
+
+        assert_eq!(gate.app.served.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,151 @@
+/// What rate limiting did with a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitAction {
+    /// Passed through to the upstream
+    #[default]
+    Allowed,
+    /// Rejected with 429 (401 for a missing or invalid JWT, 403 for a User-Agent outside allow_user_agents), the IP is not blocked
+    SoftLimited,
+    /// Rejected with 429 and the IP is (or already was) blocked
+    Blocked,
+}
+
+impl LimitAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitAction::Allowed => "allowed",
+            LimitAction::SoftLimited => "soft_limited",
+            LimitAction::Blocked => "blocked",
+        }
+    }
+}
+
+/// Client's budget under the limit that counted an allowed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitQuota {
+    pub limit: isize,
+    pub remaining: isize,
+    /// Seconds until the budget is restored (at most one window)
+    pub reset: u64,
+    pub window_secs: u64,
+}
+
+impl RateLimitQuota {
+    pub fn new(limit: isize, current_count: isize, window_secs: u64) -> Self {
+        Self {
+            limit,
+            remaining: (limit - current_count).max(0),
+            reset: window_secs,
+            window_secs,
+        }
+    }
+
+    /// `RateLimit` header value per the IETF draft, e.g. "limit=100, remaining=73, reset=42"
+    pub fn header_value(&self) -> String {
+        format!("limit={}, remaining={}, reset={}", self.limit, self.remaining, self.reset)
+    }
+
+    /// `RateLimit-Policy` header value, e.g. "100;w=60"
+    pub fn policy_value(&self) -> String {
+        format!("{};w={}", self.limit, self.window_secs)
+    }
+}
+
+/// Outcome of `check_rate_limit`, kept in the request context for the access log and metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LimitDecision {
+    pub action: LimitAction,
+    /// Stable identifier of the limit that rejected the request (e.g. "country", "ip_limit")
+    pub reason_code: Option<&'static str>,
+    /// Remaining budget of an allowed request, when a counting limit applied to it
+    pub quota: Option<RateLimitQuota>,
+}
+
+impl LimitDecision {
+    pub fn allowed() -> Self {
+        Self::default()
+    }
+
+    /// Allowed, with the budget left under the limit that counted it
+    pub fn allowed_with_quota(quota: RateLimitQuota) -> Self {
+        Self {
+            quota: Some(quota),
+            ..Self::default()
+        }
+    }
+
+    pub fn soft_limited(reason_code: &'static str) -> Self {
+        Self {
+            action: LimitAction::SoftLimited,
+            reason_code: Some(reason_code),
+            quota: None,
+        }
+    }
+
+    pub fn blocked(reason_code: &'static str) -> Self {
+        Self {
+            action: LimitAction::Blocked,
+            reason_code: Some(reason_code),
+            quota: None,
+        }
+    }
+
+    /// Whether a response has already been sent and the request must not be proxied
+    pub fn is_rejected(&self) -> bool {
+        self.action != LimitAction::Allowed
+    }
+}
+
+/// Reason code for an advanced limit dimension ("cookie_session_id" -> "cookie")
+pub fn reason_code_for_dimension(dimension: &str) -> &'static str {
+    if dimension.starts_with("user_agent_pattern_") {
+        return "user_agent_pattern";
+    }
+    if dimension.starts_with("cookie_") {
+        return "cookie";
+    }
+    match dimension {
+        "asn" => "asn",
+        "asn_country" => "asn_country",
+        "country" => "country",
+        "referer" => "referer",
+        "client_cert" => "client_cert",
+        "jwt" => "jwt",
+        "user_agent" => "user_agent",
+        _ => "ip_limit",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reason_code_for_dimension() {
+        assert_eq!(reason_code_for_dimension("cookie_session_id"), "cookie");
+        assert_eq!(reason_code_for_dimension("user_agent_pattern_facebook"), "user_agent_pattern");
+        assert_eq!(reason_code_for_dimension("asn_country"), "asn_country");
+        assert_eq!(reason_code_for_dimension("ip"), "ip_limit");
+    }
+
+    #[test]
+    fn test_ratelimit_header_format() {
+        let quota = RateLimitQuota::new(100, 27, 60);
+
+        assert_eq!(quota.header_value(), "limit=100, remaining=73, reset=60");
+        assert_eq!(quota.policy_value(), "100;w=60");
+    }
+
+    #[test]
+    fn test_remaining_never_negative() {
+        assert_eq!(RateLimitQuota::new(10, 12, 1).remaining, 0);
+    }
+
+    #[test]
+    fn test_only_allowed_is_not_rejected() {
+        assert!(!LimitDecision::allowed().is_rejected());
+        assert!(!LimitDecision::allowed_with_quota(RateLimitQuota::new(10, 1, 1)).is_rejected());
+        assert!(LimitDecision::soft_limited("country").is_rejected());
+        assert!(LimitDecision::blocked("ip_limit").is_rejected());
+    }
+}
@@ -0,0 +1,256 @@
+use crate::config::JwtLimitConfig;
+use crate::utils::secret::constant_time_eq;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use once_cell::sync::Lazy;
+use pingora_core::tls::bn::BigNum;
+use pingora_core::tls::ecdsa::EcdsaSig;
+use pingora_core::tls::hash::{hash, MessageDigest};
+use pingora_core::tls::pkey::{Id, PKey, Public};
+use pingora_core::tls::sign::Verifier;
+use pingora_http::RequestHeader;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Public keys by path, loaded at startup so requests never touch the filesystem
+static PUBLIC_KEYS: Lazy<RwLock<HashMap<String, Arc<PKey<Public>>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum JwtError {
+    #[error("malformed token")]
+    Malformed,
+    /// The token's algorithm has no configured key (or is not supported at all)
+    #[error("unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("bad signature")]
+    BadSignature,
+    #[error("token expired or not yet valid")]
+    Expired,
+    #[error("claim {0} missing")]
+    MissingClaim(String),
+    #[error("cannot load key: {0}")]
+    Key(String),
+}
+
+/// Token of an `Authorization: Bearer <token>` header
+pub fn bearer_token(req: &RequestHeader) -> Option<&str> {
+    let value = req.headers.get("authorization")?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim()).filter(|token| !token.is_empty())
+}
+
+/// Value of the configured claim, once the token's signature and validity are checked
+pub fn verified_claim(token: &str, config: &JwtLimitConfig) -> Result<String, JwtError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    verified_claim_at(token, config, now)
+}
+
+fn verified_claim_at(token: &str, config: &JwtLimitConfig, now: u64) -> Result<String, JwtError> {
+    let mut parts = token.split('.');
+    let (header, payload, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(header), Some(payload), Some(signature), None) => (header, payload, signature),
+        _ => return Err(JwtError::Malformed),
+    };
+    let signed = &token[..header.len() + 1 + payload.len()];
+    let header = decode_json(header)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| JwtError::Malformed)?;
+
+    // The algorithm must match a configured key, so an RS256 public key can never
+    // be used as an HS256 secret
+    let alg = header.get("alg").and_then(Value::as_str).unwrap_or("none");
+    let valid = match (alg, &config.secret, &config.public_key_path) {
+        ("HS256", Some(secret), _) => constant_time_eq(&hmac_sha256(secret.as_bytes(), signed.as_bytes())?, &signature),
+        ("RS256", _, Some(path)) => verify_rs256(&public_key(path, Id::RSA, alg)?, signed, &signature)?,
+        ("ES256", _, Some(path)) => verify_es256(&public_key(path, Id::EC, alg)?, signed, &signature)?,
+        _ => return Err(JwtError::UnsupportedAlgorithm(alg.to_string())),
+    };
+    if !valid {
+        return Err(JwtError::BadSignature);
+    }
+
+    let claims = decode_json(payload)?;
+    let expired = claims.get("exp").and_then(Value::as_u64).is_some_and(|exp| now >= exp);
+    let premature = claims.get("nbf").and_then(Value::as_u64).is_some_and(|nbf| now < nbf);
+    if expired || premature {
+        return Err(JwtError::Expired);
+    }
+
+    match claims.get(&config.claim) {
+        Some(Value::String(value)) if !value.is_empty() => Ok(value.clone()),
+        Some(Value::Number(value)) => Ok(value.to_string()),
+        _ => Err(JwtError::MissingClaim(config.claim.clone())),
+    }
+}
+
+fn decode_json(segment: &str) -> Result<Value, JwtError> {
+    let bytes = URL_SAFE_NO_PAD.decode(segment).map_err(|_| JwtError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| JwtError::Malformed)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, JwtError> {
+    pingora_core::tls::hash::hmac_sha256(key, data).map(|mac| mac.to_vec()).map_err(|e| JwtError::Key(e.to_string()))
+}
+
+fn verify_rs256(key: &PKey<Public>, signed: &str, signature: &[u8]) -> Result<bool, JwtError> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key).map_err(|e| JwtError::Key(e.to_string()))?;
+    verifier.update(signed.as_bytes()).map_err(|e| JwtError::Key(e.to_string()))?;
+    Ok(verifier.verify(signature).unwrap_or(false))
+}
+
+/// JWS carries ECDSA signatures as raw `r || s`, not DER
+fn verify_es256(key: &PKey<Public>, signed: &str, signature: &[u8]) -> Result<bool, JwtError> {
+    if signature.len() != 64 {
+        return Ok(false);
+    }
+    let ec_key = key.ec_key().map_err(|e| JwtError::Key(e.to_string()))?;
+    let digest = hash(MessageDigest::sha256(), signed.as_bytes()).map_err(|e| JwtError::Key(e.to_string()))?;
+    let (r, s) = signature.split_at(32);
+    let sig = BigNum::from_slice(r)
+        .and_then(|r| Ok((r, BigNum::from_slice(s)?)))
+        .and_then(|(r, s)| EcdsaSig::from_private_components(r, s))
+        .map_err(|_| JwtError::BadSignature)?;
+    Ok(sig.verify(&digest, &ec_key).unwrap_or(false))
+}
+
+/// Read the PEM public key at `path` for later token checks (called at startup)
+pub fn load_public_key(path: &str) -> Result<(), JwtError> {
+    let pem = std::fs::read(path).map_err(|e| JwtError::Key(format!("{}: {}", path, e)))?;
+    let key = PKey::public_key_from_pem(&pem).map_err(|e| JwtError::Key(format!("{}: {}", path, e)))?;
+    PUBLIC_KEYS.write().unwrap().insert(path.to_string(), Arc::new(key));
+    Ok(())
+}
+
+fn public_key(path: &str, id: Id, alg: &str) -> Result<Arc<PKey<Public>>, JwtError> {
+    let key = PUBLIC_KEYS
+        .read()
+        .unwrap()
+        .get(path)
+        .cloned()
+        .ok_or_else(|| JwtError::Key(format!("{}: not loaded", path)))?;
+    if key.id() != id {
+        return Err(JwtError::UnsupportedAlgorithm(format!("{} (key at {} is of another type)", alg, path)));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::{JwtInvalidAction, LimitConfig};
+    use pingora_core::tls::ec::{EcGroup, EcKey};
+    use pingora_core::tls::nid::Nid;
+
+    pub(crate) const SECRET: &str = "test-secret";
+
+    pub(crate) fn config() -> JwtLimitConfig {
+        JwtLimitConfig {
+            claim: "sub".to_string(),
+            limit: LimitConfig::Simple(10),
+            secret: Some(SECRET.to_string()),
+            public_key_path: None,
+            on_invalid: JwtInvalidAction::FallThrough,
+        }
+    }
+
+    /// HS256 token signed with `SECRET`
+    pub(crate) fn signed_token(claims: &str) -> String {
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(claims.as_bytes());
+        let signed = format!("{}.{}", header, payload);
+        let signature = hmac_sha256(SECRET.as_bytes(), signed.as_bytes()).unwrap();
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[test]
+    fn test_hmac_sha256_matches_rfc4231() {
+        // RFC 4231 test case 2
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?").unwrap();
+        let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn test_valid_token_yields_claim() {
+        let token = signed_token(r#"{"sub":"user-42","tenant":7}"#);
+        assert_eq!(verified_claim(&token, &config()).unwrap(), "user-42");
+
+        let tenant = JwtLimitConfig { claim: "tenant".to_string(), ..config() };
+        assert_eq!(verified_claim(&token, &tenant).unwrap(), "7");
+
+        let missing = JwtLimitConfig { claim: "org".to_string(), ..config() };
+        assert_eq!(verified_claim(&token, &missing), Err(JwtError::MissingClaim("org".to_string())));
+    }
+
+    #[test]
+    fn test_tampered_token_is_rejected() {
+        let token = signed_token(r#"{"sub":"user-42"}"#);
+        let (signed, signature) = token.rsplit_once('.').unwrap();
+        let (header, _) = signed.split_once('.').unwrap();
+
+        // Same signature over a different subject
+        let forged_payload = URL_SAFE_NO_PAD.encode(br#"{"sub":"admin"}"#);
+        let forged = format!("{}.{}.{}", header, forged_payload, signature);
+        assert_eq!(verified_claim(&forged, &config()), Err(JwtError::BadSignature));
+
+        // Signed with another secret
+        let other = JwtLimitConfig { secret: Some("other-secret".to_string()), ..config() };
+        assert_eq!(verified_claim(&token, &other), Err(JwtError::BadSignature));
+
+        // Unsigned tokens are never accepted
+        let unsigned = format!("{}.{}.", URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#), forged_payload);
+        assert_eq!(verified_claim(&unsigned, &config()), Err(JwtError::UnsupportedAlgorithm("none".to_string())));
+
+        assert_eq!(verified_claim("not-a-jwt", &config()), Err(JwtError::Malformed));
+    }
+
+    #[test]
+    fn test_expired_token_is_rejected() {
+        let token = signed_token(r#"{"sub":"user-42","exp":1000,"nbf":500}"#);
+        assert_eq!(verified_claim_at(&token, &config(), 999).unwrap(), "user-42");
+        assert_eq!(verified_claim_at(&token, &config(), 1000), Err(JwtError::Expired));
+        assert_eq!(verified_claim_at(&token, &config(), 400), Err(JwtError::Expired));
+    }
+
+    #[test]
+    fn test_public_key_is_only_read_by_load_public_key() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec_key = EcKey::generate(&group).unwrap();
+        let path = std::env::temp_dir().join(format!("pingwall-jwt-{}.pem", std::process::id()));
+        std::fs::write(&path, PKey::from_ec_key(ec_key.clone()).unwrap().public_key_to_pem().unwrap()).unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"ES256","typ":"JWT"}"#);
+        let signed = format!("{}.{}", header, URL_SAFE_NO_PAD.encode(br#"{"sub":"user-42"}"#));
+        let digest = hash(MessageDigest::sha256(), signed.as_bytes()).unwrap();
+        let sig = EcdsaSig::sign(&digest, &ec_key).unwrap();
+        let mut raw = sig.r().to_vec_padded(32).unwrap();
+        raw.extend(sig.s().to_vec_padded(32).unwrap());
+        let token = format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(raw));
+
+        let es256 = JwtLimitConfig { secret: None, public_key_path: Some(path.clone()), ..config() };
+        assert_eq!(verified_claim(&token, &es256), Err(JwtError::Key(format!("{}: not loaded", path))));
+
+        load_public_key(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(verified_claim(&token, &es256).unwrap(), "user-42");
+        assert!(load_public_key(&path).is_err());
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert_eq!(bearer_token(&req), None);
+
+        req.insert_header("Authorization", "Basic dXNlcjpwYXNz").unwrap();
+        assert_eq!(bearer_token(&req), None);
+
+        req.insert_header("Authorization", "Bearer abc.def.ghi").unwrap();
+        assert_eq!(bearer_token(&req), Some("abc.def.ghi"));
+    }
+}
@@ -1,11 +1,16 @@
 use pingora_limits::rate::Rate;
 use once_cell::sync::Lazy;
-use std::{collections::HashMap, sync::{Arc, RwLock}, time::{SystemTime, UNIX_EPOCH, Duration}};
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard}, time::{SystemTime, UNIX_EPOCH, Duration, Instant}};
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicIsize, AtomicU64, Ordering};
+use crate::config::LimitSchedule;
 use crate::metrics;
+use crate::ratelimit::schedule;
 use crate::utils::cloudflare::CloudflareContext;
 use crate::utils::useragent::UserAgentInfo;
+use thiserror::Error;
 
 // ==================== Request Context for Multi-Dimensional Rate Limiting ====================
 
@@ -17,40 +22,102 @@ pub struct RequestContext {
     pub domain: Option<String>,
     pub cloudflare: CloudflareContext,
     pub user_agent: UserAgentInfo,
+    /// Cookies sent with the request (name -> value)
+    pub cookies: HashMap<String, String>,
+    /// Host of the Referer header (lowercase), if present and valid
+    pub referer_host: Option<String>,
+    /// Names of the headers sent with the request (lowercase)
+    pub header_names: HashSet<String>,
+    /// SHA-256 fingerprint (lowercase hex) of the verified TLS client certificate
+    pub client_cert: Option<String>,
+    /// HTTP version the client's request arrived over
+    pub http_version: http::Version,
+    /// Value of the `jwt_limits` claim of the verified bearer token
+    pub jwt_claim: Option<String>,
+    /// Full request path (`path` is the matched route's path)
+    pub request_path: String,
+    /// Key on the first N segments of `request_path` instead of `path`
+    pub path_depth: Option<usize>,
 }
 
 impl RequestContext {
+    /// Same context, keyed on the first `depth` segments of the request path
+    pub fn with_path_depth(&self, depth: usize) -> Self {
+        Self {
+            path_depth: Some(depth),
+            ..self.clone()
+        }
+    }
+
+    /// Path component of rate limit keys
+    fn key_path(&self) -> String {
+        match self.path_depth {
+            Some(depth) => truncate_path(&self.request_path, depth),
+            None => self.path.clone(),
+        }
+    }
+
     /// Create a rate limit key based on the context and dimension
     pub fn create_key(&self, dimension: &str) -> String {
         let domain_prefix = self.domain.as_deref().unwrap_or("_");
+        let path = self.key_path();
 
         // Check for user_agent_pattern_* dimensions first
         if dimension.starts_with("user_agent_pattern_") {
             // Extract pattern name (e.g., "facebook" from "user_agent_pattern_facebook")
             let pattern = dimension.strip_prefix("user_agent_pattern_").unwrap_or("");
             // Key does NOT include IP - shared across all IPs with this pattern
-            return format!("{}:{}:ua_pattern:{}", domain_prefix, self.path, pattern);
+            return format!("{}:{}:ua_pattern:{}", domain_prefix, path, pattern);
+        }
+
+        // cookie_<name> dimensions: one bucket per cookie value, shared across IPs
+        if let Some(name) = dimension.strip_prefix("cookie_") {
+            let value = self.cookies.get(name).map(|v| v.as_str()).unwrap_or("");
+            return format!("{}:{}:cookie:{}:{}", domain_prefix, path, name, value);
         }
 
         match dimension {
-            "ip" => format!("{}:{}:{}", domain_prefix, self.path, self.ip),
+            "ip" => format!("{}:{}:{}", domain_prefix, path, self.ip),
             "user_agent" => {
                 let ua_cat = self.user_agent.category.as_str();
-                format!("{}:{}:ua:{}", domain_prefix, self.path, ua_cat)
+                format!("{}:{}:ua:{}", domain_prefix, path, ua_cat)
             }
             "asn" => {
                 let asn = self.cloudflare.asn.as_deref().unwrap_or("unknown");
-                format!("{}:{}:asn:{}", domain_prefix, self.path, asn)
+                format!("{}:{}:asn:{}", domain_prefix, path, asn)
             }
             "country" => {
                 let country = self.cloudflare.country.as_deref().unwrap_or("unknown");
-                format!("{}:{}:country:{}", domain_prefix, self.path, country)
+                format!("{}:{}:country:{}", domain_prefix, path, country)
+            }
+            "referer" => {
+                let referer = self.referer_host.as_deref().unwrap_or("none");
+                format!("{}:{}:referer:{}", domain_prefix, path, referer)
+            }
+            "client_cert" => {
+                let fingerprint = self.client_cert.as_deref().unwrap_or("none");
+                format!("{}:{}:client_cert:{}", domain_prefix, path, fingerprint)
+            }
+            "jwt" => {
+                let claim = self.jwt_claim.as_deref().unwrap_or("none");
+                format!("{}:{}:jwt:{}", domain_prefix, path, claim)
+            }
+            "asn_country" => {
+                let asn = self.cloudflare.asn.as_deref().unwrap_or("unknown");
+                let country = self.cloudflare.country.as_deref().unwrap_or("unknown");
+                format!("{}:{}:asn_country:{}:{}", domain_prefix, path, asn, country)
             }
-            _ => format!("{}:{}:{}", domain_prefix, self.path, self.ip), // fallback to IP
+            _ => format!("{}:{}:{}", domain_prefix, path, self.ip), // fallback to IP
         }
     }
 }
 
+/// First `depth` segments of a path: ("/user/1/posts", 1) -> "/user"
+fn truncate_path(path: &str, depth: usize) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).take(depth).collect();
+    format!("/{}", segments.join("/"))
+}
+
 // Route identifier for rate limiting (LEGACY - kept for backward compatibility)
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct RouteIdentifier {
@@ -69,15 +136,52 @@ impl fmt::Display for RouteIdentifier {
     }
 }
 
-// Rate limiter window duration (configurable via init_globals_with_window)
-static mut RATE_LIMIT_WINDOW_SECS: u64 = 1;  // Default: 1 second
+/// Limits of routes without their own (max_req_per_window, block_duration_secs) and the
+/// window of the default rate limiter; the window is read on every check, so a reload
+/// changing it takes effect immediately
+struct LimiterDefaults {
+    max_req_per_window: AtomicIsize,
+    block_duration_secs: AtomicU64,
+    window_secs: AtomicU64,
+}
 
-// Default rate limiter (backward compatible)
-static RATE_LIMITER: Lazy<Rate> = Lazy::new(|| {
-    unsafe {
-        Rate::new(Duration::from_secs(RATE_LIMIT_WINDOW_SECS))
+impl LimiterDefaults {
+    const fn new() -> Self {
+        Self {
+            max_req_per_window: AtomicIsize::new(60),
+            block_duration_secs: AtomicU64::new(300),
+            window_secs: AtomicU64::new(1), // Default: 1 second
+        }
+    }
+
+    fn set_limits(&self, max_req: isize, block_secs: u64) {
+        self.max_req_per_window.store(max_req, Ordering::Relaxed);
+        self.block_duration_secs.store(block_secs, Ordering::Relaxed);
+    }
+
+    fn set_window(&self, window_secs: u64) {
+        self.window_secs.store(window_secs, Ordering::Relaxed);
+    }
+
+    fn max_requests(&self) -> isize {
+        self.max_req_per_window.load(Ordering::Relaxed)
+    }
+
+    fn block_duration(&self) -> u64 {
+        self.block_duration_secs.load(Ordering::Relaxed)
     }
-});
+
+    fn window(&self) -> u64 {
+        self.window_secs.load(Ordering::Relaxed)
+    }
+
+    /// Rate limiter for the current default window (rate_limit_window_secs)
+    fn rate_limiter(&self) -> Arc<Rate> {
+        get_rate_limiter_for_window(self.window())
+    }
+}
+
+static DEFAULTS: LimiterDefaults = LimiterDefaults::new();
 
 // Multiple rate limiters with different windows
 // Key: window duration in seconds
@@ -86,55 +190,235 @@ static RATE_LIMITERS: Lazy<RwLock<HashMap<u64, Arc<Rate>>>> = Lazy::new(|| {
     RwLock::new(HashMap::new())
 });
 
-static mut MAX_REQ_PER_WINDOW: isize = 60;
-static mut BLOCK_DURATION_SECS: u64 = 300;
-
 // Store blocked IPs with their expiration time and the path that triggered the block
-// Using RwLock instead of Mutex for better read performance
-static BLOCKED_IPS: Lazy<RwLock<HashMap<String, (u64, String)>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+// Sharded by IP so the read lock taken by every request in is_blocked is spread over
+// BLOCKED_IP_SHARDS locks instead of one
+static BLOCKED_IPS: Lazy<BlockedIps> = Lazy::new(|| BlockedIps::new(BLOCKED_IP_SHARDS));
+const BLOCKED_IP_SHARDS: usize = 16;
 
 // Store per-route rate limit configurations
 static ROUTE_LIMITS: Lazy<RwLock<HashMap<String, (isize, u64)>>> = Lazy::new(|| RwLock::new(HashMap::new()));
 
+// Per-route time-of-day limit profiles, keyed like ROUTE_LIMITS
+static ROUTE_SCHEDULES: Lazy<RwLock<HashMap<String, LimitSchedule>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
 // Track last cleanup time to avoid cleaning up too frequently
 static LAST_CLEANUP: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
 const CLEANUP_INTERVAL_SECS: u64 = 60; // Cleanup every 60 seconds
 
-pub fn init_globals(max_req: isize, block_secs: u64) {
-    unsafe {
-        MAX_REQ_PER_WINDOW = max_req;
-        BLOCK_DURATION_SECS = block_secs;
+/// Receives (ip, block info) of blocks removed by cleanup, for unblock notifications
+pub type UnblockSender = tokio::sync::mpsc::Sender<(String, String)>;
+
+/// Failure of the limiter's state store
+#[derive(Error, Debug)]
+pub enum LimiterError {
+    #[error("limiter state '{0}' was poisoned by a panic in another request")]
+    Poisoned(&'static str),
+}
+
+impl LimiterError {
+    /// Label for pingwall_limiter_errors_total
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LimiterError::Poisoned(_) => "poisoned",
+        }
+    }
+}
+
+/// Check that the limiter state can be used before a request is counted
+///
+/// A poisoned lock is reported once, for the request that finds it (so
+/// limiter_failure_mode decides that request), and then recovered so later
+/// requests are limited normally.
+pub fn check_backend() -> Result<(), LimiterError> {
+    let poisoned = [
+        ("blocked_ips", BLOCKED_IPS.is_poisoned()),
+        ("route_limits", ROUTE_LIMITS.is_poisoned()),
+        ("route_schedules", ROUTE_SCHEDULES.is_poisoned()),
+        ("rate_limiters", RATE_LIMITERS.is_poisoned()),
+        ("leaky_buckets", LEAKY_BUCKETS.is_poisoned()),
+        ("token_buckets", TOKEN_BUCKETS.is_poisoned()),
+    ];
+    match poisoned.iter().find(|(_, is_poisoned)| *is_poisoned) {
+        Some((name, _)) => {
+            BLOCKED_IPS.clear_poison();
+            ROUTE_LIMITS.clear_poison();
+            ROUTE_SCHEDULES.clear_poison();
+            RATE_LIMITERS.clear_poison();
+            LEAKY_BUCKETS.clear_poison();
+            TOKEN_BUCKETS.clear_poison();
+            Err(LimiterError::Poisoned(name))
+        }
+        None => Ok(()),
     }
 }
 
+// Lock accessors that survive poisoning: a panic in one request while holding a
+// limiter lock must not turn every later request into a panic. The maps stay
+// usable (at worst one entry is stale), so the data is taken over and the
+// poison flag cleared.
+
+fn recover<G>(lock_name: &str, poisoned: PoisonError<G>) -> G {
+    log::warn!("Recovered limiter lock '{}' poisoned by a panicking request", lock_name);
+    poisoned.into_inner()
+}
+
+fn read_lock<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockReadGuard<'a, T> {
+    lock.read().unwrap_or_else(|e| {
+        let guard = recover(name, e);
+        lock.clear_poison();
+        guard
+    })
+}
+
+fn write_lock<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockWriteGuard<'a, T> {
+    lock.write().unwrap_or_else(|e| {
+        let guard = recover(name, e);
+        lock.clear_poison();
+        guard
+    })
+}
+
+fn lock_mutex<'a, T>(lock: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    lock.lock().unwrap_or_else(|e| {
+        let guard = recover(name, e);
+        lock.clear_poison();
+        guard
+    })
+}
+
+/// Blocked IPs (expiry, "domain:path" that triggered the block), split into
+/// independently locked shards by a hash of the IP
+pub struct BlockedIps {
+    shards: Vec<RwLock<HashMap<String, (u64, String)>>>,
+}
+
+impl BlockedIps {
+    pub fn new(shards: usize) -> Self {
+        Self { shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect() }
+    }
+
+    fn shard(&self, ip: &str) -> &RwLock<HashMap<String, (u64, String)>> {
+        let mut hasher = DefaultHasher::new();
+        ip.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn block(&self, ip: &str, expires: u64, block_info: String) {
+        write_lock(self.shard(ip), "blocked_ips").insert(ip.to_string(), (expires, block_info));
+    }
+
+    pub fn is_blocked(&self, ip: &str, now: u64) -> bool {
+        self.remaining(ip, now).is_some()
+    }
+
+    /// Seconds left on the block of `ip`, None if it is not (or no longer) blocked
+    pub fn remaining(&self, ip: &str, now: u64) -> Option<u64> {
+        read_lock(self.shard(ip), "blocked_ips")
+            .get(ip)
+            .filter(|(expires, _)| *expires > now)
+            .map(|(expires, _)| expires - now)
+    }
+
+    /// Where `ip` was blocked, also after the block expired (until cleanup)
+    pub fn blocked_path(&self, ip: &str) -> Option<String> {
+        read_lock(self.shard(ip), "blocked_ips").get(ip).map(|(_, path)| path.clone())
+    }
+
+    /// Drop expired blocks, one shard at a time; returns how many were removed
+    pub fn cleanup(&self, now: u64) -> usize {
+        self.take_expired(now).len()
+    }
+
+    /// Remove expired blocks, returning their (ip, block info)
+    pub fn take_expired(&self, now: u64) -> Vec<(String, String)> {
+        let mut expired = Vec::new();
+        for shard in &self.shards {
+            let mut blocked = write_lock(shard, "blocked_ips");
+            let ips: Vec<String> = blocked
+                .iter()
+                .filter(|(_, (expires, _))| *expires <= now)
+                .map(|(ip, _)| ip.clone())
+                .collect();
+            for ip in ips {
+                if let Some((_, info)) = blocked.remove(&ip) {
+                    expired.push((ip, info));
+                }
+            }
+        }
+        expired
+    }
+
+    /// Active blocks whose block info starts with `prefix`
+    pub fn count_active(&self, now: u64, prefix: &str) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                read_lock(shard, "blocked_ips")
+                    .values()
+                    .filter(|(expires, info)| *expires > now && info.starts_with(prefix))
+                    .count()
+            })
+            .sum()
+    }
+
+    fn is_poisoned(&self) -> bool {
+        self.shards.iter().any(|shard| shard.is_poisoned())
+    }
+
+    fn clear_poison(&self) {
+        self.shards.iter().for_each(|shard| shard.clear_poison());
+    }
+}
+
+pub fn init_globals(max_req: isize, block_secs: u64) {
+    DEFAULTS.set_limits(max_req, block_secs);
+}
+
 /// Initialize globals with custom rate limit window duration
-/// MUST be called BEFORE first rate limit check (before RATE_LIMITER is initialized)
+/// Can be called again at any time: later checks count in the new window's limiter
+/// (counts from the previous window are not carried over)
 pub fn init_globals_with_window(max_req: isize, block_secs: u64, window_secs: u64) {
-    unsafe {
-        RATE_LIMIT_WINDOW_SECS = window_secs;
-        MAX_REQ_PER_WINDOW = max_req;
-        BLOCK_DURATION_SECS = block_secs;
-    }
+    DEFAULTS.set_window(window_secs);
+    init_globals(max_req, block_secs);
 }
 
 pub fn set_route_limits(path: &str, max_req: isize, block_secs: u64) {
-    ROUTE_LIMITS.write().unwrap().insert(path.to_string(), (max_req, block_secs));
+    write_lock(&ROUTE_LIMITS, "route_limits").insert(path.to_string(), (max_req, block_secs));
+}
+
+/// Limit profiles that override the route's limits while active
+pub fn set_route_schedule(path: &str, limit_schedule: LimitSchedule) {
+    if schedule::parse_utc_offset(&limit_schedule.timezone).is_none() {
+        log::warn!("Invalid limit_schedule timezone '{}' for {}, using UTC", limit_schedule.timezone, path);
+    }
+    write_lock(&ROUTE_SCHEDULES, "route_schedules").insert(path.to_string(), limit_schedule);
+}
+
+/// (max_req, block_duration) of the route's currently active profile, if any
+fn scheduled_limits(path: &str) -> Option<(isize, Option<u64>)> {
+    let schedules = read_lock(&ROUTE_SCHEDULES, "route_schedules");
+    let profile = schedule::active_profile(schedules.get(path)?, chrono::Utc::now())?;
+    Some((profile.max_req_per_window, profile.block_duration_secs))
 }
 
 pub fn get_max_requests() -> isize {
-    unsafe { MAX_REQ_PER_WINDOW }
+    DEFAULTS.max_requests()
 }
 
 pub fn get_block_duration() -> u64 {
-    unsafe { BLOCK_DURATION_SECS }
+    DEFAULTS.block_duration()
 }
 
 pub fn get_rate_limit_window() -> u64 {
-    unsafe { RATE_LIMIT_WINDOW_SECS }
+    DEFAULTS.window()
 }
 
 pub fn get_route_max_requests(path: &str) -> isize {
-    let route_limits = ROUTE_LIMITS.read().unwrap();
+    if let Some((max_req, _)) = scheduled_limits(path) {
+        return max_req;
+    }
+    let route_limits = read_lock(&ROUTE_LIMITS, "route_limits");
     match route_limits.get(path) {
         Some((max_req, _)) => *max_req,
         None => get_max_requests(),
@@ -142,15 +426,19 @@ pub fn get_route_max_requests(path: &str) -> isize {
 }
 
 pub fn get_route_block_duration(path: &str) -> u64 {
-    let route_limits = ROUTE_LIMITS.read().unwrap();
+    if let Some((_, Some(block_duration))) = scheduled_limits(path) {
+        return block_duration;
+    }
+    let route_limits = read_lock(&ROUTE_LIMITS, "route_limits");
     match route_limits.get(path) {
         Some((_, block_duration)) => *block_duration,
         None => get_block_duration(),
     }
 }
 
-// Cleanup expired IPs periodically (called every CLEANUP_INTERVAL_SECS)
-fn cleanup_expired_ips() {
+/// Drop expired blocks, at most once per CLEANUP_INTERVAL_SECS; each dropped block
+/// is queued on `unblocked` when unblock notifications are on
+pub fn cleanup_expired_ips(unblocked: Option<&UnblockSender>) {
     let now = current_time();
     let last_cleanup = LAST_CLEANUP.load(Ordering::Relaxed);
 
@@ -163,40 +451,46 @@ fn cleanup_expired_ips() {
             Ordering::Relaxed,
         ).is_ok() {
             // We won the race to do cleanup
-            let mut blocked = BLOCKED_IPS.write().unwrap();
-            let before_count = blocked.len();
-            blocked.retain(|_, &mut (expires, _)| expires > now);
-            let after_count = blocked.len();
-            if before_count != after_count {
-                log::debug!("Cleaned up {} expired blocked IPs", before_count - after_count);
+            take_expired_blocks(&BLOCKED_IPS, now, unblocked);
+        }
+    }
+}
+
+fn take_expired_blocks(blocked: &BlockedIps, now: u64, unblocked: Option<&UnblockSender>) {
+    let expired = blocked.take_expired(now);
+    if !expired.is_empty() {
+        log::debug!("Cleaned up {} expired blocked IPs", expired.len());
+    }
+    if let Some(unblocked) = unblocked {
+        for (ip, block_info) in expired {
+            // Never wait here: this runs on the request path
+            if unblocked.try_send((ip, block_info)).is_err() {
+                log::warn!("Unblock notification queue full, dropping unblock event");
             }
         }
     }
 }
 
 pub fn is_blocked(ip: &str) -> bool {
-    // Try cleanup in background if needed (non-blocking)
-    cleanup_expired_ips();
-
-    // Use read lock for checking (much faster than write lock)
-    let blocked = BLOCKED_IPS.read().unwrap();
-
-    // Check if IP is in the blocked list
-    if let Some((expires, _)) = blocked.get(ip) {
-        // Check if still valid
-        *expires > current_time()
-    } else {
-        false
-    }
+    // Only this IP's shard is read-locked
+    BLOCKED_IPS.is_blocked(ip, current_time())
 }
 
 pub fn get_blocked_path(ip: &str) -> Option<String> {
-    let blocked = BLOCKED_IPS.read().unwrap();
-    blocked.get(ip).map(|(_, path)| path.clone())
+    BLOCKED_IPS.blocked_path(ip)
 }
 
-pub fn block_ip(ip: &str, path: &str, domain: Option<&str>) {
-    let now = current_time();
+/// Seconds left until the block on this IP expires (None if not blocked)
+pub fn get_block_remaining(ip: &str) -> Option<u64> {
+    BLOCKED_IPS.remaining(ip, current_time())
+}
+
+/// Block an IP for `block_duration_secs`; `None` falls back to the route's block_duration_secs.
+pub fn block_ip(ip: &str, path: &str, domain: Option<&str>, block_duration_secs: Option<u64>) {
+    if let Some(block_duration) = block_duration_secs {
+        block_ip_for(ip, path, domain, block_duration);
+        return;
+    }
 
     // Create a combined domain+path key for rate limiting
     let domain_path_key = if let Some(domain_str) = domain {
@@ -205,7 +499,12 @@ pub fn block_ip(ip: &str, path: &str, domain: Option<&str>) {
         path.to_string()
     };
 
-    let block_duration = get_route_block_duration(&domain_path_key);
+    block_ip_for(ip, path, domain, get_route_block_duration(&domain_path_key));
+}
+
+/// Block an IP for a given duration instead of the route's block_duration_secs
+pub fn block_ip_for(ip: &str, path: &str, domain: Option<&str>, block_duration: u64) {
+    let now = current_time();
     let expires = now + block_duration;
 
     // Store the domain information along with the path
@@ -215,21 +514,22 @@ pub fn block_ip(ip: &str, path: &str, domain: Option<&str>) {
         path.to_string()
     };
 
-    BLOCKED_IPS.write().unwrap().insert(ip.to_string(), (expires, block_info));
+    BLOCKED_IPS.block(ip, expires, block_info);
 
     // Record metrics
     let domain_str = domain.unwrap_or("unknown");
     metrics::record_rate_limit_block(domain_str, path, ip);
 
     // Update blocked IPs gauge
-    let blocked_count = BLOCKED_IPS.read().unwrap()
-        .values()
-        .filter(|(exp, info)| *exp > now && info.starts_with(&format!("{}:{}", domain_str, path)))
-        .count();
+    let blocked_count = BLOCKED_IPS.count_active(now, &format!("{}:{}", domain_str, path));
     metrics::update_blocked_ips(domain_str, path, blocked_count as i64);
 }
 
 pub fn get_current_count(ip: &str, path: &str, domain: Option<&str>) -> isize {
+    current_count_in(&DEFAULTS, ip, path, domain)
+}
+
+fn current_count_in(defaults: &LimiterDefaults, ip: &str, path: &str, domain: Option<&str>) -> isize {
     let route_id = RouteIdentifier {
         path: path.to_string(),
         domain: domain.map(|d| d.to_string()),
@@ -237,10 +537,14 @@ pub fn get_current_count(ip: &str, path: &str, domain: Option<&str>) -> isize {
     };
     
     // Get current count without incrementing
-    RATE_LIMITER.observe(&route_id.to_string(), 0)
+    defaults.rate_limiter().observe(&route_id.to_string(), 0)
 }
 
 pub fn check_and_increment(ip: &str, path: &str, domain: Option<&str>) -> bool {
+    check_and_increment_in(&DEFAULTS, ip, path, domain)
+}
+
+fn check_and_increment_in(defaults: &LimiterDefaults, ip: &str, path: &str, domain: Option<&str>) -> bool {
     let route_id = RouteIdentifier {
         path: path.to_string(),
         domain: domain.map(|d| d.to_string()),
@@ -261,7 +565,7 @@ pub fn check_and_increment(ip: &str, path: &str, domain: Option<&str>) -> bool {
         return false;
     }
     
-    let current_count = RATE_LIMITER.observe(&route_id.to_string(), 1);
+    let current_count = defaults.rate_limiter().observe(&route_id.to_string(), 1);
 
     current_count > max_requests
 }
@@ -278,14 +582,14 @@ fn current_time() -> u64 {
 fn get_rate_limiter_for_window(window_secs: u64) -> Arc<Rate> {
     // Fast path: check if limiter already exists
     {
-        let limiters = RATE_LIMITERS.read().unwrap();
+        let limiters = read_lock(&RATE_LIMITERS, "rate_limiters");
         if let Some(limiter) = limiters.get(&window_secs) {
             return Arc::clone(limiter);
         }
     }
 
     // Slow path: create new limiter
-    let mut limiters = RATE_LIMITERS.write().unwrap();
+    let mut limiters = write_lock(&RATE_LIMITERS, "rate_limiters");
 
     // Double-check in case another thread created it
     if let Some(limiter) = limiters.get(&window_secs) {
@@ -301,6 +605,30 @@ fn get_rate_limiter_for_window(window_secs: u64) -> Arc<Rate> {
     new_limiter
 }
 
+/// Count a request from an IP the reputation feed lists as suspicious; true once it
+/// made more than `max_requests` in `window_secs`
+pub fn check_suspicious(ip: &str, max_requests: isize, window_secs: u64) -> bool {
+    get_rate_limiter_for_window(window_secs).observe(&format!("suspicious:{}", ip), 1) > max_requests
+}
+
+// ==================== Scanner Detection ====================
+
+/// Counter key for the responses of one status class (e.g. "4xx") sent to an IP
+fn status_class_key(ip: &str, status: u16) -> String {
+    format!("status:{}:{}xx", ip, status / 100)
+}
+
+/// Count a 4xx response sent to an IP; true once it got more than `max_errors`
+/// of them in `window_secs` (other statuses are not counted)
+pub fn check_client_errors(ip: &str, status: u16, max_errors: isize, window_secs: u64) -> bool {
+    if max_errors <= 0 || !(400..500).contains(&status) {
+        return false;
+    }
+
+    let current_count = get_rate_limiter_for_window(window_secs).observe(&status_class_key(ip, status), 1);
+    current_count > max_errors
+}
+
 // ==================== Advanced Multi-Dimensional Rate Limiting ====================
 
 /// Check and increment rate limit with full request context
@@ -315,7 +643,7 @@ pub fn check_and_increment_advanced(
 
     // Create key based on IP (primary dimension)
     let key = context.create_key("ip");
-    let current_count = RATE_LIMITER.observe(&key, 1);
+    let current_count = DEFAULTS.rate_limiter().observe(&key, 1);
 
     current_count > max_requests
 }
@@ -323,7 +651,7 @@ pub fn check_and_increment_advanced(
 /// Get current count for request context
 pub fn get_current_count_advanced(context: &RequestContext) -> isize {
     let key = context.create_key("ip");
-    RATE_LIMITER.observe(&key, 0)
+    DEFAULTS.rate_limiter().observe(&key, 0)
 }
 
 /// Check rate limit for specific dimension (IP, ASN, Country, User-Agent)
@@ -337,7 +665,7 @@ pub fn check_dimension_limit(
     }
 
     let key = context.create_key(dimension);
-    let current_count = RATE_LIMITER.observe(&key, 1);
+    let current_count = DEFAULTS.rate_limiter().observe(&key, 1);
 
     current_count > max_requests
 }
@@ -383,3 +711,588 @@ pub fn check_dimension_limit_with_window(
 
     (is_limited, should_block, current_count)
 }
+
+// ==================== Leaky Bucket ====================
+
+/// Drop drained buckets once the map grows beyond this many keys
+const LEAKY_BUCKET_PRUNE_THRESHOLD: usize = 10_000;
+
+/// Per-key leaky bucket state: current level, when it was last drained and the
+/// rate of the limit it belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct LeakyBucket {
+    level: f64,
+    last_update: Instant,
+    leak_rate: f64,
+}
+
+impl LeakyBucket {
+    pub fn new(now: Instant, leak_rate: f64) -> Self {
+        Self { level: 0.0, last_update: now, leak_rate }
+    }
+
+    /// Drain the bucket up to `now` at its limit's rate
+    fn leak(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update).as_secs_f64();
+        self.level = (self.level - elapsed * self.leak_rate).max(0.0);
+        self.last_update = now;
+    }
+
+    /// Add one request. Returns false (and leaves the level unchanged) if it would overflow
+    pub fn try_add(&mut self, now: Instant, leak_rate: f64, capacity: f64) -> bool {
+        self.leak(now);
+        self.leak_rate = leak_rate;
+        if self.level + 1.0 > capacity {
+            return false;
+        }
+        self.level += 1.0;
+        true
+    }
+
+    /// Current fill level
+    pub fn level(&self) -> f64 {
+        self.level
+    }
+}
+
+/// Drop drained buckets, each drained at its own limit's rate
+fn prune_leaky_buckets(buckets: &mut HashMap<String, LeakyBucket>, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.leak(now);
+        bucket.level > 0.0
+    });
+}
+
+static LEAKY_BUCKETS: Lazy<Mutex<HashMap<String, LeakyBucket>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Check rate limit for specific dimension using a leaky bucket
+/// Returns: (is_limited, should_block, current_level) - same semantics as `check_dimension_limit_with_window`
+pub fn check_dimension_limit_leaky(
+    context: &RequestContext,
+    dimension: &str,
+    leak_rate: f64,
+    capacity: f64,
+    block_duration_secs: Option<u64>,
+) -> (bool, bool, isize) {
+    // Disabled if capacity <= 0
+    if capacity <= 0.0 {
+        return (false, false, 0);
+    }
+
+    let key = context.create_key(dimension);
+    let now = Instant::now();
+
+    let mut buckets = lock_mutex(&LEAKY_BUCKETS, "leaky_buckets");
+    if buckets.len() > LEAKY_BUCKET_PRUNE_THRESHOLD {
+        prune_leaky_buckets(&mut buckets, now);
+    }
+
+    let bucket = buckets.entry(key).or_insert_with(|| LeakyBucket::new(now, leak_rate));
+    let is_limited = !bucket.try_add(now, leak_rate, capacity);
+    let level = bucket.level().ceil() as isize;
+
+    let should_block = match block_duration_secs {
+        Some(duration) => is_limited && duration > 0,
+        None => is_limited,
+    };
+
+    (is_limited, should_block, level)
+}
+
+// ==================== Token Bucket ====================
+
+/// Drop refilled buckets once the map grows beyond this many keys
+const TOKEN_BUCKET_PRUNE_THRESHOLD: usize = 10_000;
+
+/// Per-key token bucket state: tokens left, when it was last refilled and the
+/// parameters of the limit it belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+    refill_per_sec: f64,
+    burst: f64,
+}
+
+impl TokenBucket {
+    /// A new bucket starts full, so a client's first burst is allowed
+    pub fn new(now: Instant, refill_per_sec: f64, burst: f64) -> Self {
+        Self { tokens: burst, last_update: now, refill_per_sec, burst }
+    }
+
+    /// Refill the bucket up to `now` at its limit's rate
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_update).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.burst);
+        self.last_update = now;
+    }
+
+    /// Take a token for one request. Returns false (and takes nothing) if the bucket is empty
+    pub fn try_take(&mut self, now: Instant, refill_per_sec: f64, burst: f64) -> bool {
+        self.refill(now);
+        self.refill_per_sec = refill_per_sec;
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Tokens left
+    pub fn tokens(&self) -> f64 {
+        self.tokens
+    }
+}
+
+/// Drop full buckets, each refilled with its own limit's parameters
+fn prune_token_buckets(buckets: &mut HashMap<String, TokenBucket>, now: Instant) {
+    buckets.retain(|_, bucket| {
+        bucket.refill(now);
+        bucket.tokens < bucket.burst
+    });
+}
+
+static TOKEN_BUCKETS: Lazy<Mutex<HashMap<String, TokenBucket>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Check rate limit for specific dimension using a token bucket
+/// Returns: (is_limited, should_block, tokens_used) - same semantics as `check_dimension_limit_with_window`
+pub fn check_dimension_limit_token(
+    context: &RequestContext,
+    dimension: &str,
+    refill_per_sec: f64,
+    burst: f64,
+    block_duration_secs: Option<u64>,
+) -> (bool, bool, isize) {
+    // Disabled if burst <= 0
+    if burst <= 0.0 {
+        return (false, false, 0);
+    }
+
+    let key = context.create_key(dimension);
+    let now = Instant::now();
+
+    let mut buckets = lock_mutex(&TOKEN_BUCKETS, "token_buckets");
+    if buckets.len() > TOKEN_BUCKET_PRUNE_THRESHOLD {
+        prune_token_buckets(&mut buckets, now);
+    }
+
+    let bucket = buckets.entry(key).or_insert_with(|| TokenBucket::new(now, refill_per_sec, burst));
+    let is_limited = !bucket.try_take(now, refill_per_sec, burst);
+    let used = (burst - bucket.tokens()).ceil() as isize;
+
+    let should_block = match block_duration_secs {
+        Some(duration) => is_limited && duration > 0,
+        None => is_limited,
+    };
+
+    (is_limited, should_block, used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(domain: &str, asn: Option<&str>, country: Option<&str>) -> RequestContext {
+        RequestContext {
+            ip: "192.0.2.1".to_string(),
+            path: "/api".to_string(),
+            domain: Some(domain.to_string()),
+            cloudflare: CloudflareContext {
+                asn: asn.map(|s| s.to_string()),
+                country: country.map(|s| s.to_string()),
+                ..Default::default()
+            },
+            user_agent: UserAgentInfo::from_string(""),
+            cookies: HashMap::new(),
+            referer_host: None,
+            header_names: HashSet::new(),
+            client_cert: None,
+            http_version: http::Version::HTTP_11,
+            jwt_claim: None,
+            request_path: "/api".to_string(),
+            path_depth: None,
+        }
+    }
+
+    fn with_session(mut ctx: RequestContext, ip: &str, session: &str) -> RequestContext {
+        ctx.ip = ip.to_string();
+        ctx.cookies.insert("session_id".to_string(), session.to_string());
+        ctx
+    }
+
+    #[test]
+    fn test_asn_country_key_is_independent() {
+        let ctx = context("keys.test", Some("12345"), Some("RU"));
+        let composite = ctx.create_key("asn_country");
+
+        assert_eq!(composite, "keys.test:/api:asn_country:12345:RU");
+        assert_ne!(composite, ctx.create_key("asn"));
+        assert_ne!(composite, ctx.create_key("country"));
+    }
+
+    #[test]
+    fn test_asn_country_bucket_counts_only_matching_requests() {
+        let both = context("bucket.test", Some("12345"), Some("RU"));
+        let other_country = context("bucket.test", Some("12345"), Some("DE"));
+
+        for _ in 0..2 {
+            let (limited, _, _) = check_dimension_limit_with_window(&both, "asn_country", 2, 60, None);
+            assert!(!limited);
+        }
+        // Same ASN from another country has its own bucket
+        let (limited, _, _) = check_dimension_limit_with_window(&other_country, "asn_country", 2, 60, None);
+        assert!(!limited);
+        // The ASN-only bucket was never touched
+        let (limited, _, count) = check_dimension_limit_with_window(&both, "asn", 2, 60, None);
+        assert!(!limited);
+        assert_eq!(count, 1);
+
+        let (limited, should_block, _) = check_dimension_limit_with_window(&both, "asn_country", 2, 60, None);
+        assert!(limited);
+        assert!(should_block);
+    }
+
+    #[test]
+    fn test_leaky_bucket_steady_stream_under_leak_rate_never_trips() {
+        let start = Instant::now();
+        let mut bucket = LeakyBucket::new(start, 10.0);
+
+        // 10 req/sec drain, capacity 5; send one request every 200ms (5 req/sec)
+        for i in 0..1000u64 {
+            let now = start + Duration::from_millis(i * 200);
+            assert!(bucket.try_add(now, 10.0, 5.0), "request {} tripped", i);
+        }
+    }
+
+    #[test]
+    fn test_leaky_bucket_burst_over_capacity_trips() {
+        let start = Instant::now();
+        let mut bucket = LeakyBucket::new(start, 1.0);
+
+        for _ in 0..5 {
+            assert!(bucket.try_add(start, 1.0, 5.0));
+        }
+        assert!(!bucket.try_add(start, 1.0, 5.0));
+
+        // One second later one slot has drained
+        let later = start + Duration::from_secs(1);
+        assert!(bucket.try_add(later, 1.0, 5.0));
+        assert!(!bucket.try_add(later, 1.0, 5.0));
+    }
+
+    #[test]
+    fn test_leaky_bucket_prune_drains_each_bucket_at_its_own_rate() {
+        let start = Instant::now();
+        let mut buckets = HashMap::new();
+        for (key, leak_rate) in [("fast", 10.0), ("slow", 0.1)] {
+            let mut bucket = LeakyBucket::new(start, leak_rate);
+            for _ in 0..5 {
+                assert!(bucket.try_add(start, leak_rate, 5.0));
+            }
+            buckets.insert(key.to_string(), bucket);
+        }
+
+        // After a second the fast limit has drained, the slow one still holds ~4.9
+        prune_leaky_buckets(&mut buckets, start + Duration::from_secs(1));
+        assert!(!buckets.contains_key("fast"));
+        let slow = buckets.get_mut("slow").unwrap();
+        assert!(slow.level() > 4.8);
+        assert!(!slow.try_add(start + Duration::from_secs(1), 0.1, 5.0));
+    }
+
+    #[test]
+    fn test_token_bucket_allows_burst_then_refill_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(start, 1.0, 5.0);
+
+        // A full bucket takes a burst of 5 at once
+        for _ in 0..5 {
+            assert!(bucket.try_take(start, 1.0, 5.0));
+        }
+        assert!(!bucket.try_take(start, 1.0, 5.0));
+
+        // Then one request per second of refill
+        let later = start + Duration::from_secs(1);
+        assert!(bucket.try_take(later, 1.0, 5.0));
+        assert!(!bucket.try_take(later, 1.0, 5.0));
+
+        // An idle client gets its burst back, but never more than the bucket holds
+        let idle = later + Duration::from_secs(60);
+        for _ in 0..5 {
+            assert!(bucket.try_take(idle, 1.0, 5.0));
+        }
+        assert!(!bucket.try_take(idle, 1.0, 5.0));
+    }
+
+    #[test]
+    fn test_token_bucket_prune_refills_each_bucket_with_its_own_limit() {
+        let start = Instant::now();
+        let mut buckets = HashMap::new();
+        for (key, refill_per_sec, burst) in [("fast", 10.0, 5.0), ("slow", 0.1, 20.0)] {
+            let mut bucket = TokenBucket::new(start, refill_per_sec, burst);
+            for _ in 0..5 {
+                assert!(bucket.try_take(start, refill_per_sec, burst));
+            }
+            buckets.insert(key.to_string(), bucket);
+        }
+
+        // After a second the fast bucket is full again; the slow one has only
+        // refilled 0.1 of its 5 used tokens and keeps its burst of 20
+        prune_token_buckets(&mut buckets, start + Duration::from_secs(1));
+        assert!(!buckets.contains_key("fast"));
+        let slow = buckets.get("slow").unwrap();
+        assert!((slow.tokens() - 15.1).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_token_bucket_limit_is_keyed_per_dimension_value() {
+        let first = context("token.test", Some("64500"), None);
+        let second = context("token.test", Some("64501"), None);
+
+        for _ in 0..3 {
+            let (limited, _, _) = check_dimension_limit_token(&first, "asn", 0.001, 3.0, Some(0));
+            assert!(!limited);
+        }
+        let (limited, should_block, used) = check_dimension_limit_token(&first, "asn", 0.001, 3.0, Some(0));
+        assert!(limited);
+        assert!(!should_block);
+        assert_eq!(used, 3);
+
+        // Another ASN has its own full bucket
+        let (limited, _, _) = check_dimension_limit_token(&second, "asn", 0.001, 3.0, Some(0));
+        assert!(!limited);
+    }
+
+    #[test]
+    fn test_same_cookie_shares_bucket_across_ips() {
+        let first = with_session(context("cookie.test", None, None), "192.0.2.1", "abc");
+        let second = with_session(context("cookie.test", None, None), "198.51.100.7", "abc");
+        assert_eq!(first.create_key("cookie_session_id"), "cookie.test:/api:cookie:session_id:abc");
+
+        let (limited, _, _) = check_dimension_limit_with_window(&first, "cookie_session_id", 1, 60, None);
+        assert!(!limited);
+        // Different IP, same session: same bucket
+        let (limited, _, count) = check_dimension_limit_with_window(&second, "cookie_session_id", 1, 60, None);
+        assert!(limited);
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_different_cookies_use_separate_buckets() {
+        let alice = with_session(context("cookies.test", None, None), "192.0.2.1", "alice");
+        let bob = with_session(context("cookies.test", None, None), "192.0.2.1", "bob");
+
+        let (limited, _, _) = check_dimension_limit_with_window(&alice, "cookie_session_id", 1, 60, None);
+        assert!(!limited);
+        // Same IP (NAT), different session: not limited
+        let (limited, _, _) = check_dimension_limit_with_window(&bob, "cookie_session_id", 1, 60, None);
+        assert!(!limited);
+    }
+
+    #[test]
+    fn test_truncate_path() {
+        assert_eq!(truncate_path("/user/1", 1), "/user");
+        assert_eq!(truncate_path("/user/1/posts/", 2), "/user/1");
+        assert_eq!(truncate_path("/user", 3), "/user");
+        assert_eq!(truncate_path("/user/1", 0), "/");
+    }
+
+    #[test]
+    fn test_path_depth_shares_counter_across_ids() {
+        let mut ctx = context("depth.test", None, None);
+        ctx.path = "/".to_string();
+
+        for id in 1..=3 {
+            ctx.request_path = format!("/user/{}", id);
+            let scoped = ctx.with_path_depth(1);
+            assert_eq!(scoped.create_key("ip"), "depth.test:/user:192.0.2.1");
+
+            let (limited, _, count) = check_dimension_limit_with_window(&scoped, "ip", 2, 60, None);
+            assert_eq!(count, id);
+            assert_eq!(limited, id > 2);
+        }
+
+        // Without path_depth the key stays on the route path
+        assert_eq!(ctx.create_key("ip"), "depth.test:/:192.0.2.1");
+    }
+
+    #[test]
+    fn test_sharded_blocked_ips_block_and_expire() {
+        let blocked = BlockedIps::new(4);
+        let ips: Vec<String> = (0..100).map(|n| format!("198.51.100.{}", n)).collect();
+        for (n, ip) in ips.iter().enumerate() {
+            // Even IPs expire at 1100, odd ones at 1200
+            let expires = if n % 2 == 0 { 1100 } else { 1200 };
+            blocked.block(ip, expires, "shard.test:/api".to_string());
+        }
+
+        assert!(ips.iter().all(|ip| blocked.is_blocked(ip, 1000)));
+        assert!(!blocked.is_blocked("203.0.113.1", 1000));
+        assert_eq!(blocked.remaining(&ips[1], 1000), Some(200));
+        assert_eq!(blocked.blocked_path(&ips[0]).as_deref(), Some("shard.test:/api"));
+        assert_eq!(blocked.count_active(1000, "shard.test:/api"), 100);
+        assert_eq!(blocked.count_active(1000, "other.test:"), 0);
+
+        // Expired blocks stop applying right away and are dropped from every shard on cleanup
+        assert!(!blocked.is_blocked(&ips[0], 1100));
+        assert!(blocked.is_blocked(&ips[1], 1100));
+        assert_eq!(blocked.cleanup(1100), 50);
+        assert_eq!(blocked.blocked_path(&ips[0]), None);
+        assert_eq!(blocked.count_active(1100, ""), 50);
+        assert_eq!(blocked.cleanup(1200), 50);
+        assert!(blocked.take_expired(1200).is_empty());
+    }
+
+    #[test]
+    fn test_expired_blocks_are_announced_as_unblocks() {
+        use crate::notification::block_service::{BlockNotifier, UnblockService};
+        use crate::config::{WebhookConfig, WebhookFormat};
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response};
+        use pingora_core::services::background::BackgroundService;
+
+        // Stub webhook passing each request body on to the test
+        let (bodies_tx, mut bodies) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let server = rt.block_on(async {
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+                let bodies_tx = bodies_tx.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                        let bodies_tx = bodies_tx.clone();
+                        async move {
+                            let body = hyper::body::to_bytes(req.into_body()).await?;
+                            bodies_tx.send(serde_json::from_slice(&body).unwrap()).unwrap();
+                            Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                        }
+                    }))
+                }
+            }))
+        });
+        let url = format!("http://{}/unblock", server.local_addr());
+        rt.spawn(server);
+
+        let webhook = WebhookConfig { url, api_key: None, format: WebhookFormat::Raw };
+        let (sender, unblock_service) = UnblockService::new(BlockNotifier::new(String::new(), String::new()).with_webhooks(vec![webhook]));
+        let (_shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        rt.spawn(async move { unblock_service.start(shutdown).await });
+
+        let blocked = BlockedIps::new(4);
+        blocked.block("198.51.100.7", 1100, "unblock.test:/login".to_string());
+        blocked.block("198.51.100.8", 1200, "unblock.test:/login".to_string());
+        take_expired_blocks(&blocked, 1100, Some(&sender));
+
+        let body = rt.block_on(async { tokio::time::timeout(Duration::from_secs(5), bodies.recv()).await }).unwrap().unwrap();
+        assert_eq!(body["type"], "unblock");
+        assert_eq!(body["ip"], "198.51.100.7");
+        assert_eq!(body["path"], "unblock.test:/login");
+        // The block still in force is neither dropped nor announced
+        assert!(blocked.is_blocked("198.51.100.8", 1100));
+        assert!(rt.block_on(async { tokio::time::timeout(Duration::from_millis(200), bodies.recv()).await }).is_err());
+    }
+
+    #[test]
+    fn test_blocked_ips_spread_over_shards() {
+        let blocked = BlockedIps::new(BLOCKED_IP_SHARDS);
+        for n in 0..1000 {
+            blocked.block(&format!("10.0.{}.{}", n / 256, n % 256), u64::MAX, String::new());
+        }
+        let sizes: Vec<usize> = blocked.shards.iter().map(|shard| shard.read().unwrap().len()).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), 1000);
+        assert!(sizes.iter().all(|&size| size > 0), "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_block_ip_uses_route_block_duration() {
+        set_route_limits("block.test/login", 5, 120);
+        block_ip("192.0.2.200", "/login", Some("block.test"), None);
+
+        assert!(is_blocked("192.0.2.200"));
+        assert_eq!(get_blocked_path("192.0.2.200").as_deref(), Some("block.test:/login"));
+        let remaining = get_block_remaining("192.0.2.200").unwrap();
+        assert!((119..=120).contains(&remaining));
+    }
+
+    #[test]
+    fn test_block_ip_honors_explicit_block_duration() {
+        set_route_limits("block.test/admin", 5, 120);
+        block_ip("192.0.2.201", "/admin", Some("block.test"), Some(3600));
+
+        let remaining = get_block_remaining("192.0.2.201").unwrap();
+        assert!((3599..=3600).contains(&remaining));
+    }
+
+    #[test]
+    fn test_poisoned_lock_is_recovered() {
+        // Panic while holding the route limits write lock
+        let result = std::thread::spawn(|| {
+            let _guard = ROUTE_LIMITS.write().unwrap();
+            panic!("simulated panic while holding the limiter lock");
+        })
+        .join();
+        assert!(result.is_err());
+
+        // Reads and writes keep working instead of panicking on every request
+        set_route_limits("poison.test/api", 7, 30);
+        assert_eq!(get_route_max_requests("poison.test/api"), 7);
+        assert_eq!(get_route_block_duration("poison.test/api"), 30);
+        assert!(!ROUTE_LIMITS.is_poisoned());
+        assert!(check_backend().is_ok());
+    }
+
+    #[test]
+    fn test_window_change_applies_to_default_limiter() {
+        // A private copy of the defaults: the process-wide window stays at 1 for other tests
+        let defaults = LimiterDefaults::new();
+        let (ip, path, domain) = ("198.51.100.70", "/api", Some("window.test"));
+        let key = "window.test:/api:198.51.100.70";
+        set_route_limits("window.test/api", 100, 60);
+
+        defaults.set_window(7);
+        assert!(!check_and_increment_in(&defaults, ip, path, domain));
+        assert!(!check_and_increment_in(&defaults, ip, path, domain));
+        assert_eq!(get_rate_limiter_for_window(7).observe(key, 0), 2);
+        assert_eq!(current_count_in(&defaults, ip, path, domain), 2);
+
+        // Back to the 1s window: the requests were not counted there
+        defaults.set_window(1);
+        assert_eq!(get_rate_limiter_for_window(1).observe(key, 0), 0);
+        assert!(!check_and_increment_in(&defaults, ip, path, domain));
+        assert_eq!(current_count_in(&defaults, ip, path, domain), 1);
+        assert_eq!(get_rate_limiter_for_window(7).observe(key, 0), 2);
+    }
+
+    #[test]
+    fn test_globals_can_be_read_while_initialized() {
+        // The writer alternates between two configs on a private copy of the defaults
+        // (the process-wide ones are left alone); readers must only ever see one of them
+        let defaults = LimiterDefaults::new();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for n in 0..1000 {
+                    if n % 2 == 0 {
+                        defaults.set_limits(61, 301);
+                    } else {
+                        defaults.set_limits(60, 300);
+                    }
+                }
+            });
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        assert!(matches!(defaults.max_requests(), 60 | 61));
+                        assert!(matches!(defaults.block_duration(), 300 | 301));
+                        assert_eq!(defaults.window(), 1);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(defaults.max_requests(), 60);
+        assert_eq!(defaults.block_duration(), 300);
+    }
+}
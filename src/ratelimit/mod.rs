@@ -1,2 +1,8 @@
+pub mod connections;
+pub mod decision;
+pub mod jwt;
 pub mod limiter;
-pub mod service;
\ No newline at end of file
+pub mod schedule;
+pub mod service;
+pub mod scanner;
+pub mod reputation;
@@ -0,0 +1,283 @@
+use crate::config::IpReputationConfig;
+use async_trait::async_trait;
+use ipnetwork::IpNetwork;
+use once_cell::sync::Lazy;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Networks from the configured reputation feed, consulted by the rate limiter
+pub static REPUTATION_LIST: Lazy<Arc<ReputationList>> = Lazy::new(|| Arc::new(ReputationList::default()));
+
+/// Whether an IP is on the reputation feed
+pub fn is_listed(ip: &str) -> bool {
+    REPUTATION_LIST.contains(ip)
+}
+
+/// Last good copy of a reputation feed
+#[derive(Debug, Default)]
+pub struct ReputationList {
+    ranges: RwLock<NetworkRanges>,
+}
+
+impl ReputationList {
+    pub fn contains(&self, ip: &str) -> bool {
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            return false;
+        };
+        self.ranges.read().unwrap_or_else(|poisoned| poisoned.into_inner()).contains(ip)
+    }
+
+    /// Number of feed entries in force
+    pub fn len(&self) -> usize {
+        self.ranges.read().unwrap_or_else(|poisoned| poisoned.into_inner()).entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn replace(&self, networks: Vec<IpNetwork>) {
+        *self.ranges.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = NetworkRanges::new(&networks);
+    }
+}
+
+/// Feed networks as sorted, non-overlapping address ranges per family, so a lookup
+/// is a binary search however many entries the feed has
+#[derive(Debug, Default)]
+struct NetworkRanges {
+    v4: Vec<(u128, u128)>,
+    v6: Vec<(u128, u128)>,
+    entries: usize,
+}
+
+impl NetworkRanges {
+    fn new(networks: &[IpNetwork]) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for network in networks {
+            match network {
+                IpNetwork::V4(net) => v4.push((u32::from(net.network()) as u128, u32::from(net.broadcast()) as u128)),
+                IpNetwork::V6(net) => v6.push((u128::from(net.network()), u128::from(net.broadcast()))),
+            }
+        }
+        Self { v4: merge(v4), v6: merge(v6), entries: networks.len() }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (ranges, ip) = match ip {
+            IpAddr::V4(ip) => (&self.v4, u32::from(ip) as u128),
+            IpAddr::V6(ip) => (&self.v6, u128::from(ip)),
+        };
+        // Last range starting at or below ip
+        let after = ranges.partition_point(|&(start, _)| start <= ip);
+        after > 0 && ranges[after - 1].1 >= ip
+    }
+}
+
+/// Sort ranges and join overlapping or adjacent ones
+fn merge(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+#[derive(Error, Debug)]
+pub enum FeedError {
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("feed answered {0}")]
+    Status(StatusCode),
+
+    #[error("feed has no valid entries")]
+    Empty,
+}
+
+/// One IP or CIDR per line; blank lines and `#` / `;` comments are skipped, as are
+/// entries that don't parse (returned as the second value)
+pub fn parse_feed(body: &str) -> (Vec<IpNetwork>, usize) {
+    let mut networks = Vec::new();
+    let mut invalid = 0;
+    for line in body.lines() {
+        let entry = line.split(['#', ';']).next().unwrap_or("").trim();
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.parse::<IpNetwork>() {
+            Ok(network) => networks.push(network),
+            Err(_) => invalid += 1,
+        }
+    }
+    (networks, invalid)
+}
+
+/// Validators of the last fetched feed, sent back so an unchanged feed answers 304
+#[derive(Debug, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Periodically fetches the reputation feed into a `ReputationList`
+///
+/// A failed fetch (network error, non-2xx, or a body without a single valid entry)
+/// keeps the previous list, so an outage of the feed never unblocks everyone.
+pub struct ReputationFeed {
+    config: IpReputationConfig,
+    list: Arc<ReputationList>,
+    client: Client,
+    validators: Mutex<Validators>,
+}
+
+impl ReputationFeed {
+    pub fn new(config: IpReputationConfig, list: Arc<ReputationList>) -> Self {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .unwrap_or_else(|_| Client::new());
+        Self { config, list, client, validators: Mutex::new(Validators::default()) }
+    }
+
+    /// Fetch the feed once: Ok(Some(entries)) when the list was replaced, Ok(None)
+    /// when the feed is unchanged since the last fetch
+    pub async fn refresh(&self) -> Result<Option<usize>, FeedError> {
+        let mut request = self.client.get(&self.config.url);
+        {
+            let validators = self.validators.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(FeedError::Status(response.status()));
+        }
+
+        let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let validators = Validators { etag: header(ETAG), last_modified: header(LAST_MODIFIED) };
+        let body = response.text().await?;
+
+        let (networks, invalid) = parse_feed(&body);
+        if invalid > 0 {
+            log::warn!("IP reputation feed {}: skipped {} invalid entries", self.config.url, invalid);
+        }
+        if networks.is_empty() {
+            return Err(FeedError::Empty);
+        }
+
+        let count = networks.len();
+        self.list.replace(networks);
+        *self.validators.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = validators;
+        Ok(Some(count))
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ReputationFeed {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.refresh_secs.max(1)));
+        loop {
+            interval.tick().await;
+            match self.refresh().await {
+                Ok(Some(count)) => log::info!("IP reputation feed loaded: {} networks", count),
+                Ok(None) => log::debug!("IP reputation feed unchanged"),
+                Err(e) => log::warn!(
+                    "IP reputation feed {} not refreshed, keeping {} networks: {}",
+                    self.config.url, self.list.len(), e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    static FEED_STATUS: AtomicU16 = AtomicU16::new(200);
+
+    /// Stub feed: answers FEED_STATUS, and 304 to a request carrying the current ETag
+    async fn stub_feed(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let status = FEED_STATUS.load(Ordering::SeqCst);
+        if status == 200 && req.headers().get("if-none-match").map_or(false, |v| v == "\"v1\"") {
+            return Ok(Response::builder().status(304).body(Body::empty()).unwrap());
+        }
+        let body = if status == 200 { "# threat feed\n185.220.0.0/16\n203.0.113.7 ; single host\nnot-an-ip\n" } else { "" };
+        Ok(Response::builder().status(status).header("ETag", "\"v1\"").body(Body::from(body)).unwrap())
+    }
+
+    #[test]
+    fn test_parse_feed() {
+        let (networks, invalid) = parse_feed("# comment\n\n10.0.0.0/8\n192.0.2.1  # inline\n2001:db8::/32\nbogus\n");
+        assert_eq!(networks.len(), 3);
+        assert_eq!(invalid, 1);
+    }
+
+    #[test]
+    fn test_lookup_matches_networks_and_hosts() {
+        let (networks, _) = parse_feed("10.0.0.0/8\n10.1.0.0/16\n192.0.2.1\n192.0.2.2\n198.51.100.0/24\n2001:db8::/32\n0.0.0.0/32\n");
+        let list = ReputationList::default();
+        list.replace(networks);
+
+        assert_eq!(list.len(), 7);
+        for listed in ["10.0.0.0", "10.1.2.3", "10.255.255.255", "192.0.2.1", "192.0.2.2", "198.51.100.255", "2001:db8::1", "0.0.0.0"] {
+            assert!(list.contains(listed), "{}", listed);
+        }
+        for unlisted in ["9.255.255.255", "11.0.0.0", "192.0.2.0", "192.0.2.3", "198.51.101.0", "2001:db9::1", "0.0.0.1", "::"] {
+            assert!(!list.contains(unlisted), "{}", unlisted);
+        }
+        // IPv4 networks don't match IPv6 addresses with the same bits
+        assert!(!list.contains("::a00:1"));
+    }
+
+    #[test]
+    fn test_feed_is_loaded_and_kept_across_failures() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let server = rt.block_on(async {
+            hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+                .serve(make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(stub_feed)) }))
+        });
+        let url = format!("http://{}/feed.txt", server.local_addr());
+        rt.spawn(server);
+
+        let config: IpReputationConfig = serde_yaml::from_str(&format!("url: \"{}\"\n", url)).unwrap();
+        let list = Arc::new(ReputationList::default());
+        let feed = ReputationFeed::new(config, list.clone());
+
+        assert_eq!(rt.block_on(feed.refresh()).unwrap(), Some(2));
+        assert!(list.contains("185.220.101.4"));
+        assert!(list.contains("203.0.113.7"));
+        assert!(!list.contains("203.0.113.8"));
+
+        // Unchanged feed: 304, nothing re-parsed
+        assert_eq!(rt.block_on(feed.refresh()).unwrap(), None);
+
+        // Feed down: the last good list stays in force
+        FEED_STATUS.store(503, Ordering::SeqCst);
+        assert!(matches!(rt.block_on(feed.refresh()), Err(FeedError::Status(StatusCode::SERVICE_UNAVAILABLE))));
+        assert_eq!(list.len(), 2);
+        assert!(list.contains("185.220.101.4"));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::config::ScannerDetectionConfig;
+use crate::ratelimit::limiter;
+
+/// Count a response sent to `ip` and block the IP once its 4xx responses exceed
+/// `max_4xx` in the window. Returns true if this response got it blocked
+pub fn record_response(config: &ScannerDetectionConfig, ip: &str, status: u16, domain: &str, route: &str) -> bool {
+    if !limiter::check_client_errors(ip, status, config.max_4xx, config.window_secs) || limiter::is_blocked(ip) {
+        return false;
+    }
+
+    let block_duration = config.block_duration_secs.unwrap_or_else(limiter::get_block_duration);
+    log::warn!(
+        "Blocking likely scanner {} for {}s: more than {} 4xx responses in {}s (last: {} on {})",
+        ip, block_duration, config.max_4xx, config.window_secs, status, route
+    );
+    limiter::block_ip_for(ip, route, Some(domain), block_duration);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ScannerDetectionConfig {
+        serde_yaml::from_str("max_4xx: 20\nwindow_secs: 60\nblock_duration_secs: 600\n").unwrap()
+    }
+
+    #[test]
+    fn test_ip_hitting_many_missing_paths_is_blocked() {
+        let config = config();
+        let ip = "198.51.100.66";
+
+        let blocked: Vec<bool> = (0..25).map(|_| record_response(&config, ip, 404, "scanner.test", "/")).collect();
+
+        assert_eq!(blocked.iter().filter(|b| **b).count(), 1);
+        assert!(blocked[20], "the 21st 404 should trigger the block");
+        assert!(limiter::is_blocked(ip));
+        assert!(limiter::get_block_remaining(ip).unwrap() > 500);
+    }
+
+    #[test]
+    fn test_ip_with_mostly_successful_responses_is_not_blocked() {
+        let config = config();
+        let ip = "198.51.100.67";
+
+        for n in 0..200 {
+            let status = if n % 20 == 0 { 404 } else { 200 };
+            assert!(!record_response(&config, ip, status, "scanner.test", "/"));
+        }
+
+        assert!(!limiter::is_blocked(ip));
+    }
+}
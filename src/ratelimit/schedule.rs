@@ -0,0 +1,112 @@
+use crate::config::{LimitProfile, LimitSchedule};
+use chrono::{DateTime, Datelike, FixedOffset, Offset, Timelike, Utc, Weekday};
+
+/// Parse a schedule timezone: "UTC"/"Z" or a fixed offset like "+02:00", "-0530", "+9"
+pub fn parse_utc_offset(timezone: &str) -> Option<FixedOffset> {
+    let tz = timezone.trim();
+    if tz.eq_ignore_ascii_case("utc") || tz.eq_ignore_ascii_case("z") {
+        return FixedOffset::east_opt(0);
+    }
+
+    let (sign, rest) = match tz.as_bytes().first()? {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+    if hours > 14 || minutes > 59 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Whether the profile applies at the given local time
+fn profile_active(profile: &LimitProfile, weekday: Weekday, hour: u32) -> bool {
+    let day_matches = profile.days.is_empty()
+        || profile.days.iter().any(|day| day.parse::<Weekday>().map_or(false, |d| d == weekday));
+    day_matches && profile.hours.contains(hour)
+}
+
+/// First profile of the schedule active at `now`, if any (timezones and days are
+/// checked when the config is loaded)
+pub fn active_profile(schedule: &LimitSchedule, now: DateTime<Utc>) -> Option<&LimitProfile> {
+    let offset = parse_utc_offset(&schedule.timezone).unwrap_or_else(|| Utc.fix());
+    let local = now.with_timezone(&offset);
+    schedule.profiles
+        .iter()
+        .find(|profile| profile_active(profile, local.weekday(), local.hour()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HourRange;
+    use chrono::TimeZone;
+
+    fn profile(hours: &str, days: &[&str], max_req: isize) -> LimitProfile {
+        LimitProfile {
+            name: None,
+            hours: HourRange::try_from(hours.to_string()).unwrap(),
+            days: days.iter().map(|d| d.to_string()).collect(),
+            max_req_per_window: max_req,
+            block_duration_secs: None,
+        }
+    }
+
+    fn schedule(timezone: &str) -> LimitSchedule {
+        LimitSchedule {
+            timezone: timezone.to_string(),
+            profiles: vec![
+                profile("09-18", &["mon", "tue", "wed", "thu", "fri"], 1000), // A: business hours
+                profile("00-24", &[], 50),                                     // B: everything else
+            ],
+        }
+    }
+
+    #[test]
+    fn test_in_schedule_hour_uses_profile_a() {
+        // Wednesday 10:30 UTC
+        let now = Utc.with_ymd_and_hms(2024, 5, 15, 10, 30, 0).unwrap();
+        assert_eq!(active_profile(&schedule("UTC"), now).unwrap().max_req_per_window, 1000);
+    }
+
+    #[test]
+    fn test_out_of_schedule_hour_uses_profile_b() {
+        // Wednesday 23:00 UTC, and Saturday 10:30 UTC
+        let night = Utc.with_ymd_and_hms(2024, 5, 15, 23, 0, 0).unwrap();
+        let weekend = Utc.with_ymd_and_hms(2024, 5, 18, 10, 30, 0).unwrap();
+        assert_eq!(active_profile(&schedule("UTC"), night).unwrap().max_req_per_window, 50);
+        assert_eq!(active_profile(&schedule("UTC"), weekend).unwrap().max_req_per_window, 50);
+    }
+
+    #[test]
+    fn test_timezone_offset_shifts_hours() {
+        // 07:30 UTC is 09:30 at +02:00
+        let now = Utc.with_ymd_and_hms(2024, 5, 15, 7, 30, 0).unwrap();
+        assert_eq!(active_profile(&schedule("UTC"), now).unwrap().max_req_per_window, 50);
+        assert_eq!(active_profile(&schedule("+02:00"), now).unwrap().max_req_per_window, 1000);
+    }
+
+    #[test]
+    fn test_hour_range_wraps_midnight() {
+        let overnight = HourRange::try_from("22-06".to_string()).unwrap();
+        assert!(overnight.contains(23));
+        assert!(overnight.contains(5));
+        assert!(!overnight.contains(6));
+        assert!(HourRange::try_from("9".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_utc_offset() {
+        assert_eq!(parse_utc_offset("UTC").unwrap().local_minus_utc(), 0);
+        assert_eq!(parse_utc_offset("-05:30").unwrap().local_minus_utc(), -19800);
+        assert_eq!(parse_utc_offset("+0200").unwrap().local_minus_utc(), 7200);
+        assert_eq!(parse_utc_offset("Europe/Paris"), None);
+    }
+}
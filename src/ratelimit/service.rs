@@ -1,36 +1,155 @@
 // src/ratelimit/service.rs
 use crate::notification::block_service::{BlockNotifier, BlockNotificationParams};
-use crate::ratelimit::limiter::{self, RequestContext};
-use crate::utils::ip::get_client_ip;
+use crate::ratelimit::decision::{reason_code_for_dimension, LimitAction, LimitDecision, RateLimitQuota};
+use crate::ratelimit::jwt;
+use crate::ratelimit::limiter::{self, LimiterError, RequestContext, UnblockSender};
+use crate::ratelimit::reputation;
+use crate::utils::host::{extract_host, host_matches_domain};
 use crate::utils::cloudflare::CloudflareContext;
 use crate::utils::useragent::UserAgentInfo;
-use crate::config::{AdvancedRateLimitConfig, RateLimitCondition};
-use log::{info, warn, debug};
+use crate::utils::path::routing_path;
+use crate::config::{AdvancedRateLimitConfig, IpReputationAction, IpReputationConfig, JwtInvalidAction, LimitAlgorithm, LimitConfig, LimiterFailureMode, RateLimitCondition};
+use crate::metrics;
+use crate::logging::{route_debug, route_info, route_warn, RouteLog};
+use std::collections::HashMap;
 use pingora::http::ResponseHeader;
 use pingora_core::Result;
 use pingora_proxy::Session;
 
+/// Seconds a client should wait before retrying a limited request
+///
+/// For a hard block the client can't succeed until the block expires, so this is the
+/// block duration. For a soft limit only the window has to slide, so it is the window.
+pub fn retry_after_secs(hard_block: bool, block_duration: u64, window_secs: u64) -> u64 {
+    if hard_block && block_duration > 0 {
+        block_duration
+    } else {
+        window_secs
+    }
+}
+
+/// Decision for a request the limiter could not check, per the failure mode
+pub fn limiter_failure_decision(mode: LimiterFailureMode, err: &LimiterError) -> LimitDecision {
+    match mode {
+        LimiterFailureMode::FailOpen => {
+            log::warn!("Rate limiter unavailable ({}), allowing request (fail_open)", err);
+            metrics::record_limiter_error(err.kind(), "fail_open");
+            LimitDecision::allowed()
+        }
+        LimiterFailureMode::FailClosed => {
+            log::warn!("Rate limiter unavailable ({}), rejecting request (fail_closed)", err);
+            metrics::record_limiter_error(err.kind(), "fail_closed");
+            LimitDecision::soft_limited("limiter_error")
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct RateLimitService {
     pub block_notifier: BlockNotifier,
+    pub failure_mode: LimiterFailureMode,
+    /// Close the client connection after a 429 instead of keeping it alive
+    pub close_on_block: bool,
+    /// How requests from IPs on the reputation feed are treated, when a feed is configured
+    pub ip_reputation: Option<IpReputationConfig>,
+    /// Key path-based limits by the normalized path with `%2F` decoded (normalize_path)
+    pub normalize_path: bool,
+    /// Where cleanup hands expired blocks for unblock notifications (notify_on_unblock)
+    pub unblock_sender: Option<UnblockSender>,
 }
 
 impl RateLimitService {
     pub fn new(block_notifier: BlockNotifier) -> Self {
-        Self { block_notifier }
+        Self { block_notifier, failure_mode: LimiterFailureMode::default(), close_on_block: false, ip_reputation: None, normalize_path: false, unblock_sender: None }
+    }
+
+    pub fn with_failure_mode(mut self, failure_mode: LimiterFailureMode) -> Self {
+        self.failure_mode = failure_mode;
+        self
+    }
+
+    pub fn with_close_on_block(mut self, close_on_block: bool) -> Self {
+        self.close_on_block = close_on_block;
+        self
+    }
+
+    pub fn with_normalize_path(mut self, normalize_path: bool) -> Self {
+        self.normalize_path = normalize_path;
+        self
+    }
+
+    pub fn with_unblock_sender(mut self, unblock_sender: UnblockSender) -> Self {
+        self.unblock_sender = Some(unblock_sender);
+        self
+    }
+
+    pub fn with_ip_reputation(mut self, ip_reputation: Option<IpReputationConfig>) -> Self {
+        self.ip_reputation = ip_reputation;
+        self
+    }
+
+    /// Finish a 429: the empty body is framed so the connection can be reused, unless
+    /// close_on_block asks to drop it (returns true when the connection should close)
+    fn finish_rejection(&self, header: &mut ResponseHeader) -> Result<bool> {
+        header.insert_header("Content-Length", "0")?;
+        if self.close_on_block {
+            header.insert_header("Connection", "close")?;
+        }
+        Ok(self.close_on_block)
     }
 
     /// Build request context from session
-    fn build_request_context(session: &Session, ip: &str, path: &str, host: Option<&str>) -> RequestContext {
+    fn build_request_context(
+        &self,
+        session: &Session,
+        ip: &str,
+        path: &str,
+        host: Option<&str>,
+        use_cloudflare: Option<bool>,
+        log: RouteLog,
+    ) -> RequestContext {
         // Extract Cloudflare context
-        let cloudflare = CloudflareContext::from_session(session);
+        let cloudflare = CloudflareContext::from_request(session.req_header(), use_cloudflare);
 
         // Extract User-Agent
         let user_agent = UserAgentInfo::from_session(session);
 
-        info!(
-            "Request context: ip={}, path={}, domain={:?}, country={:?}, asn={:?}, ua_category={}",
-            ip, path, host, cloudflare.country, cloudflare.asn, user_agent.category.as_str()
+        // Extract cookies (for cookie-keyed limits)
+        let cookies = session.req_header()
+            .headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .map(parse_cookies)
+            .fold(HashMap::new(), |mut all, cookies| {
+                all.extend(cookies);
+                all
+            });
+
+        // Extract Referer host (for referer conditions and limits)
+        let referer_host = session.req_header()
+            .headers
+            .get("referer")
+            .and_then(|h| h.to_str().ok())
+            .and_then(referer_host);
+
+        // Header names present (for missing_browser_headers); HeaderName is already lowercase
+        let header_names = session.req_header()
+            .headers
+            .keys()
+            .map(|name| name.as_str().to_string())
+            .collect();
+
+        // Client certificate identity, recorded by the TLS layer during the handshake
+        let client_cert = session.digest()
+            .and_then(|digest| digest.ssl_digest.as_ref())
+            .filter(|ssl| !ssl.cert_digest.is_empty())
+            .map(|ssl| hex_fingerprint(&ssl.cert_digest));
+
+        route_debug!(
+            log,
+            "Request context: ip={}, path={}, domain={:?}, country={:?}, asn={:?}, ua_category={}, referer={:?}",
+            ip, path, host, cloudflare.country, cloudflare.asn, user_agent.category.as_str(), referer_host
         );
 
         RequestContext {
@@ -39,26 +158,48 @@ impl RateLimitService {
             domain: host.map(|s| s.to_string()),
             cloudflare,
             user_agent,
+            cookies,
+            referer_host,
+            header_names,
+            client_cert,
+            http_version: session.req_header().version,
+            jwt_claim: None,
+            request_path: if self.normalize_path {
+                routing_path(session.req_header().uri.path()).into_owned()
+            } else {
+                session.req_header().uri.path().to_string()
+            },
+            path_depth: None,
         }
     }
 
-    /// Evaluate advanced rate limits and return (is_limited, should_block, reason, max_limit, block_duration, window_secs)
-    /// - is_limited: true if any limit exceeded
-    /// - should_block: true if IP should be blocked (false for soft limit)
-    /// - reason: description of which limit was hit
-    /// - max_limit: the max requests value
-    /// - block_duration: how long to block (if should_block = true)
-    /// - window_secs: the window duration for this limit (for Retry-After header)
-    fn evaluate_advanced_limits(
+    /// Blocks that apply whatever the rate: blocked networks, threat score and countries
+    /// (same tuple as `evaluate_advanced_limits`)
+    fn access_block(
         context: &RequestContext,
         advanced_config: &AdvancedRateLimitConfig,
         global_window_secs: u64,
-        default_block_duration: u64,
-    ) -> Option<(bool, bool, String, isize, u64, u64)> {
-        // 1. Check threat score threshold (highest priority - instant block)
+        log: RouteLog,
+    ) -> Option<(bool, bool, String, isize, Option<u64>, u64, &'static str)> {
+        // 0. Check blocked networks (permanent, whatever the rate)
+        if let Some(network) = context.ip.parse().ok().and_then(|ip| advanced_config.blocked_cidr(ip)) {
+            route_info!(log, "Blocking IP {} inside blocked network {}", context.ip, network);
+            return Some((
+                true,
+                true,
+                format!("IP {} is in blocked network {}", context.ip, network),
+                0,
+                None,
+                global_window_secs,
+                "cidr_blocked",
+            ));
+        }
+
+        // 1. Check threat score threshold (instant block)
         if let Some(threat_score) = context.cloudflare.threat_score {
             if advanced_config.should_block_threat(threat_score) {
-                info!(
+                route_info!(
+                    log,
                     "Blocking IP {} due to high threat score: {}",
                     context.ip, threat_score
                 );
@@ -67,8 +208,9 @@ impl RateLimitService {
                     true,
                     format!("Threat score {} exceeds threshold", threat_score),
                     0,
-                    default_block_duration,
+                    None,
                     global_window_secs,  // Use global window for instant blocks
+                    "threat_score",
                 ));
             }
         }
@@ -76,23 +218,48 @@ impl RateLimitService {
         // 2. Check country blocklist
         if let Some(ref country) = context.cloudflare.country {
             if advanced_config.is_country_blocked(country) {
-                info!("Blocking IP {} from blocked country: {}", context.ip, country);
+                route_info!(log, "Blocking IP {} from blocked country: {}", context.ip, country);
                 return Some((
                     true,
                     true,
                     format!("Country {} is blocked", country),
                     0,
-                    default_block_duration,
+                    None,
                     global_window_secs,  // Use global window for country blocks
+                    "country_blocked",
                 ));
             }
         }
 
+        None
+    }
+
+    /// Evaluate advanced rate limits and return (is_limited, should_block, reason, max_limit, block_duration, window_secs, reason_code)
+    /// - is_limited: true if any limit exceeded
+    /// - should_block: true if IP should be blocked (false for soft limit)
+    /// - reason: description of which limit was hit
+    /// - max_limit: the max requests value
+    /// - block_duration: how long to block (if should_block = true); None for the route's block_duration_secs
+    /// - window_secs: the window duration for this limit (for Retry-After header)
+    /// - reason_code: stable identifier of the limit for the access log and metrics
+    fn evaluate_advanced_limits(
+        context: &RequestContext,
+        advanced_config: &AdvancedRateLimitConfig,
+        global_window_secs: u64,
+        log: RouteLog,
+    ) -> Option<(bool, bool, String, isize, Option<u64>, u64, &'static str)> {
+        // 0-2. Blocked networks, threat score and countries
+        if let Some(result) = Self::access_block(context, advanced_config, global_window_secs, log) {
+            return Some(result);
+        }
+
         // 3. Check custom rules (if any match, return that rule's limit)
         if let Some(ref rules) = advanced_config.rules {
             for rule in rules {
                 if Self::rule_matches(context, rule) {
-                    info!(
+                    metrics::record_rule_match(&rule.name);
+                    route_info!(
+                        log,
                         "IP {} matched rule '{}' with limit {}",
                         context.ip, rule.name, rule.max_req
                     );
@@ -102,8 +269,9 @@ impl RateLimitService {
                         false,
                         format!("Matched rule: {}", rule.name),
                         rule.max_req,
-                        rule.block_duration,
+                        Some(rule.block_duration),
                         global_window_secs,  // Rules use global window
+                        "rule",
                     ));
                 }
             }
@@ -111,36 +279,115 @@ impl RateLimitService {
 
         // 4. Check User-Agent pattern limits (check raw User-Agent string for patterns)
 
-        // Country limit
-        if let Some(ref country) = context.cloudflare.country {
-            if let Some(limit_config) = advanced_config.get_country_limit(country) {
-                let max_req = limit_config.max_req();
-                let window_secs = limit_config.window_secs().unwrap_or(global_window_secs);
-                let block_duration = limit_config.block_duration_secs();
+        // ASN + country limit (more specific than either dimension alone)
+        if let (Some(ref asn), Some(ref country)) = (&context.cloudflare.asn, &context.cloudflare.country) {
+            if let Some(asn_country) = advanced_config.get_asn_country_limit(asn, country) {
+                let result = Self::check_limit(
+                    context,
+                    "asn_country",
+                    &format!("ASN {} in country {}", asn, country),
+                    &asn_country.limit,
+                    global_window_secs,
+                    log,
+                );
+                if result.is_some() {
+                    return result;
+                }
+            }
+        }
+
+        // Session cookie limits (one bucket per cookie value, independent of IP)
+        for (name, _value, limit_config) in advanced_config.matching_cookie_limits(&context.cookies) {
+            let result = Self::check_limit(
+                context,
+                &format!("cookie_{}", name),
+                &format!("Cookie {}", name),
+                limit_config,
+                global_window_secs,
+                log,
+            );
+            if result.is_some() {
+                return result;
+            }
+        }
+
+        // Client certificate limit (requests without a certificate fall through)
+        if let (Some(ref fingerprint), Some(limit_config)) = (&context.client_cert, &advanced_config.client_cert_limit) {
+            let result = Self::check_limit(
+                context,
+                "client_cert",
+                &format!("Client certificate {}", fingerprint),
+                limit_config,
+                global_window_secs,
+                log,
+            );
+            if result.is_some() {
+                return result;
+            }
+        }
+
+        // JWT claim limit (requests without a verified token fall through)
+        if let (Some(ref claim), Some(jwt_config)) = (&context.jwt_claim, &advanced_config.jwt_limits) {
+            let result = Self::check_limit(
+                context,
+                "jwt",
+                &format!("JWT {} {}", jwt_config.claim, claim),
+                &jwt_config.limit,
+                global_window_secs,
+                log,
+            );
+            if result.is_some() {
+                return result;
+            }
+        }
 
-                info!(
-                    "Applying country limit for {}: {} req/{} sec (block: {:?})",
-                    country, max_req, window_secs, block_duration
+        // Referer domain limit (no/invalid Referer falls through)
+        if let Some(ref referer) = context.referer_host {
+            if let Some((domain, limit_config)) = advanced_config.get_referer_limit(referer) {
+                let result = Self::check_limit(
+                    context,
+                    "referer",
+                    &format!("Referer {}", domain),
+                    limit_config,
+                    global_window_secs,
+                    log,
                 );
+                if result.is_some() {
+                    return result;
+                }
+            }
+        }
 
-                let (is_limited, should_block, _count) = limiter::check_dimension_limit_with_window(
+        // ASN limit (checked before country, it is the narrower network)
+        if let Some(ref asn) = context.cloudflare.asn {
+            if let Some(limit_config) = advanced_config.get_asn_limit(asn) {
+                let result = Self::check_limit(
                     context,
-                    "country",
-                    max_req,
-                    window_secs,
-                    block_duration,
+                    "asn",
+                    &format!("ASN {}", asn),
+                    limit_config,
+                    global_window_secs,
+                    log,
                 );
+                if result.is_some() {
+                    return result;
+                }
+            }
+        }
 
-                if is_limited {
-                    let block_dur = block_duration.unwrap_or(default_block_duration);
-                    return Some((
-                        true,
-                        should_block,
-                        format!("Country {} limit exceeded", country),
-                        max_req,
-                        block_dur,
-                        window_secs,  // ⭐ Return actual window for this limit
-                    ));
+        // Country limit
+        if let Some(ref country) = context.cloudflare.country {
+            if let Some(limit_config) = advanced_config.get_country_limit(country) {
+                let result = Self::check_limit(
+                    context,
+                    "country",
+                    &format!("Country {}", country),
+                    limit_config,
+                    global_window_secs,
+                    log,
+                );
+                if result.is_some() {
+                    return result;
                 }
             }
         }
@@ -149,7 +396,8 @@ impl RateLimitService {
         // Check each configured pattern against the raw User-Agent string
         let ua_lower = context.user_agent.raw.to_lowercase();
 
-        info!(
+        route_debug!(
+            log,
             "Checking User-Agent limits - raw: '{}', category: {:?}, has_ua_limits: {}",
             context.user_agent.raw,
             context.user_agent.category,
@@ -159,79 +407,45 @@ impl RateLimitService {
         // First check category-based limits (chrome, firefox, bot, etc.)
         let ua_category = context.user_agent.category.as_str();
         if let Some(limit_config) = advanced_config.get_user_agent_limit(ua_category) {
-            let max_req = limit_config.max_req();
-            let window_secs = limit_config.window_secs().unwrap_or(global_window_secs);
-            let block_duration = limit_config.block_duration_secs();
-
-            info!(
-                "Applying User-Agent category limit for {}: {} req/{} sec (block: {:?})",
-                ua_category, max_req, window_secs, block_duration
-            );
-
-            let (is_limited, should_block, _count) = limiter::check_dimension_limit_with_window(
+            let result = Self::check_limit(
                 context,
                 "user_agent",
-                max_req,
-                window_secs,
-                block_duration,
+                &format!("User-Agent {}", ua_category),
+                limit_config,
+                global_window_secs,
+                log,
             );
-
-            if is_limited {
-                let block_dur = block_duration.unwrap_or(default_block_duration);
-                return Some((
-                    true,
-                    should_block,
-                    format!("User-Agent {} limit exceeded", ua_category),
-                    max_req,
-                    block_dur,
-                    window_secs,
-                ));
+            if result.is_some() {
+                return result;
             }
         }
 
         // Then check pattern-based limits (e.g., "fb", "facebook", "google")
         // This allows more granular control than category matching
         if let Some(ref ua_limits) = advanced_config.user_agent_limits {
-            info!("Checking {} User-Agent pattern(s)", ua_limits.len());
+            route_debug!(log, "Checking {} User-Agent pattern(s)", ua_limits.len());
 
             for (pattern, limit_config) in ua_limits {
                 // Skip category names (already checked above)
                 if ["chrome", "firefox", "safari", "edge", "mobile", "bot", "crawler", "curl", "unknown"].contains(&pattern.as_str()) {
-                    info!("Skipping category pattern: {}", pattern);
+                    route_debug!(log, "Skipping category pattern: {}", pattern);
                     continue;
                 }
 
-                info!("Checking pattern '{}' against UA '{}'", pattern, ua_lower);
+                route_debug!(log, "Checking pattern '{}' against UA '{}'", pattern, ua_lower);
 
                 // Check if User-Agent contains the pattern
                 if ua_lower.contains(&pattern.to_lowercase()) {
-                    let max_req = limit_config.max_req();
-                    let window_secs = limit_config.window_secs().unwrap_or(global_window_secs);
-                    let block_duration = limit_config.block_duration_secs();
-
-                    info!(
-                        "Applying User-Agent pattern limit for '{}': {} req/{} sec (block: {:?})",
-                        pattern, max_req, window_secs, block_duration
-                    );
-
-                    let (is_limited, should_block, _count) = limiter::check_dimension_limit_with_window(
+                    let result = Self::check_limit(
                         context,
                         &format!("user_agent_pattern_{}", pattern),
-                        max_req,
-                        window_secs,
-                        block_duration,
+                        &format!("User-Agent pattern '{}'", pattern),
+                        limit_config,
+                        global_window_secs,
+                        log,
                     );
-
-                    if is_limited {
-                        let block_dur = block_duration.unwrap_or(default_block_duration);
-                        return Some((
-                            true,
-                            should_block,
-                            format!("User-Agent pattern '{}' limit exceeded", pattern),
-                            max_req,
-                            block_dur,
-                            window_secs,
-                        ));
+                    if result.is_some() {
+                        return result;
                     }
                 }
             }
@@ -240,6 +454,93 @@ impl RateLimitService {
         None
     }
 
+    /// Check a single dimension against its limit config
+    /// Returns the evaluation tuple (see `evaluate_advanced_limits`) only when the limit is exceeded
+    fn check_limit(
+        context: &RequestContext,
+        dimension: &str,
+        label: &str,
+        limit_config: &LimitConfig,
+        global_window_secs: u64,
+        log: RouteLog,
+    ) -> Option<(bool, bool, String, isize, Option<u64>, u64, &'static str)> {
+        let max_req = limit_config.max_req();
+        let window_secs = limit_config.window_secs().unwrap_or(global_window_secs);
+        let block_duration = limit_config.block_duration_secs();
+
+        let scoped;
+        let context = match limit_config.path_depth() {
+            Some(depth) => {
+                scoped = context.with_path_depth(depth);
+                &scoped
+            }
+            None => context,
+        };
+
+        let (is_limited, should_block, _count) = match limit_config.algorithm() {
+            LimitAlgorithm::SlidingWindow => {
+                route_debug!(
+                    log,
+                    "Applying {} limit for {}: {} req/{} sec (block: {:?})",
+                    label, context.ip, max_req, window_secs, block_duration
+                );
+                limiter::check_dimension_limit_with_window(
+                    context,
+                    dimension,
+                    max_req,
+                    window_secs,
+                    block_duration,
+                )
+            }
+            LimitAlgorithm::LeakyBucket => {
+                let leak_rate = limit_config.leak_rate(window_secs);
+                let capacity = limit_config.capacity();
+                route_debug!(
+                    log,
+                    "Applying {} leaky bucket limit for {}: capacity {} leaking {} req/sec (block: {:?})",
+                    label, context.ip, capacity, leak_rate, block_duration
+                );
+                limiter::check_dimension_limit_leaky(
+                    context,
+                    dimension,
+                    leak_rate,
+                    capacity,
+                    block_duration,
+                )
+            }
+            LimitAlgorithm::TokenBucket => {
+                let refill_per_sec = limit_config.refill_per_sec(window_secs);
+                let burst = limit_config.burst();
+                route_debug!(
+                    log,
+                    "Applying {} token bucket limit for {}: burst {} refilling {} req/sec (block: {:?})",
+                    label, context.ip, burst, refill_per_sec, block_duration
+                );
+                limiter::check_dimension_limit_token(
+                    context,
+                    dimension,
+                    refill_per_sec,
+                    burst,
+                    block_duration,
+                )
+            }
+        };
+
+        if is_limited {
+            Some((
+                true,
+                should_block,
+                format!("{} limit exceeded", label),
+                max_req,
+                block_duration,
+                window_secs,
+                reason_code_for_dimension(dimension),
+            ))
+        } else {
+            None
+        }
+    }
+
     /// Check if a rule matches the context (ALL conditions must match)
     fn rule_matches(context: &RequestContext, rule: &crate::config::RateLimitRule) -> bool {
         rule.conditions.iter().all(|cond| Self::condition_matches(context, cond))
@@ -260,9 +561,33 @@ impl RateLimitService {
             RateLimitCondition::AsnIn { values } => {
                 values.iter().any(|asn| context.cloudflare.asn_matches(asn))
             }
+            RateLimitCondition::AsnCountry { asn, country } => {
+                context.cloudflare.asn_country_matches(asn, country)
+            }
+            RateLimitCondition::RefererDomainIn { values } => {
+                context.referer_host.as_deref().map_or(false, |referer| {
+                    values.iter().any(|domain| host_matches_domain(referer, domain))
+                })
+            }
+            RateLimitCondition::RefererDomainNotIn { values } => {
+                context.referer_host.as_deref().map_or(false, |referer| {
+                    !values.iter().any(|domain| host_matches_domain(referer, domain))
+                })
+            }
             RateLimitCondition::ThreatScoreAbove { value } => {
                 context.cloudflare.is_threat_above(*value)
             }
+            RateLimitCondition::ClientCertIn { values } => {
+                context.client_cert.as_deref().map_or(false, |fingerprint| {
+                    values.iter().any(|value| normalize_fingerprint(value) == fingerprint)
+                })
+            }
+            RateLimitCondition::HttpVersionIn { values } => {
+                values.iter().any(|version| version.0 == context.http_version)
+            }
+            RateLimitCondition::MissingBrowserHeaders { headers } => {
+                headers.iter().any(|name| !context.header_names.contains(&name.to_ascii_lowercase()))
+            }
         }
     }
 
@@ -271,63 +596,91 @@ impl RateLimitService {
         session: &mut Session,
         ip: &str,
         path: &str,
+        host: Option<&str>,
         advanced_limits: Option<&AdvancedRateLimitConfig>,
-    ) -> Result<bool> {
-        info!(
+        use_cloudflare: Option<bool>,
+        log: RouteLog,
+    ) -> Result<LimitDecision> {
+        route_debug!(
+            log,
             "check_rate_limit called - ip: {}, path: {}, has_advanced_limits: {}",
             ip, path, advanced_limits.is_some()
         );
 
-        // Extract the host header if present for domain-specific rate limiting
-        // Try multiple sources in order:
-        // 1. Host header (HTTP/1.1)
-        // 2. :authority pseudo-header (HTTP/2)
-        // 3. Request URI authority (fallback)
-        let host = session.req_header()
-            .headers
-            .get("host")
-            .or_else(|| session.req_header().headers.get(":authority"))
-            .and_then(|h| h.to_str().ok())
-            .or_else(|| {
-                // Fallback: Extract from request URI
-                session.req_header().uri.authority().map(|a| a.as_str())
-            });
+        if let Some(decision) = self.backend_rejection(session).await? {
+            return Ok(decision);
+        }
+
+        if let Some(decision) = self.reputation_rejection(session, ip, path, true, log).await? {
+            return Ok(decision);
+        }
+
+        // Requests carrying a verified client certificate or JWT are counted per certificate /
+        // claim, not per IP (see keyed_by_verified_identity)
+        let mut keyed_by_identity = false;
 
         // ========== ADVANCED RATE LIMITING ==========
         // If advanced_limits is configured, use multi-dimensional rate limiting
         if let Some(advanced_config) = advanced_limits {
-            let context = Self::build_request_context(session, ip, path, host);
+            let mut context = self.build_request_context(session, ip, path, host, use_cloudflare, log);
+
+            if let Some(decision) = self.user_agent_rejection(session, advanced_config, &context, log).await? {
+                return Ok(decision);
+            }
+
+            if let Some(jwt_config) = &advanced_config.jwt_limits {
+                match jwt::bearer_token(session.req_header()).map(|token| jwt::verified_claim(token, jwt_config)) {
+                    Some(Ok(claim)) => context.jwt_claim = Some(claim),
+                    invalid => {
+                        let reason = match invalid {
+                            Some(Err(e)) => e.to_string(),
+                            _ => "no bearer token".to_string(),
+                        };
+                        if jwt_config.on_invalid == JwtInvalidAction::Reject {
+                            route_info!(log, "🔑 Rejecting request from {}: JWT {}", ip, reason);
+                            self.send_unauthorized_response(session).await?;
+                            return Ok(LimitDecision::soft_limited("jwt_invalid"));
+                        }
+                        route_debug!(log, "JWT not usable for {} ({}), falling back to IP-based limiting", ip, reason);
+                    }
+                }
+            }
+
+            keyed_by_identity = keyed_by_verified_identity(advanced_config, &context);
 
-            // Get global window and default block duration
+            // Get global window
             let global_window_secs = limiter::get_rate_limit_window();
-            let default_block_duration = limiter::get_block_duration();
 
-            // Evaluate advanced limits (threat score, country block, rules, dimension limits)
-            if let Some((is_limited, should_block, reason, limit, block_dur, window_secs)) =
-                Self::evaluate_advanced_limits(&context, advanced_config, global_window_secs, default_block_duration)
+            // Evaluate advanced limits (blocked networks, threat score, country block, rules, dimension limits)
+            if let Some((is_limited, should_block, reason, limit, block_duration_secs, window_secs, reason_code)) =
+                Self::evaluate_advanced_limits(&context, advanced_config, global_window_secs, log)
             {
+                // Without an explicit block duration the route's block_duration_secs applies
+                let block_dur = block_duration_secs
+                    .unwrap_or_else(|| limiter::get_route_block_duration(&format!("{}{}", host.unwrap_or(""), path)));
                 if should_block {
                     // Hard block: Block IP for specified duration
-                    info!("⛔ Advanced rate limit HARD BLOCK: {} - {} (limit: {}, blocking for {} secs)",
+                    route_info!(log, "⛔ Advanced rate limit HARD BLOCK: {} - {} (limit: {}, blocking for {} secs)",
                         reason, ip, limit, block_dur);
 
                     // Block the IP
-                    limiter::block_ip(ip, path, host);
+                    limiter::block_ip(ip, path, host, block_duration_secs);
 
-                    self.send_blocked_response(session).await?;
-                    return Ok(true);
+                    self.send_blocked_response(session, ip, log).await?;
+                    return Ok(LimitDecision::blocked(reason_code));
                 } else if is_limited {
                     // Soft limit: Just reject this request, don't block IP
-                    info!("⚠️ Advanced rate limit SOFT LIMIT: {} - {} (limit: {}, window: {}s, rejecting request only)",
+                    route_info!(log, "⚠️ Advanced rate limit SOFT LIMIT: {} - {} (limit: {}, window: {}s, rejecting request only)",
                         reason, ip, limit, window_secs);
                     // ⭐ Pass actual advanced limit values (not route defaults)
-                    self.send_rate_limited_response(session, path, limit, block_dur, window_secs).await?;
-                    return Ok(true);
+                    let retry_after = retry_after_secs(false, block_dur, window_secs);
+                    self.send_rate_limited_response(session, path, limit, retry_after, window_secs).await?;
+                    return Ok(LimitDecision::soft_limited(reason_code));
                 }
             }
 
             // If no advanced limit matched, fall through to default IP-based limiting
-            info!("No advanced limit matched for IP {}, falling back to IP-based limiting", ip);
+            route_debug!(log, "No advanced limit matched for IP {}, falling back to IP-based limiting", ip);
         }
 
         // ========== DEFAULT IP-BASED RATE LIMITING ==========
@@ -343,20 +696,22 @@ impl RateLimitService {
         let block_duration = limiter::get_route_block_duration(&domain_path_key);
 
         // Check if IP is already blocked
-        if limiter::is_blocked(ip) {
-            let blocked_path = limiter::get_blocked_path(ip).unwrap_or_else(|| "unknown".to_string());
-            info!("Blocked request from IP: {} (previously blocked on path: {})", ip, blocked_path);
-            self.send_blocked_response(session).await?;
-            return Ok(true);
+        if let Some(decision) = self.blocked_ip_rejection(session, ip, log).await? {
+            return Ok(decision);
+        }
+
+        if keyed_by_identity {
+            route_debug!(log, "Request from IP {} limited by client certificate or JWT claim, skipping IP-based limiting", ip);
+            return Ok(LimitDecision::allowed());
         }
 
         // Log request details for debugging
         let request_url = format!("{}", session.req_header().uri);
         if let Some(host_value) = host {
-            info!("Request from IP: {} to domain: {}, path: {} (URL: {}) - Rate limit: {}", 
+            route_debug!(log, "Request from IP: {} to domain: {}, path: {} (URL: {}) - Rate limit: {}", 
                 ip, host_value, path, request_url, max_requests);
         } else {
-            info!("Request from IP: {} to path: {} (URL: {}) - Rate limit: {}", 
+            route_debug!(log, "Request from IP: {} to path: {} (URL: {}) - Rate limit: {}", 
                 ip, path, request_url, max_requests);
         }
 
@@ -366,14 +721,14 @@ impl RateLimitService {
             let current_count = limiter::get_current_count(ip, path, host);
             
             if let Some(host_value) = host {
-                info!("⚠️ Rate limit exceeded for IP: {} on domain: {}, path: {} (count: {}/{} requests)", 
+                route_info!(log, "⚠️ Rate limit exceeded for IP: {} on domain: {}, path: {} (count: {}/{} requests)", 
                      ip, host_value, path, current_count, max_requests);
             } else {
-                info!("⚠️ Rate limit exceeded for IP: {} on path: {} (count: {}/{} requests)", 
+                route_info!(log, "⚠️ Rate limit exceeded for IP: {} on path: {} (count: {}/{} requests)", 
                      ip, path, current_count, max_requests);
             }
             
-            limiter::block_ip(ip, path, host);
+            limiter::block_ip(ip, path, host, None);
             
             // Get the User-Agent if available
             let user_agent = session.req_header()
@@ -383,7 +738,7 @@ impl RateLimitService {
                 .map(|s| s.to_string());
             
             // Send notification with enhanced information and better error handling
-            info!("Attempting to send rate limit exceeded notification for IP: {} on path: {}", ip, path);
+            route_debug!(log, "Attempting to send rate limit exceeded notification for IP: {} on path: {}", ip, path);
             
             let notification_params = BlockNotificationParams {
                 ip,
@@ -397,38 +752,139 @@ impl RateLimitService {
             };
 
             match self.block_notifier.notify_block(notification_params).await {
-                Ok(_) => info!("Successfully sent rate limit exceeded notification for IP: {} on path: {}", ip, path),
-                Err(e) => warn!("Failed to send rate limit exceeded notification: {}", e)
+                Ok(_) => route_info!(log, "Successfully sent rate limit exceeded notification for IP: {} on path: {}", ip, path),
+                Err(e) => route_warn!(log, "Failed to send rate limit exceeded notification: {}", e)
             }
 
             // Use route values for fallback IP-based limiting
             let window_secs = limiter::get_rate_limit_window();
             // ⭐ Pass route limit values (not advanced limit)
-            self.send_rate_limited_response(session, path, max_requests, block_duration, window_secs).await?;
-            return Ok(true);
+            // The IP was just blocked, so it can only retry once the block expires
+            let retry_after = retry_after_secs(true, block_duration, window_secs);
+            self.send_rate_limited_response(session, path, max_requests, retry_after, window_secs).await?;
+            return Ok(LimitDecision::blocked("ip_limit"));
         }
 
-        Ok(false)
+        Ok(ip_quota(ip, path, host, max_requests).map_or_else(LimitDecision::allowed, LimitDecision::allowed_with_quota))
     }
 
-    async fn send_blocked_response(&self, session: &mut Session) -> Result<()> {
-        // Extract IP and path information for notification
-        let ip = match get_client_ip(session) {
-            Some(ip) => ip,
-            None => "unknown".to_string(),
+    /// Check a request that is never rate limited (WebSocket upgrade, unlimited route,
+    /// health check) against everything but the counters: limiter backend, IP reputation,
+    /// allow_user_agents, blocked networks / threat score / countries and earlier blocks
+    pub async fn check_access(
+        &self,
+        session: &mut Session,
+        ip: &str,
+        path: &str,
+        host: Option<&str>,
+        advanced_limits: Option<&AdvancedRateLimitConfig>,
+        use_cloudflare: Option<bool>,
+        log: RouteLog,
+    ) -> Result<LimitDecision> {
+        if let Some(decision) = self.backend_rejection(session).await? {
+            return Ok(decision);
+        }
+
+        if let Some(decision) = self.reputation_rejection(session, ip, path, false, log).await? {
+            return Ok(decision);
+        }
+
+        if let Some(advanced_config) = advanced_limits {
+            let context = self.build_request_context(session, ip, path, host, use_cloudflare, log);
+            if let Some(decision) = self.user_agent_rejection(session, advanced_config, &context, log).await? {
+                return Ok(decision);
+            }
+
+            let global_window_secs = limiter::get_rate_limit_window();
+            if let Some((_, _, reason, _, _, _, reason_code)) = Self::access_block(&context, advanced_config, global_window_secs, log) {
+                route_info!(log, "⛔ Access block: {} - {}", reason, ip);
+                limiter::block_ip(ip, path, host, None);
+                self.send_blocked_response(session, ip, log).await?;
+                return Ok(LimitDecision::blocked(reason_code));
+            }
+        }
+
+        if let Some(decision) = self.blocked_ip_rejection(session, ip, log).await? {
+            return Ok(decision);
+        }
+        Ok(LimitDecision::allowed())
+    }
+
+    /// Decision when the limiter's state can't be used (see failure_mode)
+    async fn backend_rejection(&self, session: &mut Session) -> Result<Option<LimitDecision>> {
+        let Err(e) = limiter::check_backend() else {
+            return Ok(None);
         };
-        
-        // Extract the host header if present for domain information
-        let host = session.req_header()
-            .headers
-            .get("host")
-            .and_then(|h| h.to_str().ok());
+        let decision = limiter_failure_decision(self.failure_mode, &e);
+        if decision.is_rejected() {
+            self.send_unavailable_response(session).await?;
+        }
+        Ok(Some(decision))
+    }
+
+    /// Rejection of an IP listed in the reputation feed; `suspicious` IPs are only
+    /// counted (and limited) when `count` is set
+    async fn reputation_rejection(
+        &self,
+        session: &mut Session,
+        ip: &str,
+        path: &str,
+        count: bool,
+        log: RouteLog,
+    ) -> Result<Option<LimitDecision>> {
+        let Some(config) = self.ip_reputation.as_ref() else {
+            return Ok(None);
+        };
+        let Some(decision) = reputation_decision(config, ip, reputation::is_listed(ip), count) else {
+            return Ok(None);
+        };
+
+        route_info!(log, "Rejecting request from {}: listed in the IP reputation feed", ip);
+        if decision.action == LimitAction::Blocked {
+            self.send_blocked_response(session, ip, log).await?;
+        } else {
+            let window_secs = config.suspicious_window_secs;
+            self.send_rate_limited_response(session, path, config.suspicious_max_req, window_secs, window_secs).await?;
+        }
+        Ok(Some(decision))
+    }
+
+    async fn user_agent_rejection(
+        &self,
+        session: &mut Session,
+        advanced_config: &AdvancedRateLimitConfig,
+        context: &RequestContext,
+        log: RouteLog,
+    ) -> Result<Option<LimitDecision>> {
+        let Some(decision) = user_agent_allowlist_decision(advanced_config, &context.user_agent.raw) else {
+            return Ok(None);
+        };
+        route_info!(log, "🚫 Rejecting request from {}: User-Agent '{}' not in allow_user_agents", context.ip, context.user_agent.raw);
+        self.send_forbidden_response(session).await?;
+        Ok(Some(decision))
+    }
+
+    async fn blocked_ip_rejection(&self, session: &mut Session, ip: &str, log: RouteLog) -> Result<Option<LimitDecision>> {
+        limiter::cleanup_expired_ips(self.unblock_sender.as_ref());
+        if !limiter::is_blocked(ip) {
+            return Ok(None);
+        }
+        let blocked_path = limiter::get_blocked_path(ip).unwrap_or_else(|| "unknown".to_string());
+        route_info!(log, "Blocked request from IP: {} (previously blocked on path: {})", ip, blocked_path);
+        self.send_blocked_response(session, ip, log).await?;
+        Ok(Some(LimitDecision::blocked("ip_blocked")))
+    }
+
+    async fn send_blocked_response(&self, session: &mut Session, ip: &str, log: RouteLog) -> Result<()> {
+        // Extract the host if present for domain information
+        let host = extract_host(session);
+        let host = host.as_deref();
             
         // Get the path from the request URI
         let path = session.req_header().uri.path();
         
         // Get the blocked path from the limiter (if available)
-        let blocked_path = limiter::get_blocked_path(&ip).unwrap_or_else(|| path.to_string());
+        let blocked_path = limiter::get_blocked_path(ip).unwrap_or_else(|| path.to_string());
         
         // Get rate limit settings for the blocked path
         let max_requests = limiter::get_route_max_requests(&blocked_path);
@@ -445,10 +901,10 @@ impl RateLimitService {
         let request_url = format!("{}", session.req_header().uri);
         
         // Send notification for repeated blocked request with better error handling
-        info!("Attempting to send block notification for IP: {} on path: {}", ip, blocked_path);
+        route_debug!(log, "Attempting to send block notification for IP: {} on path: {}", ip, blocked_path);
         
         let notification_params = BlockNotificationParams {
-            ip: &ip,
+            ip,
             block_duration,
             path: &blocked_path,
             domain: host,
@@ -459,15 +915,46 @@ impl RateLimitService {
         };
 
         match self.block_notifier.notify_block(notification_params).await {
-            Ok(_) => info!("Successfully sent block notification for IP: {} on path: {}", ip, blocked_path),
-            Err(e) => warn!("Failed to send block notification: {}", e)
+            Ok(_) => route_info!(log, "Successfully sent block notification for IP: {} on path: {}", ip, blocked_path),
+            Err(e) => route_warn!(log, "Failed to send block notification: {}", e)
         }
         
         // Send 429 response
         let mut header = ResponseHeader::build(429, None)?;
         header.insert_header("X-Rate-Limit-Status", "Blocked")?;
 
-        session.set_keepalive(None);
+        // The client can retry once its block expires
+        if let Some(remaining) = limiter::get_block_remaining(ip) {
+            header.insert_header("Retry-After", remaining.to_string())?;
+        }
+
+        if self.finish_rejection(&mut header)? {
+            session.set_keepalive(None);
+        }
+        session.write_response_header(Box::new(header), true).await?;
+        Ok(())
+    }
+
+    /// 503 for fail_closed when the limiter can't be used
+    async fn send_unavailable_response(&self, session: &mut Session) -> Result<()> {
+        let mut header = ResponseHeader::build(503, None)?;
+        header.insert_header("Retry-After", "1")?;
+        header.insert_header("Content-Length", "0")?;
+        session.write_response_header(Box::new(header), true).await?;
+        Ok(())
+    }
+
+    async fn send_unauthorized_response(&self, session: &mut Session) -> Result<()> {
+        let mut header = ResponseHeader::build(401, None)?;
+        header.insert_header("WWW-Authenticate", "Bearer")?;
+        header.insert_header("Content-Length", "0")?;
+        session.write_response_header(Box::new(header), true).await?;
+        Ok(())
+    }
+
+    async fn send_forbidden_response(&self, session: &mut Session) -> Result<()> {
+        let mut header = ResponseHeader::build(403, None)?;
+        header.insert_header("Content-Length", "0")?;
         session.write_response_header(Box::new(header), true).await?;
         Ok(())
     }
@@ -477,7 +964,7 @@ impl RateLimitService {
         session: &mut Session,
         path: &str,
         max_limit: isize,
-        block_duration: u64,
+        retry_after: u64,
         window_secs: u64,
     ) -> Result<()> {
         let mut header = ResponseHeader::build(429, None)?;
@@ -486,20 +973,533 @@ impl RateLimitService {
         // ⭐ Use actual values from the limit that was triggered, not route defaults
         header.insert_header("X-Rate-Limit-Limit", max_limit.to_string())?;
         header.insert_header("X-Rate-Limit-Remaining", "0")?;
-        header.insert_header("X-Rate-Limit-Reset", block_duration.to_string())?;
+        header.insert_header("X-Rate-Limit-Reset", retry_after.to_string())?;
         header.insert_header("X-Rate-Limit-Path", path)?;
 
         // Retry-After: Standard HTTP header (RFC 6585)
-        // Tells client to wait N seconds before retrying
-        // For sliding window: client should wait for window duration
-        // ⭐ Uses actual window from the limit that was triggered
-        header.insert_header("Retry-After", window_secs.to_string())?;
+        // Tells client to wait N seconds before retrying (see retry_after_secs):
+        // the block duration for hard blocks, the window for soft limits
+        header.insert_header("Retry-After", retry_after.to_string())?;
 
         // X-RateLimit-Window: Custom header to inform client of window duration
         header.insert_header("X-RateLimit-Window", window_secs.to_string())?;
 
-        session.set_keepalive(None);
+        if self.finish_rejection(&mut header)? {
+            session.set_keepalive(None);
+        }
         session.write_response_header(Box::new(header), true).await?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Budget left under the default IP-based limit after this request was counted
+fn ip_quota(ip: &str, path: &str, host: Option<&str>, max_requests: isize) -> Option<RateLimitQuota> {
+    if max_requests <= 0 {
+        return None;
+    }
+    let current_count = limiter::get_current_count(ip, path, host);
+    Some(RateLimitQuota::new(max_requests, current_count, limiter::get_rate_limit_window()))
+}
+
+/// Lowercase hex of a certificate digest
+fn hex_fingerprint(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Configured fingerprint in the form used by `RequestContext::client_cert` ("AB:CD" -> "abcd")
+fn normalize_fingerprint(value: &str) -> String {
+    value.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase()
+}
+
+/// Host of a Referer URL, lowercased; None for missing or unparsable values
+fn referer_host(referer: &str) -> Option<String> {
+    url::Url::parse(referer)
+        .ok()?
+        .host_str()
+        .map(|host| host.to_ascii_lowercase())
+}
+
+/// Rejection for an IP on the reputation feed: always with `action: block`, past
+/// suspicious_max_req requests per window with `action: suspicious`
+fn reputation_decision(config: &IpReputationConfig, ip: &str, listed: bool, count: bool) -> Option<LimitDecision> {
+    if !listed {
+        return None;
+    }
+    match config.action {
+        IpReputationAction::Block => Some(LimitDecision::blocked("ip_reputation")),
+        IpReputationAction::Suspicious if !count => None,
+        IpReputationAction::Suspicious => {
+            limiter::check_suspicious(ip, config.suspicious_max_req, config.suspicious_window_secs)
+                .then(|| LimitDecision::soft_limited("ip_reputation"))
+        }
+    }
+}
+
+/// Whether the request is limited per verified client certificate or JWT claim
+/// instead of per IP. Session cookies are chosen by the client, so cookie-limited
+/// requests stay under the IP limit as well: a fresh cookie per request gains nothing
+fn keyed_by_verified_identity(advanced_config: &AdvancedRateLimitConfig, context: &RequestContext) -> bool {
+    (context.client_cert.is_some() && advanced_config.client_cert_limit.is_some()) || context.jwt_claim.is_some()
+}
+
+/// Rejection for a User-Agent outside the route's allow_user_agents, if it has one
+fn user_agent_allowlist_decision(advanced_config: &AdvancedRateLimitConfig, user_agent: &str) -> Option<LimitDecision> {
+    (!advanced_config.is_user_agent_allowed(user_agent)).then(|| LimitDecision::soft_limited("UA_NOT_ALLOWED"))
+}
+
+/// Parse a `Cookie` header value into name -> value pairs
+fn parse_cookies(header: &str) -> HashMap<String, String> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    /// Collects every record so tests can assert on route logging
+    struct CaptureLogger;
+
+    static CAPTURED: Mutex<Vec<(Level, String)>> = Mutex::new(Vec::new());
+    static CAPTURE_LOGGER: CaptureLogger = CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            CAPTURED.lock().unwrap().push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn captured_mentioning(needle: &str) -> Vec<(Level, String)> {
+        CAPTURED.lock().unwrap().iter().filter(|(_, msg)| msg.contains(needle)).cloned().collect()
+    }
+
+    fn context(asn: Option<&str>, country: Option<&str>) -> RequestContext {
+        RequestContext {
+            ip: "192.0.2.10".to_string(),
+            path: "/api".to_string(),
+            domain: Some("service.test".to_string()),
+            cloudflare: CloudflareContext {
+                asn: asn.map(|s| s.to_string()),
+                country: country.map(|s| s.to_string()),
+                ..Default::default()
+            },
+            user_agent: UserAgentInfo::from_string(""),
+            cookies: HashMap::new(),
+            referer_host: None,
+            header_names: HashSet::new(),
+            client_cert: None,
+            http_version: http::Version::HTTP_11,
+            jwt_claim: None,
+            request_path: "/api".to_string(),
+            path_depth: None,
+        }
+    }
+
+    fn with_referer(referer: &str) -> RequestContext {
+        let mut ctx = context(None, None);
+        ctx.domain = Some("referer.test".to_string());
+        ctx.referer_host = referer_host(referer);
+        ctx
+    }
+
+    #[test]
+    fn test_ip_quota_tracks_current_count() {
+        let (ip, path, host) = ("192.0.2.77", "/quota", Some("quota.test"));
+        limiter::set_route_limits("quota.test/quota", 5, 60);
+
+        limiter::check_and_increment(ip, path, host);
+        assert_eq!(ip_quota(ip, path, host, 5).unwrap().remaining, 4);
+
+        limiter::check_and_increment(ip, path, host);
+        limiter::check_and_increment(ip, path, host);
+        let quota = ip_quota(ip, path, host, 5).unwrap();
+        assert_eq!(quota.remaining, 2);
+        assert_eq!(quota.header_value(), format!("limit=5, remaining=2, reset={}", quota.reset));
+
+        assert_eq!(ip_quota(ip, path, host, -1), None);
+    }
+
+    #[test]
+    fn test_limiter_error_fail_open_allows() {
+        let err = LimiterError::Poisoned("test_open");
+        let before = metrics::LIMITER_ERRORS.with_label_values(&["poisoned", "fail_open"]).get();
+
+        let decision = limiter_failure_decision(LimiterFailureMode::FailOpen, &err);
+
+        assert!(!decision.is_rejected());
+        assert!(metrics::LIMITER_ERRORS.with_label_values(&["poisoned", "fail_open"]).get() > before);
+    }
+
+    #[test]
+    fn test_limiter_error_fail_closed_rejects() {
+        let err = LimiterError::Poisoned("test_closed");
+
+        let decision = limiter_failure_decision(LimiterFailureMode::FailClosed, &err);
+
+        assert!(decision.is_rejected());
+        assert_eq!(decision.reason_code, Some("limiter_error"));
+    }
+
+    fn service(close_on_block: bool) -> RateLimitService {
+        RateLimitService::new(BlockNotifier::new(String::new(), String::new())).with_close_on_block(close_on_block)
+    }
+
+    #[test]
+    fn test_blocked_response_keeps_connection_alive_by_default() {
+        let mut header = ResponseHeader::build(429, None).unwrap();
+
+        assert!(!service(false).finish_rejection(&mut header).unwrap());
+        assert!(header.headers.get("Connection").is_none());
+        // A framed empty body lets the client send its next request on the same connection
+        assert_eq!(header.headers.get("Content-Length").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_close_on_block_closes_connection() {
+        let mut header = ResponseHeader::build(429, None).unwrap();
+
+        assert!(service(true).finish_rejection(&mut header).unwrap());
+        assert_eq!(header.headers.get("Connection").unwrap(), "close");
+    }
+
+    #[test]
+    fn test_retry_after_soft_limit_uses_window() {
+        // window 3600, block 300: a soft limit must wait for the window to slide
+        assert_eq!(retry_after_secs(false, 300, 3600), 3600);
+    }
+
+    #[test]
+    fn test_retry_after_hard_block_uses_block_duration() {
+        assert_eq!(retry_after_secs(true, 300, 3600), 300);
+        // A zero-length block behaves like a soft limit
+        assert_eq!(retry_after_secs(true, 0, 60), 60);
+    }
+
+    #[test]
+    fn test_asn_country_condition_requires_both() {
+        let condition = RateLimitCondition::AsnCountry {
+            asn: "12345".to_string(),
+            country: "ru".parse().unwrap(),
+        };
+
+        assert!(RateLimitService::condition_matches(&context(Some("12345"), Some("RU")), &condition));
+        assert!(!RateLimitService::condition_matches(&context(Some("12345"), Some("DE")), &condition));
+        assert!(!RateLimitService::condition_matches(&context(Some("99999"), Some("RU")), &condition));
+        assert!(!RateLimitService::condition_matches(&context(Some("12345"), None), &condition));
+    }
+
+    #[test]
+    fn test_user_agent_allowlist() {
+        let config: AdvancedRateLimitConfig =
+            serde_yaml::from_str("allow_user_agents: [\"InternalClient/\", \"/^deploy-bot/[0-9]+$/\"]\n").unwrap();
+
+        assert_eq!(user_agent_allowlist_decision(&config, "InternalClient/2.3"), None);
+        assert_eq!(user_agent_allowlist_decision(&config, "deploy-bot/7"), None);
+
+        let rejected = user_agent_allowlist_decision(&config, "curl/7.68.0").unwrap();
+        assert!(rejected.is_rejected());
+        assert_eq!(rejected.reason_code, Some("UA_NOT_ALLOWED"));
+
+        // No User-Agent at all matches no entry
+        assert_eq!(user_agent_allowlist_decision(&config, ""), Some(rejected));
+
+        // Without an allowlist every client passes, including an empty User-Agent
+        let open: AdvancedRateLimitConfig = serde_yaml::from_str("threat_score_threshold: 50\n").unwrap();
+        assert_eq!(user_agent_allowlist_decision(&open, ""), None);
+    }
+
+    #[test]
+    fn test_blocked_cidr_is_hard_blocked_before_threat_score() {
+        let config: AdvancedRateLimitConfig =
+            serde_yaml::from_str("block_cidrs: [\"185.220.0.0/16\"]\nthreat_score_threshold: 10\n").unwrap();
+        let mut ctx = context(None, None);
+        ctx.ip = "185.220.101.4".to_string();
+        ctx.cloudflare.threat_score = Some(90);
+
+        let (limited, should_block, _, _, block_duration, _, reason_code) =
+            RateLimitService::evaluate_advanced_limits(&ctx, &config, 60, RouteLog::default()).unwrap();
+        assert!(limited && should_block);
+        // No explicit duration: the IP is blocked for the route's block_duration_secs
+        assert_eq!(block_duration, None);
+        assert_eq!(reason_code, "cidr_blocked");
+
+        ctx.ip = "198.51.100.4".to_string();
+        let (_, _, _, _, _, _, reason_code) =
+            RateLimitService::evaluate_advanced_limits(&ctx, &config, 60, RouteLog::default()).unwrap();
+        assert_eq!(reason_code, "threat_score");
+    }
+
+    #[test]
+    fn test_listed_ip_is_blocked_or_limited_per_action() {
+        let block: IpReputationConfig = serde_yaml::from_str("url: http://feed.test/ips\n").unwrap();
+        let suspicious: IpReputationConfig =
+            serde_yaml::from_str("url: http://feed.test/ips\naction: suspicious\nsuspicious_max_req: 2\nsuspicious_window_secs: 60\n").unwrap();
+
+        assert_eq!(reputation_decision(&block, "198.51.100.90", false, true), None);
+        assert_eq!(reputation_decision(&block, "198.51.100.90", true, true), Some(LimitDecision::blocked("ip_reputation")));
+
+        let ip = "198.51.100.91";
+        assert_eq!(reputation_decision(&suspicious, ip, true, true), None);
+        assert_eq!(reputation_decision(&suspicious, ip, true, true), None);
+        assert_eq!(reputation_decision(&suspicious, ip, true, true), Some(LimitDecision::soft_limited("ip_reputation")));
+
+        // Requests that are never counted (WebSocket upgrades, ...) still meet the block action
+        assert_eq!(reputation_decision(&block, "198.51.100.90", true, false), Some(LimitDecision::blocked("ip_reputation")));
+        assert_eq!(reputation_decision(&suspicious, "198.51.100.92", true, false), None);
+    }
+
+    #[test]
+    fn test_cookie_limits_keep_the_ip_limit() {
+        let config: AdvancedRateLimitConfig = serde_yaml::from_str(
+            "cookie_limits:\n  session_id: { max_req: 100, window_secs: 60 }\nclient_cert_limit: { max_req: 600, window_secs: 60 }\n",
+        ).unwrap();
+        let mut ctx = context(None, None);
+        ctx.cookies.insert("session_id".to_string(), "random-per-request".to_string());
+        assert!(!keyed_by_verified_identity(&config, &ctx));
+
+        ctx.client_cert = Some("3fa2".to_string());
+        assert!(keyed_by_verified_identity(&config, &ctx));
+    }
+
+    #[test]
+    fn test_parse_cookies() {
+        let cookies = parse_cookies("session_id=abc123; theme=dark;flag");
+
+        assert_eq!(cookies.get("session_id").map(String::as_str), Some("abc123"));
+        assert_eq!(cookies.get("theme").map(String::as_str), Some("dark"));
+        assert_eq!(cookies.len(), 2);
+    }
+
+    #[test]
+    fn test_referer_host_parsing() {
+        assert_eq!(referer_host("https://Partner.Example.com/page?x=1").as_deref(), Some("partner.example.com"));
+        assert_eq!(referer_host("not a url"), None);
+    }
+
+    #[test]
+    fn test_referer_domain_conditions() {
+        let allowed = with_referer("https://cdn.partner.com/img");
+        let disallowed = with_referer("https://hotlinker.net/page");
+        let none = context(None, None);
+        let not_in = RateLimitCondition::RefererDomainNotIn { values: vec!["partner.com".to_string()] };
+        let is_in = RateLimitCondition::RefererDomainIn { values: vec!["partner.com".to_string()] };
+
+        assert!(!RateLimitService::condition_matches(&allowed, &not_in));
+        assert!(RateLimitService::condition_matches(&disallowed, &not_in));
+        assert!(RateLimitService::condition_matches(&allowed, &is_in));
+        // No Referer falls through both conditions
+        assert!(!RateLimitService::condition_matches(&none, &not_in));
+        assert!(!RateLimitService::condition_matches(&none, &is_in));
+    }
+
+    fn with_headers(names: &[&str]) -> RequestContext {
+        let mut ctx = context(None, None);
+        ctx.header_names = names.iter().map(|n| n.to_string()).collect();
+        ctx
+    }
+
+    #[test]
+    fn test_missing_browser_headers_condition() {
+        let condition: RateLimitCondition = serde_yaml::from_str("type: missing_browser_headers").unwrap();
+        let browser = with_headers(&["host", "user-agent", "accept", "accept-language", "accept-encoding"]);
+        let script = with_headers(&["host", "user-agent", "accept"]);
+
+        assert!(!RateLimitService::condition_matches(&browser, &condition));
+        assert!(RateLimitService::condition_matches(&script, &condition));
+    }
+
+    #[test]
+    fn test_missing_browser_headers_custom_list() {
+        let condition = RateLimitCondition::MissingBrowserHeaders { headers: vec!["Sec-Fetch-Mode".to_string()] };
+
+        assert!(RateLimitService::condition_matches(&with_headers(&["accept"]), &condition));
+        assert!(!RateLimitService::condition_matches(&with_headers(&["sec-fetch-mode"]), &condition));
+    }
+
+    #[test]
+    fn test_http_version_condition() {
+        let condition: RateLimitCondition = serde_yaml::from_str("type: http_version_in\nvalues: [\"HTTP/1.0\"]").unwrap();
+        let over = |version| RequestContext { http_version: version, ..context(None, None) };
+
+        assert!(RateLimitService::condition_matches(&over(http::Version::HTTP_10), &condition));
+        assert!(!RateLimitService::condition_matches(&over(http::Version::HTTP_2), &condition));
+        assert!(!RateLimitService::condition_matches(&over(http::Version::HTTP_11), &condition));
+
+        let condition: RateLimitCondition = serde_yaml::from_str("type: http_version_in\nvalues: [http/2, \"1.1\"]").unwrap();
+        assert!(RateLimitService::condition_matches(&over(http::Version::HTTP_2), &condition));
+        assert!(RateLimitService::condition_matches(&over(http::Version::HTTP_11), &condition));
+        assert!(!RateLimitService::condition_matches(&over(http::Version::HTTP_10), &condition));
+
+        let err = serde_yaml::from_str::<RateLimitCondition>("type: http_version_in\nvalues: [\"HTTP/4\"]").unwrap_err();
+        assert!(err.to_string().contains("invalid HTTP version 'HTTP/4'"), "{}", err);
+    }
+
+    fn with_client_cert(fingerprint: Option<&str>) -> RequestContext {
+        let mut ctx = context(None, None);
+        ctx.domain = Some("mtls.test".to_string());
+        ctx.client_cert = fingerprint.map(|f| f.to_string());
+        ctx
+    }
+
+    #[test]
+    fn test_same_client_cert_shares_bucket_across_ips() {
+        let mut first = with_client_cert(Some("aa11"));
+        let mut second = with_client_cert(Some("aa11"));
+        first.ip = "198.51.100.1".to_string();
+        second.ip = "198.51.100.2".to_string();
+        let other = with_client_cert(Some("bb22"));
+
+        assert_eq!(first.create_key("client_cert"), second.create_key("client_cert"));
+        assert_ne!(first.create_key("client_cert"), other.create_key("client_cert"));
+
+        let (_, _, count) = limiter::check_dimension_limit_with_window(&first, "client_cert", 10, 60, None);
+        assert_eq!(count, 1);
+        let (_, _, count) = limiter::check_dimension_limit_with_window(&second, "client_cert", 10, 60, None);
+        assert_eq!(count, 2);
+        let (_, _, count) = limiter::check_dimension_limit_with_window(&other, "client_cert", 10, 60, None);
+        assert_eq!(count, 1);
+    }
+
+    fn with_jwt(token: &str) -> RequestContext {
+        let mut ctx = context(None, None);
+        ctx.domain = Some("jwt.test".to_string());
+        ctx.jwt_claim = jwt::verified_claim(token, &jwt::tests::config()).ok();
+        ctx
+    }
+
+    #[test]
+    fn test_same_jwt_subject_shares_bucket_across_ips() {
+        let mut first = with_jwt(&jwt::tests::signed_token(r#"{"sub":"user-1","iat":1}"#));
+        let mut second = with_jwt(&jwt::tests::signed_token(r#"{"sub":"user-1","iat":2}"#));
+        first.ip = "198.51.100.1".to_string();
+        second.ip = "198.51.100.2".to_string();
+        let other = with_jwt(&jwt::tests::signed_token(r#"{"sub":"user-2"}"#));
+
+        assert_eq!(first.jwt_claim.as_deref(), Some("user-1"));
+        assert_eq!(first.create_key("jwt"), second.create_key("jwt"));
+        assert_ne!(first.create_key("jwt"), other.create_key("jwt"));
+
+        let (_, _, count) = limiter::check_dimension_limit_with_window(&first, "jwt", 10, 60, None);
+        assert_eq!(count, 1);
+        let (_, _, count) = limiter::check_dimension_limit_with_window(&second, "jwt", 10, 60, None);
+        assert_eq!(count, 2);
+        let (_, _, count) = limiter::check_dimension_limit_with_window(&other, "jwt", 10, 60, None);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_tampered_jwt_gets_no_claim() {
+        let token = jwt::tests::signed_token(r#"{"sub":"user-1"}"#);
+        let tampered = token.replacen('.', ".e30", 1);
+
+        assert!(with_jwt(&tampered).jwt_claim.is_none());
+    }
+
+    #[test]
+    fn test_client_cert_condition() {
+        let condition = RateLimitCondition::ClientCertIn { values: vec!["AA:11".to_string()] };
+
+        assert!(RateLimitService::condition_matches(&with_client_cert(Some("aa11")), &condition));
+        assert!(!RateLimitService::condition_matches(&with_client_cert(Some("bb22")), &condition));
+        assert!(!RateLimitService::condition_matches(&with_client_cert(None), &condition));
+    }
+
+    #[test]
+    fn test_hex_fingerprint() {
+        assert_eq!(hex_fingerprint(&[0xab, 0x01, 0xff]), "ab01ff");
+    }
+
+    #[test]
+    fn test_asn_limit_is_enforced() {
+        let config: AdvancedRateLimitConfig = serde_yaml::from_str(
+            "asn_limits:\n  \"15169\": 2\n  \"32934\": { max_req: 1, window_secs: 60, block_duration_secs: 0 }\n",
+        )
+        .unwrap();
+        let mut google = context(Some("15169"), Some("US"));
+        google.domain = Some("asn.test".to_string());
+        let mut other = context(Some("64500"), Some("US"));
+        other.domain = Some("asn.test".to_string());
+
+        for _ in 0..2 {
+            assert!(RateLimitService::evaluate_advanced_limits(&google, &config, 60, RouteLog::default()).is_none());
+        }
+        let (limited, should_block, reason, max_req, block_duration, _, reason_code) =
+            RateLimitService::evaluate_advanced_limits(&google, &config, 60, RouteLog::default()).unwrap();
+        assert!(limited && should_block);
+        assert_eq!(reason, "ASN 15169 limit exceeded");
+        assert_eq!(max_req, 2);
+        // Simple format has no block_duration_secs: the route's one applies
+        assert_eq!(block_duration, None);
+        assert_eq!(reason_code, "asn");
+
+        // Extended format with block_duration_secs: 0 is a soft limit in its own window
+        let mut meta = context(Some("32934"), None);
+        meta.domain = Some("asn.test".to_string());
+        assert!(RateLimitService::evaluate_advanced_limits(&meta, &config, 1, RouteLog::default()).is_none());
+        let (limited, should_block, _, _, _, window_secs, _) =
+            RateLimitService::evaluate_advanced_limits(&meta, &config, 1, RouteLog::default()).unwrap();
+        assert!(limited && !should_block);
+        assert_eq!(window_secs, 60);
+
+        // Unlisted ASNs are not limited by this dimension
+        for _ in 0..5 {
+            assert!(RateLimitService::evaluate_advanced_limits(&other, &config, 60, RouteLog::default()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_referer_limit_only_applies_to_configured_domain() {
+        let config = AdvancedRateLimitConfig {
+            referer_limits: Some(HashMap::from([("hotlinker.net".to_string(), LimitConfig::Simple(1))])),
+            ..Default::default()
+        };
+        let allowed = with_referer("https://partner.com/");
+        let disallowed = with_referer("https://img.hotlinker.net/");
+
+        for _ in 0..3 {
+            assert!(RateLimitService::evaluate_advanced_limits(&allowed, &config, 60, RouteLog::default()).is_none());
+        }
+        assert!(RateLimitService::evaluate_advanced_limits(&disallowed, &config, 60, RouteLog::default()).is_none());
+        let (limited, _, reason, _, _, _, reason_code) =
+            RateLimitService::evaluate_advanced_limits(&disallowed, &config, 60, RouteLog::default()).unwrap();
+        assert!(limited);
+        assert_eq!(reason, "Referer hotlinker.net limit exceeded");
+        assert_eq!(reason_code, "referer");
+    }
+
+    #[test]
+    fn test_route_log_level_override() {
+        let _ = log::set_logger(&CAPTURE_LOGGER);
+        let config = AdvancedRateLimitConfig {
+            country_limits: Some(HashMap::from([("DE".parse().unwrap(), LimitConfig::Simple(100))])),
+            ..Default::default()
+        };
+
+        let mut debug_route = context(None, Some("DE"));
+        debug_route.ip = "198.51.100.21".to_string();
+        let mut warn_route = context(None, Some("DE"));
+        warn_route.ip = "198.51.100.22".to_string();
+
+        RateLimitService::evaluate_advanced_limits(&debug_route, &config, 60, RouteLog::new(Some(LevelFilter::Debug)));
+        RateLimitService::evaluate_advanced_limits(&warn_route, &config, 60, RouteLog::new(Some(LevelFilter::Warn)));
+
+        let debug_logs = captured_mentioning("198.51.100.21");
+        assert!(!debug_logs.is_empty());
+        assert!(debug_logs.iter().all(|(level, _)| *level == Level::Debug));
+        assert!(debug_logs[0].1.contains("Applying Country DE limit"));
+        assert!(captured_mentioning("198.51.100.22").is_empty());
+    }
+}
@@ -1,6 +1,102 @@
 // src/utils/cloudflare.rs
+use pingora_http::RequestHeader;
 use pingora_proxy::Session;
 use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// ISO 3166-1 alpha-2 codes, as sent in CF-IPCountry
+const ISO_COUNTRY_CODES: &str = "\
+    AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ BA BB BD BE BF BG BH BI BJ BL BM BN BO BQ \
+    BR BS BT BV BW BY BZ CA CC CD CF CG CH CI CK CL CM CN CO CR CU CV CW CX CY CZ DE DJ DK DM \
+    DO DZ EC EE EG EH ER ES ET FI FJ FK FM FO FR GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS \
+    GT GU GW GY HK HM HN HR HT HU ID IE IL IM IN IO IQ IR IS IT JE JM JO JP KE KG KH KI KM KN \
+    KP KR KW KY KZ LA LB LC LI LK LR LS LT LU LV LY MA MC MD ME MF MG MH MK ML MM MN MO MP MQ \
+    MR MS MT MU MV MW MX MY MZ NA NC NE NF NG NI NL NO NP NR NU NZ OM PA PE PF PG PH PK PL PM \
+    PN PR PS PT PW PY QA RE RO RS RU RW SA SB SC SD SE SG SH SI SJ SK SL SM SN SO SR SS ST SV \
+    SX SY SZ TC TD TF TG TH TJ TK TL TM TN TO TR TT TV TW TZ UA UG UM US UY UZ VA VC VE VG VI \
+    VN VU WF WS YE YT ZA ZM ZW";
+
+/// Whether a country code can ever appear in a request's country: an ISO 3166-1
+/// alpha-2 code or Cloudflare's "T1" for Tor ("XX", unknown, is treated as no country)
+pub fn is_known_country_code(code: &str) -> bool {
+    let code = code.to_ascii_uppercase();
+    code == "T1" || (code.len() == 2 && ISO_COUNTRY_CODES.split_whitespace().any(|c| c == code))
+}
+
+/// Codes people write for a country whose ISO code is different
+const COUNTRY_CODE_HINTS: [(&str, &str); 2] = [("UK", "GB"), ("EL", "GR")];
+
+/// Country code in the config: ISO 3166-1 alpha-2 or "T1" (Tor), kept uppercase
+///
+/// Parsing rejects anything a request's country can never be, so a typo fails
+/// config load instead of silently never matching. Matching is case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CountryCode(String);
+
+/// A config value that isn't a country code
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("'{code}' is not an ISO 3166-1 alpha-2 country code{}", hint_suffix(.hint))]
+pub struct InvalidCountryCode {
+    pub code: String,
+    pub hint: Option<&'static str>,
+}
+
+fn hint_suffix(hint: &Option<&str>) -> String {
+    hint.map(|hint| format!(" (did you mean {}?)", hint)).unwrap_or_default()
+}
+
+impl CountryCode {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether a request's country (e.g. from CF-IPCountry) is this one
+    pub fn matches(&self, country: &str) -> bool {
+        self.0.eq_ignore_ascii_case(country)
+    }
+}
+
+impl FromStr for CountryCode {
+    type Err = InvalidCountryCode;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let code = code.trim().to_ascii_uppercase();
+        if is_known_country_code(&code) {
+            return Ok(Self(code));
+        }
+        let hint = COUNTRY_CODE_HINTS.iter().find(|(wrong, _)| *wrong == code).map(|(_, right)| *right);
+        Err(InvalidCountryCode { code, hint })
+    }
+}
+
+impl TryFrom<String> for CountryCode {
+    type Error = InvalidCountryCode;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        code.parse()
+    }
+}
+
+impl From<CountryCode> for String {
+    fn from(code: CountryCode) -> Self {
+        code.0
+    }
+}
+
+impl std::borrow::Borrow<str> for CountryCode {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
 /// Context information extracted from Cloudflare headers
 #[derive(Debug, Clone, Default)]
@@ -21,7 +117,19 @@ pub struct CloudflareContext {
 impl CloudflareContext {
     /// Extract Cloudflare context from HTTP session headers
     pub fn from_session(session: &Session) -> Self {
-        let headers = &session.req_header().headers;
+        Self::from_request(session.req_header(), None)
+    }
+
+    /// Extract Cloudflare context from request headers
+    ///
+    /// `use_cloudflare` is the matched domain's override: `Some(false)` means the domain
+    /// is not behind Cloudflare, so CF headers are client-controlled and ignored.
+    pub fn from_request(req: &RequestHeader, use_cloudflare: Option<bool>) -> Self {
+        if use_cloudflare == Some(false) {
+            return Self::default();
+        }
+
+        let headers = &req.headers;
 
         // Extract CF-IPCountry
         let country = headers
@@ -83,9 +191,9 @@ impl CloudflareContext {
     }
 
     /// Check if country is in the given list
-    pub fn country_in(&self, countries: &[String]) -> bool {
+    pub fn country_in(&self, countries: &[CountryCode]) -> bool {
         if let Some(ref country) = self.country {
-            countries.iter().any(|c| c.eq_ignore_ascii_case(country))
+            countries.iter().any(|c| c.matches(country))
         } else {
             false
         }
@@ -99,12 +207,53 @@ impl CloudflareContext {
             false
         }
     }
+
+    /// Check if both ASN and country match
+    pub fn asn_country_matches(&self, asn: &str, country: &CountryCode) -> bool {
+        self.asn_matches(asn)
+            && self.country.as_deref().map_or(false, |c| country.matches(c))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_known_country_codes() {
+        assert!(is_known_country_code("US"));
+        assert!(is_known_country_code("gb"));
+        assert!(is_known_country_code("T1"));
+        // Common mistakes: UK is GB, XX is reported as no country
+        assert!(!is_known_country_code("UK"));
+        assert!(!is_known_country_code("XX"));
+        assert!(!is_known_country_code("USA"));
+    }
+
+    #[test]
+    fn test_country_code_parses_valid_codes() {
+        let code: CountryCode = " de ".parse().unwrap();
+        assert_eq!(code.as_str(), "DE");
+        assert_eq!("T1".parse::<CountryCode>().unwrap().as_str(), "T1");
+
+        // Matching a request's country ignores case
+        assert!(code.matches("DE"));
+        assert!(code.matches("de"));
+        assert!(!code.matches("AT"));
+    }
+
+    #[test]
+    fn test_invalid_country_code_errors_with_hint() {
+        let err = "uk".parse::<CountryCode>().unwrap_err();
+        assert_eq!(err.hint, Some("GB"));
+        assert_eq!(err.to_string(), "'UK' is not an ISO 3166-1 alpha-2 country code (did you mean GB?)");
+
+        let err = "XX".parse::<CountryCode>().unwrap_err();
+        assert_eq!(err.to_string(), "'XX' is not an ISO 3166-1 alpha-2 country code");
+        assert!("USA".parse::<CountryCode>().is_err());
+        assert!("".parse::<CountryCode>().is_err());
+    }
+
     #[test]
     fn test_cloudflare_context_threat_above() {
         let ctx = CloudflareContext {
@@ -130,4 +279,18 @@ mod tests {
         let blocked = vec!["CN".to_string(), "RU".to_string()];
         assert!(!ctx.country_in(&blocked));
     }
+
+    #[test]
+    fn test_cf_headers_ignored_for_non_cloudflare_domain() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("CF-IPCountry", "vn").unwrap();
+        req.insert_header("CF-Connecting-ASN", "AS15169").unwrap();
+
+        let cf = CloudflareContext::from_request(&req, Some(true));
+        assert_eq!(cf.country.as_deref(), Some("VN"));
+        assert_eq!(cf.asn.as_deref(), Some("15169"));
+
+        let direct = CloudflareContext::from_request(&req, Some(false));
+        assert!(!direct.has_cloudflare_headers());
+    }
 }
@@ -0,0 +1,172 @@
+use pingora_http::RequestHeader;
+use pingora_proxy::Session;
+use std::fmt;
+
+/// A request carrying a `Host` header that disagrees with its `:authority` / URI authority
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostConflict {
+    pub host: String,
+    pub authority: String,
+}
+
+impl fmt::Display for HostConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Host '{}' does not match authority '{}'", self.host, self.authority)
+    }
+}
+
+/// Resolve the request host
+///
+/// - HTTP/2 prefers `:authority` (the URI authority), HTTP/1.x prefers the `Host` header
+/// - When both are present and disagree, `strict` rejects the request instead of
+///   silently picking one (a request smuggling / routing confusion vector)
+pub fn resolve_host(req: &RequestHeader, strict: bool) -> Result<Option<String>, HostConflict> {
+    let host = req.headers.get("host").and_then(|h| h.to_str().ok());
+    let authority = req
+        .uri
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| req.headers.get(":authority").and_then(|h| h.to_str().ok()));
+
+    match (host, authority) {
+        (Some(host), Some(authority)) if !host.eq_ignore_ascii_case(authority) => {
+            if strict {
+                return Err(HostConflict {
+                    host: host.to_string(),
+                    authority: authority.to_string(),
+                });
+            }
+            let preferred = if req.version == http::Version::HTTP_2 { authority } else { host };
+            Ok(Some(preferred.to_string()))
+        }
+        (Some(host), _) => Ok(Some(host.to_string())),
+        (None, authority) => Ok(authority.map(|a| a.to_string())),
+    }
+}
+
+/// Host of the request: `Host` header (HTTP/1.x) or `:authority` / URI authority (HTTP/2)
+///
+/// This is the single place host extraction happens; conflicting values are resolved
+/// leniently here (strict rejection is done once in `request_filter`).
+pub fn extract_host(session: &Session) -> Option<String> {
+    host_from_request(session.req_header())
+}
+
+/// Request-header form of [`extract_host`]
+pub fn host_from_request(req: &RequestHeader) -> Option<String> {
+    resolve_host(req, false).ok().flatten()
+}
+
+/// Whether `host` is `domain` or one of its subdomains (case-insensitive)
+pub fn host_matches_domain(host: &str, domain: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    let domain = domain.trim_start_matches('.').trim_end_matches('.');
+    if host.eq_ignore_ascii_case(domain) {
+        return true;
+    }
+    host.len() > domain.len()
+        && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+        && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+}
+
+/// Whether a configured domain is a wildcard such as "*.tenants.example.com"
+pub fn is_wildcard_domain(domain: &str) -> bool {
+    domain.starts_with("*.")
+}
+
+/// Subdomain of `host` matched by a wildcard domain, e.g. "acme" for
+/// "acme.tenants.example.com" and "*.tenants.example.com" (case-insensitive)
+///
+/// The bare parent domain does not match. `host` must not include a port.
+pub fn wildcard_subdomain<'a>(host: &'a str, pattern: &str) -> Option<&'a str> {
+    let suffix = pattern.strip_prefix("*.")?.trim_end_matches('.');
+    let host = host.trim_end_matches('.');
+    let split = host.len().checked_sub(suffix.len() + 1).filter(|&split| split > 0)?;
+    if !host.is_char_boundary(split) {
+        return None;
+    }
+    let (subdomain, rest) = host.split_at(split);
+    (rest.starts_with('.') && rest[1..].eq_ignore_ascii_case(suffix)).then_some(subdomain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_subdomain() {
+        let pattern = "*.tenants.example.com";
+        assert_eq!(wildcard_subdomain("acme.tenants.example.com", pattern), Some("acme"));
+        assert_eq!(wildcard_subdomain("eu.acme.Tenants.example.com", pattern), Some("eu.acme"));
+        assert_eq!(wildcard_subdomain("tenants.example.com", pattern), None);
+        assert_eq!(wildcard_subdomain("acmetenants.example.com", pattern), None);
+        assert_eq!(wildcard_subdomain("acme.tenants.example.org", pattern), None);
+        assert_eq!(wildcard_subdomain("acme.tenants.example.com", "tenants.example.com"), None);
+    }
+
+    fn conflicting(version: http::Version) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"https://api.example.com/users", None).unwrap();
+        req.set_version(version);
+        req.insert_header("Host", "admin.example.com").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_conflict_rejected_in_strict_mode() {
+        let req = conflicting(http::Version::HTTP_11);
+        let err = resolve_host(&req, true).unwrap_err();
+
+        assert_eq!(err.host, "admin.example.com");
+        assert_eq!(err.authority, "api.example.com");
+    }
+
+    #[test]
+    fn test_conflict_lenient_prefers_host_for_h1() {
+        let req = conflicting(http::Version::HTTP_11);
+        assert_eq!(resolve_host(&req, false).unwrap().as_deref(), Some("admin.example.com"));
+    }
+
+    #[test]
+    fn test_conflict_lenient_prefers_authority_for_h2() {
+        let req = conflicting(http::Version::HTTP_2);
+        assert_eq!(resolve_host(&req, false).unwrap().as_deref(), Some("api.example.com"));
+    }
+
+    #[test]
+    fn test_matching_host_and_authority_is_not_a_conflict() {
+        let mut req = RequestHeader::build("GET", b"https://api.example.com/users", None).unwrap();
+        req.insert_header("Host", "API.example.com").unwrap();
+
+        assert_eq!(resolve_host(&req, true).unwrap().as_deref(), Some("API.example.com"));
+    }
+
+    #[test]
+    fn test_extract_h1_host_header() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("Host", "example.com:8080").unwrap();
+
+        assert_eq!(host_from_request(&req).as_deref(), Some("example.com:8080"));
+    }
+
+    #[test]
+    fn test_extract_h2_authority() {
+        let mut req = RequestHeader::build("GET", b"https://h2.example.com/path", None).unwrap();
+        req.set_version(http::Version::HTTP_2);
+
+        assert_eq!(host_from_request(&req).as_deref(), Some("h2.example.com"));
+    }
+
+    #[test]
+    fn test_extract_uri_authority_fallback() {
+        let req = RequestHeader::build("GET", b"http://absolute.example.com/path", None).unwrap();
+
+        assert_eq!(host_from_request(&req).as_deref(), Some("absolute.example.com"));
+    }
+
+    #[test]
+    fn test_extract_no_host() {
+        let req = RequestHeader::build("GET", b"/path", None).unwrap();
+
+        assert_eq!(host_from_request(&req), None);
+    }
+}
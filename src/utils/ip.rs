@@ -1,20 +1,167 @@
+use pingora_http::RequestHeader;
 use pingora_proxy::Session;
 use once_cell::sync::Lazy;
-use std::sync::atomic::{AtomicBool, Ordering};
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 // Global configuration flag for using Cloudflare
 static USE_CLOUDFLARE: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
 
+// Networks of proxies whose forwarding headers (X-Forwarded-Proto, ...) we trust
+static TRUSTED_PROXIES: Lazy<RwLock<Vec<IpNetwork>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+// Header naming the client IP, set by the fronting proxy (e.g. True-Client-IP)
+static CLIENT_IP_HEADER: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+
+// Load balancers in front of pingwall that append to X-Forwarded-For (0: take the leftmost entry)
+static TRUSTED_PROXY_HOPS: AtomicUsize = AtomicUsize::new(0);
+
 // Function to initialize the configuration
 pub fn set_use_cloudflare(use_cf: bool) {
     USE_CLOUDFLARE.store(use_cf, Ordering::SeqCst);
 }
 
+/// Configure the header to take the client IP from, ahead of the built-in chain
+/// The header is only honored on connections from trusted proxies
+pub fn set_client_ip_header(header: Option<&str>) {
+    *CLIENT_IP_HEADER.write().unwrap() = header.map(|h| h.to_string());
+}
+
+/// Configure how many X-Forwarded-For entries, counted from the right, were added by our own proxies
+pub fn set_trusted_proxy_hops(hops: usize) {
+    TRUSTED_PROXY_HOPS.store(hops, Ordering::SeqCst);
+}
+
+/// Configure the trusted proxy networks (CIDR ranges or single IPs)
+/// Invalid entries are logged and skipped
+pub fn set_trusted_proxies(proxies: &[String]) {
+    let mut networks = Vec::new();
+    for entry in proxies {
+        match entry.parse::<IpNetwork>() {
+            Ok(network) => networks.push(network),
+            Err(e) => log::warn!("Ignoring invalid trusted proxy '{}': {}", entry, e),
+        }
+    }
+    *TRUSTED_PROXIES.write().unwrap() = networks;
+}
+
+/// Check whether the given address belongs to a configured trusted proxy
+pub fn is_trusted_proxy(ip: IpAddr) -> bool {
+    TRUSTED_PROXIES.read().unwrap().iter().any(|network| network.contains(ip))
+}
+
+/// IP address of the directly connected peer (the socket address, not headers)
+pub fn peer_ip(session: &Session) -> Option<IpAddr> {
+    session.client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| addr.ip())
+}
+
+/// Canonical form of an IP address used as the client identity
+///
+/// IPv4-mapped IPv6 addresses (`::ffff:192.0.2.1`), as seen on dual-stack sockets,
+/// are converted to plain IPv4 so both forms share one rate-limit bucket and block.
+/// Values that don't parse as an IP are returned trimmed but otherwise unchanged.
+pub fn normalize_ip(ip: &str) -> String {
+    let trimmed = ip.trim();
+    match trimmed.parse::<IpAddr>() {
+        Ok(addr) => canonical_ip(addr).to_string(),
+        Err(_) => trimmed.to_string(),
+    }
+}
+
+/// Unwrap IPv4-mapped IPv6 addresses into IPv4
+fn canonical_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
 pub fn get_client_ip(session: &mut Session) -> Option<String> {
+    get_client_ip_with_cloudflare(session, None)
+}
+
+/// Client IP with a per-domain Cloudflare override (None uses the global use_cloudflare)
+pub fn get_client_ip_with_cloudflare(session: &Session, use_cloudflare: Option<bool>) -> Option<String> {
+    get_raw_client_ip(session, use_cloudflare).map(|ip| normalize_ip(&ip))
+}
+
+/// Whether CF headers are trusted, given a per-domain override (None uses the global use_cloudflare)
+pub fn is_cloudflare_enabled(use_cloudflare: Option<bool>) -> bool {
+    use_cloudflare.unwrap_or_else(|| USE_CLOUDFLARE.load(Ordering::SeqCst))
+}
+
+fn get_raw_client_ip(session: &Session, use_cloudflare: Option<bool>) -> Option<String> {
+    let peer = peer_ip(session);
+    let client_ip_header = CLIENT_IP_HEADER.read().unwrap();
+    resolve_client_ip(
+        session.req_header(),
+        peer,
+        peer.map_or(false, is_trusted_proxy),
+        is_cloudflare_enabled(use_cloudflare),
+        client_ip_header.as_deref(),
+        TRUSTED_PROXY_HOPS.load(Ordering::SeqCst),
+    )
+}
+
+/// First value of a header, for comma-separated lists like X-Forwarded-For
+fn first_header_value(req: &RequestHeader, name: &str) -> Option<String> {
+    req.headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.split(',').next().map(|s| s.trim().to_string()))
+        .filter(|s| !s.is_empty())
+}
+
+/// X-Forwarded-For entry `hops` places from the right: the address our outermost
+/// proxy received the request from. None when the list is shorter than that
+///
+/// Repeated header lines form one list in order, as a proxy may append its own line
+/// after one sent by the client
+fn forwarded_for_hop(req: &RequestHeader, hops: usize) -> Option<String> {
+    let mut entries: Vec<&str> = Vec::new();
+    for line in req.headers.get_all("X-Forwarded-For") {
+        entries.extend(line.to_str().ok()?.split(',').map(str::trim));
+    }
+    let index = entries.len().checked_sub(hops)?;
+    Some(entries[index].to_string()).filter(|ip| ip.parse::<IpAddr>().is_ok())
+}
+
+fn resolve_client_ip(
+    req: &RequestHeader,
+    peer: Option<IpAddr>,
+    peer_trusted: bool,
+    use_cloudflare: bool,
+    client_ip_header: Option<&str>,
+    trusted_proxy_hops: usize,
+) -> Option<String> {
+    // An explicitly configured header wins, but only when set by a trusted proxy
+    if let Some(header) = client_ip_header {
+        if peer_trusted {
+            let ip = first_header_value(req, header).filter(|ip| ip.parse::<IpAddr>().is_ok());
+            if ip.is_some() {
+                return ip;
+            }
+        }
+    }
+
+    // With a known number of proxies in front, entries left of theirs are client-supplied
+    // and can't be trusted; a shorter list means the request bypassed them
+    if trusted_proxy_hops > 0 {
+        if let Some(ip) = forwarded_for_hop(req, trusted_proxy_hops) {
+            return Some(ip);
+        }
+        if let Some(ip) = peer {
+            return Some(canonical_ip(ip).to_string());
+        }
+    }
+
     // Check if we should use Cloudflare headers first
-    if USE_CLOUDFLARE.load(Ordering::SeqCst) {
+    if use_cloudflare {
         // Cloudflare proxy logic - prioritize CF-specific headers
-        let cf_ip = session.req_header().headers.get("CF-Connecting-IP")
+        let cf_ip = req.headers.get("CF-Connecting-IP")
             .and_then(|v| v.to_str().ok().map(|s| s.to_string()));
             
         if cf_ip.is_some() {
@@ -22,16 +169,14 @@ pub fn get_client_ip(session: &mut Session) -> Option<String> {
         }
         
         // Try X-Forwarded-For (Cloudflare sets this too)
-        let forwarded_ip = session.req_header().headers.get("X-Forwarded-For")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|s| s.split(',').next().map(|s| s.trim().to_string()));
+        let forwarded_ip = first_header_value(req, "X-Forwarded-For");
             
         if forwarded_ip.is_some() {
             return forwarded_ip;
         }
         
         // Try True-Client-IP (another Cloudflare header)
-        let true_client_ip = session.req_header().headers.get("True-Client-IP")
+        let true_client_ip = req.headers.get("True-Client-IP")
             .and_then(|v| v.to_str().ok().map(|s| s.to_string()));
             
         if true_client_ip.is_some() {
@@ -40,26 +185,129 @@ pub fn get_client_ip(session: &mut Session) -> Option<String> {
     }
     
     // If not using Cloudflare or CF headers weren't found, try direct client address
-    if let Some(addr) = session.client_addr() {
-        let ip = addr.to_string().split(':').next().unwrap_or("127.0.0.1").to_string();
-        return Some(ip);
+    if let Some(ip) = peer {
+        return Some(canonical_ip(ip).to_string());
     }
 
     // Standard fallback headers for any proxy
-    let real_ip = session.req_header().headers.get("X-Real-IP")
+    let real_ip = req.headers.get("X-Real-IP")
         .and_then(|v| v.to_str().ok().map(|s| s.to_string()));
         
     if real_ip.is_some() {
         return real_ip;
     }
     
-    let forwarded_ip = session.req_header().headers.get("X-Forwarded-For")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.split(',').next().map(|s| s.trim().to_string()));
+    let forwarded_ip = first_header_value(req, "X-Forwarded-For");
         
     if forwarded_ip.is_some() {
         return forwarded_ip;
     }
 
     Some("127.0.0.1".to_string())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv4_mapped_ipv6_normalizes_to_ipv4() {
+        assert_eq!(normalize_ip("::ffff:192.0.2.1"), "192.0.2.1");
+        assert_eq!(normalize_ip("::ffff:192.0.2.1"), normalize_ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn test_normalize_keeps_other_addresses() {
+        assert_eq!(normalize_ip(" 192.0.2.1 "), "192.0.2.1");
+        assert_eq!(normalize_ip("2001:db8::1"), "2001:db8::1");
+        assert_eq!(normalize_ip("not-an-ip"), "not-an-ip");
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_configured_client_ip_header_is_used() {
+        let req = request(&[("Fastly-Client-IP", "203.0.113.7"), ("X-Real-IP", "198.51.100.1")]);
+        let peer = Some("10.0.0.2".parse().unwrap());
+
+        let ip = resolve_client_ip(&req, peer, true, false, Some("Fastly-Client-IP"), 0);
+        assert_eq!(ip.as_deref(), Some("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_configured_client_ip_header_requires_trusted_proxy() {
+        let req = request(&[("Fastly-Client-IP", "203.0.113.7")]);
+        let peer = Some("10.0.0.2".parse().unwrap());
+
+        let ip = resolve_client_ip(&req, peer, false, false, Some("Fastly-Client-IP"), 0);
+        assert_eq!(ip.as_deref(), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_unconfigured_keeps_builtin_precedence() {
+        let req = request(&[
+            ("CF-Connecting-IP", "203.0.113.9"),
+            ("True-Client-IP", "203.0.113.8"),
+            ("X-Forwarded-For", "198.51.100.2, 10.0.0.1"),
+        ]);
+        let peer = Some("10.0.0.2".parse().unwrap());
+
+        assert_eq!(resolve_client_ip(&req, peer, true, true, None, 0).as_deref(), Some("203.0.113.9"));
+        assert_eq!(resolve_client_ip(&req, peer, true, false, None, 0).as_deref(), Some("10.0.0.2"));
+        assert_eq!(resolve_client_ip(&req, None, false, false, None, 0).as_deref(), Some("198.51.100.2"));
+    }
+
+    #[test]
+    fn test_cf_headers_only_honored_for_cloudflare_domain() {
+        let req = request(&[("CF-Connecting-IP", "203.0.113.9")]);
+        let peer = Some("10.0.0.2".parse().unwrap());
+
+        // Domain behind Cloudflare
+        assert_eq!(resolve_client_ip(&req, peer, false, true, None, 0).as_deref(), Some("203.0.113.9"));
+        // Domain hit directly: a client-supplied CF header is ignored
+        assert_eq!(resolve_client_ip(&req, peer, false, false, None, 0).as_deref(), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_trusted_proxy_hops_counts_from_the_right() {
+        // Client spoofs an entry; LB1 appends the client, LB2 appends LB1
+        let req = request(&[("X-Forwarded-For", "6.6.6.6, 203.0.113.7, 10.0.0.1")]);
+        let peer = Some("10.0.0.2".parse().unwrap());
+
+        assert_eq!(resolve_client_ip(&req, peer, false, false, None, 2).as_deref(), Some("203.0.113.7"));
+        assert_eq!(resolve_client_ip(&req, peer, false, false, None, 1).as_deref(), Some("10.0.0.1"));
+        // Takes precedence over CF headers once configured
+        assert_eq!(resolve_client_ip(&req, peer, false, true, None, 2).as_deref(), Some("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_trusted_proxy_hops_spans_repeated_header_lines() {
+        // Client sends its own X-Forwarded-For line; LB1 appends a second one, LB2 extends it
+        let mut req = request(&[("X-Forwarded-For", "6.6.6.6")]);
+        req.append_header("X-Forwarded-For", "203.0.113.7, 10.0.0.1").unwrap();
+        let peer = Some("10.0.0.2".parse().unwrap());
+
+        assert_eq!(resolve_client_ip(&req, peer, false, false, None, 2).as_deref(), Some("203.0.113.7"));
+        assert_eq!(resolve_client_ip(&req, peer, false, false, None, 3).as_deref(), Some("6.6.6.6"));
+
+        // LB1's line is the last one, whatever the client put in its own
+        let mut req = request(&[("X-Forwarded-For", "6.6.6.6, 7.7.7.7, 8.8.8.8")]);
+        req.append_header("X-Forwarded-For", "203.0.113.7").unwrap();
+        assert_eq!(resolve_client_ip(&req, peer, false, false, None, 1).as_deref(), Some("203.0.113.7"));
+    }
+
+    #[test]
+    fn test_short_forwarded_for_falls_back_to_peer() {
+        let req = request(&[("X-Forwarded-For", "203.0.113.7")]);
+        let peer = Some("10.0.0.2".parse().unwrap());
+
+        assert_eq!(resolve_client_ip(&req, peer, false, false, None, 2).as_deref(), Some("10.0.0.2"));
+        assert_eq!(resolve_client_ip(&request(&[]), peer, false, true, None, 2).as_deref(), Some("10.0.0.2"));
+    }
+}
@@ -1,3 +1,8 @@
 pub mod ip;
 pub mod cloudflare;
 pub mod useragent;
+pub mod scheme;
+pub mod host;
+pub mod path;
+pub mod sampler;
+pub mod secret;
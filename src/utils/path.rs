@@ -0,0 +1,201 @@
+use pingora_http::RequestHeader;
+use std::borrow::Cow;
+
+/// Canonical form of a request path as forwarded: percent-escapes of unreserved
+/// characters decoded, duplicate slashes collapsed and `.` / `..` segments resolved,
+/// never climbing above the root
+///
+/// `//admin`, `/./admin`, `/api/../admin` and `/api/%2e%2e/admin` all become `/admin`.
+/// `%2F` and other escapes (`%20`, `%25`, ...) are kept as sent, since an upstream may
+/// treat an encoded slash as part of a segment; `routing_path` decodes it for matching.
+/// A trailing slash is kept. Paths that don't start with `/` (e.g. `*` of `OPTIONS *`)
+/// are left alone.
+pub fn normalize_path(path: &str) -> Cow<'_, str> {
+    if !path.starts_with('/') {
+        return Cow::Borrowed(path);
+    }
+    let decoded = decode_unreserved(path);
+    if !needs_normalization(&decoded) {
+        return decoded;
+    }
+    let path: &str = &decoded;
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    let trailing_slash = path.ends_with('/') || path.ends_with("/.") || path.ends_with("/..");
+    if segments.is_empty() || trailing_slash {
+        normalized.push('/');
+    }
+    Cow::Owned(normalized)
+}
+
+fn needs_normalization(path: &str) -> bool {
+    path.contains("//") || path.split('/').any(|segment| segment == "." || segment == "..")
+}
+
+/// Path used to pick the route and key limits: the normalized path with `%2F` also
+/// decoded, so `/api%2f..%2fadmin` is matched and limited as `/admin` even though
+/// it is forwarded with its escapes
+pub fn routing_path(path: &str) -> Cow<'_, str> {
+    let normalized = normalize_path(path);
+    let decoded = match decode_escapes(&normalized, |byte| byte == b'/') {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(decoded) => Some(decoded),
+    };
+    match decoded {
+        Some(decoded) => Cow::Owned(normalize_path(&decoded).into_owned()),
+        None => normalized,
+    }
+}
+
+/// Decode `%XX` escapes of unreserved characters (RFC 3986 section 2.3)
+fn decode_unreserved(path: &str) -> Cow<'_, str> {
+    decode_escapes(path, |byte| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte))
+}
+
+fn decode_escapes(path: &str, decode: impl Fn(u8) -> bool) -> Cow<'_, str> {
+    let mut decoded = String::new();
+    let mut copied = 0;
+    for (i, _) in path.match_indices('%') {
+        let hex = match path.get(i + 1..i + 3) {
+            Some(hex) if hex.bytes().all(|b| b.is_ascii_hexdigit()) => hex,
+            _ => continue,
+        };
+        let byte = u8::from_str_radix(hex, 16).unwrap_or_default();
+        if decode(byte) {
+            decoded.push_str(&path[copied..i]);
+            decoded.push(byte as char);
+            copied = i + 3;
+        }
+    }
+
+    if copied == 0 {
+        return Cow::Borrowed(path);
+    }
+    decoded.push_str(&path[copied..]);
+    Cow::Owned(decoded)
+}
+
+/// Rewrite the request URI to its normalized path (query untouched); true if it changed
+pub fn normalize_request_path(req: &mut RequestHeader) -> bool {
+    let normalized = match normalize_path(req.uri.path()) {
+        Cow::Borrowed(_) => return false,
+        Cow::Owned(normalized) => normalized,
+    };
+    let path_and_query = match req.uri.query() {
+        Some(query) => format!("{}?{}", normalized, query),
+        None => normalized,
+    };
+
+    let mut parts = req.uri.clone().into_parts();
+    parts.path_and_query = match path_and_query.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return false,
+    };
+    match http::Uri::from_parts(parts) {
+        Ok(uri) => {
+            req.set_uri(uri);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpstreamRoute;
+    use crate::proxy::upstream::find_matching_route;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/api//users"), "/api/users");
+        assert_eq!(normalize_path("/api/./users"), "/api/users");
+        assert_eq!(normalize_path("/api/../admin"), "/admin");
+        assert_eq!(normalize_path("//admin"), "/admin");
+        assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/api/users/"), "/api/users/");
+        assert_eq!(normalize_path("/api/.."), "/");
+        assert_eq!(normalize_path("/api/v1/.."), "/api/");
+        // Dots inside a segment are not dot segments
+        assert_eq!(normalize_path("/files/..hidden/a.b"), "/files/..hidden/a.b");
+        assert_eq!(normalize_path("*"), "*");
+        assert!(matches!(normalize_path("/api/users"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_percent_encoded_dot_segments_are_resolved() {
+        assert_eq!(normalize_path("/api/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize_path("/api/%2E%2E/admin"), "/admin");
+        assert_eq!(normalize_path("/api/.%2e/admin"), "/admin");
+        assert_eq!(normalize_path("/api/%2e/users"), "/api/users");
+        // Unreserved characters are decoded even without dot segments
+        assert_eq!(normalize_path("/%61dmin"), "/admin");
+        assert_eq!(normalize_path("/files/%2e%2ehidden"), "/files/..hidden");
+    }
+
+    #[test]
+    fn test_encoded_slashes_are_forwarded_but_decoded_for_routing() {
+        assert!(matches!(normalize_path("/files/a%2Fb"), Cow::Borrowed(_)));
+        assert_eq!(normalize_path("/api%2f..%2fadmin"), "/api%2f..%2fadmin");
+        assert_eq!(normalize_path("/api/%2e%2e/files/a%2Fb"), "/files/a%2Fb");
+
+        assert_eq!(routing_path("/files/a%2Fb"), "/files/a/b");
+        assert_eq!(routing_path("/api%2f..%2fadmin"), "/admin");
+        assert_eq!(routing_path("/api%2F%2Fusers"), "/api/users");
+        // Decoded once: an escaped escape stays an escape
+        assert_eq!(routing_path("/api%252F..%252Fadmin"), "/api%252F..%252Fadmin");
+        assert!(matches!(routing_path("/api/users"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_other_escapes_are_kept() {
+        assert!(matches!(normalize_path("/search/a%20b"), Cow::Borrowed(_)));
+        assert!(matches!(normalize_path("/files/100%25"), Cow::Borrowed(_)));
+        // Not an escape at all
+        assert!(matches!(normalize_path("/files/100%"), Cow::Borrowed(_)));
+        assert!(matches!(normalize_path("/files/%zz"), Cow::Borrowed(_)));
+        assert_eq!(normalize_path("/a%20b/%2e%2e/admin"), "/admin");
+    }
+
+    #[test]
+    fn test_request_is_rewritten_and_routed_by_normalized_path() {
+        let route = |path: &str| UpstreamRoute { path: path.to_string(), upstream: format!("{}:8000", &path[1..]).into(), ..Default::default() };
+        let routes = vec![route("/api"), route("/admin")];
+
+        let mut req = RequestHeader::build("GET", b"/api/../admin?tab=users", None).unwrap();
+        assert!(normalize_request_path(&mut req));
+        assert_eq!(req.uri.path(), "/admin");
+        assert_eq!(req.uri.query(), Some("tab=users"));
+
+        let matched = find_matching_route(&routes, req.uri.path(), None, None).unwrap();
+        assert_eq!(matched.path, "/admin");
+
+        let mut req = RequestHeader::build("GET", b"/api/%2e%2e/admin", None).unwrap();
+        assert!(normalize_request_path(&mut req));
+        assert_eq!(find_matching_route(&routes, req.uri.path(), None, None).unwrap().path, "/admin");
+
+        let mut req = RequestHeader::build("GET", b"/api%2f..%2fadmin", None).unwrap();
+        assert!(!normalize_request_path(&mut req));
+        assert_eq!(req.uri.path(), "/api%2f..%2fadmin");
+        assert_eq!(find_matching_route(&routes, &routing_path(req.uri.path()), None, None).unwrap().path, "/admin");
+
+        let mut req = RequestHeader::build("GET", b"/api/users", None).unwrap();
+        assert!(!normalize_request_path(&mut req));
+        assert_eq!(req.uri.path(), "/api/users");
+    }
+}
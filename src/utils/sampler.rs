@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Deterministic percentage sampler
+///
+/// Instead of rolling a random number per request, count requests and pick
+/// exactly `percentage`% of them, spread evenly (e.g. 25% = every 4th request).
+#[derive(Debug, Default)]
+pub struct Sampler {
+    counter: AtomicU64,
+}
+
+impl Sampler {
+    pub const fn new() -> Self {
+        Self { counter: AtomicU64::new(0) }
+    }
+
+    /// Whether the next request should be sampled
+    pub fn sample(&self, percentage: f64) -> bool {
+        if percentage <= 0.0 {
+            return false;
+        }
+        if percentage >= 100.0 {
+            return true;
+        }
+
+        let n = self.counter.fetch_add(1, Ordering::Relaxed) as f64;
+        // Sampled when the running total of selected requests ticks over
+        ((n + 1.0) * percentage / 100.0).floor() > (n * percentage / 100.0).floor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampler_picks_configured_percentage() {
+        let sampler = Sampler::new();
+        let sampled = (0..1000).filter(|_| sampler.sample(10.0)).count();
+        assert_eq!(sampled, 100);
+
+        let sampler = Sampler::new();
+        let sampled = (0..1000).filter(|_| sampler.sample(12.5)).count();
+        assert_eq!(sampled, 125);
+    }
+
+    #[test]
+    fn test_sampler_bounds() {
+        let sampler = Sampler::new();
+        assert!((0..100).all(|_| sampler.sample(100.0)));
+        assert!((0..100).all(|_| !sampler.sample(0.0)));
+    }
+}
@@ -0,0 +1,83 @@
+// src/utils/scheme.rs
+use pingora_http::RequestHeader;
+use pingora_proxy::Session;
+use crate::utils::ip::{is_trusted_proxy, peer_ip};
+
+/// Determine the scheme the client originally used to reach us
+///
+/// A TLS connection terminated by pingwall is always "https". Otherwise the
+/// `X-Forwarded-Proto` header is honored, but only when the connection comes
+/// from a trusted proxy - anyone else could simply send the header themselves.
+pub fn request_scheme(session: &Session) -> &'static str {
+    let is_tls = session
+        .digest()
+        .map_or(false, |digest| digest.ssl_digest.is_some());
+    let from_trusted_proxy = peer_ip(session).map_or(false, is_trusted_proxy);
+
+    resolve_scheme(session.req_header(), is_tls, from_trusted_proxy)
+}
+
+/// Resolve the original scheme from the connection and request headers
+pub fn resolve_scheme(req: &RequestHeader, is_tls: bool, from_trusted_proxy: bool) -> &'static str {
+    if is_tls {
+        return "https";
+    }
+
+    if from_trusted_proxy {
+        // With several proxies in the chain the first value is the client-facing one
+        let forwarded_https = req.headers
+            .get("x-forwarded-proto")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|s| s.split(',').next())
+            .map_or(false, |proto| proto.trim().eq_ignore_ascii_case("https"));
+
+        if forwarded_https {
+            return "https";
+        }
+    }
+
+    "http"
+}
+
+/// Check whether a plain HTTP request should be redirected to HTTPS
+pub fn needs_https_redirect(scheme: &str, redirect_enabled: bool) -> bool {
+    redirect_enabled && scheme != "https"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_proto(proto: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(value) = proto {
+            req.insert_header("X-Forwarded-Proto", value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_trusted_forwarded_proto_suppresses_redirect() {
+        let req = request_with_proto(Some("https"));
+        let scheme = resolve_scheme(&req, false, true);
+
+        assert_eq!(scheme, "https");
+        assert!(!needs_https_redirect(scheme, true));
+    }
+
+    #[test]
+    fn test_untrusted_forwarded_proto_is_ignored() {
+        let req = request_with_proto(Some("https"));
+        let scheme = resolve_scheme(&req, false, false);
+
+        assert_eq!(scheme, "http");
+        assert!(needs_https_redirect(scheme, true));
+    }
+
+    #[test]
+    fn test_tls_connection_is_https() {
+        let req = request_with_proto(None);
+        assert_eq!(resolve_scheme(&req, true, false), "https");
+        assert_eq!(resolve_scheme(&request_with_proto(Some("http")), false, true), "http");
+    }
+}
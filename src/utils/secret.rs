@@ -0,0 +1,21 @@
+use pingora_core::tls::memcmp;
+
+/// Compare a secret (token, MAC) without short-circuiting on the first differing byte
+///
+/// Only the length leaks, which is fine for tokens of a fixed, configured value.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret-longer"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
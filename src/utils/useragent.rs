@@ -2,6 +2,13 @@
 use pingora_proxy::Session;
 use woothee::parser::{Parser, WootheeResult};
 use log::debug;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Compiled `/regex/` User-Agent patterns, keyed by pattern (None if it doesn't compile)
+static UA_REGEX_CACHE: Lazy<RwLock<HashMap<String, Option<Regex>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
 
 /// User-Agent classification category
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -224,6 +231,50 @@ fn classify_fallback(user_agent: &str) -> UserAgentCategory {
     }
 }
 
+/// Check whether a User-Agent belongs to a health checker (case-insensitive substring match)
+pub fn is_health_check_user_agent(user_agent: &str, patterns: &[String]) -> bool {
+    if user_agent.is_empty() {
+        return false;
+    }
+    let ua_lower = user_agent.to_lowercase();
+    patterns
+        .iter()
+        .any(|pattern| !pattern.is_empty() && ua_lower.contains(&pattern.to_lowercase()))
+}
+
+/// Check a User-Agent against one pattern: a case-insensitive substring, or a regex written as `/pattern/`
+///
+/// A regex that does not compile never matches (see `user_agent_pattern_error`).
+pub fn user_agent_matches(user_agent: &str, pattern: &str) -> bool {
+    match regex_body(pattern) {
+        Some(body) => compiled_regex(body).map_or(false, |re| re.is_match(user_agent)),
+        None => !pattern.is_empty() && user_agent.to_lowercase().contains(&pattern.to_lowercase()),
+    }
+}
+
+/// Why a `/regex/` User-Agent pattern is unusable, for load-time warnings
+pub fn user_agent_pattern_error(pattern: &str) -> Option<String> {
+    regex_body(pattern).and_then(|body| Regex::new(body).err()).map(|e| e.to_string())
+}
+
+fn regex_body(pattern: &str) -> Option<&str> {
+    pattern
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+        .filter(|body| !body.is_empty())
+}
+
+fn compiled_regex(body: &str) -> Option<Regex> {
+    if let Some(cached) = UA_REGEX_CACHE.read().ok().and_then(|cache| cache.get(body).cloned()) {
+        return cached;
+    }
+    let compiled = Regex::new(body).ok();
+    if let Ok(mut cache) = UA_REGEX_CACHE.write() {
+        cache.insert(body.to_string(), compiled.clone());
+    }
+    compiled
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -256,4 +307,27 @@ mod tests {
         assert_eq!(UserAgentCategory::Chrome.as_str(), "chrome");
         assert_eq!(UserAgentCategory::Curl.as_str(), "curl");
     }
+
+    #[test]
+    fn test_health_check_user_agent() {
+        let patterns = vec!["kube-probe".to_string(), "ELB-HealthChecker".to_string()];
+
+        assert!(is_health_check_user_agent("kube-probe/1.29", &patterns));
+        assert!(is_health_check_user_agent("ELB-HealthChecker/2.0", &patterns));
+        assert!(!is_health_check_user_agent("curl/7.68.0", &patterns));
+        assert!(!is_health_check_user_agent("", &patterns));
+        assert!(!is_health_check_user_agent("kube-probe/1.29", &[]));
+    }
+
+    #[test]
+    fn test_user_agent_pattern_substring_and_regex() {
+        assert!(user_agent_matches("InternalClient/2.3 (build 41)", "internalclient"));
+        assert!(user_agent_matches("InternalClient/2.3 (build 41)", r"/^InternalClient/\d+\.\d+/"));
+        assert!(!user_agent_matches("curl/7.68.0", r"/^InternalClient/"));
+        assert!(!user_agent_matches("curl/7.68.0", ""));
+        // An unclosed group never matches and is reported
+        assert!(!user_agent_matches("anything", "/(/"));
+        assert!(user_agent_pattern_error("/(/").is_some());
+        assert!(user_agent_pattern_error("plain substring").is_none());
+    }
 }
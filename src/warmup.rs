@@ -0,0 +1,146 @@
+use crate::config::{UpstreamRoute, WarmupConfig, WarmupStep};
+use crate::proxy::sni_handler;
+use async_trait::async_trait;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Set once warmup has finished; `/ready` and the proxy gate read it
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Whether startup warmup has finished and traffic should be accepted
+pub fn is_ready() -> bool {
+    READY.load(Ordering::SeqCst)
+}
+
+pub fn set_ready(ready: bool) {
+    READY.store(ready, Ordering::SeqCst);
+}
+
+/// Runs the configured warmup steps once at startup, then marks the proxy ready
+///
+/// Until then the metrics server answers `/ready` with 503 and the proxy rejects
+/// requests with 503. Failed steps are logged but do not keep the proxy unready,
+/// so one bad upstream cannot wedge a rolling deploy.
+pub struct WarmupService {
+    config: WarmupConfig,
+    routes: Vec<UpstreamRoute>,
+}
+
+impl WarmupService {
+    pub fn new(config: WarmupConfig, routes: Vec<UpstreamRoute>) -> Self {
+        Self { config, routes }
+    }
+
+    pub async fn run(&self) {
+        for step in &self.config.steps {
+            log::info!("Warmup: {}", step.as_str());
+            match step {
+                WarmupStep::ResolveUpstreams => self.resolve_upstreams().await,
+                WarmupStep::LoadCerts => self.load_certs(),
+                WarmupStep::ProbeUpstreams => self.probe_upstreams().await,
+            }
+        }
+
+        if self.config.delay_secs > 0 {
+            log::info!("Warmup: waiting {}s before accepting traffic", self.config.delay_secs);
+            tokio::time::sleep(Duration::from_secs(self.config.delay_secs)).await;
+        }
+
+        set_ready(true);
+        log::info!("Warmup complete, accepting traffic");
+    }
+
+    async fn resolve_upstreams(&self) {
+        for authority in upstream_authorities(&self.routes) {
+            if let Err(e) = tokio::net::lookup_host(authority.as_str()).await {
+                log::warn!("Warmup: failed to resolve upstream {}: {}", authority, e);
+            }
+        }
+    }
+
+    fn load_certs(&self) {
+        for ssl in self.routes.iter().filter_map(|route| route.ssl.as_ref()) {
+            if let Err(e) = sni_handler::load_cert_bytes(&ssl.cert_path, &ssl.key_path) {
+                log::warn!("Warmup: {}", e);
+            }
+        }
+    }
+
+    async fn probe_upstreams(&self) {
+        let timeout = Duration::from_millis(self.config.probe_timeout_ms);
+        for authority in upstream_authorities(&self.routes) {
+            match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(authority.as_str())).await {
+                Ok(Ok(_)) => log::debug!("Warmup: upstream {} is reachable", authority),
+                Ok(Err(e)) => log::warn!("Warmup: upstream {} is unreachable: {}", authority, e),
+                Err(_) => log::warn!("Warmup: upstream {} did not answer within {:?}", authority, timeout),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for WarmupService {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        self.run().await;
+    }
+}
+
+/// Distinct `host:port` of every route upstream
+fn upstream_authorities(routes: &[UpstreamRoute]) -> Vec<String> {
+    let mut authorities = Vec::new();
+    for upstream in routes.iter().flat_map(|route| route.upstream.addrs()) {
+        if let Some(authority) = upstream_authority(upstream) {
+            if !authorities.contains(&authority) {
+                authorities.push(authority);
+            }
+        }
+    }
+    authorities
+}
+
+/// `host:port` of an upstream written as a URL or as `host:port[/path][?query]`
+fn upstream_authority(upstream: &str) -> Option<String> {
+    if upstream.starts_with("http://") || upstream.starts_with("https://") {
+        let url = url::Url::parse(upstream).ok()?;
+        let port = url.port_or_known_default()?;
+        return Some(format!("{}:{}", url.host_str()?, port));
+    }
+    let authority = upstream.split(['/', '?']).next()?;
+    (!authority.is_empty()).then(|| authority.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::metrics_handler;
+    use hyper::{Body, Request, StatusCode};
+
+    #[test]
+    fn test_upstream_authority() {
+        assert_eq!(upstream_authority("https://api.example.com/v1").as_deref(), Some("api.example.com:443"));
+        assert_eq!(upstream_authority("127.0.0.1:9992/base?x=1").as_deref(), Some("127.0.0.1:9992"));
+    }
+
+    #[test]
+    fn test_ready_endpoint_flips_after_warmup() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let ready_status = || {
+            let req = Request::builder().uri("/ready").body(Body::empty()).unwrap();
+            rt.block_on(metrics_handler(req)).unwrap().status()
+        };
+
+        set_ready(false);
+        assert_eq!(ready_status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let config = WarmupConfig {
+            steps: vec![WarmupStep::ResolveUpstreams],
+            ..Default::default()
+        };
+        let routes = vec![UpstreamRoute { upstream: "127.0.0.1:9992".into(), ..Default::default() }];
+        rt.block_on(WarmupService::new(config, routes).run());
+
+        assert_eq!(ready_status(), StatusCode::OK);
+    }
+}
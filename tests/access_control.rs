@@ -0,0 +1,39 @@
+mod common;
+
+#[test]
+fn test_uncounted_requests_still_meet_access_rules() {
+    let rt = common::runtime();
+    // Never reached: every request below is rejected before the upstream is picked
+    let app = common::proxy(
+        r#"
+health_check_user_agents: ["kube-probe"]
+domains:
+  - domain: access.example.com
+    routers:
+      - path: /internal
+        upstream: http://127.0.0.1:9
+        max_req_per_window: -1
+        advanced_limits:
+          allow_user_agents: ["InternalClient/"]
+      - path: /private
+        upstream: http://127.0.0.1:9
+        max_req_per_window: -1
+        advanced_limits:
+          block_cidrs: ["127.0.0.0/8"]
+"#,
+    );
+    let request = |path: &str, user_agent: &str| {
+        format!("GET {} HTTP/1.1\r\nHost: access.example.com\r\nUser-Agent: {}\r\n\r\n", path, user_agent)
+    };
+
+    // Unlimited route: the allowlist still applies, also to the health-check User-Agent
+    let response = rt.block_on(common::exchange(&app, request("/internal", "curl/8.0").as_bytes()));
+    assert_eq!(response.status, 403);
+    let response = rt.block_on(common::exchange(&app, request("/internal", "kube-probe/1.29").as_bytes()));
+    assert_eq!(response.status, 403);
+
+    // Unlimited route: blocked networks still apply
+    let response = rt.block_on(common::exchange(&app, request("/private", "curl/8.0").as_bytes()));
+    assert_eq!(response.status, 429);
+    assert_eq!(response.header("x-rate-limit-status"), Some("Blocked"));
+}
@@ -0,0 +1,107 @@
+//! Harness for tests that run requests through the proxy: a stub upstream on a
+//! random port and a `ReverseProxy` served over a real TCP connection
+
+#![allow(dead_code)]
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use pingora_core::apps::ServerApp;
+use pingora_core::protocols::l4::socket::SocketDigest;
+use pingora_core::protocols::l4::stream::Stream as L4Stream;
+use pingora_core::server::configuration::ServerConf;
+use pingora_proxy::{http_proxy, HttpProxy};
+use pingwall::{Config, ReverseProxy};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::runtime::Runtime;
+
+pub fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+/// Serve `handler` on a random localhost port
+pub fn spawn_upstream<F, Fut>(rt: &Runtime, handler: F) -> SocketAddr
+where
+    F: Fn(Request<Body>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<Response<Body>, hyper::Error>> + Send + 'static,
+{
+    let server = rt.block_on(async {
+        hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move { Ok::<_, hyper::Error>(service_fn(handler)) }
+        }))
+    });
+    let addr = server.local_addr();
+    rt.spawn(server);
+    addr
+}
+
+/// Proxy app for a YAML config, with the process-wide state initialized from it
+pub fn proxy(yaml: &str) -> Arc<HttpProxy<ReverseProxy>> {
+    let config: Config = serde_yaml::from_str(yaml).unwrap();
+    pingwall::init_globals(&config);
+
+    let proxy = ReverseProxy::new(
+        config.block_url.clone(),
+        config.api_key.clone(),
+        config.upstream_addr.clone().unwrap_or_else(|| "127.0.0.1:9".to_string()),
+        config.clone(),
+    )
+    .with_routes(config.domain_routes());
+    Arc::new(http_proxy(&Arc::new(ServerConf::default()), proxy))
+}
+
+/// A response as read off the wire; header names are lowercased
+pub struct Exchange {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Exchange {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Send a raw HTTP/1.1 request through the proxy on a fresh connection and read the
+/// response (its body by Content-Length)
+pub async fn exchange(app: &Arc<HttpProxy<ReverseProxy>>, request: &[u8]) -> Exchange {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let request = request.to_vec();
+    let client = tokio::spawn(async move {
+        let mut stream = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        stream.get_mut().write_all(&request).await.unwrap();
+
+        let mut status_line = String::new();
+        stream.read_line(&mut status_line).await.unwrap();
+        let status = status_line.split_whitespace().nth(1).expect("no status line").parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let length = headers.iter().find(|(name, _)| name == "content-length").map_or(0, |(_, v)| v.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        Exchange { status, headers, body }
+    });
+
+    let (accepted, _) = listener.accept().await.unwrap();
+    // As the listener does: the digest resolves peer and local address from the socket
+    let mut stream = L4Stream::from(accepted);
+    stream.set_socket_digest(SocketDigest::from_raw_fd(stream.as_raw_fd()));
+    let (_shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+    app.process_new(Box::new(stream), &shutdown).await;
+    client.await.unwrap()
+}
@@ -0,0 +1,39 @@
+use pingwall::config::{DomainConfig, Router};
+use pingwall::{Config, ReverseProxy};
+
+#[test]
+fn test_build_proxy_through_public_api() {
+    let config = Config {
+        upstream_addr: Some("127.0.0.1:9000".to_string()),
+        domains: vec![DomainConfig {
+            domain: "embed.example.com".to_string(),
+            routers: vec![Router {
+                name: Some("api".to_string()),
+                path: "/api".to_string(),
+                upstream: "http://127.0.0.1:9001".into(),
+                max_req_per_window: 10,
+                block_duration_secs: 60,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+        ..Config::default()
+    };
+    pingwall::init_globals(&config);
+
+    let routes = config.domain_routes();
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].domain.as_deref(), Some("embed.example.com"));
+    assert_eq!(routes[0].route_label(), "api");
+
+    let proxy = ReverseProxy::new(
+        config.block_url.clone(),
+        config.api_key.clone(),
+        config.upstream_addr.clone().unwrap(),
+        config.clone(),
+    )
+    .with_routes(routes);
+
+    assert_eq!(proxy.routes.len(), 1);
+    assert_eq!(proxy.upstream_addr, "127.0.0.1:9000");
+}
@@ -0,0 +1,73 @@
+use pingwall::config::MetricsBindFailure;
+use pingwall::metrics::MetricsService;
+use pingwall::{metrics_only_services, Config};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/// A port held open for the whole test, so binding it fails
+fn taken_port() -> (TcpListener, u16) {
+    let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    (listener, port)
+}
+
+/// Backoff between the bind attempts: 250ms, 500ms, 1s
+const RETRY_TIME: Duration = Duration::from_millis(250 + 500 + 1000);
+
+#[test]
+fn test_bind_failure_mode_is_configurable() {
+    let config: Config = serde_yaml::from_str("metrics_bind_failure: fatal\n").unwrap();
+    assert_eq!(config.metrics_bind_failure, MetricsBindFailure::Fatal);
+    let config: Config = serde_yaml::from_str("metrics_port: 9090\n").unwrap();
+    assert_eq!(config.metrics_bind_failure, MetricsBindFailure::Warn);
+}
+
+#[test]
+fn test_bind_conflict_under_warn_continues_without_metrics() {
+    let (_listener, port) = taken_port();
+
+    let started = Instant::now();
+    let metrics = MetricsService::bind(port, MetricsBindFailure::Warn).unwrap();
+
+    assert!(metrics.is_none());
+    assert!(started.elapsed() >= RETRY_TIME);
+}
+
+#[test]
+fn test_bind_conflict_under_fatal_fails_startup() {
+    let (_listener, port) = taken_port();
+
+    let started = Instant::now();
+    let err = MetricsService::bind(port, MetricsBindFailure::Fatal).err().expect("bind conflict was not reported");
+
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    assert!(started.elapsed() >= RETRY_TIME);
+
+    // The same error fails a metrics_only process before it starts serving
+    let config: Config = serde_yaml::from_str(&format!("mode: metrics_only\nmetrics_port: {}\nmetrics_bind_failure: fatal\n", port)).unwrap();
+    assert!(metrics_only_services(&config).is_err());
+}
+
+#[test]
+fn test_port_freed_during_the_retries_is_served() {
+    let (listener, port) = taken_port();
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(400));
+        drop(listener);
+    });
+
+    let metrics = MetricsService::bind(port, MetricsBindFailure::Fatal).unwrap().expect("port was freed");
+    release.join().unwrap();
+    assert_eq!(metrics.local_addr().port(), port);
+
+    let rt = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+    rt.spawn(async move { metrics.serve().await });
+
+    // The listener is already bound, so the first connection is accepted
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.0\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.0 200") || response.starts_with("HTTP/1.1 200"), "{}", response);
+}
@@ -0,0 +1,60 @@
+use pingora_core::server::ShutdownWatch;
+use pingwall::config::RunMode;
+use pingwall::{metrics_only_services, Config};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Send a request to a local port, retrying while the service comes up
+fn get(port: u16, path: &str) -> String {
+    let mut stream = (0..50)
+        .find_map(|_| {
+            std::thread::sleep(Duration::from_millis(20));
+            TcpStream::connect(("127.0.0.1", port)).ok()
+        })
+        .unwrap_or_else(|| panic!("nothing answers on port {}", port));
+    stream.write_all(format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_metrics_only_mode_binds_no_proxy_listener() {
+    let proxy_port = free_port();
+    let metrics_port = free_port();
+    let admin_port = free_port();
+    let config: Config = serde_yaml::from_str(&format!(
+        "mode: metrics_only\nport: {}\nmetrics_port: {}\nadmin:\n  port: {}\n  token: s3cret\n",
+        proxy_port, metrics_port, admin_port
+    ))
+    .unwrap();
+    assert_eq!(config.mode, RunMode::MetricsOnly);
+
+    // Only metrics and admin: no proxy service to open listeners
+    let services = metrics_only_services(&config).unwrap();
+    let names: Vec<&str> = services.iter().map(|service| service.name()).collect();
+    assert_eq!(names, ["metrics", "admin"]);
+
+    // Start them as the server would
+    let rt = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+    let (_shutdown_tx, shutdown): (_, ShutdownWatch) = tokio::sync::watch::channel(false);
+    for mut service in services {
+        let shutdown = shutdown.clone();
+        rt.spawn(async move { service.start_service(None, shutdown, 1).await });
+    }
+
+    let response = get(metrics_port, "/metrics");
+    assert!(response.starts_with("HTTP/1.0 200") || response.starts_with("HTTP/1.1 200"), "{}", response);
+    // The admin API is up too; without the token it answers 401
+    let response = get(admin_port, "/reload-certs");
+    assert!(response.starts_with("HTTP/1.0 401") || response.starts_with("HTTP/1.1 401"), "{}", response);
+
+    // Nothing listens on the proxy port
+    assert!(TcpStream::connect(("127.0.0.1", proxy_port)).is_err());
+    assert!(TcpListener::bind(("0.0.0.0", proxy_port)).is_ok());
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use hyper::{Body, Request, Response};
+
+/// Stub upstream that reports how the request body was framed and how long it was
+async fn framing_upstream(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let header = |name: &str| {
+        req.headers().get(name).map(|v| v.to_str().unwrap().to_string()).unwrap_or_else(|| "-".to_string())
+    };
+    let framing = format!("content-length={} transfer-encoding={}", header("content-length"), header("transfer-encoding"));
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    Ok(Response::new(Body::from(format!("{} body={}", framing, body.len()))))
+}
+
+fn buffered_proxy(upstream: std::net::SocketAddr) -> std::sync::Arc<pingora_proxy::HttpProxy<pingwall::ReverseProxy>> {
+    common::proxy(&format!(
+        r#"
+domains:
+  - domain: upload.example.com
+    routers:
+      - path: /upload
+        upstream: http://{}
+        request_buffering: buffer
+"#,
+        upstream
+    ))
+}
+
+#[test]
+fn test_chunked_upload_reaches_the_upstream_with_a_content_length() {
+    let rt = common::runtime();
+    let upstream = common::spawn_upstream(&rt, framing_upstream);
+    let app = buffered_proxy(upstream);
+
+    let response = rt.block_on(common::exchange(
+        &app,
+        b"POST /upload HTTP/1.1\r\nHost: upload.example.com\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+          5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+    ));
+
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"content-length=11 transfer-encoding=- body=11");
+}
+
+#[test]
+fn test_body_over_the_buffer_limit_is_rejected() {
+    let rt = common::runtime();
+    let upstream = common::spawn_upstream(&rt, framing_upstream);
+    let app = buffered_proxy(upstream);
+
+    let body = vec![b'x'; 64 * 1024 + 1];
+    let mut request = format!(
+        "POST /upload HTTP/1.1\r\nHost: upload.example.com\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+
+    let response = rt.block_on(common::exchange(&app, &request));
+    assert_eq!(response.status, 413);
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use hyper::{Body, Request, Response};
+use std::time::{Duration, Instant};
+
+/// Stub upstream that accepts the request and then goes silent
+async fn stalled_upstream(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    Ok(Response::new(Body::from("too late")))
+}
+
+#[test]
+fn test_silent_upstream_is_given_up_on_at_the_deadline() {
+    let rt = common::runtime();
+    let upstream = common::spawn_upstream(&rt, stalled_upstream);
+    // The read timeout alone would wait 30s
+    let app = common::proxy(&format!(
+        r#"
+domains:
+  - domain: deadline.example.com
+    routers:
+      - path: /api
+        upstream: http://{}
+        timeout_secs: 30
+        response_deadline_secs: 1
+"#,
+        upstream
+    ));
+
+    let start = Instant::now();
+    let response = rt.block_on(common::exchange(
+        &app,
+        b"GET /api/report HTTP/1.1\r\nHost: deadline.example.com\r\nConnection: close\r\n\r\n",
+    ));
+
+    assert_eq!(response.status, 504);
+    assert!(start.elapsed() < Duration::from_secs(5), "answered after {:?}", start.elapsed());
+}
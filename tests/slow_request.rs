@@ -0,0 +1,69 @@
+mod common;
+
+use hyper::{Body, Request, Response};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Warnings logged by the proxy in this test binary
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct WarningLog;
+
+impl log::Log for WarningLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Stub upstream answering after 300ms
+async fn slow_upstream(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    Ok(Response::new(Body::from("done")))
+}
+
+fn slow_request_warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap().iter().filter(|w| w.starts_with("Slow request:")).cloned().collect()
+}
+
+#[test]
+fn test_request_over_the_threshold_is_logged_as_slow() {
+    log::set_logger(&WarningLog).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let rt = common::runtime();
+    let upstream = common::spawn_upstream(&rt, slow_upstream);
+    let app = common::proxy(&format!(
+        r#"
+slow_request_threshold_ms: 100
+domains:
+  - domain: reports.example.com
+    routers:
+      - path: /reports
+        upstream: http://{}
+"#,
+        upstream
+    ));
+
+    let response = rt.block_on(common::exchange(
+        &app,
+        b"GET /reports/daily HTTP/1.1\r\nHost: reports.example.com\r\nConnection: close\r\n\r\n",
+    ));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"done");
+
+    // The logging hook has run by the time the connection is done
+    let warnings = slow_request_warnings();
+    assert_eq!(warnings.len(), 1, "{:?}", WARNINGS.lock().unwrap());
+    let warning = &warnings[0];
+    assert!(warning.starts_with("Slow request: GET reports.example.com/reports/daily -> upstream "), "{}", warning);
+    assert!(warning.ends_with("(threshold: 100ms)"), "{}", warning);
+    assert!(warning.contains(&upstream.to_string()), "{}", warning);
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use hyper::{Body, Request, Response};
+use std::time::Duration;
+
+/// Stub upstream that answers well after the route's 1s timeout
+async fn slow_upstream(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    Ok(Response::new(Body::from("too late")))
+}
+
+#[test]
+fn test_upstream_timeout_answers_with_the_configured_response() {
+    let rt = common::runtime();
+    let upstream = common::spawn_upstream(&rt, slow_upstream);
+    let app = common::proxy(&format!(
+        r#"
+domains:
+  - domain: slow.example.com
+    routers:
+      - path: /api
+        upstream: http://{}
+        timeout_secs: 1
+        timeout_response:
+          status: 503
+          body: '{{"error":"upstream busy"}}'
+          retry_after: 30
+"#,
+        upstream
+    ));
+
+    let response = rt.block_on(common::exchange(
+        &app,
+        b"GET /api/report HTTP/1.1\r\nHost: slow.example.com\r\nConnection: close\r\n\r\n",
+    ));
+
+    assert_eq!(response.status, 503);
+    assert_eq!(response.header("retry-after"), Some("30"));
+    assert_eq!(response.header("content-type"), Some("application/json"));
+    assert_eq!(response.body, br#"{"error":"upstream busy"}"#);
+}
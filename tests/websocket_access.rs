@@ -0,0 +1,28 @@
+mod common;
+
+use pingwall::ratelimit::limiter;
+
+#[test]
+fn test_blocked_ip_is_rejected_on_websocket_upgrade() {
+    let rt = common::runtime();
+    // Never reached: the request is rejected before the upstream is picked
+    let app = common::proxy(
+        r#"
+domains:
+  - domain: ws.example.com
+    routers:
+      - path: /socket
+        upstream: http://127.0.0.1:9
+"#,
+    );
+    limiter::block_ip_for("127.0.0.1", "/socket", Some("ws.example.com"), 300);
+
+    let response = rt.block_on(common::exchange(
+        &app,
+        b"GET /socket/chat HTTP/1.1\r\nHost: ws.example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+    ));
+
+    assert_eq!(response.status, 429);
+    assert_eq!(response.header("x-rate-limit-status"), Some("Blocked"));
+}